@@ -0,0 +1,206 @@
+use super::graph::Cfg;
+use crate::ir::gas::parser::IRInstruction;
+
+// Dominator tree, computed with the Cooper-Harvey-Kennedy iterative algorithm
+//
+// Internal function bodies are only reached through `Call`, so the graph can have
+// several roots: the entry block, every call target and any other block without
+// predecessors. They all hang off a virtual root, which keeps the tree a forest
+// of independent functions.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dominators {
+    idom: Vec<Option<usize>>,
+    reachable: Vec<bool>,
+}
+
+impl Dominators {
+    pub fn compute(ir: &[IRInstruction], cfg: &Cfg) -> Self {
+        let n = cfg.len();
+        let virtual_root = n;
+
+        let mut is_root: Vec<bool> = cfg
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(b, block)| b == 0 || block.predecessors.is_empty())
+            .collect();
+        for inst in ir {
+            if let IRInstruction::Call { target, .. } = inst {
+                if let Some(b) = cfg.block_at_pc(*target) {
+                    is_root[b] = true;
+                }
+            }
+        }
+        let roots: Vec<usize> = (0..n).filter(|&b| is_root[b]).collect();
+
+        let children = |v: usize| -> Vec<usize> {
+            if v == virtual_root {
+                roots.clone()
+            } else {
+                cfg.blocks[v]
+                    .successors
+                    .iter()
+                    .map(|edge| edge.to)
+                    .collect()
+            }
+        };
+
+        // postorder numbering from the virtual root
+        let mut postorder = Vec::with_capacity(n + 1);
+        let mut visited = vec![false; n + 1];
+        let mut stack = vec![(virtual_root, children(virtual_root), 0)];
+        visited[virtual_root] = true;
+        while let Some((v, next, i)) = stack.last_mut() {
+            if *i < next.len() {
+                let child = next[*i];
+                *i += 1;
+                if !visited[child] {
+                    visited[child] = true;
+                    let grandchildren = children(child);
+                    stack.push((child, grandchildren, 0));
+                }
+            } else {
+                postorder.push(*v);
+                stack.pop();
+            }
+        }
+        let mut number = vec![usize::MAX; n + 1];
+        for (i, &v) in postorder.iter().enumerate() {
+            number[v] = i;
+        }
+
+        let predecessors = |b: usize| -> Vec<usize> {
+            let mut preds = cfg.blocks[b].predecessors.clone();
+            if is_root[b] {
+                preds.push(virtual_root);
+            }
+            preds
+        };
+
+        let mut idom = vec![usize::MAX; n + 1];
+        idom[virtual_root] = virtual_root;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in postorder.iter().rev().filter(|&&b| b != virtual_root) {
+                let mut new_idom = usize::MAX;
+                for p in predecessors(b) {
+                    if idom[p] == usize::MAX {
+                        continue;
+                    }
+                    new_idom = if new_idom == usize::MAX {
+                        p
+                    } else {
+                        intersect(&idom, &number, p, new_idom)
+                    };
+                }
+                if new_idom != idom[b] {
+                    idom[b] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        Dominators {
+            idom: (0..n)
+                .map(|b| (idom[b] != usize::MAX && idom[b] != virtual_root).then_some(idom[b]))
+                .collect(),
+            reachable: visited[..n].to_vec(),
+        }
+    }
+
+    /// Immediate dominator of `b`, None for roots and unreachable blocks
+    pub fn idom(&self, b: usize) -> Option<usize> {
+        self.idom[b]
+    }
+
+    /// Check if every path to `b` goes through `a`
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        if a == b {
+            return self.reachable[b];
+        }
+        let mut current = b;
+        while let Some(parent) = self.idom[current] {
+            if parent == a {
+                return true;
+            }
+            current = parent;
+        }
+        false
+    }
+
+    pub fn is_reachable(&self, b: usize) -> bool {
+        self.reachable[b]
+    }
+}
+
+fn intersect(idom: &[usize], number: &[usize], mut a: usize, mut b: usize) -> usize {
+    while a != b {
+        while number[a] < number[b] {
+            a = idom[a];
+        }
+        while number[b] < number[a] {
+            b = idom[b];
+        }
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::test_util::{load, s};
+
+    #[test]
+    fn test_diamond() {
+        // 0 -> {1, 2} -> 3
+        let ir = vec![
+            load(1, 1),
+            load(2, 10),
+            IRInstruction::ConditionalJump {
+                condition: s(1),
+                target: s(2),
+            },
+            load(1, 20),
+            IRInstruction::Jump { target: s(1) },
+            IRInstruction::JumpDest { pc: 10 },
+            IRInstruction::JumpDest { pc: 20 },
+            IRInstruction::Stop,
+        ];
+        let cfg = Cfg::build(&ir);
+        let doms = Dominators::compute(&ir, &cfg);
+
+        assert_eq!(cfg.len(), 4);
+        assert_eq!(doms.idom(0), None);
+        assert_eq!(doms.idom(1), Some(0));
+        assert_eq!(doms.idom(2), Some(0));
+        assert_eq!(doms.idom(3), Some(0));
+        assert!(doms.dominates(0, 3));
+        assert!(!doms.dominates(1, 3));
+        assert!(!doms.dominates(2, 3));
+    }
+
+    #[test]
+    fn test_unreachable_and_function_roots() {
+        let ir = vec![
+            IRInstruction::Call {
+                target: 5,
+                return_pc: 9,
+                args: vec![],
+                returns: vec![],
+            },
+            IRInstruction::JumpDest { pc: 5 },
+            IRInstruction::Return { values: vec![] },
+            IRInstruction::JumpDest { pc: 9 },
+            IRInstruction::Stop,
+        ];
+        let cfg = Cfg::build(&ir);
+        let doms = Dominators::compute(&ir, &cfg);
+
+        assert!(doms.is_reachable(1));
+        assert_eq!(doms.idom(1), None);
+        assert_eq!(doms.idom(2), Some(0));
+        assert!(!doms.dominates(1, 2));
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use super::graph::{Cfg, EdgeKind};
+use crate::ir::gas::parser::{IRInstruction, Instruction};
+
+// Graphviz export of the CFG, for debugging translation of larger contracts
+//
+//     dot -Tsvg cfg.dot -o cfg.svg
+//
+// Blocks whose jump target could not be resolved are drawn in red together with
+// their dynamic edges, those are what usually blows the graph up.
+
+/// What to print inside each block
+#[derive(Debug, Clone, Copy)]
+pub enum Listing<'a> {
+    /// The block's IR, with instruction indices
+    Ir,
+    /// The EVM instructions the IR was generated from, with pcs
+    ///
+    /// `pcs` is the pc of every IR instruction, as returned by
+    /// `generate_ir_with_pcs` and kept up by `PassManager::run_with_pcs`, so the
+    /// listing follows the IR through optimisation. Instructions that left no IR,
+    /// like a `POP` or a store forwarded away, aren't listed.
+    Evm {
+        instructions: &'a [Instruction],
+        pcs: &'a [usize],
+    },
+}
+
+/// Render `cfg` in Graphviz DOT format
+pub fn to_dot(ir: &[IRInstruction], cfg: &Cfg, listing: Listing) -> String {
+    // instruction at every pc
+    let mut evm = HashMap::new();
+    if let Listing::Evm { instructions, .. } = listing {
+        let mut pc = 0;
+        for inst in instructions {
+            evm.insert(pc, inst);
+            pc += 1 + inst.operand.as_ref().map_or(0, |operand| operand.len());
+        }
+    }
+
+    let mut dot = String::new();
+    writeln!(dot, "digraph cfg {{").unwrap();
+    writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        let mut label = match block.pc {
+            Some(pc) => format!("block {b} @ {pc:#x}\\l"),
+            None => format!("block {b}\\l"),
+        };
+        match listing {
+            Listing::Ir => {
+                for (index, inst) in ir.iter().enumerate().take(block.end).skip(block.start) {
+                    label.push_str(&escape(&format!("{index}: {inst:?}")));
+                    label.push_str("\\l");
+                }
+            }
+            Listing::Evm { pcs, .. } => {
+                let mut last = None;
+                for &pc in pcs.get(block.start..block.end).into_iter().flatten() {
+                    // IR generated from the same instruction is listed once
+                    if last.replace(pc) == Some(pc) {
+                        continue;
+                    }
+                    if let Some(inst) = evm.get(&pc) {
+                        label.push_str(&escape(&format_evm(pc, inst)));
+                        label.push_str("\\l");
+                    }
+                }
+            }
+        }
+
+        let style = if block.unresolved {
+            ", color=red, penwidth=2"
+        } else {
+            ""
+        };
+        writeln!(dot, "    b{b} [label=\"{label}\"{style}];").unwrap();
+    }
+
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        for edge in &block.successors {
+            let attrs = match edge.kind {
+                EdgeKind::Fallthrough => "label=\"fallthrough\", style=dashed",
+                EdgeKind::Taken => "label=\"taken\"",
+                EdgeKind::Dynamic => "label=\"dynamic\", color=red, style=dotted",
+            };
+            writeln!(dot, "    b{b} -> b{} [{attrs}];", edge.to).unwrap();
+        }
+    }
+
+    writeln!(dot, "}}").unwrap();
+    dot
+}
+
+fn format_evm(pc: usize, inst: &Instruction) -> String {
+    match &inst.operand {
+        Some(operand) => {
+            let hex: String = operand.iter().map(|byte| format!("{byte:02x}")).collect();
+            format!("{pc:04x}: {:?} 0x{hex}", inst.opcode)
+        }
+        None => format!("{pc:04x}: {:?}", inst.opcode),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::gas::parser::{generate_ir, generate_ir_with_pcs, parse_bytecode};
+    use crate::ir::memory::{memory::Memory, stack::Stack};
+    use crate::ir::passes::manager::{OptLevel, PassManager};
+    use crate::ir::test_util::s;
+
+    // push1 1 push1 7 jumpi stop stop jumpdest stop
+    const BYTECODE: [u8; 9] = [0x60, 0x01, 0x60, 0x07, 0x57, 0x00, 0x00, 0x5b, 0x00];
+
+    #[test]
+    fn test_ir_listing() {
+        let instructions = parse_bytecode(&BYTECODE).unwrap();
+        let ir = generate_ir(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+        let cfg = Cfg::build(&ir);
+        let dot = to_dot(&ir, &cfg, Listing::Ir);
+
+        assert!(dot.starts_with("digraph cfg {"));
+        assert!(dot.contains("b0 -> b3 [label=\"taken\"]"));
+        assert!(dot.contains("b0 -> b1 [label=\"fallthrough\", style=dashed]"));
+        assert!(dot.contains("block 3 @ 0x7"));
+        assert!(dot.contains("2: ConditionalJump"));
+        assert!(!dot.contains("color=red"));
+    }
+
+    #[test]
+    fn test_evm_listing_and_unresolved_jumps() {
+        let instructions = parse_bytecode(&BYTECODE).unwrap();
+        let (ir, pcs) =
+            generate_ir_with_pcs(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+        let cfg = Cfg::build(&ir);
+        let listing = Listing::Evm {
+            instructions: &instructions,
+            pcs: &pcs,
+        };
+        let dot = to_dot(&ir, &cfg, listing);
+
+        assert!(dot.contains("0002: PUSH1 0x07\\l0004: JUMPI\\l"));
+        assert!(dot.contains("0007: JUMPDEST\\l0008: STOP\\l"));
+
+        // the jump target is computed, so every JUMPDEST is a successor
+        let ir = vec![
+            IRInstruction::Jump { target: s(1) },
+            IRInstruction::JumpDest { pc: 1 },
+            IRInstruction::Stop,
+        ];
+        let cfg = Cfg::build(&ir);
+        let dot = to_dot(&ir, &cfg, Listing::Ir);
+
+        assert!(dot.contains("b0 [label=\"block 0\\l0: Jump"));
+        assert!(dot.contains("color=red, penwidth=2];"));
+        assert!(dot.contains("b0 -> b1 [label=\"dynamic\", color=red, style=dotted];"));
+    }
+
+    #[test]
+    fn test_optimised_evm_listing() {
+        // mstore(0, 1) mstore(0, 2) then a jump over a stop, the first store is
+        // overwritten before anything reads it and goes at -O1
+        let bytecode = [
+            0x60, 0x01, 0x60, 0x00, 0x52, 0x60, 0x02, 0x60, 0x00, 0x52, 0x60, 0x0e, 0x56, 0x00,
+            0x5b, 0x00,
+        ];
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        let (mut ir, mut pcs) =
+            generate_ir_with_pcs(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+        PassManager::new(OptLevel::O1)
+            .run_with_pcs(&mut ir, &mut pcs)
+            .unwrap();
+        assert!(!pcs.contains(&4));
+        let cfg = Cfg::build(&ir);
+        let listing = Listing::Evm {
+            instructions: &instructions,
+            pcs: &pcs,
+        };
+        let dot = to_dot(&ir, &cfg, listing);
+
+        assert!(dot.contains("0002: PUSH1 0x00\\l0005: PUSH1 0x02\\l"));
+        assert!(dot.contains("0009: MSTORE\\l000a: PUSH1 0x0e\\l000c: JUMP\\l"));
+        assert!(dot.contains("000e: JUMPDEST\\l000f: STOP\\l"));
+        assert!(!dot.contains("0004: MSTORE"));
+    }
+}
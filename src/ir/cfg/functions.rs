@@ -0,0 +1,371 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use super::graph::{Cfg, EdgeKind};
+use crate::ir::gas::parser::IRInstruction;
+use crate::ir::passes::constants::ConstantSlots;
+use crate::MyU256 as U256;
+use alloy_primitives::U256 as U;
+
+// Internal function recovery
+//
+// Legacy Solidity code calls an internal function by pushing a return address, the
+// arguments and the function entry, then jumping; the function returns by jumping to
+// the address it finds on the stack. These are recovered from the CFG and the jumps
+// rewritten into `Call`/`Return` with explicit argument and result slots.
+//
+// Slots must line up across the call, so a function is only recovered when all of
+// its call sites put the return address into the same slot with the same number of
+// arguments, and every return leaves the same number of results.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallSite {
+    // block ending in the jump into the function
+    pub block: usize,
+    pub return_pc: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InternalFunction {
+    // pc of the entry JUMPDEST
+    pub entry: usize,
+    // slot holding the return address on entry, results are returned from here upwards
+    pub frame: U256,
+    pub args: Vec<U256>,
+    pub returns: Vec<U256>,
+    // blocks of the body, entry first
+    pub blocks: Vec<usize>,
+    pub call_sites: Vec<CallSite>,
+    // blocks ending in the jump back to the caller
+    pub return_sites: Vec<usize>,
+}
+
+// A jump that looks like a call before checking it against the other call sites
+struct CandidateCall {
+    block: usize,
+    entry: usize,
+    return_pc: usize,
+    frame: usize,
+    target_slot: usize,
+}
+
+/// Find internal functions in `ir`
+pub fn find_functions(ir: &[IRInstruction], cfg: &Cfg) -> Vec<InternalFunction> {
+    let candidates: Vec<CandidateCall> = (0..cfg.len())
+        .filter_map(|b| candidate_call(ir, cfg, b))
+        .collect();
+    let continuations: HashMap<usize, usize> = candidates
+        .iter()
+        .map(|call| (call.block, call.return_pc))
+        .collect();
+
+    let mut by_entry: HashMap<usize, Vec<&CandidateCall>> = HashMap::new();
+    for call in &candidates {
+        by_entry.entry(call.entry).or_default().push(call);
+    }
+    let mut entries: Vec<usize> = by_entry.keys().copied().collect();
+    entries.sort_unstable();
+
+    entries
+        .into_iter()
+        .filter_map(|entry| build_function(ir, cfg, entry, &by_entry[&entry], &continuations))
+        .collect()
+}
+
+/// Find internal functions and rewrite their calls and returns in place
+pub fn recover_functions(ir: &mut [IRInstruction]) -> Vec<InternalFunction> {
+    let cfg = Cfg::build(ir);
+    let functions = find_functions(ir, &cfg);
+
+    for function in &functions {
+        for site in &function.call_sites {
+            let last = cfg.blocks[site.block].end - 1;
+            ir[last] = IRInstruction::Call {
+                target: function.entry,
+                return_pc: site.return_pc,
+                args: function.args.clone(),
+                returns: function.returns.clone(),
+            };
+        }
+        for &site in &function.return_sites {
+            let last = cfg.blocks[site].end - 1;
+            if let IRInstruction::Jump { .. } = ir[last] {
+                ir[last] = IRInstruction::Return {
+                    values: function.returns.clone(),
+                };
+            }
+        }
+    }
+
+    functions
+}
+
+// A block ending in a static jump to a JUMPDEST while a return address it pushed
+// itself is still on the stack below the jump target
+fn candidate_call(ir: &[IRInstruction], cfg: &Cfg, b: usize) -> Option<CandidateCall> {
+    let block = &cfg.blocks[b];
+    let target_slot = match &ir[block.end - 1] {
+        IRInstruction::Jump { target } => target.as_usize(),
+        _ => return None,
+    };
+    let entry = block.target?;
+    cfg.block_at_pc(entry)?;
+
+    let mut constants = ConstantSlots::new();
+    for inst in &ir[block.start..block.end - 1] {
+        constants.observe(inst);
+    }
+
+    // the return address is pushed before the arguments, so take the lowest slot
+    let (frame, return_pc) = constants
+        .iter()
+        .filter_map(|(slot, value)| {
+            let slot = slot.as_usize();
+            let pc = usize::try_from(value.0).ok()?;
+            let is_return = slot < target_slot && pc != entry && cfg.block_at_pc(pc).is_some();
+            is_return.then_some((slot, pc))
+        })
+        .min()?;
+
+    Some(CandidateCall {
+        block: b,
+        entry,
+        return_pc,
+        frame,
+        target_slot,
+    })
+}
+
+fn build_function(
+    ir: &[IRInstruction],
+    cfg: &Cfg,
+    entry: usize,
+    calls: &[&CandidateCall],
+    continuations: &HashMap<usize, usize>,
+) -> Option<InternalFunction> {
+    let frame = calls[0].frame;
+    let target_slot = calls[0].target_slot;
+    if calls
+        .iter()
+        .any(|call| call.frame != frame || call.target_slot != target_slot)
+    {
+        return None;
+    }
+
+    // walk the body, stepping over nested calls to their continuation
+    let entry_block = cfg.block_at_pc(entry)?;
+    let mut blocks = Vec::new();
+    let mut return_sites = Vec::new();
+    let mut seen = HashSet::from([entry_block]);
+    let mut queue = VecDeque::from([entry_block]);
+    while let Some(b) = queue.pop_front() {
+        blocks.push(b);
+        let block = &cfg.blocks[b];
+
+        let next: Vec<usize> = if let Some(return_pc) = continuations.get(&b) {
+            cfg.block_at_pc(*return_pc).into_iter().collect()
+        } else if block.unresolved {
+            match &ir[block.end - 1] {
+                IRInstruction::Jump { .. } => return_sites.push(b),
+                _ => return None,
+            }
+            Vec::new()
+        } else {
+            block
+                .successors
+                .iter()
+                .filter(|edge| edge.kind != EdgeKind::Dynamic)
+                .map(|edge| edge.to)
+                .collect()
+        };
+
+        for to in next {
+            if seen.insert(to) {
+                queue.push_back(to);
+            }
+        }
+    }
+
+    // every return must jump from the same height, leaving the results below it
+    let mut result_count = None;
+    for &site in &return_sites {
+        let target = match &ir[cfg.blocks[site].end - 1] {
+            IRInstruction::Jump { target } => target.as_usize(),
+            _ => return None,
+        };
+        let count = target.checked_sub(frame)?;
+        if *result_count.get_or_insert(count) != count {
+            return None;
+        }
+    }
+    let result_count = result_count?;
+
+    let slot = |n: usize| U256(U::from(n));
+    Some(InternalFunction {
+        entry,
+        frame: slot(frame),
+        args: (frame + 1..target_slot).map(slot).collect(),
+        returns: (frame..frame + result_count).map(slot).collect(),
+        blocks,
+        call_sites: calls
+            .iter()
+            .map(|call| CallSite {
+                block: call.block,
+                return_pc: call.return_pc,
+            })
+            .collect(),
+        return_sites,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::gas::parser::{generate_ir, parse_bytecode};
+    use crate::ir::memory::{memory::Memory, stack::Stack};
+    use crate::ir::test_util::{load, s};
+
+    // f(x) = x + 1, called once at pc 0 and returning to pc 13
+    const INCREMENT: [u8; 15] = [
+        0x60, 0x0d, // PUSH1 ret
+        0x60, 0x05, // PUSH1 5
+        0x60, 0x07, // PUSH1 f
+        0x56, // JUMP
+        0x5b, // f: JUMPDEST
+        0x60, 0x01, // PUSH1 1
+        0x01, // ADD
+        0x90, // SWAP1
+        0x56, // JUMP
+        0x5b, // ret: JUMPDEST
+        0x00, // STOP
+    ];
+
+    #[test]
+    fn test_recover_from_bytecode() {
+        let instructions = parse_bytecode(&INCREMENT).unwrap();
+        let mut ir = generate_ir(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+        let functions = recover_functions(&mut ir);
+
+        assert_eq!(functions.len(), 1);
+        let f = &functions[0];
+        assert_eq!(f.entry, 7);
+        assert_eq!(f.frame, s(1));
+        assert_eq!(f.args, vec![s(2)]);
+        assert_eq!(f.returns, vec![s(1)]);
+        assert_eq!(f.blocks, vec![1]);
+        assert_eq!(f.return_sites, vec![1]);
+        assert_eq!(
+            f.call_sites,
+            vec![CallSite {
+                block: 0,
+                return_pc: 13
+            }]
+        );
+
+        match &ir[3] {
+            IRInstruction::Call {
+                target,
+                return_pc,
+                args,
+                returns,
+            } => {
+                assert_eq!((*target, *return_pc), (7, 13));
+                assert_eq!(*args, vec![s(2)]);
+                assert_eq!(*returns, vec![s(1)]);
+            }
+            other => panic!("Expected Call, got {:?}", other),
+        }
+        match &ir[8] {
+            IRInstruction::Return { values } => assert_eq!(*values, vec![s(1)]),
+            other => panic!("Expected Return, got {:?}", other),
+        }
+
+        // the call now continues straight at the return address
+        let cfg = Cfg::build(&ir);
+        assert_eq!(cfg.blocks[0].successors[0].to, cfg.block_at_pc(13).unwrap());
+        assert!(cfg.blocks[1].successors.is_empty());
+    }
+
+    #[test]
+    fn test_multiple_call_sites_and_branches() {
+        // two calls to a two-argument function with an early-exit branch
+        let ir = vec![
+            load(1, 20),
+            load(2, 1),
+            load(3, 2),
+            load(4, 10),
+            IRInstruction::Jump { target: s(4) },
+            IRInstruction::JumpDest { pc: 10 },
+            IRInstruction::BinaryOp {
+                op: "add",
+                dest: s(2),
+                src1: s(3),
+                src2: s(2),
+            },
+            load(3, 15),
+            IRInstruction::ConditionalJump {
+                condition: s(2),
+                target: s(3),
+            },
+            IRInstruction::Swap { a: s(2), b: s(1) },
+            IRInstruction::Jump { target: s(2) },
+            IRInstruction::JumpDest { pc: 15 },
+            IRInstruction::Swap { a: s(2), b: s(1) },
+            IRInstruction::Jump { target: s(2) },
+            IRInstruction::JumpDest { pc: 20 },
+            load(1, 30),
+            IRInstruction::Copy {
+                dest: s(2),
+                src: s(1),
+            },
+            load(3, 7),
+            load(4, 10),
+            IRInstruction::Jump { target: s(4) },
+            IRInstruction::JumpDest { pc: 30 },
+            IRInstruction::Stop,
+        ];
+        let cfg = Cfg::build(&ir);
+        let functions = find_functions(&ir, &cfg);
+
+        assert_eq!(functions.len(), 1);
+        let f = &functions[0];
+        assert_eq!(f.entry, 10);
+        assert_eq!(f.call_sites.len(), 2);
+        assert_eq!(f.args, vec![s(2), s(3)]);
+        assert_eq!(f.blocks.len(), 3);
+        assert_eq!(f.return_sites.len(), 2);
+        assert_eq!(f.returns, vec![s(1)]);
+    }
+
+    #[test]
+    fn test_inconsistent_call_sites_rejected() {
+        let ir = vec![
+            load(1, 12),
+            load(2, 10),
+            IRInstruction::Jump { target: s(2) },
+            IRInstruction::JumpDest { pc: 10 },
+            IRInstruction::Jump { target: s(1) },
+            IRInstruction::JumpDest { pc: 12 },
+            load(1, 7),
+            load(2, 14),
+            load(3, 10),
+            IRInstruction::Jump { target: s(3) },
+            IRInstruction::JumpDest { pc: 14 },
+            IRInstruction::Stop,
+        ];
+        let cfg = Cfg::build(&ir);
+        assert!(find_functions(&ir, &cfg).is_empty());
+    }
+
+    #[test]
+    fn test_plain_jump_is_not_a_call() {
+        let ir = vec![
+            load(1, 4),
+            IRInstruction::Jump { target: s(1) },
+            IRInstruction::JumpDest { pc: 4 },
+            IRInstruction::Stop,
+        ];
+        let mut rewritten = ir.clone();
+        assert!(recover_functions(&mut rewritten).is_empty());
+        assert!(matches!(rewritten[1], IRInstruction::Jump { .. }));
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+
+use crate::ir::gas::parser::IRInstruction;
+use crate::ir::passes::constants::ConstantSlots;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    // execution runs off the end of the block
+    Fallthrough,
+    // jump (or call return) to a statically known JUMPDEST
+    Taken,
+    // the jump target is only known at runtime, any JUMPDEST may be hit
+    Dynamic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+/// A maximal straight-line run of IR, `ir[start..end]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    // pc of the JUMPDEST starting this block, if any
+    pub pc: Option<usize>,
+    // statically resolved jump target
+    pub target: Option<usize>,
+    // ends in a jump whose target could not be resolved
+    pub unresolved: bool,
+    pub successors: Vec<Edge>,
+    pub predecessors: Vec<usize>,
+}
+
+/// Control flow graph over IR, block 0 is the entry
+#[derive(Debug, Clone, Default)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    by_pc: HashMap<usize, usize>,
+}
+
+impl Cfg {
+    /// Split `ir` into basic blocks and connect them
+    ///
+    /// Jump targets are resolved from constants loaded in the same block, which
+    /// covers the `PUSH dest JUMP` pattern every compiler emits for static jumps.
+    pub fn build(ir: &[IRInstruction]) -> Self {
+        let mut cfg = Cfg::default();
+
+        let mut start = 0;
+        for (index, inst) in ir.iter().enumerate() {
+            if let IRInstruction::JumpDest { .. } = inst {
+                if index > start {
+                    cfg.push_block(ir, start, index);
+                    start = index;
+                }
+            }
+            if inst.is_terminator() {
+                cfg.push_block(ir, start, index + 1);
+                start = index + 1;
+            }
+        }
+        if start < ir.len() {
+            cfg.push_block(ir, start, ir.len());
+        }
+
+        cfg.connect(ir);
+        cfg
+    }
+
+    fn push_block(&mut self, ir: &[IRInstruction], start: usize, end: usize) {
+        let pc = match ir[start] {
+            IRInstruction::JumpDest { pc } => {
+                self.by_pc.insert(pc, self.blocks.len());
+                Some(pc)
+            }
+            _ => None,
+        };
+        self.blocks.push(BasicBlock {
+            start,
+            end,
+            pc,
+            target: None,
+            unresolved: false,
+            successors: Vec::new(),
+            predecessors: Vec::new(),
+        });
+    }
+
+    fn connect(&mut self, ir: &[IRInstruction]) {
+        let jumpdests: Vec<usize> = (0..self.blocks.len())
+            .filter(|&b| self.blocks[b].pc.is_some())
+            .collect();
+
+        for b in 0..self.blocks.len() {
+            let (start, end) = (self.blocks[b].start, self.blocks[b].end);
+            let mut constants = ConstantSlots::new();
+            for inst in &ir[start..end - 1] {
+                constants.observe(inst);
+            }
+
+            let next = (b + 1 < self.blocks.len()).then_some(b + 1);
+            let mut edges = Vec::new();
+            let jump_target = match &ir[end - 1] {
+                IRInstruction::Jump { target } => Some(*target),
+                IRInstruction::ConditionalJump { target, .. } => Some(*target),
+                _ => None,
+            };
+
+            match &ir[end - 1] {
+                IRInstruction::Jump { .. } | IRInstruction::ConditionalJump { .. } => {
+                    match jump_target.and_then(|slot| constants.get(slot)) {
+                        Some(value) => {
+                            let pc = usize::try_from(value.0).ok();
+                            self.blocks[b].target = pc;
+                            // a jump to anything but a JUMPDEST halts, so it has no edge
+                            if let Some(&to) = pc.and_then(|pc| self.by_pc.get(&pc)) {
+                                edges.push(Edge {
+                                    to,
+                                    kind: EdgeKind::Taken,
+                                });
+                            }
+                        }
+                        None => {
+                            self.blocks[b].unresolved = true;
+                            edges.extend(jumpdests.iter().map(|&to| Edge {
+                                to,
+                                kind: EdgeKind::Dynamic,
+                            }));
+                        }
+                    }
+                    if let (IRInstruction::ConditionalJump { .. }, Some(to)) = (&ir[end - 1], next)
+                    {
+                        edges.push(Edge {
+                            to,
+                            kind: EdgeKind::Fallthrough,
+                        });
+                    }
+                }
+                IRInstruction::Call { return_pc, .. } => {
+                    self.blocks[b].target = Some(*return_pc);
+                    if let Some(&to) = self.by_pc.get(return_pc) {
+                        edges.push(Edge {
+                            to,
+                            kind: EdgeKind::Taken,
+                        });
+                    }
+                }
+                IRInstruction::Stop | IRInstruction::Return { .. } => {}
+                IRInstruction::HostCall { kind, .. } if kind.halts() => {}
+                _ => {
+                    if let Some(to) = next {
+                        edges.push(Edge {
+                            to,
+                            kind: EdgeKind::Fallthrough,
+                        });
+                    }
+                }
+            }
+
+            for edge in &edges {
+                if !self.blocks[edge.to].predecessors.contains(&b) {
+                    self.blocks[edge.to].predecessors.push(b);
+                }
+            }
+            self.blocks[b].successors = edges;
+        }
+    }
+
+    /// Block starting with the JUMPDEST at `pc`
+    pub fn block_at_pc(&self, pc: usize) -> Option<usize> {
+        self.by_pc.get(&pc).copied()
+    }
+
+    /// Block containing the instruction at `index`
+    pub fn block_of(&self, index: usize) -> Option<usize> {
+        let b = self.blocks.partition_point(|block| block.end <= index);
+        (b < self.blocks.len()).then_some(b)
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::test_util::{load, s};
+
+    #[test]
+    fn test_straight_line() {
+        let ir = vec![load(1, 1), load(2, 2), IRInstruction::Stop];
+        let cfg = Cfg::build(&ir);
+
+        assert_eq!(cfg.len(), 1);
+        assert_eq!((cfg.blocks[0].start, cfg.blocks[0].end), (0, 3));
+        assert!(cfg.blocks[0].successors.is_empty());
+    }
+
+    #[test]
+    fn test_conditional_jump() {
+        let ir = vec![
+            load(1, 1),
+            load(2, 8),
+            IRInstruction::ConditionalJump {
+                condition: s(1),
+                target: s(2),
+            },
+            IRInstruction::Stop,
+            IRInstruction::JumpDest { pc: 8 },
+            IRInstruction::Stop,
+        ];
+        let cfg = Cfg::build(&ir);
+
+        assert_eq!(cfg.len(), 3);
+        assert_eq!(cfg.blocks[0].target, Some(8));
+        assert_eq!(
+            cfg.blocks[0].successors,
+            vec![
+                Edge {
+                    to: 2,
+                    kind: EdgeKind::Taken
+                },
+                Edge {
+                    to: 1,
+                    kind: EdgeKind::Fallthrough
+                },
+            ]
+        );
+        assert_eq!(cfg.block_at_pc(8), Some(2));
+        assert_eq!(cfg.blocks[2].predecessors, vec![0]);
+        assert_eq!(cfg.block_of(3), Some(1));
+        assert_eq!(cfg.block_of(6), None);
+    }
+
+    #[test]
+    fn test_fallthrough_into_jumpdest() {
+        let ir = vec![
+            load(1, 1),
+            IRInstruction::JumpDest { pc: 2 },
+            IRInstruction::Stop,
+        ];
+        let cfg = Cfg::build(&ir);
+
+        assert_eq!(cfg.len(), 2);
+        assert_eq!(
+            cfg.blocks[0].successors,
+            vec![Edge {
+                to: 1,
+                kind: EdgeKind::Fallthrough
+            }]
+        );
+    }
+
+    #[test]
+    fn test_dynamic_jump() {
+        let ir = vec![
+            IRInstruction::Jump { target: s(1) },
+            IRInstruction::JumpDest { pc: 1 },
+            IRInstruction::Stop,
+            IRInstruction::JumpDest { pc: 3 },
+            IRInstruction::Stop,
+        ];
+        let cfg = Cfg::build(&ir);
+
+        assert!(cfg.blocks[0].unresolved);
+        assert_eq!(cfg.blocks[0].successors.len(), 2);
+        assert!(cfg.blocks[0]
+            .successors
+            .iter()
+            .all(|edge| edge.kind == EdgeKind::Dynamic));
+    }
+
+    #[test]
+    fn test_invalid_jump_has_no_edge() {
+        let ir = vec![
+            load(1, 5),
+            IRInstruction::Jump { target: s(1) },
+            IRInstruction::JumpDest { pc: 3 },
+            IRInstruction::Stop,
+        ];
+        let cfg = Cfg::build(&ir);
+
+        assert_eq!(cfg.blocks[0].target, Some(5));
+        assert!(!cfg.blocks[0].unresolved);
+        assert!(cfg.blocks[0].successors.is_empty());
+    }
+}
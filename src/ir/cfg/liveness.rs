@@ -0,0 +1,130 @@
+use std::collections::HashSet;
+
+use super::graph::Cfg;
+use crate::ir::gas::parser::IRInstruction;
+use crate::MyU256 as U256;
+
+// Slot liveness
+//
+// A slot is live at a point if some path from there reads it before writing it.
+// Computed per block with the usual backward dataflow over the CFG. Dynamic edges
+// make it conservative around unresolved jumps, never wrong.
+
+#[derive(Debug, Clone, Default)]
+pub struct Liveness {
+    pub live_in: Vec<HashSet<U256>>,
+    pub live_out: Vec<HashSet<U256>>,
+}
+
+impl Liveness {
+    pub fn compute(ir: &[IRInstruction], cfg: &Cfg) -> Self {
+        let n = cfg.len();
+
+        // slots read before being written, and slots written, in each block
+        let mut uses = vec![HashSet::new(); n];
+        let mut defs = vec![HashSet::new(); n];
+        for (b, block) in cfg.blocks.iter().enumerate() {
+            for inst in &ir[block.start..block.end] {
+                for slot in inst.sources() {
+                    if !defs[b].contains(&slot) {
+                        uses[b].insert(slot);
+                    }
+                }
+                defs[b].extend(inst.defs());
+            }
+        }
+
+        let mut liveness = Liveness {
+            live_in: uses.clone(),
+            live_out: vec![HashSet::new(); n],
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in (0..n).rev() {
+                let out: HashSet<U256> = cfg.blocks[b]
+                    .successors
+                    .iter()
+                    .flat_map(|edge| liveness.live_in[edge.to].iter().copied())
+                    .collect();
+                let mut live_in = uses[b].clone();
+                live_in.extend(out.iter().filter(|slot| !defs[b].contains(slot)));
+
+                if live_in != liveness.live_in[b] || out != liveness.live_out[b] {
+                    liveness.live_in[b] = live_in;
+                    liveness.live_out[b] = out;
+                    changed = true;
+                }
+            }
+        }
+        liveness
+    }
+
+    /// Check if `slot` may be read after the end of block `b`
+    pub fn is_live_out(&self, b: usize, slot: U256) -> bool {
+        self.live_out[b].contains(&slot)
+    }
+
+    /// Check if `slot` may be read before being written once block `b` is entered
+    pub fn is_live_in(&self, b: usize, slot: U256) -> bool {
+        self.live_in[b].contains(&slot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::test_util::{load, s};
+
+    #[test]
+    fn test_liveness_across_branch() {
+        let ir = vec![
+            load(1, 7),
+            load(2, 1),
+            load(3, 6),
+            IRInstruction::ConditionalJump {
+                condition: s(2),
+                target: s(3),
+            },
+            IRInstruction::Stop,
+            IRInstruction::JumpDest { pc: 6 },
+            IRInstruction::UnaryOp {
+                op: "iszero",
+                dest: s(1),
+                src: s(1),
+            },
+            IRInstruction::Stop,
+        ];
+        let cfg = Cfg::build(&ir);
+        let liveness = Liveness::compute(&ir, &cfg);
+
+        assert!(liveness.is_live_out(0, s(1)));
+        assert!(!liveness.is_live_out(0, s(2)));
+        assert!(liveness.is_live_in(2, s(1)));
+        assert!(liveness.live_in[0].is_empty());
+        assert!(liveness.live_in[1].is_empty());
+    }
+
+    #[test]
+    fn test_loop_carried_slot() {
+        let ir = vec![
+            load(1, 0),
+            IRInstruction::JumpDest { pc: 2 },
+            load(2, 1),
+            IRInstruction::BinaryOp {
+                op: "add",
+                dest: s(1),
+                src1: s(2),
+                src2: s(1),
+            },
+            load(2, 2),
+            IRInstruction::Jump { target: s(2) },
+        ];
+        let cfg = Cfg::build(&ir);
+        let liveness = Liveness::compute(&ir, &cfg);
+
+        assert!(liveness.is_live_in(1, s(1)));
+        assert!(liveness.is_live_out(1, s(1)));
+        assert!(!liveness.is_live_in(1, s(2)));
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+
+use super::dominators::Dominators;
+use super::graph::Cfg;
+
+// Natural loops
+//
+// A back edge is an edge whose target dominates its source. The loop it forms is
+// the header plus every block that reaches the back edge without going through the
+// header. Back edges sharing a header are merged into a single loop.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    pub header: usize,
+    // blocks of the loop in ascending order, header included
+    pub blocks: Vec<usize>,
+    // sources of the back edges
+    pub latches: Vec<usize>,
+}
+
+impl Loop {
+    pub fn contains(&self, b: usize) -> bool {
+        self.blocks.binary_search(&b).is_ok()
+    }
+
+    /// Blocks outside the loop that are entered from inside it
+    pub fn exits(&self, cfg: &Cfg) -> Vec<usize> {
+        let mut exits: Vec<usize> = self
+            .blocks
+            .iter()
+            .flat_map(|&b| cfg.blocks[b].successors.iter().map(|edge| edge.to))
+            .filter(|&to| !self.contains(to))
+            .collect();
+        exits.sort_unstable();
+        exits.dedup();
+        exits
+    }
+
+    /// The only block entering the loop from outside, if it leads nowhere else
+    pub fn preheader(&self, cfg: &Cfg) -> Option<usize> {
+        let mut outside = cfg.blocks[self.header]
+            .predecessors
+            .iter()
+            .copied()
+            .filter(|&p| !self.contains(p));
+        let preheader = outside.next()?;
+        if outside.next().is_some() {
+            return None;
+        }
+        cfg.blocks[preheader]
+            .successors
+            .iter()
+            .all(|edge| edge.to == self.header)
+            .then_some(preheader)
+    }
+}
+
+/// Find the natural loops of `cfg`, innermost (smallest) first
+pub fn find_loops(cfg: &Cfg, doms: &Dominators) -> Vec<Loop> {
+    let mut by_header: BTreeMap<usize, Loop> = BTreeMap::new();
+
+    for (latch, block) in cfg.blocks.iter().enumerate() {
+        for edge in &block.successors {
+            let header = edge.to;
+            if !doms.dominates(header, latch) {
+                continue;
+            }
+            let lp = by_header.entry(header).or_insert_with(|| Loop {
+                header,
+                blocks: vec![header],
+                latches: Vec::new(),
+            });
+            if !lp.latches.contains(&latch) {
+                lp.latches.push(latch);
+            }
+
+            let mut stack = vec![latch];
+            while let Some(b) = stack.pop() {
+                if lp.blocks.contains(&b) {
+                    continue;
+                }
+                lp.blocks.push(b);
+                stack.extend(
+                    cfg.blocks[b]
+                        .predecessors
+                        .iter()
+                        .copied()
+                        .filter(|&p| doms.dominates(header, p)),
+                );
+            }
+        }
+    }
+
+    let mut loops: Vec<Loop> = by_header
+        .into_values()
+        .map(|mut lp| {
+            lp.blocks.sort_unstable();
+            lp
+        })
+        .collect();
+    loops.sort_by_key(|lp| lp.blocks.len());
+    loops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::gas::parser::IRInstruction;
+    use crate::ir::test_util::{load, s};
+
+    // 0: preheader, 1: outer header, 2: inner loop, 3: outer latch, 4: exit
+    fn nested() -> Vec<IRInstruction> {
+        vec![
+            load(1, 0),
+            IRInstruction::JumpDest { pc: 2 },
+            load(2, 30),
+            IRInstruction::ConditionalJump {
+                condition: s(1),
+                target: s(2),
+            },
+            IRInstruction::JumpDest { pc: 5 },
+            load(2, 5),
+            IRInstruction::ConditionalJump {
+                condition: s(1),
+                target: s(2),
+            },
+            load(2, 2),
+            IRInstruction::Jump { target: s(2) },
+            IRInstruction::JumpDest { pc: 30 },
+            IRInstruction::Stop,
+        ]
+    }
+
+    #[test]
+    fn test_nested_loops() {
+        let ir = nested();
+        let cfg = Cfg::build(&ir);
+        let doms = Dominators::compute(&ir, &cfg);
+        let loops = find_loops(&cfg, &doms);
+
+        assert_eq!(cfg.len(), 5);
+        assert_eq!(loops.len(), 2);
+        assert_eq!(loops[0].header, 2);
+        assert_eq!(loops[0].blocks, vec![2]);
+        assert_eq!(loops[0].latches, vec![2]);
+        assert_eq!(loops[0].exits(&cfg), vec![3]);
+        assert_eq!(loops[0].preheader(&cfg), None);
+
+        assert_eq!(loops[1].header, 1);
+        assert_eq!(loops[1].blocks, vec![1, 2, 3]);
+        assert_eq!(loops[1].latches, vec![3]);
+        assert_eq!(loops[1].exits(&cfg), vec![4]);
+        assert_eq!(loops[1].preheader(&cfg), Some(0));
+    }
+
+    #[test]
+    fn test_no_loops_without_back_edges() {
+        let ir = vec![
+            load(1, 1),
+            load(2, 4),
+            IRInstruction::ConditionalJump {
+                condition: s(1),
+                target: s(2),
+            },
+            IRInstruction::JumpDest { pc: 4 },
+            IRInstruction::Stop,
+        ];
+        let cfg = Cfg::build(&ir);
+        let doms = Dominators::compute(&ir, &cfg);
+        assert!(find_loops(&cfg, &doms).is_empty());
+    }
+}
@@ -0,0 +1,6 @@
+pub mod dominators;
+pub mod dot;
+pub mod functions;
+pub mod graph;
+pub mod liveness;
+pub mod loops;
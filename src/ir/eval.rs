@@ -0,0 +1,72 @@
+use alloy_primitives::{I256 as Signed, U256 as U};
+
+// Op evaluation
+//
+// EVM semantics of the IR's arithmetic ops by name, shared by the interpreter
+// and the runtime helpers the generator calls out to.
+
+// EVM semantics of the binary ops, `a` is the top of the stack
+pub(crate) fn binary(op: &str, a: U, b: U) -> U {
+    let signed = |value: U| Signed::from_raw(value);
+    match op {
+        "add" => a.wrapping_add(b),
+        "sub" => a.wrapping_sub(b),
+        "mul" => a.wrapping_mul(b),
+        "div" => a.checked_div(b).unwrap_or_default(),
+        "mod" => a.checked_rem(b).unwrap_or_default(),
+        "sdiv" if b.is_zero() => U::ZERO,
+        "sdiv" => signed(a).wrapping_div(signed(b)).into_raw(),
+        "smod" if b.is_zero() => U::ZERO,
+        "smod" => signed(a).wrapping_rem(signed(b)).into_raw(),
+        "exp" => a.wrapping_pow(b),
+        "signextend" if a >= U::from(31) => b,
+        "signextend" => {
+            let bit = a.to::<usize>() * 8 + 7;
+            let mask = (U::from(1) << (bit + 1)) - U::from(1);
+            if b.bit(bit) {
+                b | !mask
+            } else {
+                b & mask
+            }
+        }
+        "LT" => U::from(a < b),
+        "GT" => U::from(a > b),
+        "SLT" => U::from(signed(a) < signed(b)),
+        "SGT" => U::from(signed(a) > signed(b)),
+        "EQ" => U::from(a == b),
+        "and" => a & b,
+        "or" => a | b,
+        "xor" => a ^ b,
+        "byte" if a >= U::from(32) => U::ZERO,
+        "byte" => U::from(b.byte(31 - a.to::<usize>())),
+        "shl" if a >= U::from(256) => U::ZERO,
+        "shl" => b << a.to::<usize>(),
+        "shr" if a >= U::from(256) => U::ZERO,
+        "shr" => b >> a.to::<usize>(),
+        "sar" => {
+            let shift = if a >= U::from(255) {
+                255
+            } else {
+                a.to::<usize>()
+            };
+            signed(b).asr(shift).into_raw()
+        }
+        op => panic!("unknown binary op {op}"),
+    }
+}
+
+pub(crate) fn unary(op: &str, a: U) -> U {
+    match op {
+        "not" => !a,
+        "iszero" => U::from(a.is_zero()),
+        op => panic!("unknown unary op {op}"),
+    }
+}
+
+pub(crate) fn ternary(op: &str, a: U, b: U, n: U) -> U {
+    match op {
+        "addmod" => a.add_mod(b, n),
+        "mulmod" => a.mul_mod(b, n),
+        op => panic!("unknown ternary op {op}"),
+    }
+}
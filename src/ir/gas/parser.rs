@@ -1,11 +1,16 @@
-use crate::ir::memory::{memory::Memory, stack::Stack};
+use crate::ir::generator::register::Register;
+use crate::ir::memory::{memory::Memory, stack::{Stack, StackError}, MEMORY_LIMIT};
+use crate::ir::passes::constants::ConstantSlots;
 use crate::{MyU256 as U256, I256};
-use alloy_primitives::U256 as U;
+use alloy_primitives::{keccak256, U256 as U};
 use core::convert::TryFrom;
+use std::collections::HashSet;
+use std::fmt;
 use std::ops::{Add, Mul, Sub};
 
 // EVM Opcode definition(CANCUN)
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Opcode {
     STOP,
     ADD,
@@ -313,48 +318,103 @@ impl TryFrom<u8> for Opcode {
     }
 }
 
+const PUSH_OPCODES: [Opcode; 32] = [
+    Opcode::PUSH1,
+    Opcode::PUSH2,
+    Opcode::PUSH3,
+    Opcode::PUSH4,
+    Opcode::PUSH5,
+    Opcode::PUSH6,
+    Opcode::PUSH7,
+    Opcode::PUSH8,
+    Opcode::PUSH9,
+    Opcode::PUSH10,
+    Opcode::PUSH11,
+    Opcode::PUSH12,
+    Opcode::PUSH13,
+    Opcode::PUSH14,
+    Opcode::PUSH15,
+    Opcode::PUSH16,
+    Opcode::PUSH17,
+    Opcode::PUSH18,
+    Opcode::PUSH19,
+    Opcode::PUSH20,
+    Opcode::PUSH21,
+    Opcode::PUSH22,
+    Opcode::PUSH23,
+    Opcode::PUSH24,
+    Opcode::PUSH25,
+    Opcode::PUSH26,
+    Opcode::PUSH27,
+    Opcode::PUSH28,
+    Opcode::PUSH29,
+    Opcode::PUSH30,
+    Opcode::PUSH31,
+    Opcode::PUSH32,
+];
+
+const DUP_OPCODES: [Opcode; 16] = [
+    Opcode::DUP1,
+    Opcode::DUP2,
+    Opcode::DUP3,
+    Opcode::DUP4,
+    Opcode::DUP5,
+    Opcode::DUP6,
+    Opcode::DUP7,
+    Opcode::DUP8,
+    Opcode::DUP9,
+    Opcode::DUP10,
+    Opcode::DUP11,
+    Opcode::DUP12,
+    Opcode::DUP13,
+    Opcode::DUP14,
+    Opcode::DUP15,
+    Opcode::DUP16,
+];
+
+const SWAP_OPCODES: [Opcode; 16] = [
+    Opcode::SWAP1,
+    Opcode::SWAP2,
+    Opcode::SWAP3,
+    Opcode::SWAP4,
+    Opcode::SWAP5,
+    Opcode::SWAP6,
+    Opcode::SWAP7,
+    Opcode::SWAP8,
+    Opcode::SWAP9,
+    Opcode::SWAP10,
+    Opcode::SWAP11,
+    Opcode::SWAP12,
+    Opcode::SWAP13,
+    Opcode::SWAP14,
+    Opcode::SWAP15,
+    Opcode::SWAP16,
+];
+
 impl Opcode {
-    fn is_push(&self) -> bool {
-        matches!(
-            self,
-            Opcode::PUSH1
-                | Opcode::PUSH2
-                | Opcode::PUSH3
-                | Opcode::PUSH4
-                | Opcode::PUSH5
-                | Opcode::PUSH6
-                | Opcode::PUSH7
-                | Opcode::PUSH8
-                | Opcode::PUSH9
-                | Opcode::PUSH10
-                | Opcode::PUSH11
-                | Opcode::PUSH12
-                | Opcode::PUSH13
-                | Opcode::PUSH14
-                | Opcode::PUSH15
-                | Opcode::PUSH16
-                | Opcode::PUSH17
-                | Opcode::PUSH18
-                | Opcode::PUSH19
-                | Opcode::PUSH20
-                | Opcode::PUSH21
-                | Opcode::PUSH22
-                | Opcode::PUSH23
-                | Opcode::PUSH24
-                | Opcode::PUSH25
-                | Opcode::PUSH26
-                | Opcode::PUSH27
-                | Opcode::PUSH28
-                | Opcode::PUSH29
-                | Opcode::PUSH30
-                | Opcode::PUSH31
-                | Opcode::PUSH32
-        )
+    /// `n` for DUPn
+    pub(crate) fn dup_depth(&self) -> Option<usize> {
+        DUP_OPCODES.iter().position(|op| op == self).map(|i| i + 1)
+    }
+
+    /// `n` for SWAPn
+    pub(crate) fn swap_depth(&self) -> Option<usize> {
+        SWAP_OPCODES.iter().position(|op| op == self).map(|i| i + 1)
+    }
+
+    /// `n` for PUSHn
+    pub(crate) fn push_size(&self) -> Option<usize> {
+        PUSH_OPCODES.iter().position(|op| op == self).map(|i| i + 1)
+    }
+
+    pub(crate) fn is_push(&self) -> bool {
+        self.push_size().is_some()
     }
 }
 
 // EVM Instruction
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Instruction {
     pub opcode: Opcode,
     pub operand: Option<Vec<u8>>,
@@ -369,22 +429,249 @@ impl Default for Instruction {
     }
 }
 
+// Opcodes that need the host: the environment, logs, calls, contract creation
+// and the halts that hand data back to the caller
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum HostOp {
+    Address,
+    Balance,
+    Origin,
+    Caller,
+    CallValue,
+    CallDataLoad,
+    CallDataSize,
+    CallDataCopy,
+    CodeSize,
+    CodeCopy,
+    GasPrice,
+    ExtCodeSize,
+    ExtCodeCopy,
+    ReturnDataSize,
+    ReturnDataCopy,
+    ExtCodeHash,
+    BlockHash,
+    Coinbase,
+    Timestamp,
+    Number,
+    PrevRandao,
+    GasLimit,
+    ChainId,
+    SelfBalance,
+    BaseFee,
+    MSize,
+    Gas,
+    // number of topics
+    Log(u8),
+    Create,
+    Create2,
+    Call,
+    CallCode,
+    DelegateCall,
+    StaticCall,
+    Return,
+    Revert,
+    Invalid,
+    SelfDestruct,
+}
+
+impl TryFrom<Opcode> for HostOp {
+    type Error = &'static str;
+
+    fn try_from(opcode: Opcode) -> Result<Self, Self::Error> {
+        match opcode {
+            Opcode::ADDRESS => Ok(HostOp::Address),
+            Opcode::BALANCE => Ok(HostOp::Balance),
+            Opcode::ORIGIN => Ok(HostOp::Origin),
+            Opcode::CALLER => Ok(HostOp::Caller),
+            Opcode::CALLVALUE => Ok(HostOp::CallValue),
+            Opcode::CALLDATALOAD => Ok(HostOp::CallDataLoad),
+            Opcode::CALLDATASIZE => Ok(HostOp::CallDataSize),
+            Opcode::CALLDATACOPY => Ok(HostOp::CallDataCopy),
+            Opcode::CODESIZE => Ok(HostOp::CodeSize),
+            Opcode::CODECOPY => Ok(HostOp::CodeCopy),
+            Opcode::GASPRICE => Ok(HostOp::GasPrice),
+            Opcode::EXTCODESIZE => Ok(HostOp::ExtCodeSize),
+            Opcode::EXTCODECOPY => Ok(HostOp::ExtCodeCopy),
+            Opcode::RETURNDATASIZE => Ok(HostOp::ReturnDataSize),
+            Opcode::RETURNDATACOPY => Ok(HostOp::ReturnDataCopy),
+            Opcode::EXTCODEHASH => Ok(HostOp::ExtCodeHash),
+            Opcode::BLOCKHASH => Ok(HostOp::BlockHash),
+            Opcode::COINBASE => Ok(HostOp::Coinbase),
+            Opcode::TIMESTAMP => Ok(HostOp::Timestamp),
+            Opcode::NUMBER => Ok(HostOp::Number),
+            Opcode::PREVRANDAO => Ok(HostOp::PrevRandao),
+            Opcode::GASLIMIT => Ok(HostOp::GasLimit),
+            Opcode::CHAINID => Ok(HostOp::ChainId),
+            Opcode::SELFBALANCE => Ok(HostOp::SelfBalance),
+            Opcode::BASEFEE => Ok(HostOp::BaseFee),
+            Opcode::MSIZE => Ok(HostOp::MSize),
+            Opcode::GAS => Ok(HostOp::Gas),
+            Opcode::LOG0 => Ok(HostOp::Log(0)),
+            Opcode::LOG1 => Ok(HostOp::Log(1)),
+            Opcode::LOG2 => Ok(HostOp::Log(2)),
+            Opcode::LOG3 => Ok(HostOp::Log(3)),
+            Opcode::LOG4 => Ok(HostOp::Log(4)),
+            Opcode::CREATE => Ok(HostOp::Create),
+            Opcode::CREATE2 => Ok(HostOp::Create2),
+            Opcode::CALL => Ok(HostOp::Call),
+            Opcode::CALLCODE => Ok(HostOp::CallCode),
+            Opcode::DELEGATECALL => Ok(HostOp::DelegateCall),
+            Opcode::STATICCALL => Ok(HostOp::StaticCall),
+            Opcode::RETURN => Ok(HostOp::Return),
+            Opcode::REVERT => Ok(HostOp::Revert),
+            Opcode::INVALID => Ok(HostOp::Invalid),
+            Opcode::SELFDESTRUCT => Ok(HostOp::SelfDestruct),
+            _ => Err("Not a host opcode"),
+        }
+    }
+}
+
+impl HostOp {
+    /// Number of stack items consumed
+    pub fn inputs(&self) -> usize {
+        match self {
+            HostOp::Balance
+            | HostOp::CallDataLoad
+            | HostOp::ExtCodeSize
+            | HostOp::ExtCodeHash
+            | HostOp::BlockHash
+            | HostOp::SelfDestruct => 1,
+            HostOp::Return | HostOp::Revert => 2,
+            HostOp::CallDataCopy | HostOp::CodeCopy | HostOp::ReturnDataCopy | HostOp::Create => 3,
+            HostOp::ExtCodeCopy | HostOp::Create2 => 4,
+            HostOp::Log(topics) => 2 + *topics as usize,
+            HostOp::DelegateCall | HostOp::StaticCall => 6,
+            HostOp::Call | HostOp::CallCode => 7,
+            _ => 0,
+        }
+    }
+
+    /// Check if a result is pushed
+    pub fn has_output(&self) -> bool {
+        !matches!(
+            self,
+            HostOp::CallDataCopy
+                | HostOp::CodeCopy
+                | HostOp::ExtCodeCopy
+                | HostOp::ReturnDataCopy
+                | HostOp::Log(_)
+        ) && !self.halts()
+    }
+
+    /// Check if execution ends here
+    pub fn halts(&self) -> bool {
+        matches!(
+            self,
+            HostOp::Return | HostOp::Revert | HostOp::Invalid | HostOp::SelfDestruct
+        )
+    }
+
+    /// Check if memory contents are read, e.g. log data or call input
+    pub fn reads_memory(&self) -> bool {
+        matches!(
+            self,
+            HostOp::Log(_)
+                | HostOp::Create
+                | HostOp::Create2
+                | HostOp::Call
+                | HostOp::CallCode
+                | HostOp::DelegateCall
+                | HostOp::StaticCall
+                | HostOp::Return
+                | HostOp::Revert
+        )
+    }
+
+    /// Check if memory contents are written, e.g. copies or call output
+    pub fn writes_memory(&self) -> bool {
+        matches!(
+            self,
+            HostOp::CallDataCopy
+                | HostOp::CodeCopy
+                | HostOp::ExtCodeCopy
+                | HostOp::ReturnDataCopy
+                | HostOp::Call
+                | HostOp::CallCode
+                | HostOp::DelegateCall
+                | HostOp::StaticCall
+        )
+    }
+}
+
 // Intermediate Representation
-#[derive(Debug, Clone)]
+//
+// Operands name EVM stack slots rather than values: slot `n` is the stack item at
+// height `n` (1-based). For operations `src1` is the item that was on top of the
+// stack (µs[0]), `src2` the one below it and so on, so `sub` computes `src1 - src2`
+// exactly like the EVM. Source slots other than `dest` are consumed by the
+// operation and are never read again before being overwritten. Slots above the
+// highest one in use are free, passes use them for values that outlive the stack.
+// Operation names ("add", "LT", ..) are string literals. Behind an alias serde
+// doesn't try to borrow them from the input, which can't outlive 'static
+pub type OpName = &'static str;
+
+/// Name of the IR op each opcode lowered to a `BinaryOp`, `UnaryOp` or
+/// `TernaryOp` becomes, the only names `generate_ir` emits and serde accepts
+pub const OP_NAMES: [(Opcode, OpName); 26] = [
+    (Opcode::ADD, "add"),
+    (Opcode::SUB, "sub"),
+    (Opcode::MUL, "mul"),
+    (Opcode::DIV, "div"),
+    (Opcode::SDIV, "sdiv"),
+    (Opcode::MOD, "mod"),
+    (Opcode::SMOD, "smod"),
+    (Opcode::ADDMOD, "addmod"),
+    (Opcode::MULMOD, "mulmod"),
+    (Opcode::EXP, "exp"),
+    (Opcode::SIGNEXTEND, "signextend"),
+    (Opcode::LT, "LT"),
+    (Opcode::GT, "GT"),
+    (Opcode::SLT, "SLT"),
+    (Opcode::SGT, "SGT"),
+    (Opcode::EQ, "EQ"),
+    (Opcode::ISZERO, "iszero"),
+    (Opcode::AND, "and"),
+    (Opcode::OR, "or"),
+    (Opcode::XOR, "xor"),
+    (Opcode::NOT, "not"),
+    (Opcode::BYTE, "byte"),
+    (Opcode::SHL, "shl"),
+    (Opcode::SHR, "shr"),
+    (Opcode::SAR, "sar"),
+    (Opcode::POP, "pop"),
+];
+
+// IR op for `opcode`, None if it isn't in `OP_NAMES`
+fn ir_op(opcode: Opcode) -> Option<OpName> {
+    OP_NAMES
+        .iter()
+        .find(|(op, _)| *op == opcode)
+        .map(|&(_, name)| name)
+}
+
+// Serialized with snake_case variant names, `op` as the plain name
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum IRInstruction {
     BinaryOp {
-        op: &'static str,
+        #[cfg_attr(feature = "serde", serde(with = "op_name"))]
+        op: OpName,
         dest: U256,
         src1: U256,
         src2: U256,
     },
     UnaryOp {
-        op: &'static str,
+        #[cfg_attr(feature = "serde", serde(with = "op_name"))]
+        op: OpName,
         dest: U256,
         src: U256,
     },
     TernaryOp {
-        op: &'static str,
+        #[cfg_attr(feature = "serde", serde(with = "op_name"))]
+        op: OpName,
         dest: U256,
         src1: U256,
         src2: U256,
@@ -402,6 +689,43 @@ pub enum IRInstruction {
         offset: U256,
         value: U256,
     },
+    // Stores the low byte of `value`
+    MemoryStore8 {
+        offset: U256,
+        value: U256,
+    },
+    // keccak256 of memory[offset..offset + size]
+    Sha3 {
+        dest: U256,
+        offset: U256,
+        size: U256,
+    },
+    StorageLoad {
+        key: U256,
+        dest: U256,
+    },
+    StorageStore {
+        key: U256,
+        value: U256,
+    },
+    // `args[0]` is the top of the stack, halting kinds end the block
+    HostCall {
+        kind: HostOp,
+        dest: Option<U256>,
+        args: Vec<U256>,
+    },
+    Copy {
+        dest: U256,
+        src: U256,
+    },
+    Swap {
+        a: U256,
+        b: U256,
+    },
+    // Marks the JUMPDEST at `pc`, the only place a jump may land
+    JumpDest {
+        pc: usize,
+    },
     Jump {
         target: U256,
     },
@@ -409,31 +733,432 @@ pub enum IRInstruction {
         condition: U256,
         target: U256,
     },
+    // Internal function call, control resumes at the JUMPDEST at `return_pc`
     Call {
-        target: U256,
+        target: usize,
+        return_pc: usize,
+        args: Vec<U256>,
+        returns: Vec<U256>,
     },
     Stop,
-    Return,
+    // Return from an internal function
+    Return {
+        values: Vec<U256>,
+    },
+}
+
+// Op names are `&'static str`, deserializing maps them back onto `OP_NAMES`
+#[cfg(feature = "serde")]
+mod op_name {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(op: &super::OpName, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(op)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<super::OpName, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        super::OP_NAMES
+            .iter()
+            .map(|&(_, op)| op)
+            .find(|op| *op == name)
+            .ok_or_else(|| D::Error::custom(format!("unknown op `{name}`")))
+    }
+}
+
+impl IRInstruction {
+    /// Slot written by this instruction, if any
+    pub fn dest(&self) -> Option<U256> {
+        match self {
+            IRInstruction::BinaryOp { dest, .. }
+            | IRInstruction::UnaryOp { dest, .. }
+            | IRInstruction::TernaryOp { dest, .. }
+            | IRInstruction::LoadConst { dest, .. }
+            | IRInstruction::MemoryLoad { dest, .. }
+            | IRInstruction::Sha3 { dest, .. }
+            | IRInstruction::StorageLoad { dest, .. }
+            | IRInstruction::Copy { dest, .. } => Some(*dest),
+            IRInstruction::HostCall { dest, .. } => *dest,
+            _ => None,
+        }
+    }
+
+    /// All slots written by this instruction
+    pub fn defs(&self) -> Vec<U256> {
+        match self {
+            IRInstruction::Swap { a, b } => vec![*a, *b],
+            IRInstruction::Call { returns, .. } => returns.clone(),
+            _ => self.dest().into_iter().collect(),
+        }
+    }
+
+    /// Slots read by this instruction
+    pub fn sources(&self) -> Vec<U256> {
+        match self {
+            IRInstruction::BinaryOp { src1, src2, .. } => vec![*src1, *src2],
+            IRInstruction::UnaryOp { src, .. } => vec![*src],
+            IRInstruction::TernaryOp {
+                src1, src2, src3, ..
+            } => vec![*src1, *src2, *src3],
+            IRInstruction::MemoryLoad { offset, .. } => vec![*offset],
+            IRInstruction::MemoryStore { offset, value }
+            | IRInstruction::MemoryStore8 { offset, value } => vec![*offset, *value],
+            IRInstruction::Sha3 { offset, size, .. } => vec![*offset, *size],
+            IRInstruction::StorageLoad { key, .. } => vec![*key],
+            IRInstruction::StorageStore { key, value } => vec![*key, *value],
+            IRInstruction::Copy { src, .. } => vec![*src],
+            IRInstruction::Swap { a, b } => vec![*a, *b],
+            IRInstruction::Jump { target } => vec![*target],
+            IRInstruction::ConditionalJump { condition, target } => vec![*condition, *target],
+            IRInstruction::Call { args, .. } | IRInstruction::HostCall { args, .. } => args.clone(),
+            IRInstruction::Return { values } => values.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Rename every read of slot `from` to `to`
+    pub fn rename_source(&mut self, from: U256, to: U256) {
+        let rename = |slot: &mut U256| {
+            if *slot == from {
+                *slot = to;
+            }
+        };
+        match self {
+            IRInstruction::BinaryOp { src1, src2, .. } => {
+                rename(src1);
+                rename(src2);
+            }
+            IRInstruction::UnaryOp { src, .. } | IRInstruction::Copy { src, .. } => rename(src),
+            IRInstruction::TernaryOp {
+                src1, src2, src3, ..
+            } => {
+                rename(src1);
+                rename(src2);
+                rename(src3);
+            }
+            IRInstruction::MemoryLoad { offset, .. } => rename(offset),
+            IRInstruction::MemoryStore { offset, value }
+            | IRInstruction::MemoryStore8 { offset, value } => {
+                rename(offset);
+                rename(value);
+            }
+            IRInstruction::Sha3 { offset, size, .. } => {
+                rename(offset);
+                rename(size);
+            }
+            IRInstruction::StorageLoad { key, .. } => rename(key),
+            IRInstruction::StorageStore { key, value } => {
+                rename(key);
+                rename(value);
+            }
+            IRInstruction::Swap { a, b } => {
+                rename(a);
+                rename(b);
+            }
+            IRInstruction::Jump { target } => rename(target),
+            IRInstruction::ConditionalJump { condition, target } => {
+                rename(condition);
+                rename(target);
+            }
+            IRInstruction::Call { args, .. } | IRInstruction::HostCall { args, .. } => {
+                args.iter_mut().for_each(rename)
+            }
+            IRInstruction::Return { values } => values.iter_mut().for_each(rename),
+            _ => {}
+        }
+    }
+
+    /// Check if this instruction ends a basic block
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            self,
+            IRInstruction::Jump { .. }
+                | IRInstruction::ConditionalJump { .. }
+                | IRInstruction::Call { .. }
+                | IRInstruction::Stop
+                | IRInstruction::Return { .. }
+        ) || matches!(self, IRInstruction::HostCall { kind, .. } if kind.halts())
+    }
 }
 
 // RISC-V Instruction
-#[derive(Debug, Clone)]
+//
+// RV64I plus the M instructions inline multiplication and the runtime library use,
+// every variant is one 32-bit instruction word. `J` is `JAL` with `rd` zero, kept apart as the plain
+// jump the code generator patches, and `li` expands constants into these.
+// Immediates hold what the assembler takes: branch and jump offsets are bytes from
+// the instruction, `LUI`/`AUIPC` take the upper 20 bits and shifts the amount.
+// `check` reports immediates that don't fit their field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum RiscVInstruction {
-    ADD { rd: usize, rs1: usize, rs2: usize },
-    SUB { rd: usize, rs1: usize, rs2: usize },
-    AND { rd: usize, rs1: usize, rs2: usize },
-    OR { rd: usize, rs1: usize, rs2: usize },
-    XOR { rd: usize, rs1: usize, rs2: usize },
-    SLL { rd: usize, rs1: usize, rs2: usize },
-    SRL { rd: usize, rs1: usize, rs2: usize },
-    SRA { rd: usize, rs1: usize, rs2: usize },
-    LW { rd: usize, rs1: usize, imm: i32 },
-    SW { rs1: usize, rs2: usize, imm: i32 },
-    LI { rd: usize, imm: i64 },
+    LUI { rd: Register, imm: u32 },
+    AUIPC { rd: Register, imm: u32 },
+    JAL { rd: Register, offset: i32 },
     J { offset: i32 },
-    BEQ { rs1: usize, rs2: usize, offset: i32 },
-    JAL { rd: usize, offset: i32 },
-    JALR { rd: usize, rs1: usize, offset: i32 },
+    JALR { rd: Register, rs1: Register, offset: i32 },
+    BEQ { rs1: Register, rs2: Register, offset: i32 },
+    BNE { rs1: Register, rs2: Register, offset: i32 },
+    BLT { rs1: Register, rs2: Register, offset: i32 },
+    BGE { rs1: Register, rs2: Register, offset: i32 },
+    BLTU { rs1: Register, rs2: Register, offset: i32 },
+    BGEU { rs1: Register, rs2: Register, offset: i32 },
+    LB { rd: Register, rs1: Register, imm: i32 },
+    LH { rd: Register, rs1: Register, imm: i32 },
+    LW { rd: Register, rs1: Register, imm: i32 },
+    LD { rd: Register, rs1: Register, imm: i32 },
+    LBU { rd: Register, rs1: Register, imm: i32 },
+    LHU { rd: Register, rs1: Register, imm: i32 },
+    LWU { rd: Register, rs1: Register, imm: i32 },
+    SB { rs1: Register, rs2: Register, imm: i32 },
+    SH { rs1: Register, rs2: Register, imm: i32 },
+    SW { rs1: Register, rs2: Register, imm: i32 },
+    SD { rs1: Register, rs2: Register, imm: i32 },
+    ADDI { rd: Register, rs1: Register, imm: i32 },
+    SLTI { rd: Register, rs1: Register, imm: i32 },
+    SLTIU { rd: Register, rs1: Register, imm: i32 },
+    XORI { rd: Register, rs1: Register, imm: i32 },
+    ORI { rd: Register, rs1: Register, imm: i32 },
+    ANDI { rd: Register, rs1: Register, imm: i32 },
+    SLLI { rd: Register, rs1: Register, shamt: u32 },
+    SRLI { rd: Register, rs1: Register, shamt: u32 },
+    SRAI { rd: Register, rs1: Register, shamt: u32 },
+    ADD { rd: Register, rs1: Register, rs2: Register },
+    SUB { rd: Register, rs1: Register, rs2: Register },
+    SLL { rd: Register, rs1: Register, rs2: Register },
+    SLT { rd: Register, rs1: Register, rs2: Register },
+    SLTU { rd: Register, rs1: Register, rs2: Register },
+    XOR { rd: Register, rs1: Register, rs2: Register },
+    SRL { rd: Register, rs1: Register, rs2: Register },
+    SRA { rd: Register, rs1: Register, rs2: Register },
+    OR { rd: Register, rs1: Register, rs2: Register },
+    AND { rd: Register, rs1: Register, rs2: Register },
+    ADDIW { rd: Register, rs1: Register, imm: i32 },
+    SLLIW { rd: Register, rs1: Register, shamt: u32 },
+    SRLIW { rd: Register, rs1: Register, shamt: u32 },
+    SRAIW { rd: Register, rs1: Register, shamt: u32 },
+    ADDW { rd: Register, rs1: Register, rs2: Register },
+    SUBW { rd: Register, rs1: Register, rs2: Register },
+    SLLW { rd: Register, rs1: Register, rs2: Register },
+    SRLW { rd: Register, rs1: Register, rs2: Register },
+    SRAW { rd: Register, rs1: Register, rs2: Register },
+    FENCE { pred: u8, succ: u8 },
+    ECALL,
+    EBREAK,
+    MUL { rd: Register, rs1: Register, rs2: Register },
+    MULHU { rd: Register, rs1: Register, rs2: Register },
+    DIVU { rd: Register, rs1: Register, rs2: Register },
+    REMU { rd: Register, rs1: Register, rs2: Register },
+}
+
+/// An immediate that doesn't fit the field of its instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImmediateError {
+    OutOfRange { value: i64, min: i64, max: i64 },
+    // branch and jump offsets are in units of 2 bytes
+    Misaligned { offset: i32 },
+}
+
+impl fmt::Display for ImmediateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImmediateError::OutOfRange { value, min, max } => {
+                write!(f, "immediate {value} is outside {min}..={max}")
+            }
+            ImmediateError::Misaligned { offset } => {
+                write!(f, "offset {offset} is not a multiple of 2")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImmediateError {}
+
+// `value` as a `bits` wide two's complement immediate
+fn check_signed(value: i64, bits: u32) -> Result<(), ImmediateError> {
+    check_range(value, -(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+}
+
+fn check_range(value: i64, min: i64, max: i64) -> Result<(), ImmediateError> {
+    if (min..=max).contains(&value) {
+        Ok(())
+    } else {
+        Err(ImmediateError::OutOfRange { value, min, max })
+    }
+}
+
+// Branch or jump offset with `bits` bits, the lowest of which isn't encoded
+fn check_offset(offset: i32, bits: u32) -> Result<(), ImmediateError> {
+    check_signed(offset as i64, bits)?;
+    if offset % 2 != 0 {
+        return Err(ImmediateError::Misaligned { offset });
+    }
+    Ok(())
+}
+
+impl RiscVInstruction {
+    /// Check that every immediate fits its field
+    pub fn check(&self) -> Result<(), ImmediateError> {
+        use RiscVInstruction::*;
+        match *self {
+            LUI { imm, .. } | AUIPC { imm, .. } => check_range(imm as i64, 0, 0xf_ffff),
+            JAL { offset, .. } | J { offset } => check_offset(offset, 21),
+            BEQ { offset, .. }
+            | BNE { offset, .. }
+            | BLT { offset, .. }
+            | BGE { offset, .. }
+            | BLTU { offset, .. }
+            | BGEU { offset, .. } => check_offset(offset, 13),
+            JALR { offset: imm, .. }
+            | LB { imm, .. }
+            | LH { imm, .. }
+            | LW { imm, .. }
+            | LD { imm, .. }
+            | LBU { imm, .. }
+            | LHU { imm, .. }
+            | LWU { imm, .. }
+            | SB { imm, .. }
+            | SH { imm, .. }
+            | SW { imm, .. }
+            | SD { imm, .. }
+            | ADDI { imm, .. }
+            | SLTI { imm, .. }
+            | SLTIU { imm, .. }
+            | XORI { imm, .. }
+            | ORI { imm, .. }
+            | ANDI { imm, .. }
+            | ADDIW { imm, .. } => check_signed(imm as i64, 12),
+            SLLI { shamt, .. } | SRLI { shamt, .. } | SRAI { shamt, .. } => {
+                check_range(shamt as i64, 0, 63)
+            }
+            SLLIW { shamt, .. } | SRLIW { shamt, .. } | SRAIW { shamt, .. } => {
+                check_range(shamt as i64, 0, 31)
+            }
+            FENCE { pred, succ } => {
+                check_range(pred as i64, 0, 15)?;
+                check_range(succ as i64, 0, 15)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Assembler mnemonic
+    pub fn mnemonic(&self) -> &'static str {
+        use RiscVInstruction::*;
+        match self {
+            LUI { .. } => "lui",
+            AUIPC { .. } => "auipc",
+            JAL { .. } => "jal",
+            J { .. } => "j",
+            JALR { .. } => "jalr",
+            BEQ { .. } => "beq",
+            BNE { .. } => "bne",
+            BLT { .. } => "blt",
+            BGE { .. } => "bge",
+            BLTU { .. } => "bltu",
+            BGEU { .. } => "bgeu",
+            LB { .. } => "lb",
+            LH { .. } => "lh",
+            LW { .. } => "lw",
+            LD { .. } => "ld",
+            LBU { .. } => "lbu",
+            LHU { .. } => "lhu",
+            LWU { .. } => "lwu",
+            SB { .. } => "sb",
+            SH { .. } => "sh",
+            SW { .. } => "sw",
+            SD { .. } => "sd",
+            ADDI { .. } => "addi",
+            SLTI { .. } => "slti",
+            SLTIU { .. } => "sltiu",
+            XORI { .. } => "xori",
+            ORI { .. } => "ori",
+            ANDI { .. } => "andi",
+            SLLI { .. } => "slli",
+            SRLI { .. } => "srli",
+            SRAI { .. } => "srai",
+            ADD { .. } => "add",
+            SUB { .. } => "sub",
+            SLL { .. } => "sll",
+            SLT { .. } => "slt",
+            SLTU { .. } => "sltu",
+            XOR { .. } => "xor",
+            SRL { .. } => "srl",
+            SRA { .. } => "sra",
+            OR { .. } => "or",
+            AND { .. } => "and",
+            ADDIW { .. } => "addiw",
+            SLLIW { .. } => "slliw",
+            SRLIW { .. } => "srliw",
+            SRAIW { .. } => "sraiw",
+            ADDW { .. } => "addw",
+            SUBW { .. } => "subw",
+            SLLW { .. } => "sllw",
+            SRLW { .. } => "srlw",
+            SRAW { .. } => "sraw",
+            FENCE { .. } => "fence",
+            ECALL => "ecall",
+            EBREAK => "ebreak",
+            MUL { .. } => "mul",
+            MULHU { .. } => "mulhu",
+            DIVU { .. } => "divu",
+            REMU { .. } => "remu",
+        }
+    }
+
+    /// The `li` pseudo-instruction, loading `imm` into `rd`
+    ///
+    /// Expands like the assembler does: `ADDI` for 12 bits, `LUI` and `ADDIW` for
+    /// 32, otherwise the upper bits are loaded recursively, shifted into place and
+    /// the low 12 bits added.
+    pub fn li(rd: Register, imm: i64) -> Vec<Self> {
+        let lo = imm << 52 >> 52;
+        if imm == lo {
+            return vec![RiscVInstruction::ADDI {
+                rd,
+                rs1: Register::ZERO,
+                imm: lo as i32,
+            }];
+        }
+
+        if imm == imm as i32 as i64 {
+            // `ADDIW` wraps at 32 bits, so rounding the upper bits up past
+            // `i32::MAX` still works out
+            let upper = (imm - lo) >> 12;
+            let mut code = vec![RiscVInstruction::LUI {
+                rd,
+                imm: upper as u32 & 0xf_ffff,
+            }];
+            if lo != 0 {
+                code.push(RiscVInstruction::ADDIW {
+                    rd,
+                    rs1: rd,
+                    imm: lo as i32,
+                });
+            }
+            return code;
+        }
+
+        let upper = imm.wrapping_sub(lo) >> 12;
+        let shift = upper.trailing_zeros();
+        let mut code = Self::li(rd, upper >> shift);
+        code.push(RiscVInstruction::SLLI {
+            rd,
+            rs1: rd,
+            shamt: 12 + shift,
+        });
+        if lo != 0 {
+            code.push(RiscVInstruction::ADDI {
+                rd,
+                rs1: rd,
+                imm: lo as i32,
+            });
+        }
+        code
+    }
 }
 
 // Bytecode Parser
@@ -464,15 +1189,10 @@ fn parse_push_operand(
     index: &mut usize,
     size: usize,
 ) -> Result<Option<Vec<u8>>, &'static str> {
-    //todo: should be a feature(too performance expensive for every check), so we assume the bytecode is well formed
-    // if size == 0 || size > 32 {
-    //     return Err("Invalid PUSH size");
-    // }
-
-    // if *index + size > bytecode.len() {
-    //     return Err("Unexpected end of bytecode");
-    // }
-    let operand = bytecode[*index..*index + size].to_vec();
+    // a PUSH cut off by the end of the bytecode keeps the bytes it has,
+    // generate_ir reports it
+    let end = bytecode.len().min(*index + size);
+    let operand = bytecode[*index..end].to_vec();
     *index += size;
 
     Ok(Some(operand))
@@ -489,151 +1209,238 @@ fn pad_left(bytes: &[u8]) -> [u8; 32] {
     padded
 }
 
+// Largest SHA3 input hashed while generating IR
+const MAX_CONCRETE_HASH: usize = 1 << 20;
+
+// Offset of `len` bytes at `offset` in the memory modelled while generating IR,
+// None past `MEMORY_LIMIT`. Stack values there aren't all real (SLOAD and host
+// calls push 0), so an offset out of reach is left to runtime rather than an error.
+fn concrete_range(offset: U256, len: usize) -> Option<usize> {
+    let offset = usize::try_from(offset.0).ok()?;
+    offset.checked_add(len).filter(|&end| end <= MEMORY_LIMIT)?;
+    Some(offset)
+}
+
+// Slot operand for the stack item at `height` (1-based)
+fn slot(height: usize) -> U256 {
+    U256(U::from(height))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompileErrorKind {
+    StackUnderflow,
+    StackOverflow,
+    UnsupportedOpcode,
+    // PUSH with fewer operand bytes than its size
+    TruncatedPush,
+    // statically known jump target that isn't a JUMPDEST
+    InvalidJump { target: U256 },
+}
+
+/// Why and where `generate_ir` gave up on a contract
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompileError {
+    pub pc: usize,
+    pub opcode: Opcode,
+    pub reason: CompileErrorKind,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pc {:#x} ({:?}): ", self.pc, self.opcode)?;
+        match self.reason {
+            CompileErrorKind::StackUnderflow => write!(f, "stack underflow"),
+            CompileErrorKind::StackOverflow => write!(f, "stack overflow"),
+            CompileErrorKind::UnsupportedOpcode => write!(f, "unsupported opcode"),
+            CompileErrorKind::TruncatedPush => write!(f, "truncated push operand"),
+            CompileErrorKind::InvalidJump { target } => {
+                write!(f, "jump to {:#x}, which is not a JUMPDEST", target.0)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
 // IR Generator
 pub fn generate_ir(
     instructions: &[Instruction],
     stack: &mut Stack,
     memory: &mut Memory,
-) -> Vec<IRInstruction> {
+) -> Result<Vec<IRInstruction>, CompileError> {
+    generate_ir_with_pcs(instructions, stack, memory).map(|(ir, _)| ir)
+}
+
+/// Like `generate_ir`, also returning the EVM pc each IR instruction came from
+pub fn generate_ir_with_pcs(
+    instructions: &[Instruction],
+    stack: &mut Stack,
+    memory: &mut Memory,
+) -> Result<(Vec<IRInstruction>, Vec<usize>), CompileError> {
     let mut ir = Vec::new();
+    let mut pcs = Vec::new();
+    let mut next_pc = 0;
+
+    let mut jumpdests = HashSet::new();
+    for inst in instructions {
+        if inst.opcode == Opcode::JUMPDEST {
+            jumpdests.insert(next_pc);
+        }
+        next_pc += 1 + inst.operand.as_ref().map_or(0, |operand| operand.len());
+    }
+    // constants in the current block, to catch jumps to a non-JUMPDEST
+    let mut constants = ConstantSlots::new();
 
+    next_pc = 0;
     for inst in instructions {
+        let pc = next_pc;
+        next_pc += 1 + inst.operand.as_ref().map_or(0, |operand| operand.len());
+        let emitted = ir.len();
+
+        let fail = |reason: CompileErrorKind| CompileError {
+            pc,
+            opcode: inst.opcode,
+            reason,
+        };
+        let stack_error = |error: StackError| {
+            fail(match error {
+                StackError::Overflow => CompileErrorKind::StackOverflow,
+                StackError::Underflow => CompileErrorKind::StackUnderflow,
+            })
+        };
+        let op = || ir_op(inst.opcode).ok_or_else(|| fail(CompileErrorKind::UnsupportedOpcode));
+        let check_jump = |target: U256| match constants.get(target) {
+            Some(value) if !is_jumpdest(&jumpdests, value) => {
+                Err(fail(CompileErrorKind::InvalidJump { target: value }))
+            }
+            _ => Ok(()),
+        };
+
         match inst.opcode {
             Opcode::STOP => {
                 ir.push(IRInstruction::Stop);
-                break;
             }
             Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV | Opcode::MOD => {
-                let op = match inst.opcode {
-                    Opcode::ADD => "add",
-                    Opcode::SUB => "sub",
-                    Opcode::MUL => "mul",
-                    Opcode::DIV => "div",
-                    Opcode::MOD => "mod",
-                    _ => unreachable!(),
-                };
                 //capture stack length before any operation
                 let stack_pos = stack.len();
 
-                let src2 = stack.pop().expect("stack underflow");
-                let src1 = stack.pop().expect("stack underflow");
-                let result = match op {
-                    "add" => src1.add(src2),
-                    "sub" => src1.sub(src2),
-                    "mul" => src1.mul(src2),
-                    "div" => {
+                let src1 = stack.pop().map_err(stack_error)?;
+                let src2 = stack.pop().map_err(stack_error)?;
+                let result = match inst.opcode {
+                    Opcode::ADD => src1.add(src2),
+                    Opcode::SUB => src1.sub(src2),
+                    Opcode::MUL => src1.mul(src2),
+                    Opcode::DIV => {
                         if src2 != U256::default() {
                             src1 / src2
                         } else {
                             U256::default()
                         }
                     }
-                    "mod" => {
+                    Opcode::MOD => {
                         if src2 != U256::default() {
                             src1 % src2
                         } else {
                             U256::default()
                         }
                     }
-                    _ => unreachable!(),
+                    _ => return Err(fail(CompileErrorKind::UnsupportedOpcode)),
                 };
-                stack.push(result).expect("");
+                stack.push(result).map_err(stack_error)?;
                 ir.push(IRInstruction::BinaryOp {
-                    op,
-                    dest: U256(U::from(stack_pos - 2)),
-                    src1: U256(U::from(stack_pos - 2)),
-                    src2: U256(U::from(stack_pos - 1)),
+                    op: op()?,
+                    dest: slot(stack_pos - 1),
+                    src1: slot(stack_pos),
+                    src2: slot(stack_pos - 1),
                 });
             }
-            Opcode::SMOD => {
-                let b = stack.pop().expect("Stack underflow");
-                let a = stack.pop().expect("Stack underflow");
-
+            Opcode::SDIV | Opcode::SMOD => {
                 //capture stack length before any operation
                 let stack_pos = stack.len();
 
-                let result = if b.0.is_zero() {
-                    U256::default()
+                let a = stack.pop().map_err(stack_error)?;
+                let b = stack.pop().map_err(stack_error)?;
+
+                let result = if inst.opcode == Opcode::SDIV {
+                    I256(a.0) / I256(b.0)
                 } else {
-                    let a_i256 = I256(a.0);
-                    let b_i256 = I256(b.0);
-                    let r = a_i256 % b_i256;
-                    U256(r.0)
+                    I256(a.0) % I256(b.0)
                 };
-                stack.push(result).expect("Stack overflow");
+                stack.push(U256(result.0)).map_err(stack_error)?;
                 ir.push(IRInstruction::BinaryOp {
-                    op: "smod",
-                    dest: U256(U::from(stack_pos - 2)),
-                    src1: U256(U::from(stack_pos - 2)),
-                    src2: U256(U::from(stack_pos - 1)),
+                    op: op()?,
+                    dest: slot(stack_pos - 1),
+                    src1: slot(stack_pos),
+                    src2: slot(stack_pos - 1),
                 });
             }
             Opcode::ADDMOD => {
-                let n = stack.pop().expect("Stack underflow");
-                let b = stack.pop().expect("Stack underflow");
-                let a = stack.pop().expect("Stack underflow");
-
                 //capture stack length before any operation
                 let stack_pos = stack.len();
+
+                let a = stack.pop().map_err(stack_error)?;
+                let b = stack.pop().map_err(stack_error)?;
+                let n = stack.pop().map_err(stack_error)?;
+
                 let result = if n.0.is_zero() {
                     U256::default()
                 } else {
                     let sum = a.0.overflowing_add(b.0).0;
                     U256(sum % n.0)
                 };
-                stack.push(result).expect("Stack overflow");
+                stack.push(result).map_err(stack_error)?;
                 ir.push(IRInstruction::TernaryOp {
-                    op: "addmod",
-                    dest: U256(U::from(stack_pos - 3)),
-                    src1: U256(U::from(stack_pos - 3)),
-                    src2: U256(U::from(stack_pos - 2)),
-                    src3: U256(U::from(stack_pos - 1)),
+                    op: op()?,
+                    dest: slot(stack_pos - 2),
+                    src1: slot(stack_pos),
+                    src2: slot(stack_pos - 1),
+                    src3: slot(stack_pos - 2),
                 });
             }
             Opcode::MULMOD => {
-                let n = stack.pop().expect("Stack underflow");
-                let b = stack.pop().expect("Stack underflow");
-                let a = stack.pop().expect("Stack underflow");
-
                 //capture stack length before any operation
                 let stack_pos = stack.len();
+
+                let a = stack.pop().map_err(stack_error)?;
+                let b = stack.pop().map_err(stack_error)?;
+                let n = stack.pop().map_err(stack_error)?;
+
                 let result = if n.0.is_zero() {
                     U256::default()
                 } else {
                     let product = a.0.overflowing_mul(b.0).0;
                     U256(product % n.0)
                 };
-                stack.push(result).expect("Stack overflow");
+                stack.push(result).map_err(stack_error)?;
                 ir.push(IRInstruction::TernaryOp {
-                    op: "mulmod",
-                    dest: U256(U::from(stack_pos - 3)),
-                    src1: U256(U::from(stack_pos - 3)),
-                    src2: U256(U::from(stack_pos - 2)),
-                    src3: U256(U::from(stack_pos - 1)),
+                    op: op()?,
+                    dest: slot(stack_pos - 2),
+                    src1: slot(stack_pos),
+                    src2: slot(stack_pos - 1),
+                    src3: slot(stack_pos - 2),
                 });
             }
             Opcode::EXP => {
-                let b = stack.pop().expect("Stack underflow");
-                let a = stack.pop().expect("Stack underflow");
-                let result = U256(a.0.overflowing_pow(b.0).0);
-                stack.push(result).expect("Stack overflow");
-
                 //capture stack length before any operation
                 let stack_pos = stack.len();
+
+                let a = stack.pop().map_err(stack_error)?;
+                let b = stack.pop().map_err(stack_error)?;
+                let result = U256(a.0.overflowing_pow(b.0).0);
+                stack.push(result).map_err(stack_error)?;
+
                 ir.push(IRInstruction::BinaryOp {
-                    op: "exp",
-                    dest: U256(U::from(stack_pos - 2)),
-                    src1: U256(U::from(stack_pos - 2)),
-                    src2: U256(U::from(stack_pos - 1)),
+                    op: op()?,
+                    dest: slot(stack_pos - 1),
+                    src1: slot(stack_pos),
+                    src2: slot(stack_pos - 1),
                 });
             }
             Opcode::SIGNEXTEND => {
                 let stack_pos = stack.len();
-                let val = stack.pop().expect("stack underflow");
-                let ext = stack.pop().expect("stack underflow");
-                
+                let ext = stack.pop().map_err(stack_error)?;
+                let val = stack.pop().map_err(stack_error)?;
 
-               
                 let result = if ext.0 >= U::from(32) {
                     val
                 } else {
@@ -647,178 +1454,298 @@ pub fn generate_ir(
                     }
                     U256(extended)
                 };
-                stack.push(result).expect("stack overflow");
+                stack.push(result).map_err(stack_error)?;
                 ir.push(IRInstruction::BinaryOp {
-                    op: "signextend",
-                    dest: U256(U::from(stack_pos - 2)),
-                    src1: U256(U::from(stack_pos - 2)),
-                    src2: U256(U::from(stack_pos - 1)),
+                    op: op()?,
+                    dest: slot(stack_pos - 1),
+                    src1: slot(stack_pos),
+                    src2: slot(stack_pos - 1),
                 });
             }
             Opcode::LT | Opcode::GT | Opcode::SLT | Opcode::SGT | Opcode::EQ => {
-                let b = stack.pop().expect("Stack underflow");
-                let a = stack.pop().expect("Stack underflow");
-                let ops;
-
                 //capture stack length before any operation
                 let stack_pos = stack.len();
+
+                let a = stack.pop().map_err(stack_error)?;
+                let b = stack.pop().map_err(stack_error)?;
+
                 let result = match inst.opcode {
-                    Opcode::LT => {
-                        ops = "LT";
-                        U256(U::from(a.0 < b.0))
-                    }
-                    Opcode::GT => {
-                        ops = "GT";
-                        U256(U::from(a.0 > b.0))
-                    }
+                    Opcode::LT => U256(U::from(a.0 < b.0)),
+                    Opcode::GT => U256(U::from(a.0 > b.0)),
                     Opcode::SLT => {
-                        ops = "SLT";
                         let a_i256 = I256(a.0);
                         let b_i256 = I256(b.0);
                         U256(U::from(a_i256 < b_i256))
                     }
                     Opcode::SGT => {
-                        ops = "SGT";
                         let a_i256 = I256(a.0);
                         let b_i256 = I256(b.0);
                         U256(U::from(a_i256 > b_i256))
                     }
-                    Opcode::EQ => {
-                        ops = "EQ";
-                        U256(U::from(a.0 == b.0))
-                    }
-                    _ => unreachable!(),
+                    Opcode::EQ => U256(U::from(a.0 == b.0)),
+                    _ => return Err(fail(CompileErrorKind::UnsupportedOpcode)),
                 };
-                stack.push(result).expect("Stack overflow");
+                stack.push(result).map_err(stack_error)?;
                 ir.push(IRInstruction::BinaryOp {
-                    op: ops,
-                    dest: U256(U::from(stack_pos - 2)),
-                    src1: U256(U::from(stack_pos - 2)),
-                    src2: U256(U::from(stack_pos - 1)),
+                    op: op()?,
+                    dest: slot(stack_pos - 1),
+                    src1: slot(stack_pos),
+                    src2: slot(stack_pos - 1),
                 });
             }
             Opcode::NOT => {
-                let a = stack.pop().expect("Stack underflow");
+                //capture stack length before any operation
+                let stack_pos = stack.len();
+
+                let a = stack.pop().map_err(stack_error)?;
                 let result = U256(!a.0);
-                stack.push(result).expect("Stack overflow");
+                stack.push(result).map_err(stack_error)?;
 
+                ir.push(IRInstruction::UnaryOp {
+                    op: op()?,
+                    dest: slot(stack_pos),
+                    src: slot(stack_pos),
+                });
+            }
+            Opcode::ISZERO => {
                 //capture stack length before any operation
                 let stack_pos = stack.len();
+
+                let a = stack.pop().map_err(stack_error)?;
+                let result = U256(U::from(a.0.is_zero()));
+                stack.push(result).map_err(stack_error)?;
+
                 ir.push(IRInstruction::UnaryOp {
-                    op: "not",
-                    dest: U256(U::from(0)),
-                    src: U256(U::from(stack_pos - 1)),
+                    op: op()?,
+                    dest: slot(stack_pos),
+                    src: slot(stack_pos),
                 });
             }
             Opcode::AND | Opcode::OR | Opcode::XOR => {
                 let stack_pos = stack.len();
-                let b = stack.pop().expect("stack underflow");
-                let a = stack.pop().expect("stack underflow");
+                let a = stack.pop().map_err(stack_error)?;
+                let b = stack.pop().map_err(stack_error)?;
                 
-                let (op, result) = match inst.opcode {
-                    Opcode::AND => ("and", U256(a.0 & b.0)),
-                    Opcode::OR => ("or", U256(a.0 | b.0)),
-                    Opcode::XOR => ("xor", U256(a.0 ^ b.0)),
-                    _ => unreachable!(),
+                let result = match inst.opcode {
+                    Opcode::AND => U256(a.0 & b.0),
+                    Opcode::OR => U256(a.0 | b.0),
+                    Opcode::XOR => U256(a.0 ^ b.0),
+                    _ => return Err(fail(CompileErrorKind::UnsupportedOpcode)),
                 };
                 
-                stack.push(result).expect("stack overflow");
+                stack.push(result).map_err(stack_error)?;
                 ir.push(IRInstruction::BinaryOp {
-                    op,
-                    dest: U256(U::from(stack_pos - 2)),
-                    src1: U256(U::from(stack_pos - 2)),
-                    src2: U256(U::from(stack_pos - 1)),
+                    op: op()?,
+                    dest: slot(stack_pos - 1),
+                    src1: slot(stack_pos),
+                    src2: slot(stack_pos - 1),
                 });
             }
             Opcode::BYTE => {
-                let i = stack.pop().expect("Stack underflow");
-                let x = stack.pop().expect("Stack underflow");
+                //capture stack length before any operation
+                let stack_pos = stack.len();
+
+                let i = stack.pop().map_err(stack_error)?;
+                let x = stack.pop().map_err(stack_error)?;
                 let result = if i.0 >= U::from(32) {
                     U256::default()
                 } else {
                     let byte = (x.0 >> (U::from(8) * (U::from(31) - i.0))) & U::from(0xFF);
                     U256(byte)
                 };
-                //capture stack length before any operation
-                let stack_pos = stack.len();
-                stack.push(result).expect("Stack overflow");
+                stack.push(result).map_err(stack_error)?;
                 ir.push(IRInstruction::BinaryOp {
-                    op: "byte",
-                    dest: U256(U::from(stack_pos - 2)),
-                    src1: U256(U::from(stack_pos - 2)),
-                    src2: U256(U::from(stack_pos - 1)),
+                    op: op()?,
+                    dest: slot(stack_pos - 1),
+                    src1: slot(stack_pos),
+                    src2: slot(stack_pos - 1),
                 });
             }
             Opcode::SHA3 => {
-                let _offset = stack.pop().expect("Stack underflow");
-                let _size = stack.pop().expect("Stack underflow");
-                unimplemented!()
+                let stack_pos = stack.len();
+                let offset = stack.pop().map_err(stack_error)?;
+                let size = stack.pop().map_err(stack_error)?;
+                // huge ranges can't come from real constants, leave those to runtime
+                let hash = match usize::try_from(size.0) {
+                    Ok(0) => U256(U::from_be_bytes(memory.keccak256(0, 0))),
+                    Ok(size) if size <= MAX_CONCRETE_HASH => match concrete_range(offset, size) {
+                        Some(offset) => {
+                            memory.expand(offset + size);
+                            U256(U::from_be_bytes(memory.keccak256(offset, size)))
+                        }
+                        None => U256::default(),
+                    },
+                    _ => U256::default(),
+                };
+                stack.push(hash).map_err(stack_error)?;
+                ir.push(IRInstruction::Sha3 {
+                    dest: slot(stack_pos - 1),
+                    offset: slot(stack_pos),
+                    size: slot(stack_pos - 1),
+                });
+            }
+            opcode if HostOp::try_from(opcode).is_ok() => {
+                let kind = HostOp::try_from(opcode)
+                    .map_err(|_| fail(CompileErrorKind::UnsupportedOpcode))?;
+                let stack_pos = stack.len();
+                for _ in 0..kind.inputs() {
+                    stack.pop().map_err(stack_error)?;
+                }
+                // the host's answer is only known at runtime
+                let dest = if kind.has_output() {
+                    stack.push(U256::default()).map_err(stack_error)?;
+                    Some(slot(stack.len()))
+                } else {
+                    None
+                };
+                ir.push(IRInstruction::HostCall {
+                    kind,
+                    dest,
+                    args: (0..kind.inputs()).map(|i| slot(stack_pos - i)).collect(),
+                });
             }
             opcode if opcode.is_push() => {
                 if let Some(operand) = &inst.operand {
+                    if Some(operand.len()) != opcode.push_size() {
+                        return Err(fail(CompileErrorKind::TruncatedPush));
+                    }
                     let stack_pos = stack.len();
                     let value = U256(U::from_be_bytes(pad_left(operand)));
-                    stack.push(value).expect("can't push to stack");
+                    stack.push(value).map_err(stack_error)?;
                     ir.push(IRInstruction::LoadConst {
-                        dest: U256(U::from(stack_pos)),
+                        dest: slot(stack_pos + 1),
                         value,
                     });
                 }
             }
             Opcode::POP => {
-                stack.pop().expect("");
                 //capture stack length before any operation
                 let stack_pos = stack.len();
+                stack.pop().map_err(stack_error)?;
                 ir.push(IRInstruction::UnaryOp {
-                    op: "pop",
+                    op: op()?,
                     dest: U256(U::from(0)),
-                    src: U256(U::from(stack_pos - 1)),
+                    src: slot(stack_pos),
+                });
+            }
+            opcode if opcode.dup_depth().is_some() => {
+                let depth = opcode
+                    .dup_depth()
+                    .ok_or_else(|| fail(CompileErrorKind::UnsupportedOpcode))?;
+                let stack_pos = stack.len();
+                stack.dup(depth).map_err(stack_error)?;
+                ir.push(IRInstruction::Copy {
+                    dest: slot(stack_pos + 1),
+                    src: slot(stack_pos + 1 - depth),
+                });
+            }
+            opcode if opcode.swap_depth().is_some() => {
+                let depth = opcode
+                    .swap_depth()
+                    .ok_or_else(|| fail(CompileErrorKind::UnsupportedOpcode))?;
+                let stack_pos = stack.len();
+                stack.swap(depth).map_err(stack_error)?;
+                ir.push(IRInstruction::Swap {
+                    a: slot(stack_pos),
+                    b: slot(stack_pos - depth),
+                });
+            }
+            Opcode::PC => {
+                let stack_pos = stack.len();
+                let value = U256(U::from(pc));
+                stack.push(value).map_err(stack_error)?;
+                ir.push(IRInstruction::LoadConst {
+                    dest: slot(stack_pos + 1),
+                    value,
                 });
             }
+            Opcode::JUMPDEST => {
+                ir.push(IRInstruction::JumpDest { pc });
+            }
             Opcode::JUMP => {
-                let target = stack.pop().expect("");
-                ir.push(IRInstruction::Jump { target });
+                let stack_pos = stack.len();
+                stack.pop().map_err(stack_error)?;
+                check_jump(slot(stack_pos))?;
+                ir.push(IRInstruction::Jump {
+                    target: slot(stack_pos),
+                });
             }
             Opcode::JUMPI => {
-                let condition = stack.pop().expect("");
-                let target = stack.pop().expect("");
-                ir.push(IRInstruction::ConditionalJump { condition, target });
+                let stack_pos = stack.len();
+                stack.pop().map_err(stack_error)?;
+                stack.pop().map_err(stack_error)?;
+                check_jump(slot(stack_pos))?;
+                ir.push(IRInstruction::ConditionalJump {
+                    condition: slot(stack_pos - 1),
+                    target: slot(stack_pos),
+                });
             }
             Opcode::MLOAD => {
-                let index = stack.pop().expect("Stack underflow");
-                let value = memory.read_word(index.as_usize());
+                let stack_pos = stack.len();
+                let index = stack.pop().map_err(stack_error)?;
+                let value = match concrete_range(index, 32) {
+                    Some(offset) => memory.read_word(offset),
+                    None => [0; 32],
+                };
 
-                let _ = stack.push(U256::try_from(value).unwrap());
+                stack
+                    .push(U256(U::from_be_bytes(value)))
+                    .map_err(stack_error)?;
 
                 ir.push(IRInstruction::MemoryLoad {
-                    offset: U256(U::from(stack.len() - 1)),
-                    dest: U256::default(),
+                    offset: slot(stack_pos),
+                    dest: slot(stack_pos),
                 })
             }
             Opcode::MSTORE => {
-                let offset = stack.pop().expect("Stack underflow");
-                let value = stack.pop().expect("Stack underflow");
+                let stack_pos = stack.len();
+                let offset = stack.pop().map_err(stack_error)?;
+                let value = stack.pop().map_err(stack_error)?;
 
-                memory.write_word(offset.as_usize(), value.to_be_bytes());
+                if let Some(offset) = concrete_range(offset, 32) {
+                    memory.write_word(offset, value.to_be_bytes());
+                }
 
                 ir.push(IRInstruction::MemoryStore {
-                    offset: U256(U::from(stack.len())),
-                    value: U256::default(),
+                    offset: slot(stack_pos),
+                    value: slot(stack_pos - 1),
                 })
             }
             Opcode::MSTORE8 => {
-                let offset = stack.pop().expect("stack underflow");
-                let value = stack.pop().expect("stack underflow");
-                memory.write_byte(offset.as_usize(), value.as_usize() as u8);
-                ir.push(IRInstruction::MemoryStore {
-                    offset,
-                    value: U256(U::from(value.as_usize() & 0xFF)),
+                let stack_pos = stack.len();
+                let offset = stack.pop().map_err(stack_error)?;
+                let value = stack.pop().map_err(stack_error)?;
+                if let Some(offset) = concrete_range(offset, 1) {
+                    memory.write_byte(offset, value.as_usize() as u8);
+                }
+                ir.push(IRInstruction::MemoryStore8 {
+                    offset: slot(stack_pos),
+                    value: slot(stack_pos - 1),
+                });
+            }
+            Opcode::SLOAD => {
+                // storage isn't known at compile time
+                let stack_pos = stack.len();
+                stack.pop().map_err(stack_error)?;
+                stack.push(U256::default()).map_err(stack_error)?;
+                ir.push(IRInstruction::StorageLoad {
+                    key: slot(stack_pos),
+                    dest: slot(stack_pos),
+                });
+            }
+            Opcode::SSTORE => {
+                let stack_pos = stack.len();
+                stack.pop().map_err(stack_error)?;
+                stack.pop().map_err(stack_error)?;
+                ir.push(IRInstruction::StorageStore {
+                    key: slot(stack_pos),
+                    value: slot(stack_pos - 1),
                 });
             }
             Opcode::SHL | Opcode::SHR | Opcode::SAR => {
                 let stack_pos = stack.len();
-                let shift = stack.pop().expect("stack underflow");
-                let value = stack.pop().expect("stack underflow");
+                let shift = stack.pop().map_err(stack_error)?;
+                let value = stack.pop().map_err(stack_error)?;
                 
                 let result = if shift.0 >= U::from(256) {
                     match inst.opcode {
@@ -846,29 +1773,31 @@ pub fn generate_ir(
                                 U256(shifted)
                             }
                         }
-                        _ => unreachable!(),
+                        _ => return Err(fail(CompileErrorKind::UnsupportedOpcode)),
                     }
                 };
-                stack.push(result).expect("stack overflow");
-                let op = match inst.opcode {
-                    Opcode::SHL => "shl",
-                    Opcode::SHR => "shr",
-                    Opcode::SAR => "sar",
-                    _ => unreachable!(),
-                };
-                
+                stack.push(result).map_err(stack_error)?;
                 ir.push(IRInstruction::BinaryOp {
-                    op,
-                    dest: U256(U::from(stack_pos - 2)),
-                    src1: U256(U::from(stack_pos - 2)),
-                    src2: U256(U::from(stack_pos - 1)),
+                    op: op()?,
+                    dest: slot(stack_pos - 1),
+                    src1: slot(stack_pos),
+                    src2: slot(stack_pos - 1),
                 });
             }
-            _ => {}
+            _ => return Err(fail(CompileErrorKind::UnsupportedOpcode)),
+        }
+
+        for emitted in &ir[emitted..] {
+            constants.observe(emitted);
         }
+        pcs.resize(ir.len(), pc);
     }
 
-    ir
+    Ok((ir, pcs))
+}
+
+fn is_jumpdest(jumpdests: &HashSet<usize>, target: U256) -> bool {
+    usize::try_from(target.0).is_ok_and(|pc| jumpdests.contains(&pc))
 }
 
 // Runtime Support
@@ -906,6 +1835,16 @@ impl EVMRuntime {
         }
         value
     }
+
+    // bytes past the end of memory read as zero
+    pub fn sha3(&self, offset: usize, size: usize) -> [u8; 32] {
+        let mut data = vec![0u8; size];
+        if offset < self.memory.len() {
+            let available = size.min(self.memory.len() - offset);
+            data[..available].copy_from_slice(&self.memory[offset..offset + available]);
+        }
+        keccak256(&data).0
+    }
 }
 
 #[cfg(test)]
@@ -943,7 +1882,7 @@ mod tests {
             },
         ];
 
-        let ir = generate_ir(&instructions, &mut Stack::new(), &mut Memory::new());
+        let ir = generate_ir(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
 
         assert_eq!(ir.len(), 3);
         match &ir[0] {
@@ -975,4 +1914,329 @@ mod tests {
             _ => panic!("Expected BinaryOp"),
         }
     }
+
+    #[test]
+    fn test_generate_ir_storage() {
+        // sstore(0, 1) sload(0)
+        let bytecode = [0x60, 0x01, 0x60, 0x00, 0x55, 0x60, 0x00, 0x54];
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        let ir = generate_ir(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+
+        assert_eq!(
+            ir[2],
+            IRInstruction::StorageStore {
+                key: U256(U::from(2)),
+                value: U256(U::from(1)),
+            }
+        );
+        assert_eq!(
+            ir[4],
+            IRInstruction::StorageLoad {
+                key: U256(U::from(1)),
+                dest: U256(U::from(1)),
+            }
+        );
+    }
+
+    #[test]
+    fn test_generate_ir_host_calls() {
+        // mstore(0, caller()) return(0, 0x20)
+        let bytecode = [0x33, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3];
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        let ir = generate_ir(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+
+        assert_eq!(
+            ir[0],
+            IRInstruction::HostCall {
+                kind: HostOp::Caller,
+                dest: Some(U256(U::from(1))),
+                args: vec![],
+            }
+        );
+        assert_eq!(
+            ir[5],
+            IRInstruction::HostCall {
+                kind: HostOp::Return,
+                dest: None,
+                args: vec![U256(U::from(2)), U256(U::from(1))],
+            }
+        );
+        assert!(ir[5].is_terminator());
+        assert!(!ir[0].is_terminator());
+
+        assert_eq!(HostOp::try_from(Opcode::LOG2), Ok(HostOp::Log(2)));
+        assert_eq!(HostOp::Log(2).inputs(), 4);
+        assert_eq!(HostOp::Call.inputs(), 7);
+        assert!(HostOp::Call.has_output());
+        assert!(!HostOp::Revert.has_output());
+        assert!(HostOp::try_from(Opcode::ADD).is_err());
+    }
+
+    #[test]
+    fn test_generate_ir_errors() {
+        let compile = |bytecode: &[u8]| {
+            let instructions = parse_bytecode(bytecode).unwrap();
+            generate_ir(&instructions, &mut Stack::new(), &mut Memory::new())
+        };
+
+        // push1 1 add
+        assert_eq!(
+            compile(&[0x60, 0x01, 0x01]),
+            Err(CompileError {
+                pc: 2,
+                opcode: Opcode::ADD,
+                reason: CompileErrorKind::StackUnderflow,
+            })
+        );
+
+        // push1 0 push1 0 sdiv
+        assert!(compile(&[0x60, 0x00, 0x60, 0x00, 0x05]).is_ok());
+
+        // push1 1 push2 ff, the end of the bytecode cuts the push2 short
+        let bytecode = [0x60, 0x01, 0x61, 0xff];
+        assert_eq!(
+            parse_bytecode(&bytecode).unwrap()[1].operand,
+            Some(vec![0xff])
+        );
+        let err = compile(&bytecode).unwrap_err();
+        assert_eq!(
+            err,
+            CompileError {
+                pc: 2,
+                opcode: Opcode::PUSH2,
+                reason: CompileErrorKind::TruncatedPush,
+            }
+        );
+        assert_eq!(err.to_string(), "pc 0x2 (PUSH2): truncated push operand");
+        assert!(compile(&[0x7f]).is_err());
+
+        // push1 1 push1 1 jumpi stop, pc 1 is the operand of the first push
+        let err = compile(&[0x60, 0x01, 0x60, 0x01, 0x57, 0x00]).unwrap_err();
+        assert_eq!(err.pc, 4);
+        assert_eq!(
+            err.reason,
+            CompileErrorKind::InvalidJump {
+                target: U256(U::from(1))
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "pc 0x4 (JUMPI): jump to 0x1, which is not a JUMPDEST"
+        );
+
+        // push1 3 jump jumpdest stop
+        assert!(compile(&[0x60, 0x03, 0x56, 0x5b, 0x00]).is_ok());
+
+        // mstore, mstore8, mload and sha3 at not(0) and 2^40 - 1
+        for offset in [&[0x60, 0x00, 0x19][..], &[0x64, 0xff, 0xff, 0xff, 0xff, 0xff]] {
+            for op in [0x52, 0x53, 0x20] {
+                let bytecode = [&[0x60, 0x20][..], offset, &[op]].concat();
+                assert!(compile(&bytecode).is_ok());
+            }
+            assert!(compile(&[offset, &[0x51]].concat()).is_ok());
+        }
+
+        // sha3(not(0), 0x20) is left to runtime, sha3(not(0), 0) hashes nothing
+        for (size, hash) in [(0x20, U::ZERO), (0x00, U::from_be_bytes(keccak256([]).0))] {
+            let instructions = parse_bytecode(&[0x60, size, 0x60, 0x00, 0x19, 0x20]).unwrap();
+            let mut stack = Stack::new();
+            generate_ir(&instructions, &mut stack, &mut Memory::new()).unwrap();
+            assert_eq!(stack.pop(), Ok(U256(hash)));
+        }
+    }
+
+    #[test]
+    fn test_generate_ir_smod() {
+        // smod(0 - 7, 3) is -1
+        let bytecode = [0x60, 0x03, 0x60, 0x07, 0x60, 0x00, 0x03, 0x07];
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        let mut stack = Stack::new();
+        generate_ir(&instructions, &mut stack, &mut Memory::new()).unwrap();
+
+        assert_eq!(stack.pop(), Ok(U256(U::MAX)));
+    }
+
+    #[test]
+    fn test_generate_ir_sdiv() {
+        // sdiv(0 - 7, 2) is -3, sdiv(0 - 7, 0) is 0
+        let bytecode = [
+            0x60, 0x02, 0x60, 0x07, 0x60, 0x00, 0x03, 0x05, 0x60, 0x00, 0x60, 0x07, 0x05,
+        ];
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        let mut stack = Stack::new();
+        let ir = generate_ir(&instructions, &mut stack, &mut Memory::new()).unwrap();
+
+        assert_eq!(stack.pop(), Ok(U256(U::ZERO)));
+        assert_eq!(stack.pop(), Ok(U256(U::MAX - U::from(2))));
+        assert!(matches!(ir[4], IRInstruction::BinaryOp { op: "sdiv", .. }));
+    }
+
+    #[test]
+    fn test_immediate_ranges() {
+        let (a0, t0) = (Register::A0, Register::T0);
+        let addi = |imm| RiscVInstruction::ADDI {
+            rd: a0,
+            rs1: a0,
+            imm,
+        };
+        assert_eq!(addi(-2048).check(), Ok(()));
+        assert_eq!(addi(2047).check(), Ok(()));
+        assert_eq!(
+            addi(2048).check(),
+            Err(ImmediateError::OutOfRange {
+                value: 2048,
+                min: -2048,
+                max: 2047
+            })
+        );
+
+        let beq = |offset| RiscVInstruction::BEQ {
+            rs1: a0,
+            rs2: t0,
+            offset,
+        };
+        assert_eq!(beq(-4096).check(), Ok(()));
+        assert!(beq(4096).check().is_err());
+        assert_eq!(
+            beq(6).check().and(beq(7).check()),
+            Err(ImmediateError::Misaligned { offset: 7 })
+        );
+        let jal = |offset| RiscVInstruction::JAL { rd: a0, offset };
+        assert_eq!(jal((1 << 20) - 2).check(), Ok(()));
+        assert!(jal(1 << 20).check().is_err());
+
+        let lui = |imm| RiscVInstruction::LUI { rd: a0, imm };
+        assert_eq!(lui(0xf_ffff).check(), Ok(()));
+        assert!(lui(0x10_0000).check().is_err());
+        let slli = |shamt| RiscVInstruction::SLLI {
+            rd: a0,
+            rs1: a0,
+            shamt,
+        };
+        assert_eq!(slli(63).check(), Ok(()));
+        assert!(slli(64).check().is_err());
+        let slliw = RiscVInstruction::SLLIW {
+            rd: a0,
+            rs1: a0,
+            shamt: 32,
+        };
+        assert_eq!(
+            slliw.check().unwrap_err().to_string(),
+            "immediate 32 is outside 0..=31"
+        );
+        assert_eq!(RiscVInstruction::ECALL.check(), Ok(()));
+
+        // `li` only produces legal immediates
+        for imm in [
+            0,
+            -1,
+            2047,
+            2048,
+            -2049,
+            i32::MAX as i64,
+            i64::MIN,
+            i64::MAX,
+        ] {
+            for inst in RiscVInstruction::li(a0, imm) {
+                assert_eq!(inst.check(), Ok(()), "li {imm}");
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        // mstore(0x40, lt(0x80, 1)) caller stop
+        let bytecode = [0x60, 0x01, 0x60, 0x80, 0x10, 0x60, 0x40, 0x52, 0x33, 0x00];
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        let ir = generate_ir(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+        let riscv = vec![
+            RiscVInstruction::ADD {
+                rd: Register::T0,
+                rs1: Register::T1,
+                rs2: Register::T2,
+            },
+            RiscVInstruction::ADDI {
+                rd: Register::A0,
+                rs1: Register::ZERO,
+                imm: -1,
+            },
+        ];
+
+        let json = serde_json::to_string(&ir[2]).unwrap();
+        assert_eq!(
+            json,
+            r#"{"binary_op":{"op":"LT","dest":"0x1","src1":"0x2","src2":"0x1"}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&instructions[0]).unwrap(),
+            r#"{"opcode":"PUSH1","operand":[1]}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&riscv[1]).unwrap(),
+            r#"{"addi":{"rd":10,"rs1":0,"imm":-1}}"#
+        );
+
+        let json = serde_json::to_string(&ir).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<IRInstruction>>(&json).unwrap(),
+            ir
+        );
+        let json = serde_json::to_string(&instructions).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<Instruction>>(&json).unwrap(),
+            instructions
+        );
+
+        let bytes = bincode::serialize(&ir).unwrap();
+        assert_eq!(
+            bincode::deserialize::<Vec<IRInstruction>>(&bytes).unwrap(),
+            ir
+        );
+        let bytes = bincode::serialize(&riscv).unwrap();
+        assert_eq!(
+            bincode::deserialize::<Vec<RiscVInstruction>>(&bytes).unwrap(),
+            riscv
+        );
+
+        let unknown = r#"{"unary_op":{"op":"frobnicate","dest":"0x1","src":"0x1"}}"#;
+        assert!(serde_json::from_str::<IRInstruction>(unknown).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_op_names() {
+        let slot = U256(U::from(1));
+        for (opcode, op) in OP_NAMES {
+            let inst = IRInstruction::BinaryOp {
+                op,
+                dest: slot,
+                src1: slot,
+                src2: slot,
+            };
+            let json = serde_json::to_string(&inst).unwrap();
+            assert_eq!(
+                serde_json::from_str::<IRInstruction>(&json).unwrap(),
+                inst,
+                "{opcode:?}"
+            );
+            let bytes = bincode::serialize(&inst).unwrap();
+            assert_eq!(
+                bincode::deserialize::<IRInstruction>(&bytes).unwrap(),
+                inst
+            );
+        }
+
+        // every op generate_ir emits
+        let bytecode = [
+            0x60, 0x02, 0x60, 0x07, 0x05, 0x60, 0x03, 0x07, 0x60, 0x05, 0x60, 0x06, 0x08, 0x50,
+        ];
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        let ir = generate_ir(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+        let json = serde_json::to_string(&ir).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<IRInstruction>>(&json).unwrap(),
+            ir
+        );
+    }
 }
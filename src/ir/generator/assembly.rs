@@ -0,0 +1,572 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+
+use super::encode::{encode, EncodeError};
+use super::register::Register;
+use super::select::Selection;
+use crate::ir::gas::parser::RiscVInstruction;
+use crate::ir::source_map::INSTRUCTION_SIZE;
+
+// Assembly text output
+//
+// Selected code is written out as GNU assembler source, one line per instruction
+// so the assembled `.text` has the same layout as `encode::assemble`: compressed
+// instructions and linker relaxation are turned off and nothing is written as a
+// pseudo-instruction that could expand. Branch and jump targets become labels.
+// EVM basic blocks are named `evm_block_<pc>`, after their JUMPDEST or the first
+// instruction they were generated from, other targets get local `.L<index>`
+// labels. Calls to helpers that aren't bundled are `JAL`s to the helper's symbol,
+// left for the linker. Functions are sized up to the next one, as in `elf`.
+//
+// `.rodata` holds the JUMPDEST table the jump helper searches: the number of
+// entries, then the pc and address of every JUMPDEST in order of pc, all 64 bits.
+
+/// Entry point of the selected code
+pub const ENTRY: &str = "evm_entry";
+/// JUMPDEST table in `.rodata`
+pub const JUMPDESTS: &str = "evm_jumpdests";
+
+/// A named position in the code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    /// Index in `Selection::code`, `code.len()` for a block at the very end
+    pub index: usize,
+    /// Entry point or runtime routine rather than an EVM block
+    pub function: bool,
+}
+
+/// Symbols for the entry point, the EVM blocks and the bundled routines of
+/// `selection`, in code order
+pub fn symbols(selection: &Selection) -> Vec<Symbol> {
+    let mut symbols = vec![Symbol {
+        name: ENTRY.to_string(),
+        index: 0,
+        function: true,
+    }];
+
+    // blocks start at a JUMPDEST, at the start of the code and at any branch
+    // target that starts the code for its pc
+    let mut blocks: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (&pc, &index) in &selection.labels {
+        blocks.entry(index).or_default().push(pc);
+    }
+    let pcs = &selection.pcs;
+    let starts = targets(selection)
+        .into_iter()
+        .chain(Some(0))
+        .filter(|&index| index < pcs.len() && (index == 0 || pcs[index - 1] != pcs[index]));
+    for index in starts {
+        blocks.entry(index).or_insert_with(|| vec![pcs[index]]);
+    }
+
+    for (&routine, &index) in &selection.routines {
+        symbols.push(Symbol {
+            name: routine.to_string(),
+            index,
+            function: true,
+        });
+    }
+    for (index, block_pcs) in blocks {
+        for pc in block_pcs {
+            symbols.push(Symbol {
+                name: block_symbol(pc),
+                index,
+                function: false,
+            });
+        }
+    }
+    symbols.sort_by_key(|symbol| symbol.index);
+    symbols
+}
+
+/// Name of the EVM block starting at `pc`
+pub fn block_symbol(pc: usize) -> String {
+    format!("evm_block_{pc:#x}")
+}
+
+/// Byte offset a branch or jump in code goes to, relative to itself
+pub(crate) fn target_offset(inst: &RiscVInstruction) -> Option<i32> {
+    match *inst {
+        RiscVInstruction::JAL { offset, .. }
+        | RiscVInstruction::J { offset }
+        | RiscVInstruction::BEQ { offset, .. }
+        | RiscVInstruction::BNE { offset, .. }
+        | RiscVInstruction::BLT { offset, .. }
+        | RiscVInstruction::BGE { offset, .. }
+        | RiscVInstruction::BLTU { offset, .. }
+        | RiscVInstruction::BGEU { offset, .. } => Some(offset),
+        _ => None,
+    }
+}
+
+// Index every branch and jump inside the code goes to, helper calls aside
+fn targets(selection: &Selection) -> BTreeSet<usize> {
+    let calls: BTreeSet<usize> = selection.calls.iter().map(|&(index, _)| index).collect();
+    selection
+        .code
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !calls.contains(index))
+        .filter_map(|(index, inst)| {
+            let target = index as i64 + target_offset(inst)? as i64 / INSTRUCTION_SIZE as i64;
+            usize::try_from(target)
+                .ok()
+                .filter(|&target| target <= selection.code.len())
+        })
+        .collect()
+}
+
+// ABI name as the assembler takes it, `s0` is also `fp`
+fn reg(register: Register) -> &'static str {
+    match register.abi_name() {
+        "s0/fp" => "s0",
+        name => name,
+    }
+}
+
+// `FENCE` predecessor or successor set, as letters of `iorw`
+fn fence_set(set: u8) -> String {
+    "iorw"
+        .chars()
+        .enumerate()
+        .filter(|(bit, _)| set & (8 >> bit) != 0)
+        .map(|(_, letter)| letter)
+        .collect()
+}
+
+/// GNU assembler source for `selection`
+///
+/// Fails on the first instruction `encode` rejects, like `encode::assemble`.
+pub fn emit(selection: &Selection) -> Result<String, EncodeError> {
+    let code = &selection.code;
+    let symbols = symbols(selection);
+    let calls: HashMap<usize, &str> = selection
+        .calls
+        .iter()
+        .map(|&(index, helper)| (index, helper.symbol()))
+        .collect();
+
+    let mut labels: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    for symbol in &symbols {
+        labels
+            .entry(symbol.index)
+            .or_default()
+            .push(symbol.name.clone());
+    }
+    for target in targets(selection) {
+        labels
+            .entry(target)
+            .or_insert_with(|| vec![format!(".L{target}")]);
+    }
+
+    let uses_m = code.iter().any(|inst| {
+        matches!(
+            inst,
+            RiscVInstruction::MUL { .. }
+                | RiscVInstruction::MULHU { .. }
+                | RiscVInstruction::DIVU { .. }
+                | RiscVInstruction::REMU { .. }
+        )
+    });
+    let mut out = String::new();
+    writeln!(out, "\t.option norvc").unwrap();
+    writeln!(out, "\t.option norelax").unwrap();
+    writeln!(
+        out,
+        "\t.attribute arch, \"{}\"",
+        if uses_m { "rv64im" } else { "rv64i" }
+    )
+    .unwrap();
+    writeln!(out, "\t.text").unwrap();
+    for symbol in symbols.iter().filter(|symbol| symbol.function) {
+        writeln!(out, "\t.globl {}", symbol.name).unwrap();
+        writeln!(out, "\t.type {}, @function", symbol.name).unwrap();
+    }
+    writeln!(out, "\t.p2align 2").unwrap();
+
+    let mut functions = symbols.iter().filter(|symbol| symbol.function).peekable();
+    let mut function: Option<&str> = None;
+    let mut pc = None;
+    for index in 0..=code.len() {
+        while let Some(next) = functions.next_if(|symbol| symbol.index == index) {
+            if let Some(name) = function.replace(&next.name) {
+                writeln!(out, "\t.size {name}, .-{name}").unwrap();
+            }
+        }
+        if index == code.len() {
+            if let Some(name) = function.take() {
+                writeln!(out, "\t.size {name}, .-{name}").unwrap();
+            }
+        }
+        for label in labels.get(&index).into_iter().flatten() {
+            writeln!(out, "{label}:").unwrap();
+        }
+        let Some(inst) = code.get(index) else {
+            break;
+        };
+        let word = encode(inst).map_err(|error| EncodeError { index, error })?;
+        if let Some(&at) = selection.pcs.get(index) {
+            if pc != Some(at) {
+                writeln!(out, "\t# pc {at:#x}").unwrap();
+                pc = Some(at);
+            }
+        }
+
+        let target = target_offset(inst).map(|offset| {
+            let target = index as i64 + offset as i64 / INSTRUCTION_SIZE as i64;
+            usize::try_from(target)
+                .ok()
+                .and_then(|target| labels.get(&target))
+        });
+        let line = match (calls.get(&index), inst, target) {
+            (Some(symbol), RiscVInstruction::JAL { rd, .. }, _) => {
+                format!("jal {}, {symbol}", reg(*rd))
+            }
+            // there's no label for a target outside the code, the word is written
+            // as it is
+            (_, _, Some(None)) => format!(".4byte {word:#010x}"),
+            (_, _, target) => instruction(inst, target.flatten().map_or("", |names| &names[0]))
+                .unwrap_or_else(|| format!(".4byte {word:#010x}")),
+        };
+        writeln!(out, "\t{line}").unwrap();
+    }
+
+    writeln!(out).unwrap();
+    writeln!(out, "\t.section .rodata").unwrap();
+    writeln!(out, "\t.globl {JUMPDESTS}").unwrap();
+    writeln!(out, "\t.p2align 3").unwrap();
+    writeln!(out, "{JUMPDESTS}:").unwrap();
+    writeln!(out, "\t.dword {}", selection.labels.len()).unwrap();
+    for &pc in selection.labels.keys() {
+        writeln!(out, "\t.dword {pc:#x}, {}", block_symbol(pc)).unwrap();
+    }
+    Ok(out)
+}
+
+// One instruction, `target` labels where a branch or jump goes, `None` if it has
+// to be written as a word
+fn instruction(inst: &RiscVInstruction, target: &str) -> Option<String> {
+    use RiscVInstruction::*;
+    let mnemonic = inst.mnemonic();
+    let text = match *inst {
+        LUI { rd, imm } | AUIPC { rd, imm } => format!("{mnemonic} {}, {imm:#x}", reg(rd)),
+        JAL { rd, .. } => format!("jal {}, {target}", reg(rd)),
+        J { .. } => format!("j {target}"),
+        JALR { rd, rs1, offset } => format!("jalr {}, {offset}({})", reg(rd), reg(rs1)),
+        BEQ { rs1, rs2, .. }
+        | BNE { rs1, rs2, .. }
+        | BLT { rs1, rs2, .. }
+        | BGE { rs1, rs2, .. }
+        | BLTU { rs1, rs2, .. }
+        | BGEU { rs1, rs2, .. } => format!("{mnemonic} {}, {}, {target}", reg(rs1), reg(rs2)),
+        LB { rd, rs1, imm }
+        | LH { rd, rs1, imm }
+        | LW { rd, rs1, imm }
+        | LD { rd, rs1, imm }
+        | LBU { rd, rs1, imm }
+        | LHU { rd, rs1, imm }
+        | LWU { rd, rs1, imm } => format!("{mnemonic} {}, {imm}({})", reg(rd), reg(rs1)),
+        SB { rs1, rs2, imm }
+        | SH { rs1, rs2, imm }
+        | SW { rs1, rs2, imm }
+        | SD { rs1, rs2, imm } => {
+            format!("{mnemonic} {}, {imm}({})", reg(rs2), reg(rs1))
+        }
+        ADDI { rd, rs1, imm }
+        | SLTI { rd, rs1, imm }
+        | SLTIU { rd, rs1, imm }
+        | XORI { rd, rs1, imm }
+        | ORI { rd, rs1, imm }
+        | ANDI { rd, rs1, imm }
+        | ADDIW { rd, rs1, imm } => format!("{mnemonic} {}, {}, {imm}", reg(rd), reg(rs1)),
+        SLLI { rd, rs1, shamt }
+        | SRLI { rd, rs1, shamt }
+        | SRAI { rd, rs1, shamt }
+        | SLLIW { rd, rs1, shamt }
+        | SRLIW { rd, rs1, shamt }
+        | SRAIW { rd, rs1, shamt } => format!("{mnemonic} {}, {}, {shamt}", reg(rd), reg(rs1)),
+        ADD { rd, rs1, rs2 }
+        | SUB { rd, rs1, rs2 }
+        | SLL { rd, rs1, rs2 }
+        | SLT { rd, rs1, rs2 }
+        | SLTU { rd, rs1, rs2 }
+        | XOR { rd, rs1, rs2 }
+        | SRL { rd, rs1, rs2 }
+        | SRA { rd, rs1, rs2 }
+        | OR { rd, rs1, rs2 }
+        | AND { rd, rs1, rs2 }
+        | ADDW { rd, rs1, rs2 }
+        | SUBW { rd, rs1, rs2 }
+        | SLLW { rd, rs1, rs2 }
+        | SRLW { rd, rs1, rs2 }
+        | SRAW { rd, rs1, rs2 }
+        | MUL { rd, rs1, rs2 }
+        | MULHU { rd, rs1, rs2 }
+        | DIVU { rd, rs1, rs2 }
+        | REMU { rd, rs1, rs2 } => {
+            format!("{mnemonic} {}, {}, {}", reg(rd), reg(rs1), reg(rs2))
+        }
+        // an empty set has no name the assemblers agree on
+        FENCE { pred, succ } if pred == 0 || succ == 0 => return None,
+        FENCE { pred, succ } => format!("fence {}, {}", fence_set(pred), fence_set(succ)),
+        ECALL | EBREAK => mnemonic.to_string(),
+    };
+    Some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::gas::parser::{generate_ir_with_pcs, parse_bytecode};
+    use crate::ir::generator::library::bundle;
+    use crate::ir::generator::select::select;
+    use crate::ir::memory::{memory::Memory, stack::Stack};
+    use crate::ir::passes::manager::{OptLevel, PassManager};
+
+    // Lines of `text` that are instructions
+    fn instruction_lines(text: &str) -> Vec<&str> {
+        text.lines()
+            .filter(|line| {
+                line.starts_with('\t') && !line.starts_with("\t.") && !line.starts_with("\t#")
+            })
+            .map(str::trim)
+            .collect()
+    }
+
+    #[test]
+    fn test_emit() {
+        // loop summing 5 + 4 + ... + 1, then sum / 7 and keccak256 in memory
+        let bytecode = [
+            0x60, 0x05, 0x60, 0x00, 0x5b, 0x81, 0x01, 0x90, 0x60, 0x01, 0x90, 0x03, 0x90, 0x81,
+            0x60, 0x04, 0x57, // loop, leaves [0, sum]
+            0x60, 0x07, 0x04, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0x20, 0x00,
+        ];
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        let (ir, pcs) =
+            generate_ir_with_pcs(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+        let mut selection = select(&ir, &pcs).unwrap();
+        let text = emit(&selection).unwrap();
+        assert_eq!(instruction_lines(&text).len(), selection.code.len());
+        assert!(text.contains("\tjal ra, __evm_div\n"));
+        assert!(text.contains("\tjal ra, __evm_keccak256\n"));
+
+        bundle(&mut selection);
+        let text = emit(&selection).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            &lines[..4],
+            [
+                "\t.option norvc",
+                "\t.option norelax",
+                "\t.attribute arch, \"rv64im\"",
+                "\t.text"
+            ]
+        );
+        assert_eq!(instruction_lines(&text).len(), selection.code.len());
+
+        // the loop jumps back to the JUMPDEST, JUMPI falls through to a block
+        let label = lines
+            .iter()
+            .position(|&line| line == "evm_block_0x4:")
+            .unwrap();
+        assert_eq!(lines[label + 1], "\t# pc 0x5");
+        assert!(lines.contains(&"evm_block_0x11:"));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("\tbeq ") && line.ends_with(", evm_block_0x11")));
+        assert!(lines.contains(&"\tj evm_block_0x4"));
+
+        // DIV now calls the bundled routine, which has its own symbol
+        assert!(text.contains("\tjal ra, __evm_div\n"));
+        assert!(text.contains("\t.globl __evm_div\n\t.type __evm_div, @function\n"));
+        assert!(lines.contains(&"__evm_divmod:"));
+
+        // every function is sized up to the next one
+        let size = |name: &str| {
+            lines
+                .iter()
+                .position(|&line| line == format!("\t.size {name}, .-{name}"))
+                .unwrap()
+        };
+        let mut functions: Vec<_> = selection
+            .routines
+            .iter()
+            .map(|(&name, &index)| (index, name))
+            .collect();
+        functions.sort();
+        let (_, last) = functions[functions.len() - 1];
+        assert_eq!(lines[size(ENTRY) + 1], format!("{}:", functions[0].1));
+        assert_eq!(lines[size(last) + 1], "");
+        assert_eq!(
+            lines
+                .iter()
+                .filter(|line| line.starts_with("\t.size "))
+                .count(),
+            1 + functions.len()
+        );
+        assert!(lines.iter().any(|line| line.starts_with(".L")));
+
+        let rodata = lines
+            .iter()
+            .position(|&line| line == "\t.section .rodata")
+            .unwrap();
+        assert_eq!(
+            lines[rodata..],
+            [
+                "\t.section .rodata",
+                "\t.globl evm_jumpdests",
+                "\t.p2align 3",
+                "evm_jumpdests:",
+                "\t.dword 1",
+                "\t.dword 0x4, evm_block_0x4",
+            ]
+        );
+
+        let symbols = symbols(&selection);
+        assert_eq!(symbols[0].name, ENTRY);
+        assert_eq!(
+            symbols
+                .iter()
+                .filter(|symbol| !symbol.function)
+                .map(|symbol| symbol.name.as_str())
+                .collect::<Vec<_>>(),
+            ["evm_block_0x0", "evm_block_0x4", "evm_block_0x11"]
+        );
+
+        // optimised code keeps its pcs, and with them the same blocks
+        let (mut ir, mut pcs) =
+            generate_ir_with_pcs(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+        PassManager::new(OptLevel::O2)
+            .run_with_pcs(&mut ir, &mut pcs)
+            .unwrap();
+        let mut selection = select(&ir, &pcs).unwrap();
+        bundle(&mut selection);
+        let blocks: Vec<_> = super::symbols(&selection)
+            .into_iter()
+            .filter(|symbol| !symbol.function)
+            .map(|symbol| symbol.name)
+            .collect();
+        assert_eq!(blocks, ["evm_block_0x0", "evm_block_0x4", "evm_block_0x11"]);
+
+        // a jump out of the code can't be labelled
+        let selection = Selection {
+            code: vec![RiscVInstruction::J { offset: 64 }],
+            ..Selection::default()
+        };
+        assert!(emit(&selection)
+            .unwrap()
+            .contains("evm_entry:\n\t.4byte 0x0400006f\n"));
+
+        // nor can an instruction that doesn't encode
+        let selection = Selection {
+            code: vec![
+                RiscVInstruction::ECALL,
+                RiscVInstruction::ADDI {
+                    rd: Register::from_raw(32),
+                    rs1: Register::ZERO,
+                    imm: 0,
+                },
+            ],
+            ..Selection::default()
+        };
+        let err = emit(&selection).unwrap_err();
+        assert_eq!(err.index, 1);
+    }
+
+    #[test]
+    fn test_instruction() {
+        use RiscVInstruction::*;
+        let (t0, s0, a0, ra) = (Register::T0, Register::S0, Register::A0, Register::RA);
+        let cases = [
+            (
+                SD {
+                    rs1: s0,
+                    rs2: t0,
+                    imm: -8,
+                },
+                "sd t0, -8(s0)",
+            ),
+            (
+                LWU {
+                    rd: a0,
+                    rs1: t0,
+                    imm: 4,
+                },
+                "lwu a0, 4(t0)",
+            ),
+            (
+                LUI {
+                    rd: a0,
+                    imm: 0xfffff,
+                },
+                "lui a0, 0xfffff",
+            ),
+            (
+                JALR {
+                    rd: Register::ZERO,
+                    rs1: ra,
+                    offset: 0,
+                },
+                "jalr zero, 0(ra)",
+            ),
+            (
+                SRAIW {
+                    rd: a0,
+                    rs1: a0,
+                    shamt: 31,
+                },
+                "sraiw a0, a0, 31",
+            ),
+            (
+                XORI {
+                    rd: a0,
+                    rs1: a0,
+                    imm: -1,
+                },
+                "xori a0, a0, -1",
+            ),
+            (
+                MULHU {
+                    rd: a0,
+                    rs1: s0,
+                    rs2: t0,
+                },
+                "mulhu a0, s0, t0",
+            ),
+            (
+                FENCE {
+                    pred: 0b1111,
+                    succ: 0b0001,
+                },
+                "fence iorw, w",
+            ),
+            (EBREAK, "ebreak"),
+        ];
+        for (inst, text) in cases {
+            assert_eq!(instruction(&inst, "").as_deref(), Some(text));
+        }
+        let empty = FENCE {
+            pred: 0,
+            succ: 0b0011,
+        };
+        assert_eq!(instruction(&empty, ""), None);
+
+        assert_eq!(
+            instruction(
+                &BGEU {
+                    rs1: a0,
+                    rs2: t0,
+                    offset: -8
+                },
+                ".L2"
+            )
+            .as_deref(),
+            Some("bgeu a0, t0, .L2")
+        );
+        assert_eq!(
+            instruction(&J { offset: 16 }, "evm_block_0x4").as_deref(),
+            Some("j evm_block_0x4")
+        );
+    }
+}
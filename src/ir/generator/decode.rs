@@ -0,0 +1,470 @@
+use super::encode::{
+    ALT, AUIPC, BRANCH, JAL, JALR, LOAD, LUI, MISC_MEM, MULDIV, OP, OP_32, OP_IMM, OP_IMM_32,
+    STORE, SYSTEM,
+};
+use super::register::Register;
+use crate::ir::gas::parser::RiscVInstruction;
+use crate::ir::source_map::INSTRUCTION_SIZE;
+use std::fmt;
+
+// Machine code decoder
+//
+// The inverse of `encode`: a word decodes only if it is the encoding of some
+// `RiscVInstruction`, so encoding the result gives back the same word. `JAL` with
+// `rd` zero decodes as `J`, they share an encoding. Anything else, including
+// encodings of instructions outside RV64I plus `MUL`, `MULHU`, `DIVU` and `REMU`
+// and non-zero fields the encoder always leaves clear, is an error.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// `word` isn't the encoding of any instruction
+    Unknown { word: u32 },
+    /// `len` bytes aren't a whole number of instructions
+    Truncated { len: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Unknown { word } => write!(f, "unknown instruction {word:#010x}"),
+            DecodeError::Truncated { len } => {
+                write!(f, "{len} bytes is not a whole number of instructions")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn rd(word: u32) -> Register {
+    Register::from_raw((word >> 7 & 0x1f) as u8)
+}
+
+fn rs1(word: u32) -> Register {
+    Register::from_raw((word >> 15 & 0x1f) as u8)
+}
+
+fn rs2(word: u32) -> Register {
+    Register::from_raw((word >> 20 & 0x1f) as u8)
+}
+
+fn i_imm(word: u32) -> i32 {
+    word as i32 >> 20
+}
+
+fn s_imm(word: u32) -> i32 {
+    (word as i32 >> 25 << 5) | (word >> 7 & 0x1f) as i32
+}
+
+fn b_offset(word: u32) -> i32 {
+    (word as i32 >> 31 << 12)
+        | ((word >> 7 & 1) << 11) as i32
+        | ((word >> 25 & 0x3f) << 5) as i32
+        | ((word >> 8 & 0xf) << 1) as i32
+}
+
+fn j_offset(word: u32) -> i32 {
+    (word as i32 >> 31 << 20)
+        | ((word >> 12 & 0xff) << 12) as i32
+        | ((word >> 20 & 1) << 11) as i32
+        | ((word >> 21 & 0x3ff) << 1) as i32
+}
+
+/// Decode a 32-bit instruction word
+pub fn decode(word: u32) -> Result<RiscVInstruction, DecodeError> {
+    use RiscVInstruction::*;
+    let (rd, rs1, rs2) = (rd(word), rs1(word), rs2(word));
+    let funct3 = word >> 12 & 0b111;
+    let funct7 = word >> 25;
+    // immediate shifts have 6 bits of shift amount below `funct6`, the W forms 5
+    // below all of `funct7`
+    let (shamt, funct6) = (word >> 20 & 0x3f, word >> 26);
+    let shamt_w = word >> 20 & 0x1f;
+
+    let inst = match (word & 0x7f, funct3) {
+        (self::LUI, _) => LUI {
+            rd,
+            imm: word >> 12,
+        },
+        (self::AUIPC, _) => AUIPC {
+            rd,
+            imm: word >> 12,
+        },
+        (self::JAL, _) if rd == Register::ZERO => J {
+            offset: j_offset(word),
+        },
+        (self::JAL, _) => JAL {
+            rd,
+            offset: j_offset(word),
+        },
+        (self::JALR, 0b000) => JALR {
+            rd,
+            rs1,
+            offset: i_imm(word),
+        },
+
+        (BRANCH, _) => {
+            let offset = b_offset(word);
+            match funct3 {
+                0b000 => BEQ { rs1, rs2, offset },
+                0b001 => BNE { rs1, rs2, offset },
+                0b100 => BLT { rs1, rs2, offset },
+                0b101 => BGE { rs1, rs2, offset },
+                0b110 => BLTU { rs1, rs2, offset },
+                0b111 => BGEU { rs1, rs2, offset },
+                _ => return Err(DecodeError::Unknown { word }),
+            }
+        }
+
+        (LOAD, _) => {
+            let imm = i_imm(word);
+            match funct3 {
+                0b000 => LB { rd, rs1, imm },
+                0b001 => LH { rd, rs1, imm },
+                0b010 => LW { rd, rs1, imm },
+                0b011 => LD { rd, rs1, imm },
+                0b100 => LBU { rd, rs1, imm },
+                0b101 => LHU { rd, rs1, imm },
+                0b110 => LWU { rd, rs1, imm },
+                _ => return Err(DecodeError::Unknown { word }),
+            }
+        }
+        (STORE, _) => {
+            let imm = s_imm(word);
+            match funct3 {
+                0b000 => SB { rs1, rs2, imm },
+                0b001 => SH { rs1, rs2, imm },
+                0b010 => SW { rs1, rs2, imm },
+                0b011 => SD { rs1, rs2, imm },
+                _ => return Err(DecodeError::Unknown { word }),
+            }
+        }
+
+        (OP_IMM, 0b001) if funct6 == 0 => SLLI { rd, rs1, shamt },
+        (OP_IMM, 0b101) if funct6 == 0 => SRLI { rd, rs1, shamt },
+        (OP_IMM, 0b101) if funct6 == ALT >> 1 => SRAI { rd, rs1, shamt },
+        (OP_IMM, 0b001 | 0b101) => return Err(DecodeError::Unknown { word }),
+        (OP_IMM, _) => {
+            let imm = i_imm(word);
+            match funct3 {
+                0b000 => ADDI { rd, rs1, imm },
+                0b010 => SLTI { rd, rs1, imm },
+                0b011 => SLTIU { rd, rs1, imm },
+                0b100 => XORI { rd, rs1, imm },
+                0b110 => ORI { rd, rs1, imm },
+                0b111 => ANDI { rd, rs1, imm },
+                _ => unreachable!(),
+            }
+        }
+
+        (OP, _) => match (funct7, funct3) {
+            (0, 0b000) => ADD { rd, rs1, rs2 },
+            (ALT, 0b000) => SUB { rd, rs1, rs2 },
+            (0, 0b001) => SLL { rd, rs1, rs2 },
+            (0, 0b010) => SLT { rd, rs1, rs2 },
+            (0, 0b011) => SLTU { rd, rs1, rs2 },
+            (0, 0b100) => XOR { rd, rs1, rs2 },
+            (0, 0b101) => SRL { rd, rs1, rs2 },
+            (ALT, 0b101) => SRA { rd, rs1, rs2 },
+            (0, 0b110) => OR { rd, rs1, rs2 },
+            (0, 0b111) => AND { rd, rs1, rs2 },
+            (MULDIV, 0b000) => MUL { rd, rs1, rs2 },
+            (MULDIV, 0b011) => MULHU { rd, rs1, rs2 },
+            (MULDIV, 0b101) => DIVU { rd, rs1, rs2 },
+            (MULDIV, 0b111) => REMU { rd, rs1, rs2 },
+            _ => return Err(DecodeError::Unknown { word }),
+        },
+
+        (OP_IMM_32, 0b000) => ADDIW {
+            rd,
+            rs1,
+            imm: i_imm(word),
+        },
+        (OP_IMM_32, 0b001) if funct7 == 0 => SLLIW {
+            rd,
+            rs1,
+            shamt: shamt_w,
+        },
+        (OP_IMM_32, 0b101) if funct7 == 0 => SRLIW {
+            rd,
+            rs1,
+            shamt: shamt_w,
+        },
+        (OP_IMM_32, 0b101) if funct7 == ALT => SRAIW {
+            rd,
+            rs1,
+            shamt: shamt_w,
+        },
+        (OP_32, _) => match (funct7, funct3) {
+            (0, 0b000) => ADDW { rd, rs1, rs2 },
+            (ALT, 0b000) => SUBW { rd, rs1, rs2 },
+            (0, 0b001) => SLLW { rd, rs1, rs2 },
+            (0, 0b101) => SRLW { rd, rs1, rs2 },
+            (ALT, 0b101) => SRAW { rd, rs1, rs2 },
+            _ => return Err(DecodeError::Unknown { word }),
+        },
+
+        // `fm`, `rd` and `rs1` are always zero, as is the rest of the system word
+        (MISC_MEM, 0b000) if word & 0xf00f_ff80 == 0 => FENCE {
+            pred: (word >> 24 & 0xf) as u8,
+            succ: (word >> 20 & 0xf) as u8,
+        },
+        (SYSTEM, _) if word == SYSTEM => ECALL,
+        (SYSTEM, _) if word == 1 << 20 | SYSTEM => EBREAK,
+
+        _ => return Err(DecodeError::Unknown { word }),
+    };
+    Ok(inst)
+}
+
+/// Decode little-endian machine code, the inverse of `encode::assemble`
+pub fn disassemble(bytes: &[u8]) -> Result<Vec<RiscVInstruction>, DecodeError> {
+    if !bytes.len().is_multiple_of(INSTRUCTION_SIZE) {
+        return Err(DecodeError::Truncated { len: bytes.len() });
+    }
+    bytes
+        .chunks_exact(INSTRUCTION_SIZE)
+        .map(|word| decode(u32::from_le_bytes(word.try_into().unwrap())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::generator::encode::{assemble, encode};
+    use crate::ir::generator::library::routine;
+    use proptest::prelude::*;
+    use std::collections::HashSet;
+    use std::mem::discriminant;
+
+    // Legal operands for every operand kind
+    #[derive(Debug, Clone, Copy)]
+    struct Operands {
+        rd: Register,
+        rs1: Register,
+        rs2: Register,
+        imm: i32,
+        branch: i32,
+        jump: i32,
+        upper: u32,
+        shamt: u32,
+        shamt_w: u32,
+        pred: u8,
+        succ: u8,
+    }
+
+    // Every variant with `ops`
+    fn every_variant(ops: Operands) -> Vec<RiscVInstruction> {
+        use RiscVInstruction::*;
+        let Operands {
+            rd,
+            rs1,
+            rs2,
+            imm,
+            branch: offset,
+            ..
+        } = ops;
+        let (shamt, shamt_w) = (ops.shamt, ops.shamt_w);
+        // `JAL` with `rd` zero is `J`
+        let link = if rd == Register::ZERO {
+            Register::RA
+        } else {
+            rd
+        };
+        vec![
+            LUI { rd, imm: ops.upper },
+            AUIPC { rd, imm: ops.upper },
+            JAL {
+                rd: link,
+                offset: ops.jump,
+            },
+            J { offset: ops.jump },
+            JALR {
+                rd,
+                rs1,
+                offset: imm,
+            },
+            BEQ { rs1, rs2, offset },
+            BNE { rs1, rs2, offset },
+            BLT { rs1, rs2, offset },
+            BGE { rs1, rs2, offset },
+            BLTU { rs1, rs2, offset },
+            BGEU { rs1, rs2, offset },
+            LB { rd, rs1, imm },
+            LH { rd, rs1, imm },
+            LW { rd, rs1, imm },
+            LD { rd, rs1, imm },
+            LBU { rd, rs1, imm },
+            LHU { rd, rs1, imm },
+            LWU { rd, rs1, imm },
+            SB { rs1, rs2, imm },
+            SH { rs1, rs2, imm },
+            SW { rs1, rs2, imm },
+            SD { rs1, rs2, imm },
+            ADDI { rd, rs1, imm },
+            SLTI { rd, rs1, imm },
+            SLTIU { rd, rs1, imm },
+            XORI { rd, rs1, imm },
+            ORI { rd, rs1, imm },
+            ANDI { rd, rs1, imm },
+            SLLI { rd, rs1, shamt },
+            SRLI { rd, rs1, shamt },
+            SRAI { rd, rs1, shamt },
+            ADD { rd, rs1, rs2 },
+            SUB { rd, rs1, rs2 },
+            SLL { rd, rs1, rs2 },
+            SLT { rd, rs1, rs2 },
+            SLTU { rd, rs1, rs2 },
+            XOR { rd, rs1, rs2 },
+            SRL { rd, rs1, rs2 },
+            SRA { rd, rs1, rs2 },
+            OR { rd, rs1, rs2 },
+            AND { rd, rs1, rs2 },
+            ADDIW { rd, rs1, imm },
+            SLLIW {
+                rd,
+                rs1,
+                shamt: shamt_w,
+            },
+            SRLIW {
+                rd,
+                rs1,
+                shamt: shamt_w,
+            },
+            SRAIW {
+                rd,
+                rs1,
+                shamt: shamt_w,
+            },
+            ADDW { rd, rs1, rs2 },
+            SUBW { rd, rs1, rs2 },
+            SLLW { rd, rs1, rs2 },
+            SRLW { rd, rs1, rs2 },
+            SRAW { rd, rs1, rs2 },
+            FENCE {
+                pred: ops.pred,
+                succ: ops.succ,
+            },
+            ECALL,
+            EBREAK,
+            MUL { rd, rs1, rs2 },
+            MULHU { rd, rs1, rs2 },
+            DIVU { rd, rs1, rs2 },
+            REMU { rd, rs1, rs2 },
+        ]
+    }
+
+    fn register() -> impl Strategy<Value = Register> {
+        (0u8..32).prop_map(Register::from_raw)
+    }
+
+    fn operands() -> impl Strategy<Value = Operands> {
+        (
+            (register(), register(), register()),
+            (-2048i32..2048, -2048i32..2048, -(1i32 << 19)..1 << 19),
+            (0u32..1 << 20, 0u32..64, 0u32..32, 0u8..16, 0u8..16),
+        )
+            .prop_map(
+                |((rd, rs1, rs2), (imm, branch, jump), (upper, shamt, shamt_w, pred, succ))| {
+                    Operands {
+                        rd,
+                        rs1,
+                        rs2,
+                        imm,
+                        branch: branch * 2,
+                        jump: jump * 2,
+                        upper,
+                        shamt,
+                        shamt_w,
+                        pred,
+                        succ,
+                    }
+                },
+            )
+    }
+
+    #[test]
+    fn test_every_variant() {
+        let ops = Operands {
+            rd: Register::A0,
+            rs1: Register::A1,
+            rs2: Register::A2,
+            imm: -1,
+            branch: -4096,
+            jump: (1 << 20) - 2,
+            upper: 0xf_ffff,
+            shamt: 63,
+            shamt_w: 31,
+            pred: 15,
+            succ: 15,
+        };
+        let variants = every_variant(ops);
+        let kinds: HashSet<_> = variants.iter().map(discriminant).collect();
+        assert_eq!(kinds.len(), variants.len());
+        for inst in variants {
+            assert_eq!(decode(encode(&inst).unwrap()), Ok(inst.clone()), "{inst:?}");
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            decode(0xfff0_0513),
+            Ok(RiscVInstruction::ADDI {
+                rd: Register::A0,
+                rs1: Register::ZERO,
+                imm: -1
+            })
+        );
+        assert_eq!(decode(0x0000_006f), Ok(RiscVInstruction::J { offset: 0 }));
+        assert_eq!(decode(0x0010_0073), Ok(RiscVInstruction::EBREAK));
+
+        for word in [
+            0x0000_0000, // defined illegal
+            0xffff_ffff,
+            0x02b5_4533, // div, M but not supported
+            0x0000_2007, // flw
+            0x8330_000f, // fence.tso
+            0x0000_1073, // csrrw
+            0x0020_0073, // uret
+            0x8205_1513, // slli with a bad funct6
+            0x0205_151b, // slliw with a sixth shift bit
+            0x0000_2063, // branch with funct3 0b010
+        ] {
+            assert_eq!(
+                decode(word),
+                Err(DecodeError::Unknown { word }),
+                "{word:#x}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_disassemble() {
+        for symbol in ["__evm_divmod", "__evm_mul512", "__evm_mulmod"] {
+            let code = routine(symbol).unwrap().code;
+            assert_eq!(disassemble(&assemble(&code).unwrap()), Ok(code));
+        }
+        assert_eq!(
+            disassemble(&[0x13, 0x00, 0x00, 0x00, 0x13]),
+            Err(DecodeError::Truncated { len: 5 })
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(ops in operands()) {
+            for inst in every_variant(ops) {
+                let word = encode(&inst).unwrap();
+                prop_assert_eq!(decode(word), Ok(inst));
+            }
+        }
+
+        #[test]
+        fn test_canonical(word in any::<u32>()) {
+            if let Ok(inst) = decode(word) {
+                prop_assert_eq!(encode(&inst), Ok(word));
+            }
+        }
+    }
+}
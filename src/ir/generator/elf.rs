@@ -0,0 +1,868 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::assembly::{block_symbol, symbols, JUMPDESTS};
+use super::encode::{assemble, EncodeError};
+use super::register::Register;
+use super::runtime::{RuntimeHelper, MEMORY_BASE, SLOT_SIZE, STACK_BASE};
+use super::select::Selection;
+use crate::ir::gas::parser::RiscVInstruction;
+use crate::ir::memory::MEMORY_LIMIT;
+use crate::ir::source_map::INSTRUCTION_SIZE;
+
+// ELF output
+//
+// Selected code is written as a little-endian ELF64 RISC-V file, without going
+// through an assembler or linker. `.text` holds the code as `encode::assemble`
+// lays it out and `.rodata` the JUMPDEST table described in `assembly`. The symbol
+// table has the same symbols as the assembly: local ones for the EVM blocks,
+// global functions for the entry point and the bundled routines, and the table.
+//
+// In an object file, helpers the code calls but doesn't bundle are undefined
+// symbols with an `R_RISCV_JAL` relocation at each call for the linker to point at
+// them, and the addresses in the JUMPDEST table are `R_RISCV_64` relocations
+// against the blocks.
+//
+// An executable is laid out at `BASE` with nothing left to relocate, so it can
+// only call the bundled routines and the two helpers it defines itself:
+// `__evm_stop`, a Linux `exit(0)`, and `__evm_jump`, which searches the JUMPDEST
+// table for the target pc and jumps to its address, or exits with 1 if it isn't
+// there. `_start` comes after them: it points `sp`, `s0` and `s1` into a
+// zero-filled segment holding the call stack, the EVM stack and EVM memory, then
+// calls the entry point, and stops if that returns.
+
+/// Address executables are loaded at
+pub const BASE: u64 = 0x1_0000;
+const PAGE: u64 = 0x1000;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const SHN_UNDEF: u16 = 0;
+
+const R_RISCV_64: u32 = 2;
+const R_RISCV_JAL: u32 = 17;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+const PF_R: u32 = 0x4;
+
+/// Entry point of executables
+pub const START: &str = "_start";
+const STOP: &str = "__evm_stop";
+const JUMP: &str = "__evm_jump";
+// `__evm_stop`, `__evm_jump` then `_start`, appended to the code of executables
+const STOP_SIZE: usize = 3;
+const JUMP_SIZE: usize = 20;
+const START_SIZE: usize = 8;
+const SYS_EXIT: i32 = 93;
+
+// Zero-filled segment of executables: the call stack growing down from `s0`, the
+// 1024 EVM stack slots at `s0` and EVM memory at `s1`
+const CALL_STACK: u64 = 1 << 16;
+const EVM_STACK: u64 = (1024 * SLOT_SIZE) as u64;
+const DATA_SIZE: u64 = CALL_STACK + EVM_STACK + MEMORY_LIMIT as u64;
+
+// Section indices, `.rela.text` and `.rela.rodata` come between `.rodata` and
+// `.symtab` and are always there, even if empty
+const TEXT: u16 = 1;
+const RODATA: u16 = 2;
+const SYMTAB: u32 = 5;
+const STRTAB: u32 = 6;
+const SHSTRTAB: u16 = 7;
+
+/// Why an ELF file couldn't be written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    Encode(EncodeError),
+    /// An executable calls a helper that isn't bundled, named by its symbol
+    Unresolved(&'static str),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::Encode(err) => err.fmt(f),
+            ElfError::Unresolved(symbol) => {
+                write!(f, "{symbol} isn't bundled, an executable can't call it")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+impl From<EncodeError> for ElfError {
+    fn from(err: EncodeError) -> Self {
+        ElfError::Encode(err)
+    }
+}
+
+/// Kind of ELF file to write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfKind {
+    /// Object file for a linker
+    Relocatable,
+    /// Executable loaded at `BASE`, starting at the entry point
+    Executable,
+}
+
+#[derive(Debug, Default)]
+struct Section {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    addr: u64,
+    data: Vec<u8>,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+    offset: usize,
+}
+
+// NUL-terminated strings, starting with the empty one
+#[derive(Debug)]
+struct Strings(Vec<u8>);
+
+impl Strings {
+    fn new() -> Self {
+        Strings(vec![0])
+    }
+
+    fn add(&mut self, name: &str) -> u32 {
+        let offset = self.0.len() as u32;
+        self.0.extend_from_slice(name.as_bytes());
+        self.0.push(0);
+        offset
+    }
+}
+
+#[derive(Debug)]
+struct Sym {
+    name: u32,
+    info: u8,
+    section: u16,
+    value: u64,
+    size: u64,
+}
+
+fn align_up(value: usize, align: u64) -> usize {
+    value.next_multiple_of(align as usize)
+}
+
+// `rd` = `addr`, always two instructions so the code's length doesn't depend on
+// where it's loaded
+fn load_address(rd: Register, addr: u64) -> [RiscVInstruction; 2] {
+    let hi = (addr + 0x800) >> 12;
+    [
+        RiscVInstruction::LUI { rd, imm: hi as u32 },
+        RiscVInstruction::ADDI {
+            rd,
+            rs1: rd,
+            imm: (addr as i64 - (hi << 12) as i64) as i32,
+        },
+    ]
+}
+
+// `__evm_stop`, `__evm_jump` and `_start` at the end of `code`, with the JUMPDEST
+// table at `table` and the data segment at `data`
+fn append_start(code: &mut Vec<RiscVInstruction>, table: u64, data: u64) {
+    let stop = code.len();
+    code.extend([
+        RiscVInstruction::ADDI {
+            rd: Register::A0,
+            rs1: Register::ZERO,
+            imm: 0,
+        },
+        RiscVInstruction::ADDI {
+            rd: Register::argument(7),
+            rs1: Register::ZERO,
+            imm: SYS_EXIT,
+        },
+        RiscVInstruction::ECALL,
+    ]);
+    append_jump(code, table, stop);
+    let stack = data + CALL_STACK;
+    code.extend(load_address(Register::SP, stack));
+    code.extend(load_address(STACK_BASE, stack));
+    code.extend(load_address(MEMORY_BASE, stack + EVM_STACK));
+    // back to the entry point at 0 and `__evm_stop`
+    let back = |from: usize, to: usize| -(((from - to) * INSTRUCTION_SIZE) as i32);
+    let call = code.len();
+    code.push(RiscVInstruction::JAL {
+        rd: Register::RA,
+        offset: back(call, 0),
+    });
+    code.push(RiscVInstruction::J {
+        offset: back(call + 1, stop),
+    });
+}
+
+// `__evm_jump`, `a0` points at the target. Targets that don't fit in 64 bits or
+// aren't in the table exit with 1 through `__evm_stop`'s `ecall`.
+fn append_jump(code: &mut Vec<RiscVInstruction>, table: u64, stop: usize) {
+    use RiscVInstruction::*;
+    let (target, entry, count, pc) = (Register::T0, Register::T1, Register::T2, Register::T3);
+    let limb = |rd: Register, i: i32| LD {
+        rd,
+        rs1: Register::A0,
+        imm: 8 * i,
+    };
+    let offset = |from: usize, to: usize| (to as i32 - from as i32) * INSTRUCTION_SIZE as i32;
+    let jump = code.len();
+    let (search, miss) = (jump + 11, jump + 18);
+    for i in 1..4 {
+        code.push(limb(entry, i));
+        code.push(OR {
+            rd: target,
+            rs1: if i == 1 { Register::ZERO } else { target },
+            rs2: entry,
+        });
+    }
+    code.push(BNE {
+        rs1: target,
+        rs2: Register::ZERO,
+        offset: offset(code.len(), miss),
+    });
+    code.push(limb(target, 0));
+    code.extend(load_address(entry, table));
+    code.push(LD {
+        rd: count,
+        rs1: entry,
+        imm: 0,
+    });
+    // on a match `entry` has moved past its pc, onto its address
+    debug_assert_eq!(code.len(), search);
+    code.extend([
+        BEQ {
+            rs1: count,
+            rs2: Register::ZERO,
+            offset: offset(search, miss),
+        },
+        LD {
+            rd: pc,
+            rs1: entry,
+            imm: 8,
+        },
+        ADDI {
+            rd: entry,
+            rs1: entry,
+            imm: 16,
+        },
+        ADDI {
+            rd: count,
+            rs1: count,
+            imm: -1,
+        },
+        BNE {
+            rs1: pc,
+            rs2: target,
+            offset: offset(search + 4, search),
+        },
+        LD {
+            rd: pc,
+            rs1: entry,
+            imm: 0,
+        },
+        JALR {
+            rd: Register::ZERO,
+            rs1: pc,
+            offset: 0,
+        },
+        ADDI {
+            rd: Register::A0,
+            rs1: Register::ZERO,
+            imm: 1,
+        },
+        J {
+            offset: offset(miss + 1, stop + 1),
+        },
+    ]);
+    debug_assert_eq!(code.len(), jump + JUMP_SIZE);
+}
+
+fn rela(out: &mut Vec<u8>, offset: u64, symbol: usize, kind: u32) {
+    out.extend_from_slice(&offset.to_le_bytes());
+    out.extend_from_slice(&((symbol as u64) << 32 | kind as u64).to_le_bytes());
+    out.extend_from_slice(&0i64.to_le_bytes());
+}
+
+/// Write `selection` as an ELF file
+///
+/// An executable fails with `ElfError::Unresolved` if the code calls a helper
+/// other than `__evm_stop` and `__evm_jump` that `library::bundle` didn't add.
+pub fn write(selection: &Selection, kind: ElfKind) -> Result<Vec<u8>, ElfError> {
+    let executable = kind == ElfKind::Executable;
+    let mut calls = selection.calls.clone();
+    let mut code = selection.code.clone();
+    let stop = code.len();
+    let (jump, start) = (stop + STOP_SIZE, stop + STOP_SIZE + JUMP_SIZE);
+    if executable {
+        for (index, helper) in calls.drain(..) {
+            let to = match helper {
+                RuntimeHelper::Stop => stop,
+                RuntimeHelper::Jump => jump,
+                helper => return Err(ElfError::Unresolved(helper.symbol())),
+            };
+            code[index] = RiscVInstruction::JAL {
+                rd: Register::RA,
+                offset: ((to - index) * INSTRUCTION_SIZE) as i32,
+            };
+        }
+    }
+    let jumpdests = selection.labels.len();
+    let table_size = (1 + 2 * jumpdests) * 8;
+
+    // file layout: headers, `.text`, `.rodata`, then what isn't loaded
+    let phnum = if executable { 3 } else { 0 };
+    let text_offset = EHDR_SIZE + phnum * PHDR_SIZE;
+    let stubs = if executable {
+        STOP_SIZE + JUMP_SIZE + START_SIZE
+    } else {
+        0
+    };
+    let text_size = (code.len() + stubs) * INSTRUCTION_SIZE;
+    let rodata_offset = align_up(text_offset + text_size, 8);
+    let (text_addr, rodata_addr, data_addr) = if executable {
+        // `.rodata` gets its own page, at the same offset in it as in the file,
+        // and the data segment starts at the page after it
+        let text_end = BASE + (text_offset + text_size) as u64;
+        let rodata_addr = text_end.next_multiple_of(PAGE) + rodata_offset as u64 % PAGE;
+        let data_addr = (rodata_addr + table_size as u64).next_multiple_of(PAGE);
+        append_start(&mut code, rodata_addr, data_addr);
+        (BASE + text_offset as u64, rodata_addr, data_addr)
+    } else {
+        (0, 0, 0)
+    };
+    let code = assemble(&code)?;
+    debug_assert_eq!(code.len(), text_size);
+    let address = |index: usize| text_addr + (index * INSTRUCTION_SIZE) as u64;
+
+    let mut strtab = Strings::new();
+    let mut syms = vec![Sym {
+        name: 0,
+        info: 0,
+        section: SHN_UNDEF,
+        value: 0,
+        size: 0,
+    }];
+    let symbols = symbols(selection);
+    let mut blocks = BTreeMap::new();
+    for symbol in symbols.iter().filter(|symbol| !symbol.function) {
+        blocks.insert(symbol.name.as_str(), syms.len());
+        syms.push(Sym {
+            name: strtab.add(&symbol.name),
+            info: STB_LOCAL << 4 | STT_NOTYPE,
+            section: TEXT,
+            value: address(symbol.index),
+            size: 0,
+        });
+    }
+    let locals = syms.len();
+
+    // a function runs up to the next one
+    let functions: Vec<_> = symbols.iter().filter(|symbol| symbol.function).collect();
+    for (i, function) in functions.iter().enumerate() {
+        let end = functions
+            .get(i + 1)
+            .map_or(selection.code.len(), |next| next.index);
+        syms.push(Sym {
+            name: strtab.add(&function.name),
+            info: STB_GLOBAL << 4 | STT_FUNC,
+            section: TEXT,
+            value: address(function.index),
+            size: ((end - function.index) * INSTRUCTION_SIZE) as u64,
+        });
+    }
+    if executable {
+        let stubs = [
+            (STOP, stop, STOP_SIZE),
+            (JUMP, jump, JUMP_SIZE),
+            (START, start, START_SIZE),
+        ];
+        for (name, index, size) in stubs {
+            syms.push(Sym {
+                name: strtab.add(name),
+                info: STB_GLOBAL << 4 | STT_FUNC,
+                section: TEXT,
+                value: address(index),
+                size: (size * INSTRUCTION_SIZE) as u64,
+            });
+        }
+    }
+    syms.push(Sym {
+        name: strtab.add(JUMPDESTS),
+        info: STB_GLOBAL << 4 | STT_OBJECT,
+        section: RODATA,
+        value: rodata_addr,
+        size: table_size as u64,
+    });
+
+    let mut helpers = BTreeMap::new();
+    let mut rela_text = Vec::new();
+    for &(index, helper) in &calls {
+        let symbol = *helpers.entry(helper.symbol()).or_insert_with(|| {
+            syms.push(Sym {
+                name: strtab.add(helper.symbol()),
+                info: STB_GLOBAL << 4 | STT_NOTYPE,
+                section: SHN_UNDEF,
+                value: 0,
+                size: 0,
+            });
+            syms.len() - 1
+        });
+        rela(&mut rela_text, address(index), symbol, R_RISCV_JAL);
+    }
+
+    let mut rodata = Vec::with_capacity(table_size);
+    let mut rela_rodata = Vec::new();
+    rodata.extend_from_slice(&(jumpdests as u64).to_le_bytes());
+    for (&pc, &index) in &selection.labels {
+        rodata.extend_from_slice(&(pc as u64).to_le_bytes());
+        if executable {
+            rodata.extend_from_slice(&address(index).to_le_bytes());
+        } else {
+            let block = blocks[block_symbol(pc).as_str()];
+            rela(&mut rela_rodata, rodata.len() as u64, block, R_RISCV_64);
+            rodata.extend_from_slice(&0u64.to_le_bytes());
+        }
+    }
+
+    let mut symtab = Vec::with_capacity(syms.len() * SYM_SIZE);
+    for sym in &syms {
+        symtab.extend_from_slice(&sym.name.to_le_bytes());
+        symtab.push(sym.info);
+        symtab.push(0);
+        symtab.extend_from_slice(&sym.section.to_le_bytes());
+        symtab.extend_from_slice(&sym.value.to_le_bytes());
+        symtab.extend_from_slice(&sym.size.to_le_bytes());
+    }
+
+    let mut shstrtab = Strings::new();
+    let mut sections = vec![
+        Section::default(),
+        Section {
+            name: shstrtab.add(".text"),
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC | SHF_EXECINSTR,
+            addr: text_addr,
+            data: code,
+            align: INSTRUCTION_SIZE as u64,
+            ..Section::default()
+        },
+        Section {
+            name: shstrtab.add(".rodata"),
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC,
+            addr: rodata_addr,
+            data: rodata,
+            align: 8,
+            ..Section::default()
+        },
+        Section {
+            name: shstrtab.add(".rela.text"),
+            kind: SHT_RELA,
+            flags: SHF_INFO_LINK,
+            data: rela_text,
+            link: SYMTAB,
+            info: TEXT as u32,
+            align: 8,
+            entsize: RELA_SIZE as u64,
+            ..Section::default()
+        },
+        Section {
+            name: shstrtab.add(".rela.rodata"),
+            kind: SHT_RELA,
+            flags: SHF_INFO_LINK,
+            data: rela_rodata,
+            link: SYMTAB,
+            info: RODATA as u32,
+            align: 8,
+            entsize: RELA_SIZE as u64,
+            ..Section::default()
+        },
+        Section {
+            name: shstrtab.add(".symtab"),
+            kind: SHT_SYMTAB,
+            data: symtab,
+            link: STRTAB,
+            info: locals as u32,
+            align: 8,
+            entsize: SYM_SIZE as u64,
+            ..Section::default()
+        },
+        Section {
+            name: shstrtab.add(".strtab"),
+            kind: SHT_STRTAB,
+            data: strtab.0,
+            align: 1,
+            ..Section::default()
+        },
+    ];
+    let name = shstrtab.add(".shstrtab");
+    sections.push(Section {
+        name,
+        kind: SHT_STRTAB,
+        data: shstrtab.0,
+        align: 1,
+        ..Section::default()
+    });
+
+    let mut offset = text_offset;
+    for section in sections.iter_mut().skip(1) {
+        offset = align_up(offset, section.align);
+        section.offset = offset;
+        offset += section.data.len();
+    }
+    debug_assert_eq!(sections[RODATA as usize].offset, rodata_offset);
+    let shoff = align_up(offset, 8);
+
+    let mut out = Vec::with_capacity(shoff + sections.len() * SHDR_SIZE);
+    out.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    out.resize(16, 0);
+    out.extend_from_slice(&(if executable { ET_EXEC } else { ET_REL }).to_le_bytes());
+    out.extend_from_slice(&EM_RISCV.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes());
+    let entry = if executable { address(start) } else { 0 };
+    out.extend_from_slice(&entry.to_le_bytes());
+    let phoff = if executable { EHDR_SIZE } else { 0 };
+    out.extend_from_slice(&(phoff as u64).to_le_bytes());
+    out.extend_from_slice(&(shoff as u64).to_le_bytes());
+    // no flags, soft float and no compressed instructions
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&(phnum as u16).to_le_bytes());
+    out.extend_from_slice(&(SHDR_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&(sections.len() as u16).to_le_bytes());
+    out.extend_from_slice(&SHSTRTAB.to_le_bytes());
+
+    if executable {
+        // the headers are loaded with `.text`, as linkers do
+        let text = &sections[TEXT as usize];
+        let rodata = &sections[RODATA as usize];
+        let text_size = (text.offset + text.data.len()) as u64;
+        let rodata_size = rodata.data.len() as u64;
+        let segments = [
+            (PF_R | PF_X, 0, BASE, text_size, text_size),
+            (PF_R, rodata.offset, rodata.addr, rodata_size, rodata_size),
+            (PF_R | PF_W, 0, data_addr, 0, DATA_SIZE),
+        ];
+        for (flags, offset, addr, filesz, memsz) in segments {
+            out.extend_from_slice(&PT_LOAD.to_le_bytes());
+            out.extend_from_slice(&flags.to_le_bytes());
+            out.extend_from_slice(&(offset as u64).to_le_bytes());
+            out.extend_from_slice(&addr.to_le_bytes());
+            out.extend_from_slice(&addr.to_le_bytes());
+            out.extend_from_slice(&filesz.to_le_bytes());
+            out.extend_from_slice(&memsz.to_le_bytes());
+            out.extend_from_slice(&PAGE.to_le_bytes());
+        }
+    }
+
+    for section in &sections[1..] {
+        out.resize(section.offset, 0);
+        out.extend_from_slice(&section.data);
+    }
+    out.resize(shoff, 0);
+    for section in &sections {
+        out.extend_from_slice(&section.name.to_le_bytes());
+        out.extend_from_slice(&section.kind.to_le_bytes());
+        out.extend_from_slice(&section.flags.to_le_bytes());
+        out.extend_from_slice(&section.addr.to_le_bytes());
+        out.extend_from_slice(&(section.offset as u64).to_le_bytes());
+        out.extend_from_slice(&(section.data.len() as u64).to_le_bytes());
+        out.extend_from_slice(&section.link.to_le_bytes());
+        out.extend_from_slice(&section.info.to_le_bytes());
+        out.extend_from_slice(&section.align.to_le_bytes());
+        out.extend_from_slice(&section.entsize.to_le_bytes());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::gas::parser::{generate_ir_with_pcs, parse_bytecode};
+    use crate::ir::generator::assembly::ENTRY;
+    use crate::ir::generator::decode::disassemble;
+    use crate::ir::generator::emulator::{run, Machine, STACK_ADDR};
+    use crate::ir::generator::library::bundle;
+    use crate::ir::generator::select::select;
+    use crate::ir::interpreter::host::MockHost;
+    use crate::ir::interpreter::Halt;
+    use crate::ir::memory::{memory::Memory, stack::Stack};
+
+    fn u16_at(elf: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(elf[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(elf: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(elf[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(elf: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(elf[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn string(table: &[u8], offset: u32) -> &str {
+        let bytes = &table[offset as usize..];
+        let end = bytes.iter().position(|&b| b == 0).unwrap();
+        std::str::from_utf8(&bytes[..end]).unwrap()
+    }
+
+    // Address and contents of the section called `name`
+    fn section<'a>(elf: &'a [u8], name: &str) -> (u64, &'a [u8]) {
+        let (shoff, shnum) = (u64_at(elf, 0x28) as usize, u16_at(elf, 0x3c) as usize);
+        let header = |i: usize| shoff + i * SHDR_SIZE;
+        let data = |i: usize| {
+            let (offset, size) = (u64_at(elf, header(i) + 24), u64_at(elf, header(i) + 32));
+            &elf[offset as usize..(offset + size) as usize]
+        };
+        let names = data(u16_at(elf, 0x3e) as usize);
+        let i = (0..shnum)
+            .find(|&i| string(names, u32_at(elf, header(i))) == name)
+            .unwrap_or_else(|| panic!("no section {name}"));
+        (u64_at(elf, header(i) + 16), data(i))
+    }
+
+    // Name, value and section index of every symbol
+    fn symbol_table(elf: &[u8]) -> Vec<(String, u64, u16)> {
+        let (_, symtab) = section(elf, ".symtab");
+        let (_, strtab) = section(elf, ".strtab");
+        symtab
+            .chunks(SYM_SIZE)
+            .skip(1)
+            .map(|sym| {
+                let name = string(strtab, u32_at(sym, 0)).to_string();
+                (name, u64_at(sym, 8), u16_at(sym, 6))
+            })
+            .collect()
+    }
+
+    // Run an executable's code from the entry point, with `.rodata` loaded
+    fn run_executable(elf: &[u8]) -> (Halt, Machine) {
+        let (text_addr, text) = section(elf, ".text");
+        let (rodata_addr, rodata) = section(elf, ".rodata");
+        let mut machine = Machine::new();
+        machine.origin = text_addr;
+        for (i, word) in rodata.chunks(8).enumerate() {
+            machine.write(rodata_addr + 8 * i as u64, 8, u64_at(word, 0));
+        }
+        let selection = Selection {
+            code: disassemble(text).unwrap(),
+            ..Selection::default()
+        };
+        let halt = run(&selection, &mut machine, &mut MockHost::new());
+        (halt, machine)
+    }
+
+    // loop summing 5 + 4 + ... + 1, leaving [0, sum]
+    const LOOP: [u8; 17] = [
+        0x60, 0x05, 0x60, 0x00, 0x5b, 0x81, 0x01, 0x90, 0x60, 0x01, 0x90, 0x03, 0x90, 0x81, 0x60,
+        0x04, 0x57,
+    ];
+
+    fn selection() -> Selection {
+        // the loop, then sum / 7 and keccak256 in memory
+        let tail = [
+            0x60, 0x07, 0x04, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0x20, 0x00,
+        ];
+        select_bundled(&[&LOOP[..], &tail].concat())
+    }
+
+    fn select_bundled(bytecode: &[u8]) -> Selection {
+        let instructions = parse_bytecode(bytecode).unwrap();
+        let (ir, pcs) =
+            generate_ir_with_pcs(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+        let mut selection = select(&ir, &pcs).unwrap();
+        bundle(&mut selection);
+        selection
+    }
+
+    #[test]
+    fn test_relocatable() {
+        let selection = selection();
+        let elf = write(&selection, ElfKind::Relocatable).unwrap();
+        assert_eq!(elf[..8], [0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        assert_eq!(u16_at(&elf, 0x10), ET_REL);
+        assert_eq!(u16_at(&elf, 0x12), EM_RISCV);
+        assert_eq!(u16_at(&elf, 0x38), 0);
+
+        let (_, text) = section(&elf, ".text");
+        assert_eq!(text, assemble(&selection.code).unwrap());
+
+        let symbols = symbol_table(&elf);
+        let find = |name: &str| symbols.iter().find(|(n, ..)| n == name).unwrap();
+        assert_eq!(find(ENTRY), &(ENTRY.to_string(), 0, TEXT));
+        let label = selection.labels[&4];
+        assert_eq!(find("evm_block_0x4").1, (label * INSTRUCTION_SIZE) as u64);
+        assert_eq!(
+            find("__evm_div").1,
+            (selection.routines["__evm_div"] * INSTRUCTION_SIZE) as u64
+        );
+        assert_eq!(find(JUMPDESTS).2, RODATA);
+        assert_eq!(find("__evm_keccak256").2, SHN_UNDEF);
+
+        // every host call is relocated, and the JUMPDEST's address in the table
+        let (_, rela_text) = section(&elf, ".rela.text");
+        assert_eq!(rela_text.len(), selection.calls.len() * RELA_SIZE);
+        for (rela, &(index, helper)) in rela_text.chunks(RELA_SIZE).zip(&selection.calls) {
+            assert_eq!(u64_at(rela, 0), (index * INSTRUCTION_SIZE) as u64);
+            assert_eq!(u32_at(rela, 8), R_RISCV_JAL);
+            assert_eq!(symbols[u32_at(rela, 12) as usize - 1].0, helper.symbol());
+        }
+        let (_, rela_rodata) = section(&elf, ".rela.rodata");
+        assert_eq!(u64_at(rela_rodata, 0), 16);
+        assert_eq!(u32_at(rela_rodata, 8), R_RISCV_64);
+        assert_eq!(
+            symbols[u32_at(rela_rodata, 12) as usize - 1].0,
+            "evm_block_0x4"
+        );
+    }
+
+    #[test]
+    fn test_executable() {
+        // memory and keccak256 come from the host, which an executable doesn't have
+        assert_eq!(
+            write(&selection(), ElfKind::Executable),
+            Err(ElfError::Unresolved("__evm_mstore"))
+        );
+
+        // the loop, then sum / 7
+        let selection = select_bundled(&[&LOOP[..], &[0x60, 0x07, 0x04, 0x00]].concat());
+        let elf = write(&selection, ElfKind::Executable).unwrap();
+        assert_eq!(u16_at(&elf, 0x10), ET_EXEC);
+
+        // nothing is left to relocate
+        assert!(section(&elf, ".rela.text").1.is_empty());
+        assert!(section(&elf, ".rela.rodata").1.is_empty());
+        let symbols = symbol_table(&elf);
+        assert!(symbols.iter().all(|(_, _, section)| *section != SHN_UNDEF));
+        let find = |name: &str| symbols.iter().find(|(n, ..)| n == name).unwrap().1;
+
+        // the code, with the calls to `__evm_stop` pointed at it
+        let (text_addr, text) = section(&elf, ".text");
+        let code = disassemble(text).unwrap();
+        let address = |index: usize| text_addr + (index * INSTRUCTION_SIZE) as u64;
+        let stop = selection.code.len();
+        assert_eq!(find(STOP), address(stop));
+        assert_eq!(code.len(), stop + STOP_SIZE + JUMP_SIZE + START_SIZE);
+        for (index, inst) in code[..stop].iter().enumerate() {
+            match selection.calls.iter().find(|(i, _)| *i == index) {
+                Some(&(_, helper)) => {
+                    assert_eq!(helper, RuntimeHelper::Stop);
+                    let RiscVInstruction::JAL { rd, offset } = *inst else {
+                        panic!("{inst:?}")
+                    };
+                    assert_eq!(rd, Register::RA);
+                    assert_eq!(address(index).wrapping_add(offset as u64), find(STOP));
+                }
+                None => assert_eq!(*inst, selection.code[index]),
+            }
+        }
+        assert_eq!(code[stop + 2], RiscVInstruction::ECALL);
+
+        // three segments, page aligned with their file offsets, the last only in
+        // memory
+        assert_eq!(u16_at(&elf, 0x38), 3);
+        for i in 0..3 {
+            let phdr = EHDR_SIZE + i * PHDR_SIZE;
+            assert_eq!(u32_at(&elf, phdr), PT_LOAD);
+            assert_eq!(
+                u64_at(&elf, phdr + 8) % PAGE,
+                u64_at(&elf, phdr + 16) % PAGE
+            );
+        }
+        assert_eq!(u64_at(&elf, EHDR_SIZE + 16), BASE);
+        let data = EHDR_SIZE + 2 * PHDR_SIZE;
+        assert_eq!(u32_at(&elf, data + 4), PF_R | PF_W);
+        assert_eq!(u64_at(&elf, data + 32), 0);
+        assert_eq!(u64_at(&elf, data + 40), DATA_SIZE);
+        let data_addr = u64_at(&elf, data + 16);
+
+        // `_start` sets up the registers, calls the entry point and stops after it
+        let start = stop + STOP_SIZE + JUMP_SIZE;
+        assert_eq!(u64_at(&elf, 0x18), address(start));
+        assert_eq!(find(START), address(start));
+        let mut regs = BTreeMap::new();
+        for inst in &code[start..start + 6] {
+            match *inst {
+                RiscVInstruction::LUI { rd, imm } => {
+                    regs.insert(rd.raw(), (imm << 12) as i32 as u64);
+                }
+                RiscVInstruction::ADDI { rd, rs1, imm } => {
+                    assert_eq!(rd, rs1);
+                    regs.insert(rd.raw(), regs[&rd.raw()].wrapping_add(imm as u64));
+                }
+                _ => panic!("{inst:?}"),
+            }
+        }
+        let evm_stack = data_addr + CALL_STACK;
+        assert_eq!(regs[&Register::SP.raw()], evm_stack);
+        assert_eq!(regs[&STACK_BASE.raw()], evm_stack);
+        assert_eq!(regs[&MEMORY_BASE.raw()], evm_stack + EVM_STACK);
+        assert!(evm_stack + EVM_STACK + MEMORY_LIMIT as u64 <= data_addr + DATA_SIZE);
+        let RiscVInstruction::JAL { rd, offset } = code[start + 6] else {
+            panic!("{:?}", code[start + 6])
+        };
+        assert_eq!(rd, Register::RA);
+        assert_eq!(address(start + 6).wrapping_add(offset as u64), find(ENTRY));
+        let RiscVInstruction::J { offset } = code[start + 7] else {
+            panic!("{:?}", code[start + 7])
+        };
+        assert_eq!(address(start + 7).wrapping_add(offset as u64), find(STOP));
+
+        let (rodata_addr, rodata) = section(&elf, ".rodata");
+        let label = selection.labels[&4] * INSTRUCTION_SIZE;
+        assert_eq!(u64_at(rodata, 0), 1);
+        assert_eq!(u64_at(rodata, 8), 4);
+        assert_eq!(u64_at(rodata, 16), text_addr + label as u64);
+
+        assert!(symbols.contains(&("evm_block_0x4".to_string(), text_addr + label as u64, TEXT)));
+        assert!(symbols.contains(&(JUMPDESTS.to_string(), rodata_addr, RODATA)));
+        assert!(rodata_addr.is_multiple_of(8) && rodata_addr > text_addr);
+    }
+
+    #[test]
+    fn test_dynamic_jump() {
+        // the target is pushed before the JUMPDEST, so it's only found in the
+        // table at runtime: jumping to 5 leaves 0x2a, 4 is a STOP
+        let jump = |target| [0x60, target, 0x5b, 0x56, 0x00, 0x5b, 0x60, 0x2a, 0x00];
+        let selection = select_bundled(&jump(5));
+        assert!(selection
+            .calls
+            .iter()
+            .any(|(_, helper)| *helper == RuntimeHelper::Jump));
+        let elf = write(&selection, ElfKind::Executable).unwrap();
+        assert!(symbol_table(&elf).iter().any(|(name, ..)| name == JUMP));
+        let (halt, machine) = run_executable(&elf);
+        assert_eq!(halt, Halt::Stop);
+        assert_eq!(machine.read(STACK_ADDR, 8), 0x2a);
+
+        let elf = write(&select_bundled(&jump(4)), ElfKind::Executable).unwrap();
+        assert_eq!(run_executable(&elf).0, Halt::Invalid);
+    }
+}
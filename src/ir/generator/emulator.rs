@@ -0,0 +1,288 @@
+use std::collections::{BTreeMap, HashMap};
+
+use alloy_primitives::U256 as U;
+
+use super::register::Register;
+use super::runtime::{RuntimeHelper, STACK_BASE};
+use super::select::Selection;
+use crate::ir::gas::parser::RiscVInstruction;
+use crate::ir::interpreter::host::{Host, MockHost};
+use crate::ir::interpreter::{
+    halt, load_word, memory_range, read_memory, store_word, Halt, STEP_LIMIT,
+};
+use crate::ir::memory::memory::Memory;
+use crate::MyU256 as U256;
+
+// RV64 emulator for testing selected code
+//
+// Runtime helpers are implemented in Rust on top of the IR interpreter, with the
+// same register ABI as the real ones. Code lives at `Machine::origin`, 0 unless
+// it's an executable's, and EVM memory is kept apart in a `Memory`, like the
+// runtime keeps it behind `s1`. The only trap is the Linux `exit` executables end
+// with.
+
+pub(crate) const STACK_ADDR: u64 = 0x1000_0000;
+pub(crate) const SP_ADDR: u64 = 0x2000_0000;
+// registers a helper call may clobber, t0-t2, a0-a7 and t3-t6
+const CALLER_SAVED: [u8; 15] = [5, 6, 7, 10, 11, 12, 13, 14, 15, 16, 17, 28, 29, 30, 31];
+const SYS_EXIT: u64 = 93;
+
+#[derive(Debug, Default)]
+pub(crate) struct Machine {
+    pub regs: [u64; 32],
+    /// Address of the first instruction
+    pub origin: u64,
+    // aligned 64-bit words by address / 8
+    memory: HashMap<u64, u64>,
+}
+
+impl Machine {
+    pub fn new() -> Self {
+        let mut machine = Machine::default();
+        machine.set(STACK_BASE, STACK_ADDR);
+        machine.set(Register::SP, SP_ADDR);
+        machine
+    }
+
+    pub fn get(&self, reg: Register) -> u64 {
+        self.regs[reg.raw() as usize]
+    }
+
+    pub fn set(&mut self, reg: Register, value: u64) {
+        if reg != Register::ZERO {
+            self.regs[reg.raw() as usize] = value;
+        }
+    }
+
+    /// Little-endian read of `len` bytes
+    pub fn read(&self, addr: u64, len: usize) -> u64 {
+        if addr.is_multiple_of(8) && len == 8 {
+            return self.memory.get(&(addr / 8)).copied().unwrap_or(0);
+        }
+        (0..len).rev().fold(0, |value, i| {
+            let byte = addr + i as u64;
+            let word = self.memory.get(&(byte / 8)).copied().unwrap_or(0);
+            value << 8 | (word >> (8 * (byte % 8)) & 0xff)
+        })
+    }
+
+    pub fn write(&mut self, addr: u64, len: usize, value: u64) {
+        if addr.is_multiple_of(8) && len == 8 {
+            self.memory.insert(addr / 8, value);
+            return;
+        }
+        for i in 0..len {
+            let byte = addr + i as u64;
+            let word = self.memory.entry(byte / 8).or_default();
+            let shift = 8 * (byte % 8);
+            *word = *word & !(0xff << shift) | (value >> (8 * i) & 0xff) << shift;
+        }
+    }
+
+    pub fn read_slot(&self, addr: u64) -> U256 {
+        let limbs = [0, 1, 2, 3].map(|i| self.read(addr + 8 * i, 8));
+        U256(U::from_limbs(limbs))
+    }
+
+    pub fn write_slot(&mut self, addr: u64, value: U256) {
+        for (i, limb) in value.0.as_limbs().iter().enumerate() {
+            self.write(addr + 8 * i as u64, 8, *limb);
+        }
+    }
+}
+
+/// Run `selection` from its first instruction on a fresh machine
+pub(crate) fn execute(selection: &Selection, host: &mut MockHost) -> Halt {
+    run(selection, &mut Machine::new(), host)
+}
+
+pub(crate) fn run(selection: &Selection, machine: &mut Machine, host: &mut MockHost) -> Halt {
+    for inst in &selection.code {
+        if let Err(err) = inst.check() {
+            panic!("{inst:?}: {err}");
+        }
+    }
+    let calls: HashMap<usize, RuntimeHelper> = selection.calls.iter().copied().collect();
+    let mut memory = Memory::new();
+
+    let origin = machine.origin;
+    let mut index = 0;
+    for _ in 0..STEP_LIMIT {
+        let Some(inst) = selection.code.get(index) else {
+            return Halt::Stop;
+        };
+        let pc = origin + (index * 4) as u64;
+        let reg = |reg: Register| machine.get(reg);
+        let signed = |reg: Register| machine.get(reg) as i64;
+        let address = |rs1: Register, imm: i32| reg(rs1).wrapping_add(imm as u64);
+        let branch = |offset: i32| (index as i64 + offset as i64 / 4) as usize;
+        let mut next = index + 1;
+
+        use RiscVInstruction::*;
+        let (rd, value) = match *inst {
+            LUI { rd, imm } => (rd, (imm << 12) as i32 as u64),
+            AUIPC { rd, imm } => (rd, pc.wrapping_add((imm << 12) as i32 as u64)),
+            JAL { rd, offset } => match calls.get(&index) {
+                Some(helper) => {
+                    match call_helper(*helper, machine, &mut memory, host, &selection.labels) {
+                        Ok(Some(to)) => next = to,
+                        Ok(None) => {}
+                        Err(halt) => return halt,
+                    }
+                    // nothing generated may rely on caller saved registers surviving
+                    for reg in CALLER_SAVED {
+                        machine.set(Register::from_raw(reg), 0xdead_beef_dead_beef);
+                    }
+                    (Register::ZERO, 0)
+                }
+                None => {
+                    next = branch(offset);
+                    (rd, pc + 4)
+                }
+            },
+            J { offset } => {
+                next = branch(offset);
+                (Register::ZERO, 0)
+            }
+            JALR { rd, rs1, offset } => {
+                next = (address(rs1, offset).wrapping_sub(origin) / 4) as usize;
+                (rd, pc + 4)
+            }
+            BEQ { rs1, rs2, offset }
+            | BNE { rs1, rs2, offset }
+            | BLT { rs1, rs2, offset }
+            | BGE { rs1, rs2, offset }
+            | BLTU { rs1, rs2, offset }
+            | BGEU { rs1, rs2, offset } => {
+                let taken = match inst {
+                    BEQ { .. } => reg(rs1) == reg(rs2),
+                    BNE { .. } => reg(rs1) != reg(rs2),
+                    BLT { .. } => signed(rs1) < signed(rs2),
+                    BGE { .. } => signed(rs1) >= signed(rs2),
+                    BLTU { .. } => reg(rs1) < reg(rs2),
+                    _ => reg(rs1) >= reg(rs2),
+                };
+                if taken {
+                    next = branch(offset);
+                }
+                (Register::ZERO, 0)
+            }
+            LB { rd, rs1, imm } => (rd, machine.read(address(rs1, imm), 1) as i8 as u64),
+            LH { rd, rs1, imm } => (rd, machine.read(address(rs1, imm), 2) as i16 as u64),
+            LW { rd, rs1, imm } => (rd, machine.read(address(rs1, imm), 4) as i32 as u64),
+            LD { rd, rs1, imm } => (rd, machine.read(address(rs1, imm), 8)),
+            LBU { rd, rs1, imm } => (rd, machine.read(address(rs1, imm), 1)),
+            LHU { rd, rs1, imm } => (rd, machine.read(address(rs1, imm), 2)),
+            LWU { rd, rs1, imm } => (rd, machine.read(address(rs1, imm), 4)),
+            SB { rs1, rs2, imm }
+            | SH { rs1, rs2, imm }
+            | SW { rs1, rs2, imm }
+            | SD { rs1, rs2, imm } => {
+                let len = match inst {
+                    SB { .. } => 1,
+                    SH { .. } => 2,
+                    SW { .. } => 4,
+                    _ => 8,
+                };
+                let (addr, value) = (address(rs1, imm), reg(rs2));
+                machine.write(addr, len, value);
+                (Register::ZERO, 0)
+            }
+            ADDI { rd, rs1, imm } => (rd, address(rs1, imm)),
+            SLTI { rd, rs1, imm } => (rd, (signed(rs1) < imm as i64) as u64),
+            SLTIU { rd, rs1, imm } => (rd, (reg(rs1) < imm as i64 as u64) as u64),
+            XORI { rd, rs1, imm } => (rd, reg(rs1) ^ imm as i64 as u64),
+            ORI { rd, rs1, imm } => (rd, reg(rs1) | imm as i64 as u64),
+            ANDI { rd, rs1, imm } => (rd, reg(rs1) & imm as i64 as u64),
+            SLLI { rd, rs1, shamt } => (rd, reg(rs1) << shamt),
+            SRLI { rd, rs1, shamt } => (rd, reg(rs1) >> shamt),
+            SRAI { rd, rs1, shamt } => (rd, (signed(rs1) >> shamt) as u64),
+            ADD { rd, rs1, rs2 } => (rd, reg(rs1).wrapping_add(reg(rs2))),
+            SUB { rd, rs1, rs2 } => (rd, reg(rs1).wrapping_sub(reg(rs2))),
+            SLL { rd, rs1, rs2 } => (rd, reg(rs1) << (reg(rs2) & 63)),
+            SLT { rd, rs1, rs2 } => (rd, (signed(rs1) < signed(rs2)) as u64),
+            SLTU { rd, rs1, rs2 } => (rd, (reg(rs1) < reg(rs2)) as u64),
+            XOR { rd, rs1, rs2 } => (rd, reg(rs1) ^ reg(rs2)),
+            SRL { rd, rs1, rs2 } => (rd, reg(rs1) >> (reg(rs2) & 63)),
+            SRA { rd, rs1, rs2 } => (rd, (signed(rs1) >> (reg(rs2) & 63)) as u64),
+            OR { rd, rs1, rs2 } => (rd, reg(rs1) | reg(rs2)),
+            AND { rd, rs1, rs2 } => (rd, reg(rs1) & reg(rs2)),
+            ADDIW { rd, rs1, imm } => (rd, (reg(rs1) as i32).wrapping_add(imm) as u64),
+            SLLIW { rd, rs1, shamt } => (rd, ((reg(rs1) as u32) << shamt) as i32 as u64),
+            SRLIW { rd, rs1, shamt } => (rd, ((reg(rs1) as u32) >> shamt) as i32 as u64),
+            SRAIW { rd, rs1, shamt } => (rd, ((reg(rs1) as i32) >> shamt) as u64),
+            ADDW { rd, rs1, rs2 } => (rd, (reg(rs1) as i32).wrapping_add(reg(rs2) as i32) as u64),
+            SUBW { rd, rs1, rs2 } => (rd, (reg(rs1) as i32).wrapping_sub(reg(rs2) as i32) as u64),
+            SLLW { rd, rs1, rs2 } => (rd, ((reg(rs1) as u32) << (reg(rs2) & 31)) as i32 as u64),
+            SRLW { rd, rs1, rs2 } => (rd, ((reg(rs1) as u32) >> (reg(rs2) & 31)) as i32 as u64),
+            SRAW { rd, rs1, rs2 } => (rd, ((reg(rs1) as i32) >> (reg(rs2) & 31)) as u64),
+            MUL { rd, rs1, rs2 } => (rd, reg(rs1).wrapping_mul(reg(rs2))),
+            MULHU { rd, rs1, rs2 } => (rd, ((reg(rs1) as u128 * reg(rs2) as u128) >> 64) as u64),
+            // division by zero gives all ones and leaves the dividend as the remainder
+            DIVU { rd, rs1, rs2 } => (rd, reg(rs1).checked_div(reg(rs2)).unwrap_or(u64::MAX)),
+            REMU { rd, rs1, rs2 } => (rd, reg(rs1).checked_rem(reg(rs2)).unwrap_or(reg(rs1))),
+            // single hart, nothing to order
+            FENCE { .. } => (Register::ZERO, 0),
+            // exit status 0 is a STOP, anything else a bad jump
+            ECALL if reg(Register::A7) == SYS_EXIT => {
+                return match reg(Register::A0) {
+                    0 => Halt::Stop,
+                    _ => Halt::Invalid,
+                };
+            }
+            ECALL | EBREAK => panic!("{inst:?} at {pc:#x}, the runtime doesn't use traps"),
+        };
+        machine.set(rd, value);
+        index = next;
+    }
+    Halt::StepLimit
+}
+
+// What the runtime does for `helper`, returns the code index to continue at for jumps
+fn call_helper(
+    helper: RuntimeHelper,
+    machine: &mut Machine,
+    memory: &mut Memory,
+    host: &mut MockHost,
+    labels: &BTreeMap<usize, usize>,
+) -> Result<Option<usize>, Halt> {
+    let first = usize::from(helper.has_result());
+    let args: Vec<U256> = (0..helper.operands())
+        .map(|i| machine.read_slot(machine.get(Register::argument(first + i))))
+        .collect();
+
+    let result = match helper {
+        RuntimeHelper::Keccak256 => {
+            let data = read_memory(memory, args[0], args[1])?;
+            U256(U::from_be_bytes(alloy_primitives::keccak256(data).0))
+        }
+        RuntimeHelper::MLoad => load_word(memory, args[0])?,
+        RuntimeHelper::MStore => return store_word(memory, args[0], args[1]).map(|_| None),
+        RuntimeHelper::MStore8 => {
+            let offset = memory_range(memory, args[0], 1)?;
+            memory.write_byte(offset, args[1].0.byte(0));
+            return Ok(None);
+        }
+        RuntimeHelper::SLoad => host.sload(args[0]),
+        RuntimeHelper::SStore => {
+            host.sstore(args[0], args[1]);
+            return Ok(None);
+        }
+        RuntimeHelper::Host(kind) if kind.halts() => return Err(halt(kind, &args, memory)),
+        RuntimeHelper::Host(kind) => match host.call(kind, &args, memory) {
+            Some(value) => value,
+            None => return Ok(None),
+        },
+        RuntimeHelper::Jump => {
+            return usize::try_from(args[0].0)
+                .ok()
+                .and_then(|pc| labels.get(&pc))
+                .map(|&to| Some(to))
+                .ok_or(Halt::Invalid)
+        }
+        RuntimeHelper::Stop => return Err(Halt::Stop),
+        helper => helper.eval(&args, memory).unwrap(),
+    };
+    machine.write_slot(machine.get(Register::A0), result);
+    Ok(None)
+}
@@ -0,0 +1,605 @@
+use super::register::{InvalidRegister, Register};
+use crate::ir::gas::parser::{ImmediateError, RiscVInstruction};
+use crate::ir::source_map::INSTRUCTION_SIZE;
+use std::fmt;
+
+// Machine code encoder
+//
+// Every instruction is one 32-bit word in one of the six base formats, stored
+// little-endian. Immediates are checked with `RiscVInstruction::check` before
+// their bits are scattered into the word, so a value that doesn't fit is an error
+// instead of silently losing its upper bits. The same goes for register numbers.
+
+pub(crate) const LOAD: u32 = 0b000_0011;
+pub(crate) const MISC_MEM: u32 = 0b000_1111;
+pub(crate) const OP_IMM: u32 = 0b001_0011;
+pub(crate) const AUIPC: u32 = 0b001_0111;
+pub(crate) const OP_IMM_32: u32 = 0b001_1011;
+pub(crate) const STORE: u32 = 0b010_0011;
+pub(crate) const OP: u32 = 0b011_0011;
+pub(crate) const LUI: u32 = 0b011_0111;
+pub(crate) const OP_32: u32 = 0b011_1011;
+pub(crate) const BRANCH: u32 = 0b110_0011;
+pub(crate) const JALR: u32 = 0b110_0111;
+pub(crate) const JAL: u32 = 0b110_1111;
+pub(crate) const SYSTEM: u32 = 0b111_0011;
+
+// funct7 of SUB and the arithmetic shifts
+pub(crate) const ALT: u32 = 0b010_0000;
+// funct7 of the M extension
+pub(crate) const MULDIV: u32 = 0b000_0001;
+
+/// Why one instruction can't be encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstructionError {
+    Immediate(ImmediateError),
+    Register(InvalidRegister),
+}
+
+impl From<ImmediateError> for InstructionError {
+    fn from(err: ImmediateError) -> Self {
+        InstructionError::Immediate(err)
+    }
+}
+
+impl From<InvalidRegister> for InstructionError {
+    fn from(err: InvalidRegister) -> Self {
+        InstructionError::Register(err)
+    }
+}
+
+impl fmt::Display for InstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstructionError::Immediate(err) => err.fmt(f),
+            InstructionError::Register(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for InstructionError {}
+
+/// An instruction that can't be encoded, at `index` in the code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodeError {
+    pub index: usize,
+    pub error: InstructionError,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "instruction {}: {}", self.index, self.error)
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+fn reg(register: Register) -> Result<u32, InvalidRegister> {
+    Register::try_from(register.raw()).map(|register| register.raw() as u32)
+}
+
+fn r_type(
+    opcode: u32,
+    funct3: u32,
+    funct7: u32,
+    rd: Register,
+    rs1: Register,
+    rs2: Register,
+) -> Result<u32, InvalidRegister> {
+    Ok(funct7 << 25 | reg(rs2)? << 20 | reg(rs1)? << 15 | funct3 << 12 | reg(rd)? << 7 | opcode)
+}
+
+fn i_type(
+    opcode: u32,
+    funct3: u32,
+    rd: Register,
+    rs1: Register,
+    imm: i32,
+) -> Result<u32, InvalidRegister> {
+    Ok((imm as u32 & 0xfff) << 20 | reg(rs1)? << 15 | funct3 << 12 | reg(rd)? << 7 | opcode)
+}
+
+fn s_type(
+    opcode: u32,
+    funct3: u32,
+    rs1: Register,
+    rs2: Register,
+    imm: i32,
+) -> Result<u32, InvalidRegister> {
+    let imm = imm as u32;
+    Ok((imm >> 5 & 0x7f) << 25
+        | reg(rs2)? << 20
+        | reg(rs1)? << 15
+        | funct3 << 12
+        | (imm & 0x1f) << 7
+        | opcode)
+}
+
+fn b_type(funct3: u32, rs1: Register, rs2: Register, offset: i32) -> Result<u32, InvalidRegister> {
+    let imm = offset as u32;
+    Ok((imm >> 12 & 1) << 31
+        | (imm >> 5 & 0x3f) << 25
+        | reg(rs2)? << 20
+        | reg(rs1)? << 15
+        | funct3 << 12
+        | (imm >> 1 & 0xf) << 8
+        | (imm >> 11 & 1) << 7
+        | BRANCH)
+}
+
+fn u_type(opcode: u32, rd: Register, imm: u32) -> Result<u32, InvalidRegister> {
+    Ok(imm << 12 | reg(rd)? << 7 | opcode)
+}
+
+fn j_type(rd: Register, offset: i32) -> Result<u32, InvalidRegister> {
+    let imm = offset as u32;
+    Ok((imm >> 20 & 1) << 31
+        | (imm >> 1 & 0x3ff) << 21
+        | (imm >> 11 & 1) << 20
+        | (imm >> 12 & 0xff) << 12
+        | reg(rd)? << 7
+        | JAL)
+}
+
+// Shifts by an immediate are I-type with the shift amount in the low bits of the
+// immediate and `funct7` above it. The RV64 shifts take a sixth bit of shift
+// amount out of `funct7`, whose low bit is always clear.
+fn shift(
+    opcode: u32,
+    funct3: u32,
+    funct7: u32,
+    rd: Register,
+    rs1: Register,
+    shamt: u32,
+) -> Result<u32, InvalidRegister> {
+    i_type(opcode, funct3, rd, rs1, (funct7 << 5 | shamt) as i32)
+}
+
+/// Encode `inst` as a 32-bit instruction word
+pub fn encode(inst: &RiscVInstruction) -> Result<u32, InstructionError> {
+    use RiscVInstruction::*;
+    inst.check()?;
+    let word = match *inst {
+        LUI { rd, imm } => u_type(self::LUI, rd, imm),
+        AUIPC { rd, imm } => u_type(self::AUIPC, rd, imm),
+        JAL { rd, offset } => j_type(rd, offset),
+        J { offset } => j_type(Register::ZERO, offset),
+        JALR { rd, rs1, offset } => i_type(self::JALR, 0b000, rd, rs1, offset),
+
+        BEQ { rs1, rs2, offset } => b_type(0b000, rs1, rs2, offset),
+        BNE { rs1, rs2, offset } => b_type(0b001, rs1, rs2, offset),
+        BLT { rs1, rs2, offset } => b_type(0b100, rs1, rs2, offset),
+        BGE { rs1, rs2, offset } => b_type(0b101, rs1, rs2, offset),
+        BLTU { rs1, rs2, offset } => b_type(0b110, rs1, rs2, offset),
+        BGEU { rs1, rs2, offset } => b_type(0b111, rs1, rs2, offset),
+
+        LB { rd, rs1, imm } => i_type(LOAD, 0b000, rd, rs1, imm),
+        LH { rd, rs1, imm } => i_type(LOAD, 0b001, rd, rs1, imm),
+        LW { rd, rs1, imm } => i_type(LOAD, 0b010, rd, rs1, imm),
+        LD { rd, rs1, imm } => i_type(LOAD, 0b011, rd, rs1, imm),
+        LBU { rd, rs1, imm } => i_type(LOAD, 0b100, rd, rs1, imm),
+        LHU { rd, rs1, imm } => i_type(LOAD, 0b101, rd, rs1, imm),
+        LWU { rd, rs1, imm } => i_type(LOAD, 0b110, rd, rs1, imm),
+        SB { rs1, rs2, imm } => s_type(STORE, 0b000, rs1, rs2, imm),
+        SH { rs1, rs2, imm } => s_type(STORE, 0b001, rs1, rs2, imm),
+        SW { rs1, rs2, imm } => s_type(STORE, 0b010, rs1, rs2, imm),
+        SD { rs1, rs2, imm } => s_type(STORE, 0b011, rs1, rs2, imm),
+
+        ADDI { rd, rs1, imm } => i_type(OP_IMM, 0b000, rd, rs1, imm),
+        SLTI { rd, rs1, imm } => i_type(OP_IMM, 0b010, rd, rs1, imm),
+        SLTIU { rd, rs1, imm } => i_type(OP_IMM, 0b011, rd, rs1, imm),
+        XORI { rd, rs1, imm } => i_type(OP_IMM, 0b100, rd, rs1, imm),
+        ORI { rd, rs1, imm } => i_type(OP_IMM, 0b110, rd, rs1, imm),
+        ANDI { rd, rs1, imm } => i_type(OP_IMM, 0b111, rd, rs1, imm),
+        SLLI { rd, rs1, shamt } => shift(OP_IMM, 0b001, 0, rd, rs1, shamt),
+        SRLI { rd, rs1, shamt } => shift(OP_IMM, 0b101, 0, rd, rs1, shamt),
+        SRAI { rd, rs1, shamt } => shift(OP_IMM, 0b101, ALT, rd, rs1, shamt),
+
+        ADD { rd, rs1, rs2 } => r_type(OP, 0b000, 0, rd, rs1, rs2),
+        SUB { rd, rs1, rs2 } => r_type(OP, 0b000, ALT, rd, rs1, rs2),
+        SLL { rd, rs1, rs2 } => r_type(OP, 0b001, 0, rd, rs1, rs2),
+        SLT { rd, rs1, rs2 } => r_type(OP, 0b010, 0, rd, rs1, rs2),
+        SLTU { rd, rs1, rs2 } => r_type(OP, 0b011, 0, rd, rs1, rs2),
+        XOR { rd, rs1, rs2 } => r_type(OP, 0b100, 0, rd, rs1, rs2),
+        SRL { rd, rs1, rs2 } => r_type(OP, 0b101, 0, rd, rs1, rs2),
+        SRA { rd, rs1, rs2 } => r_type(OP, 0b101, ALT, rd, rs1, rs2),
+        OR { rd, rs1, rs2 } => r_type(OP, 0b110, 0, rd, rs1, rs2),
+        AND { rd, rs1, rs2 } => r_type(OP, 0b111, 0, rd, rs1, rs2),
+
+        ADDIW { rd, rs1, imm } => i_type(OP_IMM_32, 0b000, rd, rs1, imm),
+        SLLIW { rd, rs1, shamt } => shift(OP_IMM_32, 0b001, 0, rd, rs1, shamt),
+        SRLIW { rd, rs1, shamt } => shift(OP_IMM_32, 0b101, 0, rd, rs1, shamt),
+        SRAIW { rd, rs1, shamt } => shift(OP_IMM_32, 0b101, ALT, rd, rs1, shamt),
+        ADDW { rd, rs1, rs2 } => r_type(OP_32, 0b000, 0, rd, rs1, rs2),
+        SUBW { rd, rs1, rs2 } => r_type(OP_32, 0b000, ALT, rd, rs1, rs2),
+        SLLW { rd, rs1, rs2 } => r_type(OP_32, 0b001, 0, rd, rs1, rs2),
+        SRLW { rd, rs1, rs2 } => r_type(OP_32, 0b101, 0, rd, rs1, rs2),
+        SRAW { rd, rs1, rs2 } => r_type(OP_32, 0b101, ALT, rd, rs1, rs2),
+
+        FENCE { pred, succ } => {
+            let imm = (pred as i32) << 4 | succ as i32;
+            i_type(MISC_MEM, 0b000, Register::ZERO, Register::ZERO, imm)
+        }
+        ECALL => i_type(SYSTEM, 0b000, Register::ZERO, Register::ZERO, 0),
+        EBREAK => i_type(SYSTEM, 0b000, Register::ZERO, Register::ZERO, 1),
+
+        MUL { rd, rs1, rs2 } => r_type(OP, 0b000, MULDIV, rd, rs1, rs2),
+        MULHU { rd, rs1, rs2 } => r_type(OP, 0b011, MULDIV, rd, rs1, rs2),
+        DIVU { rd, rs1, rs2 } => r_type(OP, 0b101, MULDIV, rd, rs1, rs2),
+        REMU { rd, rs1, rs2 } => r_type(OP, 0b111, MULDIV, rd, rs1, rs2),
+    }?;
+    Ok(word)
+}
+
+/// Encode `code` as little-endian machine code
+pub fn assemble(code: &[RiscVInstruction]) -> Result<Vec<u8>, EncodeError> {
+    let mut bytes = Vec::with_capacity(code.len() * INSTRUCTION_SIZE);
+    for (index, inst) in code.iter().enumerate() {
+        let word = encode(inst).map_err(|error| EncodeError { index, error })?;
+        bytes.extend_from_slice(&word.to_le_bytes());
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::generator::library::routine;
+    use crate::ir::generator::runtime::RuntimeHelper;
+
+    const ZERO: Register = Register::ZERO;
+    const RA: Register = Register::RA;
+    const SP: Register = Register::SP;
+    const T0: Register = Register::T0;
+    const T1: Register = Register::T1;
+    const T6: Register = Register::T6;
+    const S0: Register = Register::S0;
+    const S1: Register = Register::S1;
+    const A0: Register = Register::A0;
+    const A1: Register = Register::A1;
+    const A2: Register = Register::A2;
+
+    #[test]
+    fn test_encode() {
+        use RiscVInstruction::*;
+
+        // words as listed by the RISC-V spec and assemblers
+        let cases = [
+            (
+                ADDI {
+                    rd: ZERO,
+                    rs1: ZERO,
+                    imm: 0,
+                },
+                0x0000_0013,
+            ),
+            (ECALL, 0x0000_0073),
+            (EBREAK, 0x0010_0073),
+            (
+                JALR {
+                    rd: ZERO,
+                    rs1: RA,
+                    offset: 0,
+                },
+                0x0000_8067,
+            ),
+            (
+                JALR {
+                    rd: RA,
+                    rs1: T0,
+                    offset: -1,
+                },
+                0xfff2_80e7,
+            ),
+            (
+                ADDI {
+                    rd: A0,
+                    rs1: ZERO,
+                    imm: -1,
+                },
+                0xfff0_0513,
+            ),
+            (
+                LUI {
+                    rd: A0,
+                    imm: 0x12345,
+                },
+                0x1234_5537,
+            ),
+            (AUIPC { rd: RA, imm: 0 }, 0x0000_0097),
+            (JAL { rd: RA, offset: 0 }, 0x0000_00ef),
+            (
+                JAL {
+                    rd: RA,
+                    offset: 1_048_574,
+                },
+                0x7fff_f0ef,
+            ),
+            (
+                JAL {
+                    rd: A0,
+                    offset: 2048,
+                },
+                0x0010_056f,
+            ),
+            (J { offset: -1_048_576 }, 0x8000_006f),
+            (
+                SD {
+                    rs1: SP,
+                    rs2: RA,
+                    imm: 8,
+                },
+                0x0011_3423,
+            ),
+            (
+                LD {
+                    rd: RA,
+                    rs1: SP,
+                    imm: 8,
+                },
+                0x0081_3083,
+            ),
+            (
+                SB {
+                    rs1: A0,
+                    rs2: T0,
+                    imm: -1,
+                },
+                0xfe55_0fa3,
+            ),
+            (
+                LWU {
+                    rd: T1,
+                    rs1: S1,
+                    imm: 2047,
+                },
+                0x7ff4_e303,
+            ),
+            (
+                SLTIU {
+                    rd: A0,
+                    rs1: A0,
+                    imm: 1,
+                },
+                0x0015_3513,
+            ),
+            (
+                ANDI {
+                    rd: A0,
+                    rs1: A0,
+                    imm: -2048,
+                },
+                0x8005_7513,
+            ),
+            (
+                ADD {
+                    rd: A0,
+                    rs1: A0,
+                    rs2: A1,
+                },
+                0x00b5_0533,
+            ),
+            (
+                SUB {
+                    rd: A0,
+                    rs1: A0,
+                    rs2: A1,
+                },
+                0x40b5_0533,
+            ),
+            (
+                MUL {
+                    rd: A0,
+                    rs1: A0,
+                    rs2: A1,
+                },
+                0x02b5_0533,
+            ),
+            (
+                MULHU {
+                    rd: A0,
+                    rs1: A0,
+                    rs2: A1,
+                },
+                0x02b5_3533,
+            ),
+            (
+                DIVU {
+                    rd: A0,
+                    rs1: A0,
+                    rs2: A1,
+                },
+                0x02b5_5533,
+            ),
+            (
+                REMU {
+                    rd: A0,
+                    rs1: A0,
+                    rs2: A1,
+                },
+                0x02b5_7533,
+            ),
+            (
+                SRAI {
+                    rd: A0,
+                    rs1: A0,
+                    shamt: 63,
+                },
+                0x43f5_5513,
+            ),
+            (
+                SLLI {
+                    rd: A0,
+                    rs1: A0,
+                    shamt: 32,
+                },
+                0x0205_1513,
+            ),
+            (
+                SRLI {
+                    rd: T0,
+                    rs1: T1,
+                    shamt: 1,
+                },
+                0x0013_5293,
+            ),
+            (
+                ADDIW {
+                    rd: A0,
+                    rs1: A0,
+                    imm: 1,
+                },
+                0x0015_051b,
+            ),
+            (
+                SRAIW {
+                    rd: A0,
+                    rs1: A0,
+                    shamt: 31,
+                },
+                0x41f5_551b,
+            ),
+            (
+                SRAW {
+                    rd: A0,
+                    rs1: A1,
+                    rs2: A2,
+                },
+                0x40c5_d53b,
+            ),
+            (
+                SUBW {
+                    rd: S1,
+                    rs1: S0,
+                    rs2: T6,
+                },
+                0x41f4_04bb,
+            ),
+            (
+                FENCE {
+                    pred: 0b0011,
+                    succ: 0b0011,
+                },
+                0x0330_000f,
+            ),
+            (
+                FENCE {
+                    pred: 0b1111,
+                    succ: 0b1111,
+                },
+                0x0ff0_000f,
+            ),
+            (
+                BEQ {
+                    rs1: A0,
+                    rs2: A1,
+                    offset: -4,
+                },
+                0xfeb5_0ee3,
+            ),
+            (
+                BNE {
+                    rs1: T0,
+                    rs2: ZERO,
+                    offset: 4094,
+                },
+                0x7e02_9fe3,
+            ),
+            (
+                BGEU {
+                    rs1: S0,
+                    rs2: S1,
+                    offset: -4096,
+                },
+                0x8094_7063,
+            ),
+            (
+                BLT {
+                    rs1: A0,
+                    rs2: A1,
+                    offset: 2048,
+                },
+                0x00b5_40e3,
+            ),
+        ];
+        for (inst, word) in cases {
+            assert_eq!(encode(&inst), Ok(word), "{inst:?}");
+        }
+    }
+
+    #[test]
+    fn test_assemble() {
+        let code = [
+            RiscVInstruction::ADDI {
+                rd: A0,
+                rs1: ZERO,
+                imm: -1,
+            },
+            RiscVInstruction::JALR {
+                rd: ZERO,
+                rs1: RA,
+                offset: 0,
+            },
+        ];
+        assert_eq!(
+            assemble(&code),
+            Ok(vec![0x13, 0x05, 0xf0, 0xff, 0x67, 0x80, 0x00, 0x00])
+        );
+
+        // everything the library generates fits
+        for helper in [
+            RuntimeHelper::Div,
+            RuntimeHelper::SMod,
+            RuntimeHelper::MulMod,
+        ] {
+            let code = routine(helper.symbol()).unwrap().code;
+            let bytes = assemble(&code).unwrap();
+            assert_eq!(bytes.len(), code.len() * INSTRUCTION_SIZE);
+        }
+        for symbol in ["__evm_divmod", "__evm_mul512"] {
+            let code = routine(symbol).unwrap().code;
+            let bytes = assemble(&code).unwrap();
+            assert_eq!(bytes.len(), code.len() * INSTRUCTION_SIZE);
+        }
+
+        let code = [
+            RiscVInstruction::ECALL,
+            RiscVInstruction::BEQ {
+                rs1: A0,
+                rs2: A1,
+                offset: 4096,
+            },
+        ];
+        assert_eq!(
+            assemble(&code),
+            Err(EncodeError {
+                index: 1,
+                error: InstructionError::Immediate(ImmediateError::OutOfRange {
+                    value: 4096,
+                    min: -4096,
+                    max: 4095
+                })
+            })
+        );
+        let misaligned = RiscVInstruction::J { offset: 3 };
+        assert_eq!(
+            encode(&misaligned),
+            Err(InstructionError::Immediate(ImmediateError::Misaligned {
+                offset: 3
+            }))
+        );
+        let invalid = RiscVInstruction::ADDI {
+            rd: Register::from_raw(32),
+            rs1: ZERO,
+            imm: 0,
+        };
+        assert_eq!(
+            encode(&invalid),
+            Err(InstructionError::Register(InvalidRegister(32)))
+        );
+        assert_eq!(
+            assemble(&[invalid]).unwrap_err().to_string(),
+            "instruction 0: no register x32"
+        );
+    }
+}
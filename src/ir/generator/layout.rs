@@ -0,0 +1,401 @@
+use super::register::Register;
+use super::select::Selection;
+use crate::ir::gas::parser::RiscVInstruction;
+use crate::ir::source_map::INSTRUCTION_SIZE;
+
+// Label resolution and branch relaxation
+//
+// Branches and jumps are emitted against labels with a zero offset and recorded
+// as fixups, and `resolve` sets the offsets once the code is all there. A
+// conditional branch reaches ±4KiB and `JAL` ±1MiB, anything further is relaxed:
+// a branch is inverted to skip over a jump, and a jump becomes `AUIPC`+`JALR`,
+// through the link register for a `JAL` like the `call` pseudo-instruction and
+// through `t1` for a `J` like `tail`. Relaxing moves the code after it, which can
+// push other fixups out of range, so layout repeats until nothing grows. A
+// sequence never shrinks back, so that always ends.
+//
+// `t1` mustn't be live across a `J` that could be relaxed. The runtime library
+// keeps it live across jumps within its routines, which are far too short for
+// those to need it.
+
+/// Register a far `J` goes through
+pub const SCRATCH: Register = Register::T1;
+
+// Bytes either way a conditional branch and a `JAL` reach
+const BRANCH_RANGE: i64 = 1 << 12;
+const JUMP_RANGE: i64 = 1 << 20;
+
+/// A position in the code, bound to an index once it's emitted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Label(pub usize);
+
+/// A branch or jump to a label, with its offset still to be set by `resolve`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixup {
+    /// Index in `code` of its first instruction
+    pub index: usize,
+    pub label: Label,
+    /// `J`, `JAL` or conditional branch as emitted, its offset is ignored
+    pub inst: RiscVInstruction,
+    /// Instructions it takes, more than one once relaxed
+    pub len: usize,
+}
+
+impl Fixup {
+    pub fn new(index: usize, label: Label, inst: RiscVInstruction) -> Self {
+        Fixup {
+            index,
+            label,
+            inst,
+            len: 1,
+        }
+    }
+}
+
+/// Relax every fixup of `selection` that can't reach its label and set its offset
+///
+/// Instructions inserted by relaxing take the EVM pc of the branch or jump they
+/// come from, and every index in `selection` is moved past them.
+pub fn resolve(selection: &mut Selection) {
+    loop {
+        let targets = targets(selection);
+        let mut inserts = Vec::new();
+        for fixup in &mut selection.fixups {
+            let offset = distance(fixup.index, targets[fixup.label.0]);
+            let len = length(&fixup.inst, offset).max(fixup.len);
+            if len > fixup.len {
+                inserts.push((fixup.index + fixup.len, len - fixup.len));
+                fixup.len = len;
+            }
+        }
+        if inserts.is_empty() {
+            break;
+        }
+        insert(selection, inserts);
+    }
+
+    let targets = targets(selection);
+    for fixup in &selection.fixups {
+        let offset = distance(fixup.index, targets[fixup.label.0]);
+        for (i, inst) in sequence(&fixup.inst, fixup.len, offset)
+            .into_iter()
+            .enumerate()
+        {
+            selection.code[fixup.index + i] = inst;
+        }
+    }
+}
+
+// Index each label of `selection` is bound to
+fn targets(selection: &Selection) -> Vec<usize> {
+    selection
+        .positions
+        .iter()
+        .map(|index| index.expect("unbound label"))
+        .collect()
+}
+
+// Bytes from the instruction at `from` to the one at `to`
+fn distance(from: usize, to: usize) -> i64 {
+    (to as i64 - from as i64) * INSTRUCTION_SIZE as i64
+}
+
+fn reaches(offset: i64, range: i64) -> bool {
+    (-range..range).contains(&offset)
+}
+
+// Instructions `inst` needs to go `offset` bytes
+fn length(inst: &RiscVInstruction, offset: i64) -> usize {
+    let jump = |offset| if reaches(offset, JUMP_RANGE) { 1 } else { 2 };
+    match inst {
+        RiscVInstruction::J { .. } | RiscVInstruction::JAL { .. } => jump(offset),
+        _ if reaches(offset, BRANCH_RANGE) => 1,
+        // the jump comes after the inverted branch
+        _ => 1 + jump(offset - INSTRUCTION_SIZE as i64),
+    }
+}
+
+// `len` instructions doing `inst` to `offset` bytes on
+fn sequence(inst: &RiscVInstruction, len: usize, offset: i64) -> Vec<RiscVInstruction> {
+    match *inst {
+        RiscVInstruction::J { .. } if len == 1 => vec![RiscVInstruction::J {
+            offset: offset as i32,
+        }],
+        RiscVInstruction::J { .. } => far(Register::ZERO, SCRATCH, offset),
+        RiscVInstruction::JAL { rd, .. } if len == 1 => vec![RiscVInstruction::JAL {
+            rd,
+            offset: offset as i32,
+        }],
+        RiscVInstruction::JAL { rd, .. } => far(rd, rd, offset),
+        _ if len == 1 => vec![branch(inst, offset as i32, false)],
+        _ => {
+            let skip = (len * INSTRUCTION_SIZE) as i32;
+            let mut code = vec![branch(inst, skip, true)];
+            let jump = RiscVInstruction::J { offset: 0 };
+            code.extend(sequence(&jump, len - 1, offset - INSTRUCTION_SIZE as i64));
+            code
+        }
+    }
+}
+
+// `AUIPC`+`JALR` to `offset` bytes on, linking in `rd`
+fn far(rd: Register, scratch: Register, offset: i64) -> Vec<RiscVInstruction> {
+    // `JALR` sign-extends its low 12 bits, so round the upper part to make up for it
+    let upper = (offset + 0x800) >> 12;
+    let lower = offset - (upper << 12);
+    vec![
+        RiscVInstruction::AUIPC {
+            rd: scratch,
+            imm: upper as u32 & 0xf_ffff,
+        },
+        RiscVInstruction::JALR {
+            rd,
+            rs1: scratch,
+            offset: lower as i32,
+        },
+    ]
+}
+
+// Conditional branch `inst` to `offset` bytes on, on the opposite condition if
+// `invert`
+fn branch(inst: &RiscVInstruction, offset: i32, invert: bool) -> RiscVInstruction {
+    use RiscVInstruction::*;
+    match (inst.clone(), invert) {
+        (BEQ { rs1, rs2, .. }, false) | (BNE { rs1, rs2, .. }, true) => BEQ { rs1, rs2, offset },
+        (BNE { rs1, rs2, .. }, false) | (BEQ { rs1, rs2, .. }, true) => BNE { rs1, rs2, offset },
+        (BLT { rs1, rs2, .. }, false) | (BGE { rs1, rs2, .. }, true) => BLT { rs1, rs2, offset },
+        (BGE { rs1, rs2, .. }, false) | (BLT { rs1, rs2, .. }, true) => BGE { rs1, rs2, offset },
+        (BLTU { rs1, rs2, .. }, false) | (BGEU { rs1, rs2, .. }, true) => BLTU { rs1, rs2, offset },
+        (BGEU { rs1, rs2, .. }, false) | (BLTU { rs1, rs2, .. }, true) => BGEU { rs1, rs2, offset },
+        (inst, _) => panic!("{inst:?} isn't a branch or jump"),
+    }
+}
+
+// Make room for `count` more instructions at each `(index, count)` of `inserts`
+fn insert(selection: &mut Selection, mut inserts: Vec<(usize, usize)>) {
+    inserts.sort_unstable();
+
+    // instructions inserted up to and including each index
+    let mut total = 0;
+    let inserted: Vec<(usize, usize)> = inserts
+        .iter()
+        .map(|&(at, count)| {
+            total += count;
+            (at, total)
+        })
+        .collect();
+    let shift = |index: usize| match inserted.partition_point(|&(at, _)| at <= index) {
+        0 => index,
+        n => index + inserted[n - 1].1,
+    };
+
+    let old = std::mem::take(&mut selection.code);
+    let old_pcs = std::mem::take(&mut selection.pcs);
+    let mut code = Vec::with_capacity(old.len() + total);
+    let mut pcs = Vec::with_capacity(old_pcs.len() + total);
+    let mut inserts = inserts.into_iter().peekable();
+    for index in 0..=old.len() {
+        while let Some((_, count)) = inserts.next_if(|&(at, _)| at == index) {
+            // filled in once the offsets are known
+            let nop = RiscVInstruction::ADDI {
+                rd: Register::ZERO,
+                rs1: Register::ZERO,
+                imm: 0,
+            };
+            code.extend(std::iter::repeat_n(nop, count));
+            if index <= old_pcs.len() {
+                pcs.extend(std::iter::repeat_n(old_pcs[index - 1], count));
+            }
+        }
+        code.extend(old.get(index).cloned());
+        pcs.extend(old_pcs.get(index).copied());
+    }
+    selection.code = code;
+    selection.pcs = pcs;
+
+    for index in selection.labels.values_mut() {
+        *index = shift(*index);
+    }
+    for (index, _) in &mut selection.calls {
+        *index = shift(*index);
+    }
+    for index in selection.routines.values_mut() {
+        *index = shift(*index);
+    }
+    for index in selection.positions.iter_mut().flatten() {
+        *index = shift(*index);
+    }
+    for fixup in &mut selection.fixups {
+        fixup.index = shift(fixup.index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::generator::emulator::{run, Machine};
+    use crate::ir::generator::runtime::RuntimeHelper;
+    use crate::ir::interpreter::host::MockHost;
+    use crate::ir::interpreter::Halt;
+
+    const ZERO: Register = Register::ZERO;
+    const RA: Register = Register::RA;
+    const A0: Register = Register::A0;
+    const A1: Register = Register::A1;
+
+    fn addi(rd: Register, rs1: Register, imm: i32) -> RiscVInstruction {
+        RiscVInstruction::ADDI { rd, rs1, imm }
+    }
+
+    fn emit_to(selection: &mut Selection, inst: RiscVInstruction, label: Label) {
+        let index = selection.code.len();
+        selection
+            .fixups
+            .push(Fixup::new(index, label, inst.clone()));
+        selection.code.push(inst);
+    }
+
+    fn stop(selection: &mut Selection) {
+        let index = selection.code.len();
+        selection.calls.push((index, RuntimeHelper::Stop));
+        selection
+            .code
+            .push(RiscVInstruction::JAL { rd: RA, offset: 0 });
+    }
+
+    fn execute(selection: &Selection) -> Machine {
+        let mut machine = Machine::new();
+        assert_eq!(
+            run(selection, &mut machine, &mut MockHost::new()),
+            Halt::Stop
+        );
+        machine
+    }
+
+    #[test]
+    fn test_relax_branch() {
+        let mut selection = Selection::default();
+        let end = selection.label();
+        selection.code.push(addi(A1, A1, 1));
+        emit_to(
+            &mut selection,
+            RiscVInstruction::BNE {
+                rs1: A1,
+                rs2: ZERO,
+                offset: 0,
+            },
+            end,
+        );
+        // 4400 bytes over
+        for _ in 0..1100 {
+            selection.code.push(addi(A0, A0, 1));
+        }
+        selection.pcs = (0..selection.code.len()).collect();
+        selection.labels.insert(7, selection.code.len());
+        selection.bind(end);
+        stop(&mut selection);
+        resolve(&mut selection);
+
+        // branch over a jump, both at the branch's pc
+        assert_eq!(
+            selection.code[1..3],
+            [
+                RiscVInstruction::BEQ {
+                    rs1: A1,
+                    rs2: ZERO,
+                    offset: 8
+                },
+                RiscVInstruction::J { offset: 1101 * 4 }
+            ]
+        );
+        assert_eq!(selection.code.len(), 1104);
+        assert_eq!(selection.pcs[..4], [0, 1, 1, 2]);
+        assert_eq!(selection.pcs.len(), 1103);
+        assert_eq!(selection.labels[&7], 1103);
+        assert_eq!(selection.calls, [(1103, RuntimeHelper::Stop)]);
+        assert_eq!(selection.positions, [Some(1103)]);
+
+        let machine = execute(&selection);
+        assert_eq!(machine.get(A0), 0);
+        assert_eq!(machine.get(A1), 1);
+    }
+
+    #[test]
+    fn test_relax_jump() {
+        let mut selection = Selection::default();
+        let (near, far) = (selection.label(), selection.label());
+        let beq = RiscVInstruction::BEQ {
+            rs1: ZERO,
+            rs2: ZERO,
+            offset: 0,
+        };
+        emit_to(&mut selection, beq, near);
+        emit_to(&mut selection, RiscVInstruction::J { offset: 0 }, far);
+        // the branch reaches until the jump is relaxed
+        for _ in 0..1021 {
+            selection.code.push(addi(A0, A0, 1));
+        }
+        selection.bind(near);
+        selection.code.push(addi(A1, ZERO, 7));
+        emit_to(
+            &mut selection,
+            RiscVInstruction::JAL { rd: RA, offset: 0 },
+            far,
+        );
+        // 1MiB over
+        for _ in 0..1 << 18 {
+            selection.code.push(addi(A0, A0, 1));
+        }
+        selection.bind(far);
+        stop(&mut selection);
+        resolve(&mut selection);
+
+        assert_eq!(
+            selection.fixups.iter().map(|f| f.len).collect::<Vec<_>>(),
+            [2, 2, 2]
+        );
+        assert_eq!(
+            selection.code[..2],
+            [
+                RiscVInstruction::BNE {
+                    rs1: ZERO,
+                    rs2: ZERO,
+                    offset: 8
+                },
+                RiscVInstruction::J { offset: 1024 * 4 }
+            ]
+        );
+        let target = selection.positions[far.0].unwrap();
+        let jump = (target - 2) as i64 * 4;
+        assert_eq!(selection.code[2..4], far_jump(ZERO, SCRATCH, jump));
+        assert_eq!(selection.code[1025], addi(A1, ZERO, 7));
+        let call = (target - 1026) as i64 * 4;
+        assert_eq!(selection.code[1026..1028], far_jump(RA, RA, call));
+        assert_eq!(selection.calls, [(target, RuntimeHelper::Stop)]);
+
+        let machine = execute(&selection);
+        assert_eq!(machine.get(A0), 0);
+        assert_eq!(machine.get(A1), 7);
+        assert_eq!(machine.get(RA), 1028 * 4);
+    }
+
+    fn far_jump(rd: Register, scratch: Register, offset: i64) -> Vec<RiscVInstruction> {
+        let (upper, lower) = ((offset + 0x800) >> 12, offset & 0xfff);
+        let lower = if lower >= 0x800 {
+            lower - 0x1000
+        } else {
+            lower
+        };
+        vec![
+            RiscVInstruction::AUIPC {
+                rd: scratch,
+                imm: upper as u32,
+            },
+            RiscVInstruction::JALR {
+                rd,
+                rs1: scratch,
+                offset: lower as i32,
+            },
+        ]
+    }
+}
@@ -0,0 +1,983 @@
+use std::collections::HashMap;
+
+use super::layout::{resolve, Fixup, Label};
+use super::register::Register;
+use super::runtime::{helper_call, RuntimeHelper, SLOT_SIZE};
+use super::select::Selection;
+use crate::ir::gas::parser::RiscVInstruction;
+
+// Runtime library routines
+//
+// The arithmetic helpers too large to expand inline are written here in RV64IM,
+// so they can be bundled with the generated code instead of coming from the host.
+// They follow the helper ABI in `runtime`: `a0` points at the result slot and
+// `a1`.. at the operands, any of which may be the same slot. Targets without M
+// can't run them and take every helper from the host instead.
+//
+// Everything is built on two internal routines working on 64-bit limbs.
+// `__evm_divmod` is Knuth's algorithm D for numerators of up to eight limbs,
+// `__evm_mul512` is the schoolbook 512-bit product with MUL and MULHU. DIV and MOD
+// divide the four limb operand, SDIV and SMOD divide magnitudes and fix up the
+// sign, ADDMOD divides the five limb sum and MULMOD the eight limb product.
+
+const ZERO: Register = Register::ZERO;
+const RA: Register = Register::RA;
+const SP: Register = Register::SP;
+const T0: Register = Register::T0;
+const T1: Register = Register::T1;
+const T2: Register = Register::T2;
+const T3: Register = Register::T3;
+const T4: Register = Register::T4;
+const T5: Register = Register::T5;
+const T6: Register = Register::T6;
+const A0: Register = Register::A0;
+const A1: Register = Register::A1;
+const A2: Register = Register::A2;
+const A3: Register = Register::A3;
+const A4: Register = Register::A4;
+const A5: Register = Register::A5;
+const A6: Register = Register::A6;
+const A7: Register = Register::A7;
+
+const LIMB: i32 = 8;
+const LIMBS: i32 = (SLOT_SIZE as i32) / LIMB;
+
+// Stack frame of the helper routines, offsets from `sp`
+const SAVED_RA: i32 = 0;
+const SAVED_DEST: i32 = 8;
+const NEGATE: i32 = 16;
+const DIVISOR: i32 = 32;
+// one limb longer than the longest numerator, and one more to keep sp aligned
+const REMAINDER: i32 = DIVISOR + LIMBS * LIMB;
+const NUMERATOR: i32 = REMAINDER + (2 * LIMBS + 2) * LIMB;
+const FRAME: i32 = NUMERATOR + 2 * LIMBS * LIMB;
+
+const DIVMOD: &str = "__evm_divmod";
+const MUL512: &str = "__evm_mul512";
+
+/// A routine of the runtime library
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Routine {
+    /// Code with every branch and jump offset still zero
+    pub code: Vec<RiscVInstruction>,
+    /// Index in `code` each label of `fixups` is bound to
+    pub labels: Vec<usize>,
+    /// Branches and jumps within the routine
+    pub fixups: Vec<Fixup>,
+    /// Routine called by the `JAL` at each index
+    pub calls: Vec<(usize, &'static str)>,
+}
+
+/// Check if the library has a routine for `helper`, the rest come from the host
+pub fn implements(helper: RuntimeHelper) -> bool {
+    matches!(
+        helper,
+        RuntimeHelper::Div
+            | RuntimeHelper::SDiv
+            | RuntimeHelper::Mod
+            | RuntimeHelper::SMod
+            | RuntimeHelper::AddMod
+            | RuntimeHelper::MulMod
+    )
+}
+
+/// The routine linked under `symbol`
+pub fn routine(symbol: &str) -> Option<Routine> {
+    let mut asm = Asm::default();
+    match symbol {
+        DIVMOD => divmod(&mut asm),
+        MUL512 => mul512(&mut asm),
+        "__evm_div" => divide(&mut asm, Division::Quotient, false),
+        "__evm_sdiv" => divide(&mut asm, Division::Quotient, true),
+        "__evm_mod" => divide(&mut asm, Division::Remainder, false),
+        "__evm_smod" => divide(&mut asm, Division::Remainder, true),
+        "__evm_addmod" => modulo(&mut asm, false),
+        "__evm_mulmod" => modulo(&mut asm, true),
+        _ => return None,
+    }
+    Some(asm.finish())
+}
+
+/// Append the routines `selection` calls to its code and point the calls at them
+///
+/// Calls to helpers the library doesn't implement are left in `calls` for the host.
+/// The code is laid out again with `layout::resolve`.
+pub fn bundle(selection: &mut Selection) {
+    let mut symbols: Vec<&'static str> = Vec::new();
+    for (_, helper) in &selection.calls {
+        if implements(*helper) && !symbols.contains(&helper.symbol()) {
+            symbols.push(helper.symbol());
+        }
+    }
+
+    if symbols.is_empty() {
+        return;
+    }
+
+    // running off the end of the code stops, like in the EVM, rather than running
+    // into the routines
+    let stop = helper_call(RuntimeHelper::Stop, None, &[]);
+    selection.code.extend(stop);
+    selection
+        .calls
+        .push((selection.code.len() - 1, RuntimeHelper::Stop));
+
+    let mut calls = Vec::new();
+    let mut entries = HashMap::new();
+    let mut i = 0;
+    while let Some(&symbol) = symbols.get(i) {
+        let routine = routine(symbol).unwrap();
+        let entry = selection.code.len();
+        for (index, callee) in routine.calls {
+            if !symbols.contains(&callee) {
+                symbols.push(callee);
+            }
+            calls.push((entry + index, callee));
+        }
+        let base = selection.positions.len();
+        selection
+            .positions
+            .extend(routine.labels.iter().map(|&index| Some(entry + index)));
+        selection
+            .fixups
+            .extend(routine.fixups.into_iter().map(|fixup| Fixup {
+                index: entry + fixup.index,
+                label: Label(base + fixup.label.0),
+                ..fixup
+            }));
+        let label = selection.label();
+        selection.bind(label);
+        entries.insert(symbol, label);
+        selection.routines.insert(symbol, entry);
+        selection.code.extend(routine.code);
+        i += 1;
+    }
+
+    selection.calls.retain(|&(index, helper)| {
+        if implements(helper) {
+            calls.push((index, helper.symbol()));
+        }
+        !implements(helper)
+    });
+    for (index, symbol) in calls {
+        let call = RiscVInstruction::JAL { rd: RA, offset: 0 };
+        selection
+            .fixups
+            .push(Fixup::new(index, entries[symbol], call));
+    }
+    resolve(selection);
+}
+
+// Routine under construction, branches go to labels that are bound later
+#[derive(Debug, Default)]
+struct Asm {
+    code: Vec<RiscVInstruction>,
+    // index each label is bound to
+    labels: Vec<Option<usize>>,
+    fixups: Vec<Fixup>,
+    calls: Vec<(usize, &'static str)>,
+}
+
+impl Asm {
+    fn emit(&mut self, inst: RiscVInstruction) {
+        self.code.push(inst);
+    }
+
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    fn branch(&mut self, inst: RiscVInstruction, label: Label) {
+        self.fixups
+            .push(Fixup::new(self.code.len(), label, inst.clone()));
+        self.emit(inst);
+    }
+
+    fn beq(&mut self, rs1: Register, rs2: Register, label: Label) {
+        self.branch(
+            RiscVInstruction::BEQ {
+                rs1,
+                rs2,
+                offset: 0,
+            },
+            label,
+        );
+    }
+
+    fn bne(&mut self, rs1: Register, rs2: Register, label: Label) {
+        self.branch(
+            RiscVInstruction::BNE {
+                rs1,
+                rs2,
+                offset: 0,
+            },
+            label,
+        );
+    }
+
+    fn bltu(&mut self, rs1: Register, rs2: Register, label: Label) {
+        self.branch(
+            RiscVInstruction::BLTU {
+                rs1,
+                rs2,
+                offset: 0,
+            },
+            label,
+        );
+    }
+
+    fn bgeu(&mut self, rs1: Register, rs2: Register, label: Label) {
+        self.branch(
+            RiscVInstruction::BGEU {
+                rs1,
+                rs2,
+                offset: 0,
+            },
+            label,
+        );
+    }
+
+    fn j(&mut self, label: Label) {
+        self.branch(RiscVInstruction::J { offset: 0 }, label);
+    }
+
+    fn call(&mut self, symbol: &'static str) {
+        self.calls.push((self.code.len(), symbol));
+        self.emit(RiscVInstruction::JAL { rd: RA, offset: 0 });
+    }
+
+    fn ret(&mut self) {
+        self.emit(RiscVInstruction::JALR {
+            rd: ZERO,
+            rs1: RA,
+            offset: 0,
+        });
+    }
+
+    fn addi(&mut self, rd: Register, rs1: Register, imm: i32) {
+        self.emit(RiscVInstruction::ADDI { rd, rs1, imm });
+    }
+
+    fn add(&mut self, rd: Register, rs1: Register, rs2: Register) {
+        self.emit(RiscVInstruction::ADD { rd, rs1, rs2 });
+    }
+
+    fn sub(&mut self, rd: Register, rs1: Register, rs2: Register) {
+        self.emit(RiscVInstruction::SUB { rd, rs1, rs2 });
+    }
+
+    fn or(&mut self, rd: Register, rs1: Register, rs2: Register) {
+        self.emit(RiscVInstruction::OR { rd, rs1, rs2 });
+    }
+
+    fn sltu(&mut self, rd: Register, rs1: Register, rs2: Register) {
+        self.emit(RiscVInstruction::SLTU { rd, rs1, rs2 });
+    }
+
+    fn sll(&mut self, rd: Register, rs1: Register, rs2: Register) {
+        self.emit(RiscVInstruction::SLL { rd, rs1, rs2 });
+    }
+
+    fn srl(&mut self, rd: Register, rs1: Register, rs2: Register) {
+        self.emit(RiscVInstruction::SRL { rd, rs1, rs2 });
+    }
+
+    fn slli(&mut self, rd: Register, rs1: Register, shamt: u32) {
+        self.emit(RiscVInstruction::SLLI { rd, rs1, shamt });
+    }
+
+    fn srli(&mut self, rd: Register, rs1: Register, shamt: u32) {
+        self.emit(RiscVInstruction::SRLI { rd, rs1, shamt });
+    }
+
+    fn mul(&mut self, rd: Register, rs1: Register, rs2: Register) {
+        self.emit(RiscVInstruction::MUL { rd, rs1, rs2 });
+    }
+
+    fn mulhu(&mut self, rd: Register, rs1: Register, rs2: Register) {
+        self.emit(RiscVInstruction::MULHU { rd, rs1, rs2 });
+    }
+
+    fn divu(&mut self, rd: Register, rs1: Register, rs2: Register) {
+        self.emit(RiscVInstruction::DIVU { rd, rs1, rs2 });
+    }
+
+    fn remu(&mut self, rd: Register, rs1: Register, rs2: Register) {
+        self.emit(RiscVInstruction::REMU { rd, rs1, rs2 });
+    }
+
+    fn ld(&mut self, rd: Register, rs1: Register, imm: i32) {
+        self.emit(RiscVInstruction::LD { rd, rs1, imm });
+    }
+
+    fn sd(&mut self, rs2: Register, rs1: Register, imm: i32) {
+        self.emit(RiscVInstruction::SD { rs1, rs2, imm });
+    }
+
+    // Copy the slot-sized value at `src + from` to `dest + to`, through t0-t3
+    fn copy(&mut self, src: Register, from: i32, dest: Register, to: i32) {
+        let regs = [T0, T1, T2, T3];
+        for (limb, reg) in regs.into_iter().enumerate() {
+            self.ld(reg, src, from + limb as i32 * LIMB);
+        }
+        for (limb, reg) in regs.into_iter().enumerate() {
+            self.sd(reg, dest, to + limb as i32 * LIMB);
+        }
+    }
+
+    // Go to `label` if the slot at `ptr` is zero, through t0-t3
+    fn beqz_slot(&mut self, ptr: Register, label: Label) {
+        for (limb, reg) in [T0, T1, T2, T3].into_iter().enumerate() {
+            self.ld(reg, ptr, limb as i32 * LIMB);
+        }
+        self.or(T0, T0, T1);
+        self.or(T2, T2, T3);
+        self.or(T0, T0, T2);
+        self.beq(T0, ZERO, label);
+    }
+
+    // `rd = 1` if the slot at `ptr + offset` is negative
+    fn sign(&mut self, rd: Register, ptr: Register, offset: i32) {
+        self.ld(rd, ptr, offset + (LIMBS - 1) * LIMB);
+        self.srli(rd, rd, 63);
+    }
+
+    // `rd = rs1 >> (64 - s)` given `complement = 63 - s`, which is zero for `s` zero
+    // where a plain shift by 64 would be no shift at all
+    fn srl_complement(&mut self, rd: Register, rs1: Register, complement: Register) {
+        self.srli(rd, rs1, 1);
+        self.srl(rd, rd, complement);
+    }
+
+    // `rd = rs1 << (64 - s)` given `complement = 63 - s`
+    fn sll_complement(&mut self, rd: Register, rs1: Register, complement: Register) {
+        self.slli(rd, rs1, 1);
+        self.sll(rd, rd, complement);
+    }
+
+    // `rd` = the low 32 bits of `rs1`
+    fn low_half(&mut self, rd: Register, rs1: Register) {
+        self.slli(rd, rs1, 32);
+        self.srli(rd, rd, 32);
+    }
+
+    // Two's complement the slot at `sp + offset` if `flag` is set, through t0 and t1
+    fn negate_if(&mut self, flag: Register, offset: i32) {
+        let skip = self.label();
+        self.beq(flag, ZERO, skip);
+        self.addi(T0, ZERO, 1);
+        for limb in 0..LIMBS {
+            self.ld(T1, SP, offset + limb * LIMB);
+            self.emit(RiscVInstruction::XORI {
+                rd: T1,
+                rs1: T1,
+                imm: -1,
+            });
+            self.add(T1, T1, T0);
+            self.sltu(T0, T1, T0);
+            self.sd(T1, SP, offset + limb * LIMB);
+        }
+        self.bind(skip);
+    }
+
+    // Set up the helper stack frame, saving `ra` and the result pointer
+    fn enter(&mut self) {
+        self.addi(SP, SP, -FRAME);
+        self.sd(RA, SP, SAVED_RA);
+        self.sd(A0, SP, SAVED_DEST);
+    }
+
+    // Copy the value at `sp + offset` to the result slot and return, or with no
+    // offset store zero
+    fn leave(&mut self, offset: Option<i32>) {
+        self.ld(A0, SP, SAVED_DEST);
+        match offset {
+            Some(offset) => self.copy(SP, offset, A0, 0),
+            None => {
+                for limb in 0..LIMBS {
+                    self.sd(ZERO, A0, limb * LIMB);
+                }
+            }
+        }
+        self.ld(RA, SP, SAVED_RA);
+        self.addi(SP, SP, FRAME);
+        self.ret();
+    }
+
+    // Divide the `limbs` limbs at `sp + NUMERATOR` by the slot at `sp + DIVISOR`,
+    // leaving the remainder at `sp + REMAINDER`
+    fn call_divmod(&mut self, limbs: i32) {
+        self.addi(A0, SP, NUMERATOR);
+        self.addi(A1, ZERO, limbs);
+        self.addi(A2, SP, DIVISOR);
+        self.addi(A3, SP, REMAINDER);
+        self.call(DIVMOD);
+    }
+
+    fn finish(self) -> Routine {
+        Routine {
+            code: self.code,
+            labels: self
+                .labels
+                .into_iter()
+                .map(|index| index.expect("unbound label"))
+                .collect(),
+            fixups: self.fixups,
+            calls: self.calls,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Division {
+    Quotient,
+    Remainder,
+}
+
+// __evm_divmod: a0 numerator, a1 its limb count (at least four), a2 divisor, a3
+// a1 + 1 limbs of workspace
+//
+// Knuth's algorithm D (TAOCP 4.3.1). The divisor, and the numerator as it is
+// copied to the workspace, are shifted left until the divisor's top limb has its
+// high bit set. Each quotient limb estimated from the top two limbs of what is
+// left and the divisor's top limb is then at most two too large, and checking it
+// against the divisor's second limb leaves at most one add-back to correct it.
+// The numerator is replaced by the quotient and the remainder is left in the low
+// four limbs of the workspace. The divisor must not be zero, it is shifted in place.
+fn divmod(asm: &mut Asm) {
+    // a5 is the offset of the divisor's top non-zero limb
+    let (find, found) = (asm.label(), asm.label());
+    asm.addi(A5, ZERO, (LIMBS - 1) * LIMB);
+    asm.bind(find);
+    asm.add(T0, A2, A5);
+    asm.ld(A6, T0, 0);
+    asm.bne(A6, ZERO, found);
+    asm.addi(A5, A5, -LIMB);
+    asm.j(find);
+    asm.bind(found);
+
+    // t5 is the shift, the leading zeros of that limb by binary search, t6 63 - t5
+    asm.add(T0, A6, ZERO);
+    asm.add(T5, ZERO, ZERO);
+    for bits in [32, 16, 8, 4, 2, 1] {
+        let skip = asm.label();
+        asm.srli(T1, T0, 64 - bits);
+        asm.bne(T1, ZERO, skip);
+        asm.slli(T0, T0, bits);
+        asm.addi(T5, T5, bits as i32);
+        asm.bind(skip);
+    }
+    asm.addi(T6, ZERO, 63);
+    asm.sub(T6, T6, T5);
+
+    // shift the divisor from the top down, then keep its top limb in a6 and the
+    // one below in a7, zero for a single limb
+    for limb in (0..LIMBS).rev() {
+        asm.ld(T0, A2, limb * LIMB);
+        asm.sll(T0, T0, T5);
+        if limb > 0 {
+            asm.ld(T1, A2, (limb - 1) * LIMB);
+            asm.srl_complement(T1, T1, T6);
+            asm.or(T0, T0, T1);
+        }
+        asm.sd(T0, A2, limb * LIMB);
+    }
+    let single = asm.label();
+    asm.add(T0, A2, A5);
+    asm.ld(A6, T0, 0);
+    asm.add(A7, ZERO, ZERO);
+    asm.beq(A5, ZERO, single);
+    asm.ld(A7, T0, -LIMB);
+    asm.bind(single);
+
+    // shift the numerator into the workspace from the top down, t2 is its length
+    let (shift, shifted, clear) = (asm.label(), asm.label(), asm.label());
+    asm.slli(T2, A1, 3);
+    asm.add(T3, A0, T2);
+    asm.add(T4, A3, T2);
+    asm.ld(T0, T3, -LIMB);
+    asm.srl_complement(T0, T0, T6);
+    asm.sd(T0, T4, 0);
+    asm.bind(shift);
+    asm.addi(T3, T3, -LIMB);
+    asm.addi(T4, T4, -LIMB);
+    asm.ld(T0, T3, 0);
+    asm.sll(T0, T0, T5);
+    asm.beq(T3, A0, shifted);
+    asm.ld(T1, T3, -LIMB);
+    asm.srl_complement(T1, T1, T6);
+    asm.or(T0, T0, T1);
+    asm.sd(T0, T4, 0);
+    asm.j(shift);
+    asm.bind(shifted);
+    asm.sd(T0, T4, 0);
+
+    // quotient limbs are stored as they are found, the ones above stay zero
+    asm.add(T3, A0, T2);
+    asm.add(T1, A0, ZERO);
+    asm.bind(clear);
+    asm.sd(ZERO, T1, 0);
+    asm.addi(T1, T1, LIMB);
+    asm.bne(T1, T3, clear);
+
+    // a4 points at the lowest limb of the part of the workspace divided next, one
+    // limb longer than the divisor, from the top down. a1 keeps the shift.
+    asm.add(A4, A3, T2);
+    asm.sub(A4, A4, A5);
+    asm.addi(A4, A4, -LIMB);
+    asm.add(A1, T5, ZERO);
+
+    let (next, estimated, check, lower) = (asm.label(), asm.label(), asm.label(), asm.label());
+    let (multiply, subtract, subtracted) = (asm.label(), asm.label(), asm.label());
+    let (add_back, added, store, done) = (asm.label(), asm.label(), asm.label(), asm.label());
+    asm.bind(next);
+    // estimate t6 from the top two limbs in t1 and t2, t3 is the remainder of it.
+    // The top limb is at most the divisor's, if it is equal the estimate is
+    // 2^64 - 1 and the remainder may not fit a limb, which rules out the check.
+    let estimate = asm.label();
+    asm.add(T0, A4, A5);
+    asm.ld(T1, T0, LIMB);
+    asm.ld(T2, T0, 0);
+    asm.bltu(T1, A6, estimate);
+    asm.addi(T6, ZERO, -1);
+    asm.add(T3, T2, A6);
+    asm.bltu(T3, A6, multiply);
+    asm.j(estimated);
+    asm.bind(estimate);
+    divide_limb(asm);
+    asm.bind(estimated);
+
+    // lower the estimate while t6 * a7 > t3:(the third limb from the top), and the
+    // remainder still fits a limb
+    asm.beq(A7, ZERO, multiply);
+    asm.bind(check);
+    asm.mulhu(T1, T6, A7);
+    asm.mul(T2, T6, A7);
+    asm.bltu(T3, T1, lower);
+    asm.bne(T1, T3, multiply);
+    asm.add(T0, A4, A5);
+    asm.ld(T0, T0, -LIMB);
+    asm.bgeu(T0, T2, multiply);
+    asm.bind(lower);
+    asm.addi(T6, T6, -1);
+    asm.add(T3, T3, A6);
+    asm.bgeu(T3, A6, check);
+
+    // subtract t6 times the divisor, t4 is the limb offset and t5 carries the high
+    // half of the product plus the borrow
+    asm.bind(multiply);
+    asm.add(T5, ZERO, ZERO);
+    asm.add(T4, ZERO, ZERO);
+    asm.bind(subtract);
+    asm.add(T0, A2, T4);
+    asm.ld(T0, T0, 0);
+    asm.mulhu(T1, T6, T0);
+    asm.mul(T0, T6, T0);
+    asm.add(T0, T0, T5);
+    asm.sltu(T2, T0, T5);
+    asm.add(T1, T1, T2);
+    asm.add(T3, A4, T4);
+    asm.ld(T2, T3, 0);
+    asm.sltu(T5, T2, T0);
+    asm.sub(T2, T2, T0);
+    asm.sd(T2, T3, 0);
+    asm.add(T5, T1, T5);
+    asm.beq(T4, A5, subtracted);
+    asm.addi(T4, T4, LIMB);
+    asm.j(subtract);
+    asm.bind(subtracted);
+    asm.ld(T2, T3, LIMB);
+    asm.sltu(T0, T2, T5);
+    asm.sub(T2, T2, T5);
+    asm.sd(T2, T3, LIMB);
+    asm.beq(T0, ZERO, store);
+
+    // the estimate was one too large, add the divisor back, t5 carries
+    asm.addi(T6, T6, -1);
+    asm.add(T5, ZERO, ZERO);
+    asm.add(T4, ZERO, ZERO);
+    asm.bind(add_back);
+    asm.add(T0, A2, T4);
+    asm.ld(T0, T0, 0);
+    asm.add(T3, A4, T4);
+    asm.ld(T2, T3, 0);
+    asm.add(T2, T2, T0);
+    asm.sltu(T1, T2, T0);
+    asm.add(T2, T2, T5);
+    asm.sltu(T0, T2, T5);
+    asm.or(T5, T1, T0);
+    asm.sd(T2, T3, 0);
+    asm.beq(T4, A5, added);
+    asm.addi(T4, T4, LIMB);
+    asm.j(add_back);
+    asm.bind(added);
+    asm.ld(T2, T3, LIMB);
+    asm.add(T2, T2, T5);
+    asm.sd(T2, T3, LIMB);
+
+    asm.bind(store);
+    asm.sub(T0, A4, A3);
+    asm.add(T0, A0, T0);
+    asm.sd(T6, T0, 0);
+    asm.beq(A4, A3, done);
+    asm.addi(A4, A4, -LIMB);
+    asm.j(next);
+
+    // shift the remainder back down, everything above it is zero by now
+    asm.bind(done);
+    asm.addi(A7, ZERO, 63);
+    asm.sub(A7, A7, A1);
+    for limb in 0..LIMBS {
+        asm.ld(T0, A3, limb * LIMB);
+        asm.srl(T0, T0, A1);
+        asm.ld(T1, A3, (limb + 1) * LIMB);
+        asm.sll_complement(T1, T1, A7);
+        asm.or(T0, T0, T1);
+        asm.sd(T0, A3, limb * LIMB);
+    }
+    asm.ret();
+}
+
+// t6 = t1:t2 / a6 and t3 the remainder, for t1 < a6 and a6 with its high bit set
+//
+// RV64 only divides 64 by 64 bits, so this is long division in 32-bit halves as
+// in Hacker's Delight `divlu`. Clobbers t0-t2, t4 and t5.
+fn divide_limb(asm: &mut Asm) {
+    asm.srli(T5, T2, 32);
+    divide_half(asm, T6, T5);
+    asm.low_half(T2, T2);
+    divide_half(asm, T5, T2);
+    asm.add(T3, T1, ZERO);
+    asm.slli(T6, T6, 32);
+    asm.or(T6, T6, T5);
+}
+
+// One step of `divide_limb`: `q` = t1:`half` / a6, where `half` is 32 bits and
+// t1 < a6, and t1 the remainder. Clobbers t0, t3 and t4.
+fn divide_half(asm: &mut Asm, q: Register, half: Register) {
+    let (again, lower, done) = (asm.label(), asm.label(), asm.label());
+    // estimate from the divisor's high half, t3 is the remainder of it
+    asm.srli(T4, A6, 32);
+    asm.divu(q, T1, T4);
+    asm.remu(T3, T1, T4);
+    // too large if it takes more than 32 bits or q * low half > t3:half
+    asm.bind(again);
+    asm.srli(T0, q, 32);
+    asm.bne(T0, ZERO, lower);
+    asm.low_half(T0, A6);
+    asm.mul(T0, q, T0);
+    asm.slli(T4, T3, 32);
+    asm.or(T4, T4, half);
+    asm.bgeu(T4, T0, done);
+    asm.bind(lower);
+    asm.addi(q, q, -1);
+    asm.srli(T4, A6, 32);
+    asm.add(T3, T3, T4);
+    asm.srli(T0, T3, 32);
+    asm.beq(T0, ZERO, again);
+    asm.bind(done);
+    asm.slli(T1, T1, 32);
+    asm.or(T1, T1, half);
+    asm.mul(T0, q, A6);
+    asm.sub(T1, T1, T0);
+}
+
+// __evm_mul512: a0 eight limbs for the product of the slots at a1 and a2
+//
+// Schoolbook multiplication, adding a row of four limb products from MUL and
+// MULHU for each limb of a1. a0 must not overlap either operand.
+fn mul512(asm: &mut Asm) {
+    let row = asm.label();
+    for limb in 0..2 * LIMBS {
+        asm.sd(ZERO, A0, limb * LIMB);
+    }
+    asm.add(T6, ZERO, ZERO);
+
+    // t5 is the limb of a1 at offset t6, t4 where its row starts, t3 carries
+    asm.bind(row);
+    asm.add(T0, A1, T6);
+    asm.ld(T5, T0, 0);
+    asm.add(T4, A0, T6);
+    asm.add(T3, ZERO, ZERO);
+    for limb in 0..LIMBS {
+        asm.ld(T0, A2, limb * LIMB);
+        asm.mulhu(T1, T5, T0);
+        asm.mul(T0, T5, T0);
+        asm.add(T0, T0, T3);
+        asm.sltu(T3, T0, T3);
+        asm.add(T1, T1, T3);
+        asm.ld(T2, T4, limb * LIMB);
+        asm.add(T0, T0, T2);
+        asm.sltu(T2, T0, T2);
+        asm.add(T3, T1, T2);
+        asm.sd(T0, T4, limb * LIMB);
+    }
+    asm.sd(T3, T4, LIMBS * LIMB);
+    asm.addi(T6, T6, LIMB);
+    asm.addi(T0, ZERO, LIMBS * LIMB);
+    asm.bne(T6, T0, row);
+    asm.ret();
+}
+
+// __evm_div, __evm_sdiv, __evm_mod and __evm_smod, division by zero gives zero
+//
+// Signed division divides the magnitudes, the quotient is negative if the signs
+// differ and the remainder takes the sign of the numerator. The magnitude of the
+// most negative value is itself as an unsigned number, so it needs no special case.
+fn divide(asm: &mut Asm, result: Division, signed: bool) {
+    let zero = asm.label();
+    asm.enter();
+    asm.beqz_slot(A2, zero);
+    asm.copy(A2, 0, SP, DIVISOR);
+    asm.copy(A1, 0, SP, NUMERATOR);
+    if signed {
+        asm.sign(T4, SP, NUMERATOR);
+        asm.sign(T5, SP, DIVISOR);
+        let negate = match result {
+            Division::Quotient => {
+                asm.emit(RiscVInstruction::XOR {
+                    rd: T6,
+                    rs1: T4,
+                    rs2: T5,
+                });
+                T6
+            }
+            Division::Remainder => T4,
+        };
+        asm.sd(negate, SP, NEGATE);
+        asm.negate_if(T4, NUMERATOR);
+        asm.negate_if(T5, DIVISOR);
+    }
+    asm.call_divmod(LIMBS);
+
+    let offset = match result {
+        Division::Quotient => NUMERATOR,
+        Division::Remainder => REMAINDER,
+    };
+    if signed {
+        asm.ld(T4, SP, NEGATE);
+        asm.negate_if(T4, offset);
+    }
+    asm.leave(Some(offset));
+    asm.bind(zero);
+    asm.leave(None);
+}
+
+// __evm_addmod and __evm_mulmod, the sum or product is reduced at full width and
+// a zero modulus gives zero
+fn modulo(asm: &mut Asm, product: bool) {
+    let zero = asm.label();
+    asm.enter();
+    asm.beqz_slot(A3, zero);
+    asm.copy(A3, 0, SP, DIVISOR);
+    if product {
+        asm.addi(A0, SP, NUMERATOR);
+        asm.call(MUL512);
+        asm.call_divmod(2 * LIMBS);
+    } else {
+        // five limb sum, t4 carries
+        for limb in 0..LIMBS {
+            asm.ld(T0, A1, limb * LIMB);
+            asm.ld(T1, A2, limb * LIMB);
+            asm.add(T0, T0, T1);
+            if limb == 0 {
+                asm.sltu(T4, T0, T1);
+            } else {
+                asm.sltu(T1, T0, T1);
+                asm.add(T0, T0, T4);
+                asm.sltu(T2, T0, T4);
+                asm.or(T4, T1, T2);
+            }
+            asm.sd(T0, SP, NUMERATOR + limb * LIMB);
+        }
+        asm.sd(T4, SP, NUMERATOR + LIMBS * LIMB);
+        asm.call_divmod(LIMBS + 1);
+    }
+    asm.leave(Some(REMAINDER));
+    asm.bind(zero);
+    asm.leave(None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::gas::parser::IRInstruction;
+    use crate::ir::generator::emulator::{run, Machine, STACK_ADDR};
+    use crate::ir::generator::select::select;
+    use crate::ir::interpreter::host::MockHost;
+    use crate::ir::interpreter::Halt;
+    use crate::ir::memory::memory::Memory;
+    use crate::ir::test_util::s;
+    use crate::MyU256 as U256;
+    use alloy_primitives::U256 as U;
+
+    // Run `inst` with its helper bundled and slots 1.. preset to `operands` in
+    // stack order from the top, checking slot `dest` against `helper.eval`
+    fn check(inst: IRInstruction, helper: RuntimeHelper, operands: &[U256]) {
+        let mut selection = select(std::slice::from_ref(&inst), &[]).unwrap();
+        bundle(&mut selection);
+        assert_eq!(selection.calls.len(), 1);
+
+        let mut machine = Machine::new();
+        for (i, value) in operands.iter().rev().enumerate() {
+            machine.write_slot(STACK_ADDR + i as u64 * 32, *value);
+        }
+        assert_eq!(
+            run(&selection, &mut machine, &mut MockHost::new()),
+            Halt::Stop
+        );
+        let expected = helper.eval(operands, &Memory::new()).unwrap();
+        assert_eq!(
+            machine.read_slot(STACK_ADDR),
+            expected,
+            "{inst:?} {operands:?}"
+        );
+    }
+
+    fn values() -> Vec<U256> {
+        let one = U::from(1);
+        [
+            U::ZERO,
+            one,
+            U::from(3),
+            U::from(u64::MAX),
+            one << 64,
+            (one << 128) - one,
+            (one << 192) | U::from(u64::MAX),
+            one << 255,
+            U::MAX - U::from(6),
+            U::MAX,
+            U::from_limbs([0x0123_4567_89ab_cdef, u64::MAX, 0, 0xfedc_ba98_7654_3210]),
+        ]
+        .into_iter()
+        .map(U256)
+        .collect()
+    }
+
+    #[test]
+    fn test_division() {
+        for op in ["div", "sdiv", "mod", "smod"] {
+            let helper = RuntimeHelper::for_op(op).unwrap();
+            for a in values() {
+                for b in values() {
+                    let inst = IRInstruction::BinaryOp {
+                        op,
+                        dest: s(1),
+                        src1: s(2),
+                        src2: s(1),
+                    };
+                    check(inst, helper, &[a, b]);
+                }
+            }
+        }
+
+        // the estimate of the lowest quotient limb is one too large even after the
+        // check, so the divisor is added back
+        let a = U256(U::from_limbs([0, 0, 1 << 63, (1 << 63) - 1]));
+        let b = U256(U::from_limbs([1, 0, 1 << 63, 0]));
+        for op in ["div", "mod"] {
+            let inst = IRInstruction::BinaryOp {
+                op,
+                dest: s(1),
+                src1: s(2),
+                src2: s(1),
+            };
+            check(inst, RuntimeHelper::for_op(op).unwrap(), &[a, b]);
+        }
+    }
+
+    #[test]
+    fn test_modular_arithmetic() {
+        let values = values();
+        for (op, helper) in [
+            ("addmod", RuntimeHelper::AddMod),
+            ("mulmod", RuntimeHelper::MulMod),
+        ] {
+            for a in values.iter().step_by(2) {
+                for b in values.iter().skip(1).step_by(2) {
+                    for n in values.iter().skip(1).step_by(3) {
+                        let inst = IRInstruction::TernaryOp {
+                            op,
+                            dest: s(1),
+                            src1: s(3),
+                            src2: s(2),
+                            src3: s(1),
+                        };
+                        check(inst, helper, &[*a, *b, *n]);
+                    }
+                }
+            }
+            // zero modulus
+            let inst = IRInstruction::TernaryOp {
+                op,
+                dest: s(1),
+                src1: s(3),
+                src2: s(2),
+                src3: s(1),
+            };
+            check(inst, helper, &[U256(U::MAX), U256(U::MAX), s(0)]);
+        }
+    }
+
+    #[test]
+    fn test_bundle() {
+        let ir = [
+            IRInstruction::BinaryOp {
+                op: "div",
+                dest: s(1),
+                src1: s(2),
+                src2: s(1),
+            },
+            IRInstruction::BinaryOp {
+                op: "mod",
+                dest: s(1),
+                src1: s(2),
+                src2: s(1),
+            },
+            IRInstruction::StorageStore {
+                key: s(1),
+                value: s(2),
+            },
+        ];
+        let mut selection = select(&ir, &[]).unwrap();
+        let generated = selection.code.len();
+        bundle(&mut selection);
+
+        // the shared division routine is bundled once, after a stop
+        assert_eq!(
+            selection.routines.keys().copied().collect::<Vec<_>>(),
+            ["__evm_div", "__evm_divmod", "__evm_mod"]
+        );
+        assert_eq!(selection.routines["__evm_div"], generated + 1);
+        assert_eq!(
+            selection.calls.iter().map(|(_, h)| *h).collect::<Vec<_>>(),
+            [RuntimeHelper::SStore, RuntimeHelper::Stop]
+        );
+        let divmod = selection.routines["__evm_divmod"];
+        let calls: Vec<usize> = selection
+            .code
+            .iter()
+            .enumerate()
+            .filter_map(|(index, inst)| match inst {
+                RiscVInstruction::JAL { offset, .. } if *offset != 0 => {
+                    Some((index as i32 + offset / 4) as usize)
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                selection.routines["__evm_div"],
+                selection.routines["__evm_mod"],
+                divmod,
+                divmod
+            ]
+        );
+    }
+}
@@ -1,4 +1,12 @@
 pub mod register;
 pub mod allocator;
-pub mod Register;
-
+pub mod runtime;
+pub mod library;
+pub mod encode;
+pub mod decode;
+pub mod assembly;
+pub mod elf;
+pub mod layout;
+pub mod select;
+#[cfg(test)]
+pub(crate) mod emulator;
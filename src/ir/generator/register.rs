@@ -1,7 +1,44 @@
+use std::fmt;
+
 /// Represents a RV64I physical register
-#[derive(Debug, Clone, PartialEq, Eq, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq, Copy, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(into = "u8", try_from = "u8")
+)]
 pub struct Register(u8);
 
+/// A register number above x31
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidRegister(pub u8);
+
+impl fmt::Display for InvalidRegister {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no register x{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidRegister {}
+
+impl TryFrom<u8> for Register {
+    type Error = InvalidRegister;
+
+    fn try_from(index: u8) -> Result<Self, Self::Error> {
+        if index < 32 {
+            Ok(Register(index))
+        } else {
+            Err(InvalidRegister(index))
+        }
+    }
+}
+
+impl From<Register> for u8 {
+    fn from(register: Register) -> u8 {
+        register.0
+    }
+}
+
 // +---------------+-------------------------+-----------------------------------------------------------+
 // | Group         | Registers               | Description                                               |
 // +---------------+-------------------------+-----------------------------------------------------------+
@@ -27,8 +64,29 @@ pub enum RegKind {
 }
 
 impl Register {
-    /// Create a new register from raw index (0-31)
-    pub fn from_raw(index: u8) -> Self {
+    pub const ZERO: Register = Register(0);
+    pub const RA: Register = Register(1);
+    pub const SP: Register = Register(2);
+    pub const T0: Register = Register(5);
+    pub const T1: Register = Register(6);
+    pub const T2: Register = Register(7);
+    pub const S0: Register = Register(8);
+    pub const S1: Register = Register(9);
+    pub const A0: Register = Register(10);
+    pub const A1: Register = Register(11);
+    pub const A2: Register = Register(12);
+    pub const A3: Register = Register(13);
+    pub const A4: Register = Register(14);
+    pub const A5: Register = Register(15);
+    pub const A6: Register = Register(16);
+    pub const A7: Register = Register(17);
+    pub const T3: Register = Register(28);
+    pub const T4: Register = Register(29);
+    pub const T5: Register = Register(30);
+    pub const T6: Register = Register(31);
+
+    /// Create a new register from raw index (0-31), unchecked
+    pub(crate) fn from_raw(index: u8) -> Self {
         Register(index)
     }
 
@@ -37,6 +95,12 @@ impl Register {
         self.0
     }
 
+    /// Argument register `a<n>`
+    pub fn argument(n: usize) -> Self {
+        assert!(n < 8, "no argument register a{n}");
+        Register(10 + n as u8)
+    }
+
     /// Get register kind
     pub fn kind(&self) -> RegKind {
         match self.0 {
@@ -174,4 +238,20 @@ mod tests {
     fn test_invalid_register() {
         Register::from_raw(32).kind();
     }
+
+    #[test]
+    fn test_try_from() {
+        assert_eq!(Register::try_from(31), Ok(Register::T6));
+        assert_eq!(Register::try_from(32), Err(InvalidRegister(32)));
+        assert_eq!(InvalidRegister(255).to_string(), "no register x255");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        assert_eq!(serde_json::to_string(&Register::A0).unwrap(), "10");
+        assert_eq!(serde_json::from_str::<Register>("31").unwrap(), Register::T6);
+        assert!(serde_json::from_str::<Register>("32").is_err());
+        assert!(bincode::deserialize::<Register>(&[32]).is_err());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,434 @@
+use super::register::Register;
+use crate::ir::eval::{binary, ternary};
+use crate::ir::gas::parser::{HostOp, IRInstruction, RiscVInstruction};
+use crate::ir::memory::memory::Memory;
+use crate::ir::memory::MEMORY_LIMIT;
+use crate::MyU256 as U256;
+use alloy_primitives::U256 as U;
+
+// Runtime library interface
+//
+// Operations too large to expand inline become calls into a runtime library
+// linked with the generated code. Two registers are fixed for the whole program:
+// `s0` points at the EVM stack, where slot n lives at `s0 + (n - 1) * 32` as four
+// little-endian 64-bit limbs, and `s1` points at EVM memory.
+//
+// Helpers take pointers: `a0` to the result slot (if there is a result), then one
+// register per operand slot in order, then the memory base if the helper reads
+// memory. Host helpers get at memory through the host instead, a `CALL` already
+// needs all eight argument registers. Everything else follows the standard RISC-V
+// calling convention. Helpers that end execution or jump never return.
+
+/// Size of an EVM stack slot in bytes
+pub const SLOT_SIZE: usize = 32;
+/// s0, base of the EVM stack slots
+pub const STACK_BASE: Register = Register::S0;
+/// s1, base of EVM memory
+pub const MEMORY_BASE: Register = Register::S1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeHelper {
+    // keccak256(memory[offset..offset + size])
+    Keccak256,
+    // EVM arithmetic not expanded inline, operands in stack order
+    Mul,
+    Div,
+    SDiv,
+    Mod,
+    SMod,
+    AddMod,
+    MulMod,
+    Exp,
+    SignExtend,
+    Slt,
+    Sgt,
+    Byte,
+    Shl,
+    Shr,
+    Sar,
+    MLoad,
+    MStore,
+    MStore8,
+    SLoad,
+    SStore,
+    Host(HostOp),
+    // continue at the JUMPDEST whose pc is in the operand slot, halts if there is none
+    Jump,
+    Stop,
+}
+
+impl RuntimeHelper {
+    /// Helper computing the IR op `op`
+    pub fn for_op(op: &str) -> Option<Self> {
+        let helper = match op {
+            "mul" => RuntimeHelper::Mul,
+            "div" => RuntimeHelper::Div,
+            "sdiv" => RuntimeHelper::SDiv,
+            "mod" => RuntimeHelper::Mod,
+            "smod" => RuntimeHelper::SMod,
+            "addmod" => RuntimeHelper::AddMod,
+            "mulmod" => RuntimeHelper::MulMod,
+            "exp" => RuntimeHelper::Exp,
+            "signextend" => RuntimeHelper::SignExtend,
+            "SLT" => RuntimeHelper::Slt,
+            "SGT" => RuntimeHelper::Sgt,
+            "byte" => RuntimeHelper::Byte,
+            "shl" => RuntimeHelper::Shl,
+            "shr" => RuntimeHelper::Shr,
+            "sar" => RuntimeHelper::Sar,
+            _ => return None,
+        };
+        Some(helper)
+    }
+
+    /// Symbol the helper is linked under
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            RuntimeHelper::Keccak256 => "__evm_keccak256",
+            RuntimeHelper::Mul => "__evm_mul",
+            RuntimeHelper::Div => "__evm_div",
+            RuntimeHelper::SDiv => "__evm_sdiv",
+            RuntimeHelper::Mod => "__evm_mod",
+            RuntimeHelper::SMod => "__evm_smod",
+            RuntimeHelper::AddMod => "__evm_addmod",
+            RuntimeHelper::MulMod => "__evm_mulmod",
+            RuntimeHelper::Exp => "__evm_exp",
+            RuntimeHelper::SignExtend => "__evm_signextend",
+            RuntimeHelper::Slt => "__evm_slt",
+            RuntimeHelper::Sgt => "__evm_sgt",
+            RuntimeHelper::Byte => "__evm_byte",
+            RuntimeHelper::Shl => "__evm_shl",
+            RuntimeHelper::Shr => "__evm_shr",
+            RuntimeHelper::Sar => "__evm_sar",
+            RuntimeHelper::MLoad => "__evm_mload",
+            RuntimeHelper::MStore => "__evm_mstore",
+            RuntimeHelper::MStore8 => "__evm_mstore8",
+            RuntimeHelper::SLoad => "__evm_sload",
+            RuntimeHelper::SStore => "__evm_sstore",
+            RuntimeHelper::Host(kind) => host_symbol(*kind),
+            RuntimeHelper::Jump => "__evm_jump",
+            RuntimeHelper::Stop => "__evm_stop",
+        }
+    }
+
+    /// Number of operand slots
+    pub fn operands(&self) -> usize {
+        match self {
+            RuntimeHelper::Stop => 0,
+            RuntimeHelper::MLoad | RuntimeHelper::SLoad => 1,
+            RuntimeHelper::Jump => 1,
+            RuntimeHelper::AddMod | RuntimeHelper::MulMod => 3,
+            RuntimeHelper::Host(kind) => kind.inputs(),
+            _ => 2,
+        }
+    }
+
+    /// Check if `a0` points at a result slot
+    pub fn has_result(&self) -> bool {
+        match self {
+            RuntimeHelper::MStore
+            | RuntimeHelper::MStore8
+            | RuntimeHelper::SStore
+            | RuntimeHelper::Jump
+            | RuntimeHelper::Stop => false,
+            RuntimeHelper::Host(kind) => kind.has_output(),
+            _ => true,
+        }
+    }
+
+    pub fn reads_memory(&self) -> bool {
+        matches!(
+            self,
+            RuntimeHelper::Keccak256
+                | RuntimeHelper::MLoad
+                | RuntimeHelper::MStore
+                | RuntimeHelper::MStore8
+        )
+    }
+
+    /// Reference semantics of the helper, what the runtime routine must compute
+    ///
+    /// None for helpers that depend on the host or don't compute a value, and
+    /// for hashes of memory past `MEMORY_LIMIT`.
+    pub fn eval(&self, operands: &[U256], memory: &Memory) -> Option<U256> {
+        let word = |i: usize| operands[i].0;
+        let value = match self {
+            RuntimeHelper::Keccak256 => {
+                let size = usize::try_from(word(1)).ok()?;
+                let offset = match size {
+                    0 => 0,
+                    _ => usize::try_from(word(0)).ok().filter(|offset| {
+                        offset
+                            .checked_add(size)
+                            .is_some_and(|end| end <= MEMORY_LIMIT)
+                    })?,
+                };
+                U::from_be_bytes(memory.keccak256(offset, size))
+            }
+            RuntimeHelper::AddMod => ternary("addmod", word(0), word(1), word(2)),
+            RuntimeHelper::MulMod => ternary("mulmod", word(0), word(1), word(2)),
+            helper => {
+                let op = OPS.iter().find(|(_, h)| h == helper)?.0;
+                binary(op, word(0), word(1))
+            }
+        };
+        Some(U256(value))
+    }
+}
+
+// Binary IR ops and their helpers
+const OPS: [(&str, RuntimeHelper); 13] = [
+    ("mul", RuntimeHelper::Mul),
+    ("div", RuntimeHelper::Div),
+    ("sdiv", RuntimeHelper::SDiv),
+    ("mod", RuntimeHelper::Mod),
+    ("smod", RuntimeHelper::SMod),
+    ("exp", RuntimeHelper::Exp),
+    ("signextend", RuntimeHelper::SignExtend),
+    ("SLT", RuntimeHelper::Slt),
+    ("SGT", RuntimeHelper::Sgt),
+    ("byte", RuntimeHelper::Byte),
+    ("shl", RuntimeHelper::Shl),
+    ("shr", RuntimeHelper::Shr),
+    ("sar", RuntimeHelper::Sar),
+];
+
+fn host_symbol(kind: HostOp) -> &'static str {
+    match kind {
+        HostOp::Address => "__evm_address",
+        HostOp::Balance => "__evm_balance",
+        HostOp::Origin => "__evm_origin",
+        HostOp::Caller => "__evm_caller",
+        HostOp::CallValue => "__evm_callvalue",
+        HostOp::CallDataLoad => "__evm_calldataload",
+        HostOp::CallDataSize => "__evm_calldatasize",
+        HostOp::CallDataCopy => "__evm_calldatacopy",
+        HostOp::CodeSize => "__evm_codesize",
+        HostOp::CodeCopy => "__evm_codecopy",
+        HostOp::GasPrice => "__evm_gasprice",
+        HostOp::ExtCodeSize => "__evm_extcodesize",
+        HostOp::ExtCodeCopy => "__evm_extcodecopy",
+        HostOp::ReturnDataSize => "__evm_returndatasize",
+        HostOp::ReturnDataCopy => "__evm_returndatacopy",
+        HostOp::ExtCodeHash => "__evm_extcodehash",
+        HostOp::BlockHash => "__evm_blockhash",
+        HostOp::Coinbase => "__evm_coinbase",
+        HostOp::Timestamp => "__evm_timestamp",
+        HostOp::Number => "__evm_number",
+        HostOp::PrevRandao => "__evm_prevrandao",
+        HostOp::GasLimit => "__evm_gaslimit",
+        HostOp::ChainId => "__evm_chainid",
+        HostOp::SelfBalance => "__evm_selfbalance",
+        HostOp::BaseFee => "__evm_basefee",
+        HostOp::MSize => "__evm_msize",
+        HostOp::Gas => "__evm_gas",
+        HostOp::Log(0) => "__evm_log0",
+        HostOp::Log(1) => "__evm_log1",
+        HostOp::Log(2) => "__evm_log2",
+        HostOp::Log(3) => "__evm_log3",
+        HostOp::Log(_) => "__evm_log4",
+        HostOp::Create => "__evm_create",
+        HostOp::Create2 => "__evm_create2",
+        HostOp::Call => "__evm_call",
+        HostOp::CallCode => "__evm_callcode",
+        HostOp::DelegateCall => "__evm_delegatecall",
+        HostOp::StaticCall => "__evm_staticcall",
+        HostOp::Return => "__evm_return",
+        HostOp::Revert => "__evm_revert",
+        HostOp::Invalid => "__evm_invalid",
+        HostOp::SelfDestruct => "__evm_selfdestruct",
+    }
+}
+
+// Largest `ADDI` immediate
+const MAX_ADDI: i64 = 2047;
+
+/// Byte offset of `slot` from the stack base
+pub fn slot_offset(slot: U256) -> i64 {
+    (slot.as_usize() as i64 - 1) * SLOT_SIZE as i64
+}
+
+/// Helper and its result and operand slots for an IR instruction without an inline lowering
+pub fn helper_for(inst: &IRInstruction) -> Option<(RuntimeHelper, Option<U256>, Vec<U256>)> {
+    let call = match inst {
+        IRInstruction::Sha3 { dest, offset, size } => {
+            (RuntimeHelper::Keccak256, Some(*dest), vec![*offset, *size])
+        }
+        IRInstruction::BinaryOp {
+            op,
+            dest,
+            src1,
+            src2,
+        } => (RuntimeHelper::for_op(op)?, Some(*dest), vec![*src1, *src2]),
+        IRInstruction::UnaryOp { op, dest, src } => {
+            (RuntimeHelper::for_op(op)?, Some(*dest), vec![*src])
+        }
+        IRInstruction::TernaryOp {
+            op,
+            dest,
+            src1,
+            src2,
+            src3,
+        } => {
+            let helper = match *op {
+                "addmod" => RuntimeHelper::AddMod,
+                "mulmod" => RuntimeHelper::MulMod,
+                _ => return None,
+            };
+            (helper, Some(*dest), vec![*src1, *src2, *src3])
+        }
+        IRInstruction::MemoryLoad { offset, dest } => {
+            (RuntimeHelper::MLoad, Some(*dest), vec![*offset])
+        }
+        IRInstruction::MemoryStore { offset, value } => {
+            (RuntimeHelper::MStore, None, vec![*offset, *value])
+        }
+        IRInstruction::MemoryStore8 { offset, value } => {
+            (RuntimeHelper::MStore8, None, vec![*offset, *value])
+        }
+        IRInstruction::StorageLoad { key, dest } => (RuntimeHelper::SLoad, Some(*dest), vec![*key]),
+        IRInstruction::StorageStore { key, value } => {
+            (RuntimeHelper::SStore, None, vec![*key, *value])
+        }
+        IRInstruction::HostCall { kind, dest, args } => {
+            (RuntimeHelper::Host(*kind), *dest, args.clone())
+        }
+        IRInstruction::Stop => (RuntimeHelper::Stop, None, Vec::new()),
+        _ => return None,
+    };
+    Some(call)
+}
+
+/// Helper call an IR instruction lowers to, if it isn't expanded inline
+pub fn lower_to_helper(inst: &IRInstruction) -> Option<Vec<RiscVInstruction>> {
+    let (helper, dest, operands) = helper_for(inst)?;
+    Some(helper_call(helper, dest, &operands))
+}
+
+/// Call `helper` with the result going to slot `dest`
+///
+/// The call is the final `JAL`, its offset is left at 0 until the code is laid
+/// out and it can be resolved against `helper.symbol()`.
+pub fn helper_call(
+    helper: RuntimeHelper,
+    dest: Option<U256>,
+    operands: &[U256],
+) -> Vec<RiscVInstruction> {
+    assert_eq!(operands.len(), helper.operands());
+    assert_eq!(dest.is_some(), helper.has_result());
+
+    let mut code = Vec::new();
+    for (i, slot) in dest.iter().chain(operands).enumerate() {
+        let reg = Register::argument(i);
+        let offset = slot_offset(*slot);
+        if offset <= MAX_ADDI {
+            code.push(RiscVInstruction::ADDI {
+                rd: reg,
+                rs1: STACK_BASE,
+                imm: offset as i32,
+            });
+        } else {
+            code.extend(RiscVInstruction::li(reg, offset));
+            code.push(RiscVInstruction::ADD {
+                rd: reg,
+                rs1: STACK_BASE,
+                rs2: reg,
+            });
+        }
+    }
+    if helper.reads_memory() {
+        code.push(RiscVInstruction::ADDI {
+            rd: Register::argument(usize::from(dest.is_some()) + operands.len()),
+            rs1: MEMORY_BASE,
+            imm: 0,
+        });
+    }
+    code.push(RiscVInstruction::JAL {
+        rd: Register::RA,
+        offset: 0,
+    });
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::gas::parser::{generate_ir, parse_bytecode};
+    use crate::ir::memory::stack::Stack;
+
+    #[test]
+    fn test_sha3_lowering() {
+        // mstore(0, 0x2a) keccak256(0, 0x20)
+        let bytecode = [0x60, 0x2a, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0x20];
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        let mut stack = Stack::new();
+        let mut memory = Memory::new();
+        let ir = generate_ir(&instructions, &mut stack, &mut memory).unwrap();
+
+        let sha3 = ir.last().unwrap();
+        assert_eq!(
+            *sha3,
+            IRInstruction::Sha3 {
+                dest: U256(U::from(1)),
+                offset: U256(U::from(2)),
+                size: U256(U::from(1)),
+            }
+        );
+
+        // concrete evaluation and the helper agree
+        let expected = RuntimeHelper::Keccak256
+            .eval(&[U256(U::from(0)), U256(U::from(0x20))], &memory)
+            .unwrap();
+        assert_eq!(stack.pop(), Ok(expected));
+        let mut word = [0u8; 32];
+        word[31] = 0x2a;
+        assert_eq!(
+            expected.0,
+            U::from_be_bytes(alloy_primitives::keccak256(word).0)
+        );
+
+        let code = lower_to_helper(sha3).unwrap();
+        assert_eq!(
+            code,
+            vec![
+                RiscVInstruction::ADDI {
+                    rd: Register::A0,
+                    rs1: Register::S0,
+                    imm: 0
+                },
+                RiscVInstruction::ADDI {
+                    rd: Register::A1,
+                    rs1: Register::S0,
+                    imm: 32
+                },
+                RiscVInstruction::ADDI {
+                    rd: Register::A2,
+                    rs1: Register::S0,
+                    imm: 0
+                },
+                RiscVInstruction::ADDI {
+                    rd: Register::A3,
+                    rs1: Register::S1,
+                    imm: 0
+                },
+                RiscVInstruction::JAL {
+                    rd: Register::RA,
+                    offset: 0
+                },
+            ]
+        );
+        assert!(lower_to_helper(&IRInstruction::JumpDest { pc: 0 }).is_none());
+
+        // ranges past the memory limit aren't hashed
+        let eval = |offset: U, size: U| {
+            RuntimeHelper::Keccak256.eval(&[U256(offset), U256(size)], &memory)
+        };
+        assert_eq!(eval(U::MAX, U::from(0x20)), None);
+        assert_eq!(eval(U::from(0xff_ffff_ffffu64), U::from(1)), None);
+        assert_eq!(eval(U::ZERO, U::MAX), None);
+        assert_eq!(
+            eval(U::MAX, U::ZERO),
+            Some(U256(U::from_be_bytes(alloy_primitives::keccak256([]).0)))
+        );
+    }
+}
@@ -0,0 +1,1327 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use super::allocator::RegisterAllocator;
+use super::layout::{resolve, Fixup, Label};
+use super::register::Register;
+use super::runtime::{helper_call, helper_for, slot_offset, RuntimeHelper, SLOT_SIZE, STACK_BASE};
+use crate::ir::cfg::graph::Cfg;
+use crate::ir::gas::parser::{HostOp, IRInstruction, RiscVInstruction};
+use crate::ir::passes::bit_width::{infer_bit_widths, BitWidths};
+use crate::MyU256 as U256;
+
+// Instruction selection
+//
+// Slots stay in the stack frame at `s0` (see `runtime`), each IR instruction
+// loads the words it needs into registers from `RegisterAllocator`, computes and
+// stores the result back, so nothing is live in a register between instructions.
+// Slots are accessed as four 64-bit limbs, least significant first, and EVM
+// arithmetic on them is done limb by limb with `SLTU` propagating carries. Limbs
+// of an operand that `infer_bit_widths` shows are zero aren't loaded for copies,
+// comparisons and conditions.
+//
+// Addition, subtraction, comparisons, bitwise ops, copies, constants and control
+// flow are expanded inline, as is multiplication when the M extension is there.
+// Everything else calls a runtime helper. Internal calls keep their return pc in
+// a slot-sized frame on `sp`, which `Return` hands to the jump helper.
+
+/// Bytes per limb accessed with `LD`/`SD`
+const LIMB: usize = 8;
+const LIMBS: usize = SLOT_SIZE / LIMB;
+// Largest `LD`/`SD` immediate
+const MAX_IMM: i64 = 2047;
+
+// Allocator keys for registers that don't hold a slot's value: slot n's address is
+// keyed by `ADDRESS + n`, way past the EVM stack limit
+const ADDRESS: usize = 1 << 32;
+const SCRATCH: usize = usize::MAX;
+
+/// RISC-V code for a list of IR instructions, before linking with the runtime
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Selection {
+    pub code: Vec<RiscVInstruction>,
+    /// EVM pc each instruction came from, for `SourceMap::new`
+    pub pcs: Vec<usize>,
+    /// Index in `code` of the JUMPDEST at each pc
+    pub labels: BTreeMap<usize, usize>,
+    /// Helper called by the `JAL` at each index, still to be linked
+    pub calls: Vec<(usize, RuntimeHelper)>,
+    /// Index in `code` of each routine bundled from the runtime library, these
+    /// come after the selected code and have no `pcs`
+    pub routines: BTreeMap<&'static str, usize>,
+    /// Index in `code` each `Label` is bound to
+    pub positions: Vec<Option<usize>>,
+    /// Branches and jumps to labels, see `layout::resolve`
+    pub fixups: Vec<Fixup>,
+}
+
+impl Selection {
+    /// A new label, to be bound with `bind`
+    pub fn label(&mut self) -> Label {
+        self.positions.push(None);
+        Label(self.positions.len() - 1)
+    }
+
+    /// Bind `label` to the next instruction
+    pub fn bind(&mut self, label: Label) {
+        self.positions[label.0] = Some(self.code.len());
+    }
+}
+
+/// Extensions the generated code may use on top of RV64I
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Isa {
+    /// M, integer multiplication and division
+    pub m: bool,
+}
+
+impl Default for Isa {
+    fn default() -> Self {
+        Isa { m: true }
+    }
+}
+
+/// Why IR couldn't be selected
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectError {
+    /// `pcs` given to `select` that aren't one per IR instruction
+    PcCount { instructions: usize, pcs: usize },
+    /// An instruction with neither an inline lowering nor a runtime helper
+    Unsupported(Box<IRInstruction>),
+}
+
+impl fmt::Display for SelectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelectError::PcCount { instructions, pcs } => {
+                write!(f, "{pcs} pcs for {instructions} IR instructions")
+            }
+            SelectError::Unsupported(inst) => write!(f, "no lowering for {inst:?}"),
+        }
+    }
+}
+
+impl std::error::Error for SelectError {}
+
+/// Select RISC-V instructions for `ir` on RV64IM
+///
+/// `pcs` is the EVM pc of every IR instruction, as returned by
+/// `generate_ir_with_pcs` and kept up by `PassManager::run_with_pcs`, or empty if
+/// they aren't known.
+pub fn select(ir: &[IRInstruction], pcs: &[usize]) -> Result<Selection, SelectError> {
+    select_for(ir, pcs, Isa::default())
+}
+
+/// Select RISC-V instructions for `ir`, using only the extensions in `isa`
+pub fn select_for(ir: &[IRInstruction], pcs: &[usize], isa: Isa) -> Result<Selection, SelectError> {
+    if !pcs.is_empty() && pcs.len() != ir.len() {
+        return Err(SelectError::PcCount {
+            instructions: ir.len(),
+            pcs: pcs.len(),
+        });
+    }
+
+    let jumpdests: HashSet<usize> = ir
+        .iter()
+        .filter_map(|inst| match inst {
+            IRInstruction::JumpDest { pc } => Some(*pc),
+            _ => None,
+        })
+        .collect();
+
+    // statically known target of every jump, by index
+    let cfg = Cfg::build(ir);
+    let targets: HashMap<usize, usize> = cfg
+        .blocks
+        .iter()
+        .filter_map(|block| match ir[block.end - 1] {
+            IRInstruction::Jump { .. } | IRInstruction::ConditionalJump { .. } => {
+                Some((block.end - 1, block.target?))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let mut selector = Selector {
+        selection: Selection::default(),
+        alloc: RegisterAllocator::new(),
+        bases: HashMap::new(),
+        blocks: HashMap::new(),
+        jumpdests,
+        widths: infer_bit_widths(ir),
+        isa,
+        index: 0,
+        pc: 0,
+    };
+    for (index, inst) in ir.iter().enumerate() {
+        selector.index = index;
+        selector.pc = pcs.get(index).copied().unwrap_or_default();
+        selector.select(inst, targets.get(&index).copied())?;
+        selector.alloc.clear_allocations();
+        selector.bases.clear();
+    }
+
+    // every block label is a JUMPDEST, checked when it was jumped to
+    let mut selection = selector.selection;
+    resolve(&mut selection);
+    Ok(selection)
+}
+
+struct Selector {
+    selection: Selection,
+    alloc: RegisterAllocator,
+    // register and immediate addressing each slot used by the current instruction
+    bases: HashMap<U256, (Register, i32)>,
+    // label of the JUMPDEST at each pc
+    blocks: HashMap<usize, Label>,
+    jumpdests: HashSet<usize>,
+    widths: BitWidths,
+    isa: Isa,
+    // index in the IR and EVM pc of the instruction being selected
+    index: usize,
+    pc: usize,
+}
+
+impl Selector {
+    fn emit(&mut self, inst: RiscVInstruction) {
+        self.selection.code.push(inst);
+        self.selection.pcs.push(self.pc);
+    }
+
+    // Emit the branch or jump `inst` to `label`, its offset is set on layout
+    fn branch(&mut self, inst: RiscVInstruction, label: Label) {
+        let index = self.selection.code.len();
+        self.selection
+            .fixups
+            .push(Fixup::new(index, label, inst.clone()));
+        self.emit(inst);
+    }
+
+    fn block(&mut self, pc: usize) -> Label {
+        *self
+            .blocks
+            .entry(pc)
+            .or_insert_with(|| self.selection.label())
+    }
+
+    fn select(&mut self, inst: &IRInstruction, target: Option<usize>) -> Result<(), SelectError> {
+        match inst {
+            IRInstruction::LoadConst { dest, value } => {
+                for (limb, &bits) in value.0.as_limbs().iter().enumerate() {
+                    let reg = if bits == 0 {
+                        Register::ZERO
+                    } else {
+                        let reg = self.register(*dest);
+                        self.li(reg, bits as i64);
+                        reg
+                    };
+                    self.store(reg, *dest, limb);
+                }
+            }
+            IRInstruction::Copy { dest, src } if dest == src => {}
+            IRInstruction::Copy { dest, src } => {
+                let limbs = self.limbs(&[*src]);
+                for limb in 0..LIMBS {
+                    let reg = if limb < limbs {
+                        self.load(*src, limb)
+                    } else {
+                        Register::ZERO
+                    };
+                    self.store(reg, *dest, limb);
+                }
+            }
+            IRInstruction::Swap { a, b } => {
+                for limb in 0..LIMBS {
+                    let value_a = self.load(*a, limb);
+                    let value_b = self.load(*b, limb);
+                    self.store(value_a, *b, limb);
+                    self.store(value_b, *a, limb);
+                }
+            }
+            IRInstruction::BinaryOp {
+                op: op @ ("and" | "or" | "xor"),
+                dest,
+                src1,
+                src2,
+            } => {
+                for limb in 0..LIMBS {
+                    let a = self.load(*src1, limb);
+                    let b = self.load(*src2, limb);
+                    let rd = self.register(*dest);
+                    self.emit(match *op {
+                        "and" => RiscVInstruction::AND { rd, rs1: a, rs2: b },
+                        "or" => RiscVInstruction::OR { rd, rs1: a, rs2: b },
+                        _ => RiscVInstruction::XOR { rd, rs1: a, rs2: b },
+                    });
+                    self.store(rd, *dest, limb);
+                }
+            }
+            IRInstruction::BinaryOp {
+                op: "add",
+                dest,
+                src1,
+                src2,
+            } => self.add(*dest, *src1, *src2),
+            IRInstruction::BinaryOp {
+                op: "sub",
+                dest,
+                src1,
+                src2,
+            } => self.sub(*dest, *src1, *src2),
+            IRInstruction::BinaryOp {
+                op: "LT",
+                dest,
+                src1,
+                src2,
+            } => self.less_than(*dest, *src1, *src2),
+            IRInstruction::BinaryOp {
+                op: "GT",
+                dest,
+                src1,
+                src2,
+            } => self.less_than(*dest, *src2, *src1),
+            IRInstruction::BinaryOp {
+                op: "EQ",
+                dest,
+                src1,
+                src2,
+            } => {
+                let acc = self.scratch(0);
+                for limb in 0..self.limbs(&[*src1, *src2]) {
+                    let a = self.load(*src1, limb);
+                    let b = self.load(*src2, limb);
+                    if limb == 0 {
+                        self.emit(RiscVInstruction::XOR {
+                            rd: acc,
+                            rs1: a,
+                            rs2: b,
+                        });
+                    } else {
+                        self.emit(RiscVInstruction::XOR {
+                            rd: a,
+                            rs1: a,
+                            rs2: b,
+                        });
+                        self.emit(RiscVInstruction::OR {
+                            rd: acc,
+                            rs1: acc,
+                            rs2: a,
+                        });
+                    }
+                }
+                self.emit(RiscVInstruction::SLTIU {
+                    rd: acc,
+                    rs1: acc,
+                    imm: 1,
+                });
+                self.store_flag(acc, *dest);
+            }
+            IRInstruction::UnaryOp {
+                op: "iszero",
+                dest,
+                src,
+            } => {
+                let acc = self.scratch(0);
+                self.load_to(acc, *src, 0);
+                for limb in 1..self.limbs(&[*src]) {
+                    let a = self.load(*src, limb);
+                    self.emit(RiscVInstruction::OR {
+                        rd: acc,
+                        rs1: acc,
+                        rs2: a,
+                    });
+                }
+                self.emit(RiscVInstruction::SLTIU {
+                    rd: acc,
+                    rs1: acc,
+                    imm: 1,
+                });
+                self.store_flag(acc, *dest);
+            }
+            IRInstruction::BinaryOp {
+                op: "mul",
+                dest,
+                src1,
+                src2,
+            } if self.isa.m => self.mul(*dest, *src1, *src2),
+            IRInstruction::UnaryOp { op: "pop", .. } => {}
+            IRInstruction::UnaryOp {
+                op: "not",
+                dest,
+                src,
+            } => {
+                for limb in 0..LIMBS {
+                    let a = self.load(*src, limb);
+                    let rd = self.register(*dest);
+                    self.emit(RiscVInstruction::XORI {
+                        rd,
+                        rs1: a,
+                        imm: -1,
+                    });
+                    self.store(rd, *dest, limb);
+                }
+            }
+            IRInstruction::JumpDest { pc } => {
+                self.selection.labels.insert(*pc, self.selection.code.len());
+                let label = self.block(*pc);
+                self.selection.bind(label);
+            }
+            IRInstruction::Jump { target: slot } => self.jump(*slot, target),
+            IRInstruction::ConditionalJump {
+                condition,
+                target: slot,
+            } => {
+                let flag = self.scratch(0);
+                self.load_to(flag, *condition, 0);
+                for limb in 1..self.limbs(&[*condition]) {
+                    let next = self.load(*condition, limb);
+                    self.emit(RiscVInstruction::OR {
+                        rd: flag,
+                        rs1: flag,
+                        rs2: next,
+                    });
+                }
+                let skip = self.selection.label();
+                self.branch(
+                    RiscVInstruction::BEQ {
+                        rs1: flag,
+                        rs2: Register::ZERO,
+                        offset: 0,
+                    },
+                    skip,
+                );
+                self.jump(*slot, target);
+                self.selection.bind(skip);
+            }
+            IRInstruction::Call {
+                target, return_pc, ..
+            } => {
+                let reg = self.scratch(0);
+                self.emit(RiscVInstruction::ADDI {
+                    rd: Register::SP,
+                    rs1: Register::SP,
+                    imm: -(SLOT_SIZE as i32),
+                });
+                self.li(reg, *return_pc as i64);
+                self.emit(RiscVInstruction::SD {
+                    rs1: Register::SP,
+                    rs2: reg,
+                    imm: 0,
+                });
+                for limb in 1..LIMBS {
+                    self.emit(RiscVInstruction::SD {
+                        rs1: Register::SP,
+                        rs2: Register::ZERO,
+                        imm: (limb * LIMB) as i32,
+                    });
+                }
+                self.jump_to(*target);
+            }
+            IRInstruction::Return { .. } => {
+                // the frame is released first, the jump helper reads it before
+                // anything can write below `sp`
+                self.emit(RiscVInstruction::ADDI {
+                    rd: Register::A0,
+                    rs1: Register::SP,
+                    imm: 0,
+                });
+                self.emit(RiscVInstruction::ADDI {
+                    rd: Register::SP,
+                    rs1: Register::SP,
+                    imm: SLOT_SIZE as i32,
+                });
+                self.call(
+                    RuntimeHelper::Jump,
+                    vec![RiscVInstruction::JAL {
+                        rd: Register::RA,
+                        offset: 0,
+                    }],
+                );
+            }
+            inst => {
+                let (helper, dest, operands) = helper_for(inst)
+                    .ok_or_else(|| SelectError::Unsupported(Box::new(inst.clone())))?;
+                self.call(helper, helper_call(helper, dest, &operands));
+            }
+        }
+        Ok(())
+    }
+
+    // Jump to the target in `slot`, `target` if it's known statically
+    fn jump(&mut self, slot: U256, target: Option<usize>) {
+        match target {
+            Some(pc) => self.jump_to(pc),
+            None => self.call(
+                RuntimeHelper::Jump,
+                helper_call(RuntimeHelper::Jump, None, &[slot]),
+            ),
+        }
+    }
+
+    fn jump_to(&mut self, pc: usize) {
+        if self.jumpdests.contains(&pc) {
+            let label = self.block(pc);
+            self.branch(RiscVInstruction::J { offset: 0 }, label);
+        } else {
+            let invalid = RuntimeHelper::Host(HostOp::Invalid);
+            self.call(invalid, helper_call(invalid, None, &[]));
+        }
+    }
+
+    // Emit a helper call sequence, ending in its `JAL`
+    fn call(&mut self, helper: RuntimeHelper, code: Vec<RiscVInstruction>) {
+        for inst in code {
+            self.emit(inst);
+        }
+        let index = self.selection.code.len() - 1;
+        self.selection.calls.push((index, helper));
+    }
+
+    // `dest = a + b`, carries out of each limb are recovered with `sum < a`
+    fn add(&mut self, dest: U256, a: U256, b: U256) {
+        let (sum, carry) = (self.scratch(0), self.scratch(1));
+        for limb in 0..LIMBS {
+            let a = self.load(a, limb);
+            let b = self.load(b, limb);
+            self.emit(RiscVInstruction::ADD {
+                rd: sum,
+                rs1: a,
+                rs2: b,
+            });
+            if limb == 0 {
+                self.emit(RiscVInstruction::SLTU {
+                    rd: carry,
+                    rs1: sum,
+                    rs2: a,
+                });
+            } else if limb < LIMBS - 1 {
+                // at most one of the two additions carries
+                self.emit(RiscVInstruction::SLTU {
+                    rd: b,
+                    rs1: sum,
+                    rs2: a,
+                });
+                self.emit(RiscVInstruction::ADD {
+                    rd: sum,
+                    rs1: sum,
+                    rs2: carry,
+                });
+                self.emit(RiscVInstruction::SLTU {
+                    rd: carry,
+                    rs1: sum,
+                    rs2: carry,
+                });
+                self.emit(RiscVInstruction::OR {
+                    rd: carry,
+                    rs1: carry,
+                    rs2: b,
+                });
+            } else {
+                self.emit(RiscVInstruction::ADD {
+                    rd: sum,
+                    rs1: sum,
+                    rs2: carry,
+                });
+            }
+            self.store(sum, dest, limb);
+        }
+    }
+
+    // `dest = a - b`
+    fn sub(&mut self, dest: U256, a: U256, b: U256) {
+        let (diff, borrow) = (self.scratch(0), self.scratch(1));
+        for limb in 0..LIMBS {
+            let a = self.load(a, limb);
+            let b = self.load(b, limb);
+            if limb == LIMBS - 1 {
+                // no borrow out of the top limb
+                self.emit(RiscVInstruction::SUB {
+                    rd: diff,
+                    rs1: a,
+                    rs2: b,
+                });
+                self.emit(RiscVInstruction::SUB {
+                    rd: diff,
+                    rs1: diff,
+                    rs2: borrow,
+                });
+            } else {
+                self.borrow(limb, diff, borrow, a, b, true);
+            }
+            self.store(diff, dest, limb);
+        }
+    }
+
+    // `dest = a < b`, the borrow out of `a - b`
+    //
+    // Limbs that are zero in both only pass the borrow on, so they're left out.
+    fn less_than(&mut self, dest: U256, a: U256, b: U256) {
+        let (diff, borrow) = (self.scratch(0), self.scratch(1));
+        for limb in 0..self.limbs(&[a, b]) {
+            let a = self.load(a, limb);
+            let b = self.load(b, limb);
+            self.borrow(limb, diff, borrow, a, b, false);
+        }
+        self.store_flag(borrow, dest);
+    }
+
+    // Subtract one limb of `b` from `a` and the borrow in from the limb below,
+    // leaving the borrow out in `borrow` and, if `difference`, the result in `diff`
+    //
+    // `a` and `b` are overwritten, and may be the same register.
+    fn borrow(
+        &mut self,
+        limb: usize,
+        diff: Register,
+        borrow: Register,
+        a: Register,
+        b: Register,
+        difference: bool,
+    ) {
+        self.emit(RiscVInstruction::SUB {
+            rd: diff,
+            rs1: a,
+            rs2: b,
+        });
+        if limb == 0 {
+            self.emit(RiscVInstruction::SLTU {
+                rd: borrow,
+                rs1: a,
+                rs2: b,
+            });
+            return;
+        }
+        // at most one of the two subtractions borrows
+        self.emit(RiscVInstruction::SLTU {
+            rd: b,
+            rs1: a,
+            rs2: b,
+        });
+        self.emit(RiscVInstruction::SLTU {
+            rd: a,
+            rs1: diff,
+            rs2: borrow,
+        });
+        if difference {
+            self.emit(RiscVInstruction::SUB {
+                rd: diff,
+                rs1: diff,
+                rs2: borrow,
+            });
+        }
+        self.emit(RiscVInstruction::OR {
+            rd: borrow,
+            rs1: a,
+            rs2: b,
+        });
+    }
+
+    // `dest = a * b` truncated to 256 bits, column by column
+    //
+    // Every limb product is added into a three limb accumulator for its column,
+    // the top column only needs the low halves and column 2 drops the carry out
+    // of its high halves. Accumulator limbs start out unused so the first product
+    // into each is written there directly instead of being added.
+    fn mul(&mut self, dest: U256, a: U256, b: U256) {
+        let a_limbs: Vec<Register> = (0..LIMBS).map(|limb| self.scratch(limb)).collect();
+        for (limb, &reg) in a_limbs.iter().enumerate() {
+            self.load_to(reg, a, limb);
+        }
+        let b_limbs: Vec<Register> = if a == b {
+            a_limbs.clone()
+        } else {
+            (0..LIMBS).map(|limb| self.scratch(LIMBS + limb)).collect()
+        };
+        if a != b {
+            for (limb, &reg) in b_limbs.iter().enumerate() {
+                self.load_to(reg, b, limb);
+            }
+        }
+
+        let mut acc = [0, 1, 2].map(|n| self.scratch(2 * LIMBS + n));
+        let (lo, hi) = (self.scratch(2 * LIMBS + 3), self.scratch(2 * LIMBS + 4));
+        let mut used = [false; 3];
+        for column in 0..LIMBS {
+            let top = column == LIMBS - 1;
+            for i in 0..=column {
+                let (x, y) = (a_limbs[i], b_limbs[column - i]);
+
+                // low half into acc[0], carry out left in `lo`
+                let carry = used[0] && !top;
+                if used[0] {
+                    self.emit(RiscVInstruction::MUL {
+                        rd: lo,
+                        rs1: x,
+                        rs2: y,
+                    });
+                    self.emit(RiscVInstruction::ADD {
+                        rd: acc[0],
+                        rs1: acc[0],
+                        rs2: lo,
+                    });
+                    if carry {
+                        self.emit(RiscVInstruction::SLTU {
+                            rd: lo,
+                            rs1: acc[0],
+                            rs2: lo,
+                        });
+                    }
+                } else {
+                    self.emit(RiscVInstruction::MUL {
+                        rd: acc[0],
+                        rs1: x,
+                        rs2: y,
+                    });
+                    used[0] = true;
+                }
+                if top {
+                    continue;
+                }
+
+                // high half plus carry into acc[1], it's at most 2^64 - 2 so adding
+                // the carry can't overflow
+                let high = if used[1] { hi } else { acc[1] };
+                self.emit(RiscVInstruction::MULHU {
+                    rd: high,
+                    rs1: x,
+                    rs2: y,
+                });
+                if carry {
+                    self.emit(RiscVInstruction::ADD {
+                        rd: high,
+                        rs1: high,
+                        rs2: lo,
+                    });
+                }
+                if !used[1] {
+                    used[1] = true;
+                    continue;
+                }
+                self.emit(RiscVInstruction::ADD {
+                    rd: acc[1],
+                    rs1: acc[1],
+                    rs2: hi,
+                });
+                if column == LIMBS - 2 {
+                    continue;
+                }
+
+                // carry into acc[2]
+                let out = if used[2] { hi } else { acc[2] };
+                self.emit(RiscVInstruction::SLTU {
+                    rd: out,
+                    rs1: acc[1],
+                    rs2: hi,
+                });
+                if used[2] {
+                    self.emit(RiscVInstruction::ADD {
+                        rd: acc[2],
+                        rs1: acc[2],
+                        rs2: hi,
+                    });
+                }
+                used[2] = true;
+            }
+
+            self.store(acc[0], dest, column);
+            acc.rotate_left(1);
+            used.rotate_left(1);
+            used[2] = false;
+        }
+    }
+
+    // Store 0 or 1 from `reg` into `slot`
+    fn store_flag(&mut self, reg: Register, slot: U256) {
+        self.store(reg, slot, 0);
+        for limb in 1..LIMBS {
+            self.store(Register::ZERO, slot, limb);
+        }
+    }
+
+    // Limbs that can be nonzero in any of `slots` where the current instruction
+    // reads them, at least one
+    fn limbs(&self, slots: &[U256]) -> usize {
+        let width = slots
+            .iter()
+            .map(|slot| self.widths.operand(self.index, *slot) as usize)
+            .max()
+            .unwrap_or_default();
+        width.div_ceil(LIMB * 8).clamp(1, LIMBS)
+    }
+
+    fn li(&mut self, rd: Register, imm: i64) {
+        for inst in RiscVInstruction::li(rd, imm) {
+            self.emit(inst);
+        }
+    }
+
+    // The `n`th register for values that aren't slots
+    fn scratch(&mut self, n: usize) -> Register {
+        self.alloc.get_register(SCRATCH - n)
+    }
+
+    fn register(&mut self, slot: U256) -> Register {
+        self.alloc.get_register(slot.as_usize())
+    }
+
+    // Register and immediate for the first limb of `slot`
+    fn base(&mut self, slot: U256) -> (Register, i32) {
+        if let Some(&base) = self.bases.get(&slot) {
+            return base;
+        }
+        let offset = slot_offset(slot);
+        let base = if offset + (SLOT_SIZE - LIMB) as i64 <= MAX_IMM {
+            (STACK_BASE, offset as i32)
+        } else {
+            let reg = self.alloc.get_register(ADDRESS + slot.as_usize());
+            self.li(reg, offset);
+            self.emit(RiscVInstruction::ADD {
+                rd: reg,
+                rs1: STACK_BASE,
+                rs2: reg,
+            });
+            (reg, 0)
+        };
+        self.bases.insert(slot, base);
+        base
+    }
+
+    // Load `limb` of `slot` into the slot's register
+    fn load(&mut self, slot: U256, limb: usize) -> Register {
+        let rd = self.register(slot);
+        self.load_to(rd, slot, limb);
+        rd
+    }
+
+    fn load_to(&mut self, rd: Register, slot: U256, limb: usize) {
+        let (rs1, imm) = self.base(slot);
+        self.emit(RiscVInstruction::LD {
+            rd,
+            rs1,
+            imm: imm + (limb * LIMB) as i32,
+        });
+    }
+
+    fn store(&mut self, reg: Register, slot: U256, limb: usize) {
+        let (rs1, imm) = self.base(slot);
+        self.emit(RiscVInstruction::SD {
+            rs1,
+            rs2: reg,
+            imm: imm + (limb * LIMB) as i32,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::gas::parser::{generate_ir_with_pcs, parse_bytecode};
+    use crate::ir::generator::emulator::{execute, run, Machine, STACK_ADDR};
+    use crate::ir::generator::library::bundle;
+    use crate::ir::interpreter::host::MockHost;
+    use crate::ir::interpreter::{run_ir, Halt};
+    use crate::ir::memory::{memory::Memory, stack::Stack};
+    use crate::ir::passes::manager::{OptLevel, PassManager};
+    use crate::ir::source_map::SourceMap;
+    use crate::ir::test_util::s;
+    use alloy_primitives::U256 as U;
+
+    // Bytecode -> IR -> RISC-V, run on the emulator and checked against the IR
+    // interpreter, with and without optimisation
+    fn check(bytecode: &[u8], host: MockHost) -> (Halt, MockHost) {
+        let instructions = parse_bytecode(bytecode).unwrap();
+        let (ir, pcs) =
+            generate_ir_with_pcs(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+        let mut expected_host = host.clone();
+        let expected = run_ir(&ir, &mut expected_host);
+
+        let (mut optimised, mut optimised_pcs) = (ir.clone(), pcs.clone());
+        PassManager::new(OptLevel::O2)
+            .run_with_pcs(&mut optimised, &mut optimised_pcs)
+            .unwrap();
+        for (ir, pcs) in [(ir, pcs), (optimised, optimised_pcs)] {
+            let mut selection = select(&ir, &pcs).unwrap();
+            bundle(&mut selection);
+            let mut riscv_host = host.clone();
+            assert_eq!(execute(&selection, &mut riscv_host), expected);
+            assert_eq!(riscv_host, expected_host);
+        }
+        (expected, expected_host)
+    }
+
+    #[test]
+    fn test_load_const() {
+        let ir = [IRInstruction::LoadConst {
+            dest: s(2),
+            value: s(0xffff_ffff_0000_0001),
+        }];
+        let selection = select(&ir, &[]).unwrap();
+        let reg = selection.code[0].clone();
+        let RiscVInstruction::ADDI { rd, .. } = reg else {
+            panic!("{reg:?}");
+        };
+        // -1 shifted up with 1 added
+        let mut expected = vec![
+            RiscVInstruction::ADDI {
+                rd,
+                rs1: Register::ZERO,
+                imm: -1,
+            },
+            RiscVInstruction::SLLI {
+                rd,
+                rs1: rd,
+                shamt: 32,
+            },
+            RiscVInstruction::ADDI {
+                rd,
+                rs1: rd,
+                imm: 1,
+            },
+            RiscVInstruction::SD {
+                rs1: Register::S0,
+                rs2: rd,
+                imm: 32,
+            },
+        ];
+        for limb in 1..4 {
+            expected.push(RiscVInstruction::SD {
+                rs1: Register::S0,
+                rs2: Register::ZERO,
+                imm: 32 + 8 * limb,
+            });
+        }
+        assert_eq!(selection.code, expected);
+        assert!(selection.calls.is_empty());
+
+        // every shape of `li` expansion
+        let limbs: [u64; 11] = [
+            0x7ff,
+            0x800,
+            0x7fff_f800,
+            0x7fff_ffff,
+            0x8000_0000,
+            0xffff_ffff,
+            0x1234_5678_9abc_def0,
+            0x8000_0000_0000_0000,
+            0x7fff_ffff_ffff_ffff,
+            0xffff_ffff_8000_0000,
+            0xffff_ffff_ffff_f800,
+        ];
+        for value in limbs.map(|limb| [limb, !limb, limb << 1, limb.rotate_right(7)]) {
+            let value = U256(U::from_limbs(value));
+            let inst = IRInstruction::LoadConst { dest: s(1), value };
+            assert_eq!(run_inline(inst, &[], 1), value);
+        }
+    }
+
+    #[test]
+    fn test_far_slot() {
+        // slot 100 is 3168 bytes up, past the reach of an immediate
+        let ir = [IRInstruction::Copy {
+            dest: s(100),
+            src: s(1),
+        }];
+        let selection = select(&ir, &[]).unwrap();
+        // the address is computed once, after the first limb of slot 1 is loaded,
+        // as 4096 - 928
+        let RiscVInstruction::LUI { rd, imm: 1 } = selection.code[1] else {
+            panic!("{:?}", selection.code[1]);
+        };
+        assert_eq!(
+            selection.code[2..4],
+            [
+                RiscVInstruction::ADDIW {
+                    rd,
+                    rs1: rd,
+                    imm: -928
+                },
+                RiscVInstruction::ADD {
+                    rd,
+                    rs1: Register::S0,
+                    rs2: rd
+                }
+            ]
+        );
+        assert_eq!(selection.code.len(), 3 + 2 * 4);
+    }
+
+    #[test]
+    fn test_jumps_and_source_map() {
+        // 0: PUSH1 6 JUMP INVALID 0 0 6: JUMPDEST STOP
+        let bytecode = [0x60, 0x06, 0x56, 0xfe, 0x00, 0x00, 0x5b, 0x00];
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        let (ir, pcs) =
+            generate_ir_with_pcs(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+        let selection = select(&ir, &pcs).unwrap();
+
+        let label = selection.labels[&6];
+        let jump = selection
+            .code
+            .iter()
+            .position(|inst| matches!(inst, RiscVInstruction::J { .. }))
+            .unwrap();
+        assert_eq!(
+            selection.code[jump],
+            RiscVInstruction::J {
+                offset: (label - jump) as i32 * 4
+            }
+        );
+        // STOP after the JUMPDEST is a helper call
+        assert_eq!(
+            selection.calls.last(),
+            Some(&(selection.code.len() - 1, RuntimeHelper::Stop))
+        );
+
+        let map = SourceMap::new(&instructions, &selection.pcs);
+        assert_eq!(map.evm_range(jump * 4), Some(2..3));
+        assert_eq!(map.evm_range(label * 4), Some(7..8));
+        assert_eq!(map.riscv_ranges(0), vec![0..jump * 4]);
+    }
+
+    #[test]
+    fn test_bitwise_and_storage() {
+        let bytecode = [
+            0x60, 0x0f, 0x60, 0x3c, 0x16, 0x60, 0x00, 0x55, // 0x3c & 0x0f
+            0x60, 0x0f, 0x60, 0x3c, 0x17, 0x60, 0x01, 0x55, // 0x3c | 0x0f
+            0x60, 0x0f, 0x60, 0x3c, 0x18, 0x60, 0x02, 0x55, // 0x3c ^ 0x0f
+            0x60, 0x00, 0x19, 0x60, 0x03, 0x55, // ~0
+            0x60, 0x07, 0x60, 0x05, 0x90, 0x03, 0x60, 0x04, 0x55, // swap, 7 - 5 via helper
+            0x00,
+        ];
+        let (_, host) = check(&bytecode, MockHost::new());
+        assert_eq!(host.storage[&s(0)], s(0x0c));
+        assert_eq!(host.storage[&s(1)], s(0x3f));
+        assert_eq!(host.storage[&s(2)], s(0x33));
+        assert_eq!(host.storage[&s(3)], U256(U::MAX));
+        assert_eq!(host.storage[&s(4)], s(2));
+    }
+
+    #[test]
+    fn test_end_to_end() {
+        // sum 5 + 4 + ... + 1, then mapping[0x11] = sum, return the mapping slot
+        let bytecode = [
+            0x60, 0x05, 0x60, 0x00, 0x5b, 0x81, 0x01, 0x90, 0x60, 0x01, 0x90, 0x03, 0x90, 0x81,
+            0x60, 0x04, 0x57, // loop, leaves [0, sum]
+            0x60, 0x11, 0x60, 0x00, 0x52, 0x60, 0x01, 0x60, 0x20, 0x52, 0x60, 0x40, 0x60, 0x00,
+            0x20, // keccak256(0x11 . 1)
+            0x81, 0x81, 0x55, // sstore(hash, sum)
+            0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3, // return(hash)
+        ];
+        let (halt, host) = check(&bytecode, MockHost::new());
+        let Halt::Return(data) = halt else {
+            panic!("{halt:?}");
+        };
+        let key = U256(U::from_be_slice(&data));
+        assert_eq!(host.storage[&key], s(15));
+    }
+
+    #[test]
+    fn test_internal_call() {
+        // f(5) = 5 + 1 through an internal function, stored in slot 0
+        let bytecode = [
+            0x60, 0x0d, 0x60, 0x05, 0x60, 0x07, 0x56, 0x5b, 0x60, 0x01, 0x01, 0x90, 0x56, 0x5b,
+            0x60, 0x00, 0x55, 0x00,
+        ];
+        let (_, host) = check(&bytecode, MockHost::new());
+        assert_eq!(host.storage[&s(0)], s(6));
+
+        // after function recovery without inlining the return goes through the jump helper
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        let (mut ir, mut pcs) =
+            generate_ir_with_pcs(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+        PassManager::new(OptLevel::Os)
+            .run_with_pcs(&mut ir, &mut pcs)
+            .unwrap();
+        assert!(ir
+            .iter()
+            .any(|inst| matches!(inst, IRInstruction::Return { .. })));
+        let selection = select(&ir, &pcs).unwrap();
+        assert!(selection
+            .calls
+            .iter()
+            .any(|(_, helper)| *helper == RuntimeHelper::Jump));
+
+        // pcs that don't belong to the IR
+        assert_eq!(
+            select(&ir, &pcs[1..]),
+            Err(SelectError::PcCount {
+                instructions: ir.len(),
+                pcs: ir.len() - 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_unsupported() {
+        // a ternary op with no helper
+        let inst = IRInstruction::TernaryOp {
+            op: "addmul",
+            dest: s(4),
+            src1: s(3),
+            src2: s(2),
+            src3: s(1),
+        };
+        let ir = [IRInstruction::Stop, inst.clone()];
+        let err = select(&ir, &[]).unwrap_err();
+        assert_eq!(err, SelectError::Unsupported(Box::new(inst)));
+        assert!(err.to_string().starts_with("no lowering for TernaryOp"));
+    }
+
+    #[test]
+    fn test_calldata_branch() {
+        // if calldataload(0) { sstore(0, 2) } else { sstore(0, 1) }
+        let bytecode = [
+            0x60, 0x00, 0x35, 0x60, 0x0c, 0x57, 0x60, 0x01, 0x60, 0x00, 0x55, 0x00, 0x5b, 0x60,
+            0x02, 0x60, 0x00, 0x55, 0x00,
+        ];
+        let (_, host) = check(&bytecode, MockHost::new());
+        assert_eq!(host.storage[&s(0)], s(1));
+        let (_, host) = check(&bytecode, MockHost::with_calldata(&[1]));
+        assert_eq!(host.storage[&s(0)], s(2));
+    }
+
+    // Run `inst` alone with `slots` preset, returning slot `result`
+    fn run_inline(inst: IRInstruction, slots: &[(u64, U256)], result: u64) -> U256 {
+        let selection = select(&[inst], &[]).unwrap();
+        assert!(selection.calls.is_empty());
+        let mut machine = Machine::new();
+        for &(slot, value) in slots {
+            machine.write_slot(STACK_ADDR + (slot - 1) * 32, value);
+        }
+        assert_eq!(
+            run(&selection, &mut machine, &mut MockHost::new()),
+            Halt::Stop
+        );
+        machine.read_slot(STACK_ADDR + (result - 1) * 32)
+    }
+
+    fn edge_cases() -> Vec<U256> {
+        let one = U::from(1);
+        [
+            U::ZERO,
+            one,
+            U::from(2),
+            U::from(u64::MAX),
+            one << 64,
+            (one << 128) - one,
+            one << 128,
+            (one << 192) | U::from(u64::MAX),
+            one << 255,
+            U::MAX - one,
+            U::MAX,
+            U::from_limbs([0x0123_4567_89ab_cdef, u64::MAX, 0, 0xfedc_ba98_7654_3210]),
+        ]
+        .into_iter()
+        .map(U256)
+        .collect()
+    }
+
+    #[test]
+    fn test_limb_arithmetic() {
+        let flag = |b: bool| U256(U::from(b));
+        for a in edge_cases() {
+            for b in edge_cases() {
+                for (op, expected) in [
+                    ("add", a + b),
+                    ("sub", a - b),
+                    ("LT", flag(a.0 < b.0)),
+                    ("GT", flag(a.0 > b.0)),
+                    ("EQ", flag(a == b)),
+                ] {
+                    // the result overwrites the second operand, like generated IR
+                    let inst = IRInstruction::BinaryOp {
+                        op,
+                        dest: s(1),
+                        src1: s(2),
+                        src2: s(1),
+                    };
+                    let result = run_inline(inst, &[(1, b), (2, a)], 1);
+                    assert_eq!(result, expected, "{op} {a} {b}");
+
+                    // and to a slot of its own
+                    let inst = IRInstruction::BinaryOp {
+                        op,
+                        dest: s(3),
+                        src1: s(1),
+                        src2: s(2),
+                    };
+                    let result = run_inline(inst, &[(1, a), (2, b)], 3);
+                    assert_eq!(result, expected, "{op} {a} {b}");
+                }
+            }
+
+            // both operands in the same slot
+            for (op, expected) in [
+                ("add", a + a),
+                ("sub", U256::default()),
+                ("LT", flag(false)),
+                ("EQ", flag(true)),
+            ] {
+                let inst = IRInstruction::BinaryOp {
+                    op,
+                    dest: s(1),
+                    src1: s(1),
+                    src2: s(1),
+                };
+                assert_eq!(run_inline(inst, &[(1, a)], 1), expected, "{op} {a}");
+            }
+
+            let inst = IRInstruction::UnaryOp {
+                op: "iszero",
+                dest: s(1),
+                src: s(1),
+            };
+            assert_eq!(run_inline(inst, &[(1, a)], 1), flag(a.0.is_zero()));
+        }
+    }
+
+    #[test]
+    fn test_add_instruction_count() {
+        let inst = IRInstruction::BinaryOp {
+            op: "add",
+            dest: s(1),
+            src1: s(2),
+            src2: s(1),
+        };
+        // 8 loads and 4 stores, 4 limb additions and 3 carries in, 3 carries out
+        // of which the middle two also need the carry from adding the carry in
+        assert_eq!(
+            select(&[inst], &[]).unwrap().code.len(),
+            8 + 4 + 4 + 3 + 3 + 2 * 2
+        );
+    }
+
+    #[test]
+    fn test_narrow_operands() {
+        // the constants and every flag fit into the first limb
+        let ir = [
+            IRInstruction::LoadConst {
+                dest: s(1),
+                value: s(5),
+            },
+            IRInstruction::LoadConst {
+                dest: s(2),
+                value: s(7),
+            },
+            IRInstruction::BinaryOp {
+                op: "LT",
+                dest: s(3),
+                src1: s(1),
+                src2: s(2),
+            },
+            IRInstruction::Copy {
+                dest: s(4),
+                src: s(3),
+            },
+            IRInstruction::UnaryOp {
+                op: "iszero",
+                dest: s(4),
+                src: s(4),
+            },
+        ];
+        let loads = |selection: &Selection| {
+            selection
+                .code
+                .iter()
+                .filter(|inst| matches!(inst, RiscVInstruction::LD { .. }))
+                .count()
+        };
+        let selection = select(&ir, &[]).unwrap();
+        assert_eq!(loads(&selection), 2 + 1 + 1);
+
+        let mut machine = Machine::new();
+        machine.write_slot(STACK_ADDR + 3 * 32, U256(U::MAX));
+        assert_eq!(
+            run(&selection, &mut machine, &mut MockHost::new()),
+            Halt::Stop
+        );
+        assert_eq!(machine.read_slot(STACK_ADDR + 2 * 32), s(1));
+        assert_eq!(machine.read_slot(STACK_ADDR + 3 * 32), s(0));
+
+        // nothing is known about a slot that wasn't written before
+        let copy = [ir[3].clone()];
+        assert_eq!(loads(&select(&copy, &[]).unwrap()), 4);
+    }
+
+    #[test]
+    fn test_mul() {
+        let mul = |dest, src1, src2| IRInstruction::BinaryOp {
+            op: "mul",
+            dest: s(dest),
+            src1: s(src1),
+            src2: s(src2),
+        };
+        for a in edge_cases() {
+            for b in edge_cases() {
+                let expected = a * b;
+                assert_eq!(
+                    run_inline(mul(1, 2, 1), &[(1, b), (2, a)], 1),
+                    expected,
+                    "{a} * {b}"
+                );
+                assert_eq!(
+                    run_inline(mul(3, 1, 2), &[(1, a), (2, b)], 3),
+                    expected,
+                    "{a} * {b}"
+                );
+            }
+            assert_eq!(run_inline(mul(1, 1, 1), &[(1, a)], 1), a * a, "{a}^2");
+        }
+
+        // 8 loads and 4 stores, 10 low and 6 high halves, 9 low halves added with
+        // 5 carries out of them going into high halves, 4 high halves added with
+        // 1 carry out of those
+        let code = select(&[mul(1, 2, 1)], &[]).unwrap().code;
+        assert_eq!(code.len(), 8 + 4 + 10 + 6 + 9 + 2 * 5 + 4 + 1);
+        let registers: HashSet<Register> = code
+            .iter()
+            .filter_map(|inst| match inst {
+                RiscVInstruction::MUL { rd, .. } | RiscVInstruction::MULHU { rd, .. } => Some(*rd),
+                _ => None,
+            })
+            .collect();
+        assert!(registers.len() <= 5);
+    }
+
+    #[test]
+    fn test_mul_without_m() {
+        let inst = IRInstruction::BinaryOp {
+            op: "mul",
+            dest: s(1),
+            src1: s(2),
+            src2: s(1),
+        };
+        let selection = select_for(&[inst], &[], Isa { m: false }).unwrap();
+        assert_eq!(
+            selection.calls,
+            vec![(selection.code.len() - 1, RuntimeHelper::Mul)]
+        );
+        assert!(!selection
+            .code
+            .iter()
+            .any(|inst| matches!(inst, RiscVInstruction::MUL { .. })));
+
+        // the host's helper gives the same result
+        let bytecode = [
+            0x7f, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 3, // PUSH32 2^255 + 3
+            0x80, 0x02, 0x60, 0x00, 0x55, 0x00, // dup, mul, sstore(0, _)
+        ];
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        let (ir, pcs) =
+            generate_ir_with_pcs(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+        let mut hosts = [MockHost::new(), MockHost::new()];
+        for (m, host) in [true, false].into_iter().zip(&mut hosts) {
+            let mut selection = select_for(&ir, &pcs, Isa { m }).unwrap();
+            bundle(&mut selection);
+            let calls_mul = selection
+                .calls
+                .iter()
+                .any(|&(_, helper)| helper == RuntimeHelper::Mul);
+            assert_eq!(calls_mul, !m);
+            assert_eq!(execute(&selection, host), Halt::Stop);
+        }
+        assert_eq!(hosts[0], hosts[1]);
+        assert_eq!(hosts[0].storage[&s(0)], s(9));
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use crate::ir::gas::parser::HostOp;
+use crate::ir::memory::memory::Memory;
+use crate::MyU256 as U256;
+use alloy_primitives::U256 as U;
+
+/// Everything outside the contract's own stack and memory
+pub trait Host {
+    fn sload(&mut self, key: U256) -> U256;
+
+    fn sstore(&mut self, key: U256, value: U256);
+
+    /// Run a host operation that doesn't halt, `args[0]` is the top of the stack
+    ///
+    /// Returns the value pushed for kinds with an output.
+    fn call(&mut self, kind: HostOp, args: &[U256], memory: &mut Memory) -> Option<U256>;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Log {
+    pub topics: Vec<U256>,
+    pub data: Vec<u8>,
+}
+
+/// Deterministic in-memory host for tests
+///
+/// Environment queries answer from `env` (zero when missing), calls into other
+/// contracts and creations always fail and return no data.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MockHost {
+    pub storage: HashMap<U256, U256>,
+    pub calldata: Vec<u8>,
+    pub code: Vec<u8>,
+    pub env: HashMap<HostOp, U256>,
+    pub logs: Vec<Log>,
+}
+
+impl MockHost {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_calldata(calldata: &[u8]) -> Self {
+        MockHost {
+            calldata: calldata.to_vec(),
+            ..Self::default()
+        }
+    }
+}
+
+// `len` bytes of `data` from `offset`, zero past the end like calldata and code reads
+fn read_padded(data: &[u8], offset: usize, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| {
+            offset
+                .checked_add(i)
+                .and_then(|index| data.get(index))
+                .copied()
+                .unwrap_or(0)
+        })
+        .collect()
+}
+
+fn copy_to_memory(memory: &mut Memory, dest: usize, data: &[u8]) {
+    for (i, byte) in data.iter().enumerate() {
+        memory.write_byte(dest + i, *byte);
+    }
+}
+
+impl Host for MockHost {
+    fn sload(&mut self, key: U256) -> U256 {
+        self.storage.get(&key).copied().unwrap_or_default()
+    }
+
+    fn sstore(&mut self, key: U256, value: U256) {
+        if value.0.is_zero() {
+            self.storage.remove(&key);
+        } else {
+            self.storage.insert(key, value);
+        }
+    }
+
+    fn call(&mut self, kind: HostOp, args: &[U256], memory: &mut Memory) -> Option<U256> {
+        let arg = |i: usize| args[i].as_usize();
+        let result = match kind {
+            HostOp::CallDataLoad => {
+                let word: [u8; 32] = read_padded(&self.calldata, arg(0), 32).try_into().unwrap();
+                U256(U::from_be_bytes(word))
+            }
+            HostOp::CallDataSize => U256(U::from(self.calldata.len())),
+            HostOp::CodeSize => U256(U::from(self.code.len())),
+            HostOp::CallDataCopy | HostOp::CodeCopy => {
+                let source = match kind {
+                    HostOp::CallDataCopy => &self.calldata,
+                    _ => &self.code,
+                };
+                let data = read_padded(source, arg(1), arg(2));
+                copy_to_memory(memory, arg(0), &data);
+                return None;
+            }
+            HostOp::ExtCodeCopy | HostOp::ReturnDataCopy => return None,
+            HostOp::MSize => U256(U::from(memory.size())),
+            HostOp::Log(topics) => {
+                self.logs.push(Log {
+                    topics: args[2..2 + topics as usize].to_vec(),
+                    data: memory.read_bytes(arg(0), arg(1)),
+                });
+                return None;
+            }
+            HostOp::ReturnDataSize
+            | HostOp::Create
+            | HostOp::Create2
+            | HostOp::Call
+            | HostOp::CallCode
+            | HostOp::DelegateCall
+            | HostOp::StaticCall => U256::default(),
+            kind => self.env.get(&kind).copied().unwrap_or_default(),
+        };
+        kind.has_output().then_some(result)
+    }
+}
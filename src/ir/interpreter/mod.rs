@@ -0,0 +1,394 @@
+pub mod host;
+pub mod reference;
+
+use std::collections::HashMap;
+
+use alloy_primitives::U256 as U;
+
+use crate::ir::eval::{binary, ternary, unary};
+use crate::ir::gas::parser::{HostOp, IRInstruction};
+use crate::ir::memory::memory::Memory;
+use crate::ir::memory::MEMORY_LIMIT;
+use crate::MyU256 as U256;
+use host::Host;
+
+// IR interpreter
+//
+// Runs IR directly against a `Host`, without going through the RISC-V backend.
+// Running the same bytecode here and in the reference interpreter, before and
+// after optimisation, checks that translation and every pass keep behaviour.
+//
+// Gas isn't metered. Instead runs stop after `STEP_LIMIT` instructions, and
+// memory beyond `MEMORY_LIMIT` is treated as running out of gas.
+
+/// Instructions executed before a run is given up on
+pub const STEP_LIMIT: usize = 1_000_000;
+
+/// How a run ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Halt {
+    Stop,
+    Return(Vec<u8>),
+    Revert(Vec<u8>),
+    // beneficiary
+    SelfDestruct(U256),
+    // INVALID, a bad jump, too much memory or anything else that halts exceptionally
+    Invalid,
+    StepLimit,
+}
+
+/// Execute `ir` from its first instruction
+pub fn run_ir(ir: &[IRInstruction], host: &mut impl Host) -> Halt {
+    let jumpdests: HashMap<usize, usize> = ir
+        .iter()
+        .enumerate()
+        .filter_map(|(index, inst)| match inst {
+            IRInstruction::JumpDest { pc } => Some((*pc, index)),
+            _ => None,
+        })
+        .collect();
+    let jump = |target: U256| {
+        usize::try_from(target.0)
+            .ok()
+            .and_then(|pc| jumpdests.get(&pc).copied())
+            .ok_or(Halt::Invalid)
+    };
+
+    let mut slots: HashMap<U256, U256> = HashMap::new();
+    let get =
+        |slots: &HashMap<U256, U256>, slot: &U256| slots.get(slot).copied().unwrap_or_default();
+    let mut memory = Memory::new();
+    // return pcs of the internal calls in progress
+    let mut calls = Vec::new();
+
+    let mut index = 0;
+    for _ in 0..STEP_LIMIT {
+        let Some(inst) = ir.get(index) else {
+            return Halt::Stop;
+        };
+        let mut next = index + 1;
+
+        let result = match inst {
+            IRInstruction::BinaryOp {
+                op,
+                dest,
+                src1,
+                src2,
+            } => {
+                let value = binary(op, get(&slots, src1).0, get(&slots, src2).0);
+                slots.insert(*dest, U256(value));
+                Ok(())
+            }
+            IRInstruction::UnaryOp { op: "pop", .. } => Ok(()),
+            IRInstruction::UnaryOp { op, dest, src } => {
+                let value = unary(op, get(&slots, src).0);
+                slots.insert(*dest, U256(value));
+                Ok(())
+            }
+            IRInstruction::TernaryOp {
+                op,
+                dest,
+                src1,
+                src2,
+                src3,
+            } => {
+                let (a, b, n) = (
+                    get(&slots, src1).0,
+                    get(&slots, src2).0,
+                    get(&slots, src3).0,
+                );
+                slots.insert(*dest, U256(ternary(op, a, b, n)));
+                Ok(())
+            }
+            IRInstruction::LoadConst { dest, value } => {
+                slots.insert(*dest, *value);
+                Ok(())
+            }
+            IRInstruction::Copy { dest, src } => {
+                slots.insert(*dest, get(&slots, src));
+                Ok(())
+            }
+            IRInstruction::Swap { a, b } => {
+                let (value_a, value_b) = (get(&slots, a), get(&slots, b));
+                slots.insert(*a, value_b);
+                slots.insert(*b, value_a);
+                Ok(())
+            }
+            IRInstruction::MemoryLoad { offset, dest } => {
+                load_word(&mut memory, get(&slots, offset)).map(|value| {
+                    slots.insert(*dest, value);
+                })
+            }
+            IRInstruction::MemoryStore { offset, value } => {
+                store_word(&mut memory, get(&slots, offset), get(&slots, value))
+            }
+            IRInstruction::MemoryStore8 { offset, value } => {
+                let byte = get(&slots, value).0.byte(0);
+                memory_range(&mut memory, get(&slots, offset), 1)
+                    .map(|offset| memory.write_byte(offset, byte))
+            }
+            IRInstruction::Sha3 { dest, offset, size } => {
+                read_memory(&mut memory, get(&slots, offset), get(&slots, size)).map(|data| {
+                    let hash = alloy_primitives::keccak256(data);
+                    slots.insert(*dest, U256(U::from_be_bytes(hash.0)));
+                })
+            }
+            IRInstruction::StorageLoad { key, dest } => {
+                let value = host.sload(get(&slots, key));
+                slots.insert(*dest, value);
+                Ok(())
+            }
+            IRInstruction::StorageStore { key, value } => {
+                host.sstore(get(&slots, key), get(&slots, value));
+                Ok(())
+            }
+            IRInstruction::HostCall { kind, dest, args } => {
+                let args: Vec<U256> = args.iter().map(|slot| get(&slots, slot)).collect();
+                if kind.halts() {
+                    return halt(*kind, &args, &mut memory);
+                }
+                if let (Some(dest), Some(value)) = (dest, host.call(*kind, &args, &mut memory)) {
+                    slots.insert(*dest, value);
+                }
+                Ok(())
+            }
+            IRInstruction::JumpDest { .. } => Ok(()),
+            IRInstruction::Jump { target } => jump(get(&slots, target)).map(|to| next = to),
+            IRInstruction::ConditionalJump { condition, target } => {
+                if get(&slots, condition).0.is_zero() {
+                    Ok(())
+                } else {
+                    jump(get(&slots, target)).map(|to| next = to)
+                }
+            }
+            IRInstruction::Call {
+                target, return_pc, ..
+            } => {
+                calls.push(*return_pc);
+                jump(U256(U::from(*target))).map(|to| next = to)
+            }
+            IRInstruction::Return { .. } => match calls.pop() {
+                Some(return_pc) => jump(U256(U::from(return_pc))).map(|to| next = to),
+                None => Err(Halt::Invalid),
+            },
+            IRInstruction::Stop => Err(Halt::Stop),
+        };
+
+        if let Err(halt) = result {
+            return halt;
+        }
+        index = next;
+    }
+    Halt::StepLimit
+}
+
+// Expand memory to cover `offset..offset + len`, fails when that is out of reach
+pub(crate) fn memory_range(memory: &mut Memory, offset: U256, len: usize) -> Result<usize, Halt> {
+    match usize::try_from(offset.0) {
+        Ok(offset) if offset.saturating_add(len) <= MEMORY_LIMIT => {
+            if len > 0 {
+                memory.expand(offset + len);
+            }
+            Ok(offset)
+        }
+        _ => Err(Halt::Invalid),
+    }
+}
+
+pub(crate) fn read_memory(memory: &mut Memory, offset: U256, size: U256) -> Result<Vec<u8>, Halt> {
+    if size.0.is_zero() {
+        return Ok(Vec::new());
+    }
+    let size = usize::try_from(size.0).map_err(|_| Halt::Invalid)?;
+    let offset = memory_range(memory, offset, size)?;
+    Ok(memory.read_bytes(offset, size))
+}
+
+pub(crate) fn load_word(memory: &mut Memory, offset: U256) -> Result<U256, Halt> {
+    let offset = memory_range(memory, offset, 32)?;
+    let word: [u8; 32] = memory.read_bytes(offset, 32).try_into().unwrap();
+    Ok(U256(U::from_be_bytes(word)))
+}
+
+pub(crate) fn store_word(memory: &mut Memory, offset: U256, value: U256) -> Result<(), Halt> {
+    let offset = memory_range(memory, offset, 32)?;
+    for (i, byte) in value.0.to_be_bytes::<32>().into_iter().enumerate() {
+        memory.write_byte(offset + i, byte);
+    }
+    Ok(())
+}
+
+// Result of a halting host operation
+pub(crate) fn halt(kind: HostOp, args: &[U256], memory: &mut Memory) -> Halt {
+    match kind {
+        HostOp::Return | HostOp::Revert => match read_memory(memory, args[0], args[1]) {
+            Ok(data) if kind == HostOp::Return => Halt::Return(data),
+            Ok(data) => Halt::Revert(data),
+            Err(halt) => halt,
+        },
+        HostOp::SelfDestruct => Halt::SelfDestruct(args[0]),
+        _ => Halt::Invalid,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::host::{Log, MockHost};
+    use super::reference::run_evm;
+    use super::*;
+    use crate::ir::gas::parser::{generate_ir, parse_bytecode};
+    use crate::ir::memory::stack::Stack;
+    use crate::ir::passes::manager::{OptLevel, PassManager};
+    use crate::ir::test_util::s;
+
+    // Runs `bytecode` through the reference interpreter, the unoptimised IR, every
+    // O2 pass on its own and the full O2 pipeline, and checks they all agree
+    fn check(bytecode: &[u8], host: MockHost) -> (Halt, MockHost) {
+        let instructions = parse_bytecode(bytecode).unwrap();
+        let mut expected_host = host.clone();
+        let expected = run_evm(&instructions, &mut expected_host);
+
+        let ir = generate_ir(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+        let mut variants = vec![("unoptimised", ir.clone())];
+        for pass in OptLevel::O2.pipeline() {
+            let mut optimised = ir.clone();
+            pass.run(&mut optimised, &mut Vec::new());
+            variants.push((pass.name(), optimised));
+        }
+        let mut optimised = ir.clone();
+        PassManager::new(OptLevel::O2).run(&mut optimised).unwrap();
+        variants.push(("O2", optimised));
+
+        for (name, ir) in variants {
+            let mut ir_host = host.clone();
+            assert_eq!(run_ir(&ir, &mut ir_host), expected, "{name}");
+            assert_eq!(ir_host, expected_host, "{name}");
+        }
+        (expected, expected_host)
+    }
+
+    #[test]
+    fn test_arithmetic_and_storage() {
+        let bytecode = [
+            0x60, 0x07, 0x60, 0x05, 0x01, 0x60, 0x03, 0x02, 0x60, 0x00, 0x55, // (7 + 5) * 3
+            0x60, 0x02, 0x60, 0x09, 0x03, 0x60, 0x01, 0x55, // 9 - 2
+            0x60, 0x03, 0x60, 0x02, 0x0a, 0x60, 0x02, 0x55, // 2 ** 3
+            0x60, 0xff, 0x60, 0x04, 0x1b, 0x60, 0x03, 0x55, // 0xff << 4
+            0x00,
+        ];
+        let (halt, host) = check(&bytecode, MockHost::new());
+        assert_eq!(halt, Halt::Stop);
+        assert_eq!(host.storage[&s(0)], s(36));
+        assert_eq!(host.storage[&s(1)], s(7));
+        assert_eq!(host.storage[&s(2)], s(8));
+        assert_eq!(host.storage[&s(3)], s(0xff0));
+    }
+
+    #[test]
+    fn test_memory_and_return() {
+        // mstore(0, 0x2a) mstore8(0x3f, 0xff) return(0, 0x40)
+        let bytecode = [
+            0x60, 0x2a, 0x60, 0x00, 0x52, 0x60, 0xff, 0x60, 0x3f, 0x53, 0x60, 0x40, 0x60, 0x00,
+            0xf3,
+        ];
+        let (halt, _) = check(&bytecode, MockHost::new());
+        let mut data = vec![0u8; 0x40];
+        data[0x1f] = 0x2a;
+        data[0x3f] = 0xff;
+        assert_eq!(halt, Halt::Return(data));
+    }
+
+    #[test]
+    fn test_loop() {
+        // sum 5 + 4 + ... + 1 into slot 0
+        let bytecode = [
+            0x60, 0x05, 0x60, 0x00, 0x5b, 0x81, 0x01, 0x90, 0x60, 0x01, 0x90, 0x03, 0x90, 0x81,
+            0x60, 0x04, 0x57, 0x60, 0x00, 0x55, 0x00,
+        ];
+        let (halt, host) = check(&bytecode, MockHost::new());
+        assert_eq!(halt, Halt::Stop);
+        assert_eq!(host.storage[&s(0)], s(15));
+    }
+
+    #[test]
+    fn test_mapping_storage() {
+        // mapping[0x11] = 0x99 at keccak256(key . 1), read back into slot 2
+        let bytecode = [
+            0x60, 0x11, 0x60, 0x00, 0x52, 0x60, 0x01, 0x60, 0x20, 0x52, 0x60, 0x40, 0x60, 0x00,
+            0x20, 0x60, 0x99, 0x81, 0x55, 0x54, 0x60, 0x02, 0x55, 0x00,
+        ];
+        let (_, host) = check(&bytecode, MockHost::new());
+        assert_eq!(host.storage.len(), 2);
+        assert_eq!(host.storage[&s(2)], s(0x99));
+    }
+
+    #[test]
+    fn test_signed_ops() {
+        let bytecode = [
+            0x60, 0x07, 0x60, 0x00, 0x03, // -7
+            0x60, 0x03, 0x81, 0x07, 0x60, 0x00, 0x55, // -7 smod 3
+            0x60, 0x01, 0x81, 0x12, 0x60, 0x01, 0x55, // -7 slt 1
+            0x80, 0x60, 0x02, 0x1d, 0x60, 0x02, 0x55, // -7 sar 2
+            0x60, 0x00, 0x1a, 0x60, 0x03, 0x55, // byte 0 of -7
+            0x60, 0x80, 0x60, 0x00, 0x0b, 0x60, 0x04, 0x55, // signextend(0, 0x80)
+            0x60, 0x02, 0x60, 0x07, 0x60, 0x00, 0x03, 0x05, 0x60, 0x05, 0x55, // -7 sdiv 2
+            0x00,
+        ];
+        let (_, host) = check(&bytecode, MockHost::new());
+        assert_eq!(host.storage[&s(0)], U256(U::MAX));
+        assert_eq!(host.storage[&s(1)], s(1));
+        assert_eq!(host.storage[&s(2)], U256(U::MAX - U::from(1)));
+        assert_eq!(host.storage[&s(3)], s(0xff));
+        assert_eq!(host.storage[&s(4)], U256(U::MAX - U::from(0x7f)));
+        assert_eq!(host.storage[&s(5)], U256(U::MAX - U::from(2)));
+    }
+
+    #[test]
+    fn test_calldata_branch() {
+        // if calldataload(0) { sstore(0, 2) } else { sstore(0, 1) }
+        let bytecode = [
+            0x60, 0x00, 0x35, 0x60, 0x0c, 0x57, 0x60, 0x01, 0x60, 0x00, 0x55, 0x00, 0x5b, 0x60,
+            0x02, 0x60, 0x00, 0x55, 0x00,
+        ];
+        let (_, host) = check(&bytecode, MockHost::new());
+        assert_eq!(host.storage[&s(0)], s(1));
+        let (_, host) = check(&bytecode, MockHost::with_calldata(&[1]));
+        assert_eq!(host.storage[&s(0)], s(2));
+    }
+
+    #[test]
+    fn test_log_and_revert() {
+        // mstore(0, 0xab) log2(0, 0x20, 1, 2) revert(0, 0)
+        let bytecode = [
+            0x60, 0xab, 0x60, 0x00, 0x52, 0x60, 0x02, 0x60, 0x01, 0x60, 0x20, 0x60, 0x00, 0xa2,
+            0x60, 0x00, 0x60, 0x00, 0xfd,
+        ];
+        let (halt, host) = check(&bytecode, MockHost::new());
+        assert_eq!(halt, Halt::Revert(Vec::new()));
+        let mut data = vec![0u8; 0x20];
+        data[0x1f] = 0xab;
+        assert_eq!(
+            host.logs,
+            vec![Log {
+                topics: vec![s(1), s(2)],
+                data,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_invalid_jump() {
+        // jump(3) where 3 isn't a JUMPDEST
+        let bytecode = [0x60, 0x03, 0x56, 0x00];
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        assert_eq!(run_evm(&instructions, &mut MockHost::new()), Halt::Invalid);
+        let ir = [
+            IRInstruction::LoadConst {
+                dest: s(1),
+                value: s(3),
+            },
+            IRInstruction::Jump { target: s(1) },
+        ];
+        assert_eq!(run_ir(&ir, &mut MockHost::new()), Halt::Invalid);
+    }
+}
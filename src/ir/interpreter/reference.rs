@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+
+use alloy_primitives::U256 as U;
+
+use super::host::Host;
+use super::{halt, load_word, memory_range, read_memory, store_word, Halt, STEP_LIMIT};
+use crate::ir::gas::parser::{HostOp, Instruction, Opcode};
+use crate::ir::memory::memory::Memory;
+use crate::MyU256 as U256;
+
+// Reference EVM interpreter
+//
+// A plain stack machine over the bytecode, written independently of
+// `generate_ir` and the IR interpreter so their results can be checked
+// against it. Signed arithmetic is done on the two's complement bits directly.
+
+const STACK_LIMIT: usize = 1024;
+const SIGN_BIT: usize = 255;
+
+/// Execute `instructions` from pc 0
+pub fn run_evm(instructions: &[Instruction], host: &mut impl Host) -> Halt {
+    let mut pcs = Vec::with_capacity(instructions.len());
+    let mut jumpdests = HashMap::new();
+    let mut pc = 0;
+    for (index, inst) in instructions.iter().enumerate() {
+        if inst.opcode == Opcode::JUMPDEST {
+            jumpdests.insert(pc, index);
+        }
+        pcs.push(pc);
+        pc += 1 + inst.operand.as_ref().map_or(0, |operand| operand.len());
+    }
+
+    let mut stack: Vec<U> = Vec::new();
+    let mut memory = Memory::new();
+    let mut index = 0;
+
+    for _ in 0..STEP_LIMIT {
+        let Some(inst) = instructions.get(index) else {
+            return Halt::Stop;
+        };
+        index += 1;
+        match step(inst, pcs[index - 1], &mut stack, &mut memory, host) {
+            Ok(None) => {}
+            Ok(Some(target)) => match usize::try_from(target)
+                .ok()
+                .and_then(|pc| jumpdests.get(&pc))
+            {
+                Some(&to) => index = to,
+                None => return Halt::Invalid,
+            },
+            Err(halt) => return halt,
+        }
+        if stack.len() > STACK_LIMIT {
+            return Halt::Invalid;
+        }
+    }
+    Halt::StepLimit
+}
+
+// Run one instruction, returns the jump target if it jumps
+fn step(
+    inst: &Instruction,
+    pc: usize,
+    stack: &mut Vec<U>,
+    memory: &mut Memory,
+    host: &mut impl Host,
+) -> Result<Option<U>, Halt> {
+    let mut pop = || stack.pop().ok_or(Halt::Invalid);
+
+    let result = match inst.opcode {
+        Opcode::STOP => return Err(Halt::Stop),
+        Opcode::ADD => pop()?.wrapping_add(pop()?),
+        Opcode::MUL => pop()?.wrapping_mul(pop()?),
+        Opcode::SUB => pop()?.wrapping_sub(pop()?),
+        Opcode::DIV => {
+            let (a, b) = (pop()?, pop()?);
+            if b.is_zero() {
+                U::ZERO
+            } else {
+                a / b
+            }
+        }
+        Opcode::SDIV => {
+            let (a, b) = (pop()?, pop()?);
+            if b.is_zero() {
+                U::ZERO
+            } else {
+                let quotient = abs(a) / abs(b);
+                if a.bit(SIGN_BIT) != b.bit(SIGN_BIT) {
+                    quotient.wrapping_neg()
+                } else {
+                    quotient
+                }
+            }
+        }
+        Opcode::MOD => {
+            let (a, b) = (pop()?, pop()?);
+            if b.is_zero() {
+                U::ZERO
+            } else {
+                a % b
+            }
+        }
+        Opcode::SMOD => {
+            let (a, b) = (pop()?, pop()?);
+            if b.is_zero() {
+                U::ZERO
+            } else {
+                let remainder = abs(a) % abs(b);
+                if a.bit(SIGN_BIT) {
+                    remainder.wrapping_neg()
+                } else {
+                    remainder
+                }
+            }
+        }
+        Opcode::ADDMOD => {
+            let (a, b, n) = (pop()?, pop()?, pop()?);
+            if n.is_zero() {
+                U::ZERO
+            } else {
+                // 257-bit sum
+                let (sum, carry) = a.overflowing_add(b);
+                let wide = alloy_primitives::Uint::<320, 5>::from(sum)
+                    + (alloy_primitives::Uint::<320, 5>::from(carry as u8) << 256);
+                U::from(wide % alloy_primitives::Uint::<320, 5>::from(n))
+            }
+        }
+        Opcode::MULMOD => {
+            let (a, b, n) = (pop()?, pop()?, pop()?);
+            if n.is_zero() {
+                U::ZERO
+            } else {
+                let wide = alloy_primitives::Uint::<512, 8>::from(a)
+                    * alloy_primitives::Uint::<512, 8>::from(b);
+                U::from(wide % alloy_primitives::Uint::<512, 8>::from(n))
+            }
+        }
+        Opcode::EXP => {
+            let (mut base, mut exponent) = (pop()?, pop()?);
+            let mut result = U::from(1);
+            while !exponent.is_zero() {
+                if exponent.bit(0) {
+                    result = result.wrapping_mul(base);
+                }
+                base = base.wrapping_mul(base);
+                exponent >>= 1;
+            }
+            result
+        }
+        Opcode::SIGNEXTEND => {
+            let (b, x) = (pop()?, pop()?);
+            if b >= U::from(31) {
+                x
+            } else {
+                let bit = b.to::<usize>() * 8 + 7;
+                let low = x & ((U::from(1) << (bit + 1)) - U::from(1));
+                if x.bit(bit) {
+                    low | (U::MAX << (bit + 1))
+                } else {
+                    low
+                }
+            }
+        }
+        Opcode::LT => U::from(pop()? < pop()?),
+        Opcode::GT => U::from(pop()? > pop()?),
+        Opcode::SLT => {
+            let (a, b) = (pop()?, pop()?);
+            U::from(flip_sign(a) < flip_sign(b))
+        }
+        Opcode::SGT => {
+            let (a, b) = (pop()?, pop()?);
+            U::from(flip_sign(a) > flip_sign(b))
+        }
+        Opcode::EQ => U::from(pop()? == pop()?),
+        Opcode::ISZERO => U::from(pop()?.is_zero()),
+        Opcode::AND => pop()? & pop()?,
+        Opcode::OR => pop()? | pop()?,
+        Opcode::XOR => pop()? ^ pop()?,
+        Opcode::NOT => !pop()?,
+        Opcode::BYTE => {
+            let (i, x) = (pop()?, pop()?);
+            if i >= U::from(32) {
+                U::ZERO
+            } else {
+                (x >> (8 * (31 - i.to::<usize>()))) & U::from(0xff)
+            }
+        }
+        Opcode::SHL | Opcode::SHR | Opcode::SAR => {
+            let (shift, value) = (pop()?, pop()?);
+            let negative = inst.opcode == Opcode::SAR && value.bit(SIGN_BIT);
+            if shift >= U::from(256) {
+                if negative {
+                    U::MAX
+                } else {
+                    U::ZERO
+                }
+            } else {
+                let shift = shift.to::<usize>();
+                match inst.opcode {
+                    Opcode::SHL => value << shift,
+                    _ if negative => !(!value >> shift),
+                    _ => value >> shift,
+                }
+            }
+        }
+        Opcode::SHA3 => {
+            let (offset, size) = (pop()?, pop()?);
+            let data = read_memory(memory, U256(offset), U256(size))?;
+            U::from_be_bytes(alloy_primitives::keccak256(data).0)
+        }
+        Opcode::POP => {
+            pop()?;
+            return Ok(None);
+        }
+        Opcode::MLOAD => load_word(memory, U256(pop()?))?.0,
+        Opcode::MSTORE => {
+            let (offset, value) = (pop()?, pop()?);
+            store_word(memory, U256(offset), U256(value))?;
+            return Ok(None);
+        }
+        Opcode::MSTORE8 => {
+            let (offset, value) = (pop()?, pop()?);
+            let offset = memory_range(memory, U256(offset), 1)?;
+            memory.write_byte(offset, value.byte(0));
+            return Ok(None);
+        }
+        Opcode::SLOAD => host.sload(U256(pop()?)).0,
+        Opcode::SSTORE => {
+            let (key, value) = (pop()?, pop()?);
+            host.sstore(U256(key), U256(value));
+            return Ok(None);
+        }
+        Opcode::JUMP => return pop().map(Some),
+        Opcode::JUMPI => {
+            let (target, condition) = (pop()?, pop()?);
+            return Ok((!condition.is_zero()).then_some(target));
+        }
+        Opcode::PC => U::from(pc),
+        Opcode::JUMPDEST => return Ok(None),
+        opcode if opcode.is_push() => {
+            let operand = inst.operand.as_deref().unwrap_or_default();
+            let mut word = [0u8; 32];
+            word[32 - operand.len()..].copy_from_slice(operand);
+            U::from_be_bytes(word)
+        }
+        opcode if opcode.dup_depth().is_some() => {
+            let depth = opcode.dup_depth().unwrap();
+            match stack.len().checked_sub(depth) {
+                Some(index) => stack[index],
+                None => return Err(Halt::Invalid),
+            }
+        }
+        opcode if opcode.swap_depth().is_some() => {
+            let depth = opcode.swap_depth().unwrap();
+            let top = stack.len().checked_sub(1).ok_or(Halt::Invalid)?;
+            let other = top.checked_sub(depth).ok_or(Halt::Invalid)?;
+            stack.swap(top, other);
+            return Ok(None);
+        }
+        opcode => match HostOp::try_from(opcode) {
+            Ok(kind) => {
+                let args = (0..kind.inputs())
+                    .map(|_| pop().map(U256))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if kind.halts() {
+                    return Err(halt(kind, &args, memory));
+                }
+                match host.call(kind, &args, memory) {
+                    Some(value) => value.0,
+                    None => return Ok(None),
+                }
+            }
+            Err(_) => return Err(Halt::Invalid),
+        },
+    };
+
+    stack.push(result);
+    Ok(None)
+}
+
+fn abs(value: U) -> U {
+    if value.bit(SIGN_BIT) {
+        value.wrapping_neg()
+    } else {
+        value
+    }
+}
+
+// Maps two's complement order onto unsigned order
+fn flip_sign(value: U) -> U {
+    value ^ (U::from(1) << SIGN_BIT)
+}
@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 
+use alloy_primitives::keccak256;
+
 const WORD_SIZE: usize = 32; // 32 bytes per word
 const CHUNK_SIZE: usize = 1024 * WORD_SIZE; // 32KB chunks (1024 words)
 
@@ -75,6 +77,17 @@ impl Memory {
         word[index % WORD_SIZE] = value;
         self.write_word(index, word);
     }
+
+    pub fn read_bytes(&self, offset: usize, len: usize) -> Vec<u8> {
+        (offset..offset + len)
+            .map(|index| self.read_byte(index))
+            .collect()
+    }
+
+    /// keccak256 of `memory[offset..offset + len]`, what SHA3 pushes
+    pub fn keccak256(&self, offset: usize, len: usize) -> [u8; WORD_SIZE] {
+        keccak256(self.read_bytes(offset, len)).0
+    }
 }
 
 #[cfg(test)]
@@ -106,6 +119,17 @@ mod tests {
         assert_eq!(word[4], 42);
     }
 
+    #[test]
+    fn test_keccak256() {
+        let mut mem = Memory::new();
+        mem.write_word(0, [0xab; WORD_SIZE]);
+
+        assert_eq!(mem.read_bytes(30, 4), vec![0xab, 0xab, 0, 0]);
+        assert_eq!(mem.keccak256(0, 32), keccak256([0xab; WORD_SIZE]).0);
+        // keccak256 of no data
+        assert_eq!(mem.keccak256(64, 0)[..4], [0xc5, 0xd2, 0x46, 0x01]);
+    }
+
     #[test]
     fn test_expansion() {
         let mut mem = Memory::new();
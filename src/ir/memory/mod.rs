@@ -1,2 +1,6 @@
 pub mod stack;
-pub mod memory;
\ No newline at end of file
+pub mod memory;
+pub mod symbolic;
+
+/// Highest memory address a run may touch
+pub const MEMORY_LIMIT: usize = 1 << 24;
@@ -1,8 +1,28 @@
+use std::fmt;
+
 use crate::MyU256 as U256;
 
 const STACK_SIZE: usize = 1024;
 const SENTINEL: U256 = U256(U256::MAX); // Use MAX as a sentinel value
 
+/// Why a stack operation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+    Overflow,
+    Underflow,
+}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackError::Overflow => write!(f, "stack overflow"),
+            StackError::Underflow => write!(f, "stack underflow"),
+        }
+    }
+}
+
+impl std::error::Error for StackError {}
+
 pub struct Stack {
     data: [U256; STACK_SIZE],
     top: usize,
@@ -16,33 +36,50 @@ impl Stack {
         }
     }
 
-    pub fn push(&mut self, value: U256) -> Result<(), &'static str> {
+    pub fn push(&mut self, value: U256) -> Result<(), StackError> {
         if self.top < self.data.len() {
             self.data[self.top] = value;
             self.top += 1;
             Ok(())
         } else {
-            Err("Stack overflow")
+            Err(StackError::Overflow)
         }
     }
 
-    pub fn pop(&mut self) -> Result<U256, &'static str> {
+    pub fn pop(&mut self) -> Result<U256, StackError> {
         if self.top > 0 {
             self.top -= 1;
             let value = self.data[self.top];
             self.data[self.top] = SENTINEL;
             Ok(value)
         } else {
-            Err("Stack underflow")
+            Err(StackError::Underflow)
         }
     }
 
-    pub fn peek(&self) -> Result<U256, &'static str> {
+    pub fn peek(&self) -> Result<U256, StackError> {
         if self.top > 0 {
             Ok(self.data[self.top - 1])
         } else {
-            Err("Stack is empty")
+            Err(StackError::Underflow)
+        }
+    }
+
+    /// Push a copy of the `n`-th item from the top (DUPn)
+    pub fn dup(&mut self, n: usize) -> Result<(), StackError> {
+        if n == 0 || n > self.top {
+            return Err(StackError::Underflow);
+        }
+        self.push(self.data[self.top - n])
+    }
+
+    /// Exchange the top item with the one `n` below it (SWAPn)
+    pub fn swap(&mut self, n: usize) -> Result<(), StackError> {
+        if n == 0 || n >= self.top {
+            return Err(StackError::Underflow);
         }
+        self.data.swap(self.top - 1, self.top - 1 - n);
+        Ok(())
     }
 
     pub fn is_empty(&self) -> bool {
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+
+use crate::MyU256 as U256;
+
+const WORD_SIZE: usize = 32;
+
+/// Compile-time view of `Memory`: the words known to hold a constant, or
+/// whatever abstract value `T` an analysis tracks
+///
+/// Anything not recorded is unknown. Stores at an unknown offset or from a
+/// callee wipe the whole view, partial overwrites drop the words they touch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolicMemory<T = U256> {
+    // byte offset of the word -> its value
+    words: BTreeMap<usize, T>,
+}
+
+impl<T> Default for SymbolicMemory<T> {
+    fn default() -> Self {
+        SymbolicMemory {
+            words: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T: Clone + PartialEq> SymbolicMemory<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Value of the 32-byte word at `offset`, if known
+    pub fn load(&self, offset: usize) -> Option<T> {
+        self.words.get(&offset).cloned()
+    }
+
+    /// Record a 32-byte store, `None` if the stored value is unknown
+    pub fn store(&mut self, offset: usize, value: Option<T>) {
+        self.clobber(offset, WORD_SIZE);
+        if let Some(value) = value {
+            self.words.insert(offset, value);
+        }
+    }
+
+    /// Forget every word overlapping `offset..offset + len`
+    pub fn clobber(&mut self, offset: usize, len: usize) {
+        let first = offset.saturating_sub(WORD_SIZE - 1);
+        let last = offset.saturating_add(len);
+        self.words.retain(|&word, _| word < first || word >= last);
+    }
+
+    /// Forget everything
+    pub fn clobber_all(&mut self) {
+        self.words.clear();
+    }
+
+    /// Words known on both paths into a merge point
+    pub fn meet(&self, other: &SymbolicMemory<T>) -> SymbolicMemory<T> {
+        SymbolicMemory {
+            words: self
+                .words
+                .iter()
+                .filter(|(offset, value)| other.words.get(offset) == Some(value))
+                .map(|(offset, value)| (*offset, value.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::U256 as U;
+
+    fn v(n: u64) -> U256 {
+        U256(U::from(n))
+    }
+
+    #[test]
+    fn test_store_and_load() {
+        let mut mem: SymbolicMemory = SymbolicMemory::new();
+        mem.store(0x40, Some(v(0x80)));
+
+        assert_eq!(mem.load(0x40), Some(v(0x80)));
+        assert_eq!(mem.load(0x20), None);
+        assert_eq!(mem.load(0x41), None);
+
+        mem.store(0x40, None);
+        assert!(mem.is_empty());
+    }
+
+    #[test]
+    fn test_overlapping_writes_clobber() {
+        let mut mem: SymbolicMemory = SymbolicMemory::new();
+        mem.store(0x00, Some(v(1)));
+        mem.store(0x20, Some(v(2)));
+        mem.store(0x40, Some(v(3)));
+
+        // an unaligned word touches 0x00 and 0x20
+        mem.store(0x10, Some(v(4)));
+        assert_eq!(mem.load(0x00), None);
+        assert_eq!(mem.load(0x20), None);
+        assert_eq!(mem.load(0x10), Some(v(4)));
+        assert_eq!(mem.load(0x40), Some(v(3)));
+
+        // a single byte only touches the word containing it
+        mem.clobber(0x5f, 1);
+        assert_eq!(mem.load(0x40), None);
+        assert_eq!(mem.load(0x10), Some(v(4)));
+    }
+
+    #[test]
+    fn test_meet() {
+        let mut a: SymbolicMemory = SymbolicMemory::new();
+        a.store(0x40, Some(v(0x80)));
+        a.store(0x00, Some(v(1)));
+        let mut b: SymbolicMemory = SymbolicMemory::new();
+        b.store(0x40, Some(v(0x80)));
+        b.store(0x00, Some(v(2)));
+
+        let merged = a.meet(&b);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged.load(0x40), Some(v(0x80)));
+    }
+}
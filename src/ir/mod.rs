@@ -1,3 +1,10 @@
+pub mod cfg;
+pub mod eval;
 pub mod gas;
 pub mod memory;
-pub mod generator;
\ No newline at end of file
+pub mod generator;
+pub mod passes;
+pub mod source_map;
+pub mod interpreter;
+#[cfg(test)]
+pub(crate) mod test_util;
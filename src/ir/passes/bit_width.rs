@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+
+use super::constants::ConstantSlots;
+use crate::ir::gas::parser::IRInstruction;
+use crate::MyU256 as U256;
+use alloy_primitives::U256 as U;
+
+// Bit-width inference
+//
+// Most EVM values are far narrower than 256 bits: comparison results are booleans,
+// offsets are small constants and addresses are masked to 160 bits. This analysis
+// computes an upper bound on the significant bits of every value an instruction
+// produces and of every slot it reads. Instruction selection uses the operand
+// widths to leave out the limbs of a value that are known to be zero, so copies,
+// comparisons and conditions on anything that fits into 64 bits touch a single
+// register instead of four limbs.
+
+const FULL_WIDTH: u16 = 256;
+const REGISTER_WIDTH: u16 = 64;
+
+/// Upper bound on the significant bits of each instruction's result
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitWidths {
+    widths: Vec<Option<u16>>,
+    // known width of each slot an instruction reads, when it's narrower than 256
+    operands: Vec<Vec<(U256, u16)>>,
+}
+
+impl BitWidths {
+    /// Width of the value produced by the instruction at `index`, None if it produces nothing
+    pub fn of(&self, index: usize) -> Option<u16> {
+        self.widths.get(index).copied().flatten()
+    }
+
+    /// Upper bound on the significant bits of `slot` where the instruction at `index`
+    /// reads it
+    pub fn operand(&self, index: usize, slot: U256) -> u16 {
+        self.operands
+            .get(index)
+            .and_then(|operands| operands.iter().find(|(read, _)| *read == slot))
+            .map_or(FULL_WIDTH, |&(_, width)| width)
+    }
+
+    /// Check if the result of the instruction at `index` fits into a single RV64 register
+    pub fn fits_in_register(&self, index: usize) -> bool {
+        matches!(self.of(index), Some(width) if width <= REGISTER_WIDTH)
+    }
+
+    pub fn len(&self) -> usize {
+        self.widths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.widths.is_empty()
+    }
+}
+
+/// Infer the bit-width of every value produced by `ir`
+pub fn infer_bit_widths(ir: &[IRInstruction]) -> BitWidths {
+    let mut constants = ConstantSlots::new();
+    let mut slots: HashMap<U256, u16> = HashMap::new();
+    let mut widths = Vec::with_capacity(ir.len());
+    let mut operands = Vec::with_capacity(ir.len());
+
+    for inst in ir {
+        operands.push(
+            inst.sources()
+                .into_iter()
+                .filter_map(|slot| Some((slot, *slots.get(&slot)?)))
+                .collect(),
+        );
+        let width = result_width(inst, &slots, &constants);
+        widths.push(width);
+
+        match inst {
+            // nothing is known about the stack at the start of a block
+            IRInstruction::JumpDest { .. } => slots.clear(),
+            _ if inst.is_terminator() => slots.clear(),
+            IRInstruction::Swap { a, b } => {
+                let width_a = slots.remove(a);
+                let width_b = slots.remove(b);
+                if let Some(width) = width_a {
+                    slots.insert(*b, width);
+                }
+                if let Some(width) = width_b {
+                    slots.insert(*a, width);
+                }
+            }
+            _ => {
+                // an overwritten slot loses its old width even when the new one is unknown
+                for def in inst.defs() {
+                    slots.remove(&def);
+                }
+                if let (Some(dest), Some(width)) = (inst.dest(), width) {
+                    slots.insert(dest, width);
+                }
+            }
+        }
+        constants.observe(inst);
+    }
+
+    BitWidths { widths, operands }
+}
+
+fn result_width(
+    inst: &IRInstruction,
+    slots: &HashMap<U256, u16>,
+    constants: &ConstantSlots,
+) -> Option<u16> {
+    let width = |slot: &U256| slots.get(slot).copied().unwrap_or(FULL_WIDTH);
+    let shift = |slot: &U256| {
+        constants
+            .get(*slot)
+            .filter(|amount| amount.0 < U::from(FULL_WIDTH))
+            .map(|amount| amount.as_usize() as u16)
+    };
+
+    let result = match inst {
+        IRInstruction::LoadConst { value, .. } => value.0.bit_len() as u16,
+        IRInstruction::BinaryOp { op, src1, src2, .. } => match *op {
+            "LT" | "GT" | "SLT" | "SGT" | "EQ" => 1,
+            "and" => width(src1).min(width(src2)),
+            "or" | "xor" => width(src1).max(width(src2)),
+            "add" => width(src1).max(width(src2)) + 1,
+            "mul" => width(src1) + width(src2),
+            "div" => width(src1),
+            "mod" => width(src1).min(width(src2)),
+            "byte" => 8,
+            "shr" => match shift(src1) {
+                Some(amount) => width(src2).saturating_sub(amount),
+                None => width(src2),
+            },
+            "shl" => match shift(src1) {
+                Some(amount) => width(src2) + amount,
+                None => FULL_WIDTH,
+            },
+            // sub wraps, signed ops and exp can set the high bits
+            _ => FULL_WIDTH,
+        },
+        IRInstruction::UnaryOp { op, .. } => match *op {
+            "iszero" => 1,
+            "pop" => return None,
+            _ => FULL_WIDTH,
+        },
+        // the result is always below the modulus
+        IRInstruction::TernaryOp { src3, .. } => width(src3),
+        IRInstruction::Copy { src, .. } => width(src),
+        // memory, storage, hashes and host values can be anything
+        _ if inst.dest().is_some() => FULL_WIDTH,
+        _ => return None,
+    };
+
+    Some(result.min(FULL_WIDTH))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::gas::parser::{generate_ir, HostOp, Instruction, Opcode};
+    use crate::ir::memory::{memory::Memory, stack::Stack};
+    use crate::ir::test_util::{binary, s};
+
+    fn push(value: &[u8]) -> Instruction {
+        Instruction {
+            opcode: Opcode::PUSH1,
+            operand: Some(value.to_vec()),
+        }
+    }
+
+    #[test]
+    fn test_constants_and_comparisons() {
+        let ir = vec![
+            IRInstruction::LoadConst {
+                dest: s(1),
+                value: s(0x80),
+            },
+            IRInstruction::LoadConst {
+                dest: s(2),
+                value: s(0),
+            },
+            binary("LT", 1, 2, 1),
+        ];
+        let widths = infer_bit_widths(&ir);
+
+        assert_eq!(widths.of(0), Some(8));
+        assert_eq!(widths.of(1), Some(0));
+        assert_eq!(widths.of(2), Some(1));
+        assert!(widths.fits_in_register(2));
+    }
+
+    #[test]
+    fn test_address_mask() {
+        let mask = U256((U::from(1) << 160) - U::from(1));
+        let ir = vec![
+            IRInstruction::MemoryLoad {
+                offset: s(1),
+                dest: s(1),
+            },
+            IRInstruction::LoadConst {
+                dest: s(2),
+                value: mask,
+            },
+            binary("and", 1, 2, 1),
+        ];
+        let widths = infer_bit_widths(&ir);
+
+        assert_eq!(widths.of(0), Some(256));
+        assert_eq!(widths.of(2), Some(160));
+        assert!(!widths.fits_in_register(2));
+    }
+
+    #[test]
+    fn test_arithmetic_growth() {
+        let ir = vec![
+            IRInstruction::LoadConst {
+                dest: s(1),
+                value: s(u32::MAX as u64),
+            },
+            IRInstruction::LoadConst {
+                dest: s(2),
+                value: s(u32::MAX as u64),
+            },
+            binary("add", 1, 2, 1),
+            IRInstruction::LoadConst {
+                dest: s(2),
+                value: s(u32::MAX as u64),
+            },
+            binary("mul", 1, 2, 1),
+            IRInstruction::LoadConst {
+                dest: s(2),
+                value: s(40),
+            },
+            binary("shr", 1, 2, 1),
+        ];
+        let widths = infer_bit_widths(&ir);
+
+        assert_eq!(widths.of(2), Some(33));
+        assert_eq!(widths.of(4), Some(65));
+        assert!(!widths.fits_in_register(4));
+        assert_eq!(widths.of(6), Some(25));
+        assert!(widths.fits_in_register(6));
+    }
+
+    #[test]
+    fn test_shifts_and_wrapping_ops() {
+        let ir = vec![
+            IRInstruction::LoadConst {
+                dest: s(1),
+                value: s(1),
+            },
+            IRInstruction::LoadConst {
+                dest: s(2),
+                value: s(255),
+            },
+            binary("shl", 1, 2, 1),
+            IRInstruction::LoadConst {
+                dest: s(2),
+                value: s(1),
+            },
+            binary("sub", 1, 2, 1),
+        ];
+        let widths = infer_bit_widths(&ir);
+
+        assert_eq!(widths.of(2), Some(256));
+        assert_eq!(widths.of(4), Some(256));
+    }
+
+    #[test]
+    fn test_no_result_and_block_boundaries() {
+        let ir = vec![
+            IRInstruction::LoadConst {
+                dest: s(1),
+                value: s(1),
+            },
+            IRInstruction::Jump { target: s(0) },
+            binary("and", 1, 1, 1),
+        ];
+        let widths = infer_bit_widths(&ir);
+
+        assert_eq!(widths.of(1), None);
+        assert_eq!(widths.of(2), Some(256));
+        assert_eq!(widths.len(), 3);
+    }
+
+    #[test]
+    fn test_overwritten_slots() {
+        let ir = vec![
+            IRInstruction::LoadConst {
+                dest: s(1),
+                value: s(1),
+            },
+            IRInstruction::StorageLoad {
+                key: s(1),
+                dest: s(1),
+            },
+            IRInstruction::Copy {
+                dest: s(2),
+                src: s(1),
+            },
+            IRInstruction::LoadConst {
+                dest: s(2),
+                value: s(1),
+            },
+            IRInstruction::HostCall {
+                kind: HostOp::Caller,
+                dest: Some(s(2)),
+                args: Vec::new(),
+            },
+            IRInstruction::Copy {
+                dest: s(3),
+                src: s(2),
+            },
+        ];
+        let widths = infer_bit_widths(&ir);
+
+        assert_eq!(widths.of(0), Some(1));
+        assert_eq!(widths.of(1), Some(256));
+        assert_eq!(widths.of(2), Some(256));
+        assert!(!widths.fits_in_register(2));
+        assert_eq!(widths.of(4), Some(256));
+        assert_eq!(widths.of(5), Some(256));
+    }
+
+    #[test]
+    fn test_generated_ir() {
+        let instructions = vec![
+            push(&[0x01]),
+            push(&[0x02]),
+            Instruction {
+                opcode: Opcode::LT,
+                operand: None,
+            },
+            Instruction {
+                opcode: Opcode::ISZERO,
+                operand: None,
+            },
+        ];
+        let ir = generate_ir(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+        let widths = infer_bit_widths(&ir);
+
+        assert_eq!(widths.of(0), Some(1));
+        assert_eq!(widths.of(1), Some(2));
+        assert_eq!(widths.of(2), Some(1));
+        assert_eq!(widths.of(3), Some(1));
+        assert_eq!(widths.operand(3, s(1)), 1);
+        assert_eq!(widths.operand(3, s(2)), 256);
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+use crate::ir::gas::parser::IRInstruction;
+use crate::MyU256 as U256;
+
+/// Tracks which slots hold a known constant while walking straight-line IR
+#[derive(Debug, Default, Clone)]
+pub struct ConstantSlots {
+    values: HashMap<U256, U256>,
+}
+
+impl ConstantSlots {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the constant currently held by a slot
+    pub fn get(&self, slot: U256) -> Option<U256> {
+        self.values.get(&slot).copied()
+    }
+
+    /// Record a constant derived outside of `observe`, e.g. by folding
+    pub fn set(&mut self, slot: U256, value: U256) {
+        self.values.insert(slot, value);
+    }
+
+    /// Iterate over all slots with a known constant
+    pub fn iter(&self) -> impl Iterator<Item = (U256, U256)> + '_ {
+        self.values.iter().map(|(slot, value)| (*slot, *value))
+    }
+
+    /// Update the known constants with the effect of an instruction
+    pub fn observe(&mut self, inst: &IRInstruction) {
+        if inst.is_terminator() {
+            self.values.clear();
+            return;
+        }
+
+        match inst {
+            IRInstruction::JumpDest { .. } => self.values.clear(),
+            IRInstruction::LoadConst { dest, value } => {
+                self.values.insert(*dest, *value);
+            }
+            IRInstruction::Copy { dest, src } => match self.get(*src) {
+                Some(value) => {
+                    self.values.insert(*dest, value);
+                }
+                None => {
+                    self.values.remove(dest);
+                }
+            },
+            IRInstruction::Swap { a, b } => {
+                let value_a = self.values.remove(a);
+                let value_b = self.values.remove(b);
+                if let Some(value) = value_a {
+                    self.values.insert(*b, value);
+                }
+                if let Some(value) = value_b {
+                    self.values.insert(*a, value);
+                }
+            }
+            _ => {
+                for slot in inst.defs() {
+                    self.values.remove(&slot);
+                }
+            }
+        }
+    }
+
+    /// Forget everything, e.g. at a control flow merge point
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::ir::cfg::graph::Cfg;
+use crate::ir::gas::parser::IRInstruction;
+use crate::MyU256 as U256;
+use alloy_primitives::U256 as U;
+
+// Inlining of small internal functions
+//
+// Runs after `recover_functions` has turned internal calls into `Call`/`Return`.
+// A call costs a JAL, a JALR and saving `ra`, which dwarfs getters and small math
+// helpers, so straight-line functions are copied into their callers when that
+// doesn't grow the code much: either the body is about as cheap as the call it
+// replaces, or there is only one caller. Once every call is inlined the callee's
+// own block is removed, unless a fallthrough or a jump can still reach it.
+
+// rough cost of a call in IR instructions (argument setup, call, return, ra save)
+const CALL_OVERHEAD: usize = 4;
+// bodies this small are always worth inlining
+const ALWAYS_INLINE_SIZE: usize = 2 * CALL_OVERHEAD;
+// upper bound for functions with a single caller
+const SINGLE_CALLER_SIZE: usize = 64;
+
+/// Check if a function body of `size` instructions called from `call_count` places is worth inlining
+pub fn should_inline(size: usize, call_count: usize) -> bool {
+    size <= ALWAYS_INLINE_SIZE || (call_count == 1 && size <= SINGLE_CALLER_SIZE)
+}
+
+/// Inline small straight-line internal functions, returns true if anything changed
+pub fn inline_functions(ir: &mut Vec<IRInstruction>) -> bool {
+    inline_functions_with_pcs(ir, &mut Vec::new())
+}
+
+/// `inline_functions`, keeping the EVM pc of every instruction in `pcs` (if it
+/// isn't empty) in step. Inlined bodies keep the pcs of the callee, the copies of
+/// the results and the jump to the continuation get the call's.
+pub fn inline_functions_with_pcs(ir: &mut Vec<IRInstruction>, pcs: &mut Vec<usize>) -> bool {
+    let cfg = Cfg::build(ir);
+
+    let mut call_counts: HashMap<usize, usize> = HashMap::new();
+    for inst in ir.iter() {
+        if let IRInstruction::Call { target, .. } = inst {
+            *call_counts.entry(*target).or_default() += 1;
+        }
+    }
+
+    // where the callee body is without its JUMPDEST and Return, plus the returned slots
+    let mut bodies: HashMap<usize, (Range<usize>, Vec<U256>)> = HashMap::new();
+    // callee blocks nothing reaches once their calls are gone
+    let mut dead: Vec<Range<usize>> = Vec::new();
+    for (&entry, &count) in &call_counts {
+        let Some(b) = cfg.block_at_pc(entry) else {
+            continue;
+        };
+        let block = &cfg.blocks[b];
+        if let IRInstruction::Return { values } = &ir[block.end - 1] {
+            let range = block.start + 1..block.end - 1;
+            if should_inline(range.len(), count) {
+                bodies.insert(entry, (range, values.clone()));
+                if block.predecessors.is_empty() {
+                    dead.push(block.start..block.end);
+                }
+            }
+        }
+    }
+    if bodies.is_empty() {
+        return false;
+    }
+
+    let old_pcs = std::mem::take(pcs);
+    let mut inlined = Vec::with_capacity(ir.len());
+    let mut changed = false;
+    for (index, inst) in ir.iter().enumerate() {
+        if dead.iter().any(|block| block.contains(&index)) {
+            continue;
+        }
+        let IRInstruction::Call {
+            target,
+            return_pc,
+            args,
+            returns,
+        } = inst
+        else {
+            inlined.push(inst.clone());
+            pcs.extend(old_pcs.get(index));
+            continue;
+        };
+        let Some((range, values)) = bodies.get(target) else {
+            inlined.push(inst.clone());
+            pcs.extend(old_pcs.get(index));
+            continue;
+        };
+
+        let body = &ir[range.clone()];
+        inlined.extend(body.iter().cloned());
+        if let Some(body_pcs) = old_pcs.get(range.clone()) {
+            pcs.extend_from_slice(body_pcs);
+        }
+        for (dest, src) in returns.iter().zip(values) {
+            if dest != src {
+                inlined.push(IRInstruction::Copy {
+                    dest: *dest,
+                    src: *src,
+                });
+            }
+        }
+
+        // the continuation usually follows the call directly, otherwise jump there
+        // through a slot above everything the call touched, which is free
+        let falls_through = matches!(
+            ir.get(index + 1),
+            Some(IRInstruction::JumpDest { pc }) if pc == return_pc
+        );
+        if !falls_through {
+            let free = body
+                .iter()
+                .flat_map(|inst| inst.defs().into_iter().chain(inst.sources()))
+                .chain(args.iter().copied())
+                .chain(returns.iter().copied())
+                .map(|slot| slot.as_usize())
+                .max()
+                .unwrap_or(0)
+                + 1;
+            let free = U256(U::from(free));
+            inlined.push(IRInstruction::LoadConst {
+                dest: free,
+                value: U256(U::from(*return_pc)),
+            });
+            inlined.push(IRInstruction::Jump { target: free });
+        }
+        if let Some(&pc) = old_pcs.get(index) {
+            pcs.resize(inlined.len(), pc);
+        }
+        changed = true;
+    }
+
+    *ir = inlined;
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::cfg::functions::recover_functions;
+    use crate::ir::gas::parser::{generate_ir, parse_bytecode};
+    use crate::ir::memory::{memory::Memory, stack::Stack};
+    use crate::ir::test_util::{load, s};
+
+    fn call(target: usize, return_pc: usize) -> IRInstruction {
+        IRInstruction::Call {
+            target,
+            return_pc,
+            args: vec![s(2)],
+            returns: vec![s(1)],
+        }
+    }
+
+    fn increment() -> Vec<IRInstruction> {
+        vec![
+            IRInstruction::JumpDest { pc: 7 },
+            load(3, 1),
+            IRInstruction::BinaryOp {
+                op: "add",
+                dest: s(2),
+                src1: s(3),
+                src2: s(2),
+            },
+            IRInstruction::Swap { a: s(2), b: s(1) },
+            IRInstruction::Return { values: vec![s(1)] },
+        ]
+    }
+
+    #[test]
+    fn test_heuristic() {
+        assert!(should_inline(3, 10));
+        assert!(should_inline(40, 1));
+        assert!(!should_inline(40, 2));
+        assert!(!should_inline(100, 1));
+    }
+
+    #[test]
+    fn test_inline_recovered_call() {
+        let bytecode = [
+            0x60, 0x0d, 0x60, 0x05, 0x60, 0x07, 0x56, // call f(5), return to 13
+            0x5b, 0x60, 0x01, 0x01, 0x90, 0x56, // f: x + 1
+            0x5b, 0x00, // ret: STOP
+        ];
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        let mut ir = generate_ir(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+        recover_functions(&mut ir);
+
+        assert!(inline_functions(&mut ir));
+        assert!(!ir
+            .iter()
+            .any(|inst| matches!(inst, IRInstruction::Call { .. })));
+        assert!(matches!(ir[3], IRInstruction::LoadConst { .. }));
+        assert!(matches!(ir[4], IRInstruction::BinaryOp { op: "add", .. }));
+        assert!(matches!(ir[5], IRInstruction::Swap { .. }));
+        assert!(matches!(ir[7], IRInstruction::Jump { .. }));
+    }
+
+    #[test]
+    fn test_inline_falls_through_to_continuation() {
+        let mut ir = vec![
+            load(1, 20),
+            load(2, 5),
+            load(3, 7),
+            call(7, 20),
+            IRInstruction::JumpDest { pc: 20 },
+            IRInstruction::Stop,
+        ];
+        ir.extend(increment());
+
+        assert!(inline_functions(&mut ir));
+        assert!(matches!(ir[5], IRInstruction::Swap { .. }));
+        assert!(matches!(ir[6], IRInstruction::JumpDest { pc: 20 }));
+    }
+
+    #[test]
+    fn test_inline_with_jump_to_continuation() {
+        let mut ir = vec![load(1, 20), load(2, 5), load(3, 7), call(7, 20)];
+        ir.extend(increment());
+        ir.extend([IRInstruction::JumpDest { pc: 20 }, IRInstruction::Stop]);
+
+        assert!(inline_functions(&mut ir));
+        let jump = ir
+            .iter()
+            .position(|inst| matches!(inst, IRInstruction::Jump { .. }))
+            .unwrap();
+        match (&ir[jump - 1], &ir[jump]) {
+            (IRInstruction::LoadConst { dest, value }, IRInstruction::Jump { target }) => {
+                assert_eq!(*dest, s(4));
+                assert_eq!(*target, s(4));
+                assert_eq!(*value, s(20));
+            }
+            other => panic!("Expected jump to the continuation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_single_caller_body_removed() {
+        let mut body = vec![IRInstruction::JumpDest { pc: 7 }];
+        body.extend((0..20).map(|_| load(3, 1)));
+        body.push(IRInstruction::Return { values: vec![s(1)] });
+
+        let mut ir = vec![
+            call(7, 30),
+            IRInstruction::JumpDest { pc: 30 },
+            IRInstruction::Stop,
+        ];
+        ir.extend(body.clone());
+        let mut pcs: Vec<usize> = (0..ir.len()).collect();
+
+        assert!(inline_functions_with_pcs(&mut ir, &mut pcs));
+        assert_eq!(ir.len(), 22);
+        assert_eq!(pcs.len(), 22);
+        assert!(matches!(ir[20], IRInstruction::JumpDest { pc: 30 }));
+        assert!(matches!(ir[21], IRInstruction::Stop));
+
+        // a dynamic jump may still land on the callee
+        let mut ir = vec![
+            call(7, 30),
+            IRInstruction::JumpDest { pc: 30 },
+            IRInstruction::Jump { target: s(1) },
+        ];
+        ir.extend(body);
+
+        assert!(inline_functions(&mut ir));
+        assert_eq!(ir.len(), 44);
+        assert!(ir.contains(&IRInstruction::JumpDest { pc: 7 }));
+    }
+
+    #[test]
+    fn test_large_function_with_many_callers_kept() {
+        let mut body = vec![IRInstruction::JumpDest { pc: 7 }];
+        body.extend((0..20).map(|_| load(3, 1)));
+        body.push(IRInstruction::Return { values: vec![s(1)] });
+
+        let mut ir = vec![call(7, 30), IRInstruction::JumpDest { pc: 30 }, call(7, 40)];
+        ir.extend(body);
+        ir.extend([IRInstruction::JumpDest { pc: 40 }, IRInstruction::Stop]);
+
+        assert!(!inline_functions(&mut ir));
+    }
+
+    #[test]
+    fn test_branching_function_kept() {
+        let mut ir = vec![call(7, 20)];
+        ir.extend([
+            IRInstruction::JumpDest { pc: 7 },
+            IRInstruction::ConditionalJump {
+                condition: s(2),
+                target: s(1),
+            },
+            IRInstruction::Return { values: vec![s(1)] },
+            IRInstruction::JumpDest { pc: 20 },
+            IRInstruction::Stop,
+        ]);
+
+        assert!(!inline_functions(&mut ir));
+    }
+}
@@ -0,0 +1,462 @@
+use std::collections::HashSet;
+
+use crate::ir::cfg::dominators::Dominators;
+use crate::ir::cfg::graph::Cfg;
+use crate::ir::cfg::liveness::Liveness;
+use crate::ir::cfg::loops::{find_loops, Loop};
+use crate::ir::gas::parser::IRInstruction;
+use crate::MyU256 as U256;
+use alloy_primitives::U256 as U;
+
+// Loop-invariant code motion
+//
+// Pure instructions whose operands don't change inside a loop are moved into the
+// loop's preheader. EVM arithmetic never traps, so running them once up front is
+// safe even when the loop body wouldn't have reached them.
+//
+// Stack slots get reused all over a loop body, so an invariant value usually
+// shares its slot with values that do change. When all reads of such a value are
+// in its own block it moves to a free slot above the stack and those reads are
+// renamed, otherwise the slot has to be written nowhere else in the loop. A call
+// in the loop writes more than its results: the callee may use any slot from its
+// frame up, so all of those count as written there.
+//
+// Constants and copies are cheap to redo, they are only hoisted when they feed
+// something else that is.
+
+struct Hoist {
+    index: usize,
+    // the instruction as it will sit in the preheader
+    inst: IRInstruction,
+    // reads renamed to the new slot when the value moved out of its stack slot
+    renamed: Option<(U256, U256, Vec<usize>)>,
+}
+
+/// Move loop-invariant computations out of loops, returns true if anything changed
+pub fn hoist_invariants(ir: &mut Vec<IRInstruction>) -> bool {
+    hoist_invariants_with_pcs(ir, &mut Vec::new())
+}
+
+/// `hoist_invariants`, keeping the EVM pc of every instruction in `pcs` (if it
+/// isn't empty) in step, hoisted instructions take theirs along
+pub fn hoist_invariants_with_pcs(ir: &mut Vec<IRInstruction>, pcs: &mut Vec<usize>) -> bool {
+    let mut changed = false;
+    // every hoist moves instructions out of at least one loop, so this terminates
+    while hoist_one_loop(ir, pcs) {
+        changed = true;
+    }
+    changed
+}
+
+fn hoist_one_loop(ir: &mut Vec<IRInstruction>, pcs: &mut Vec<usize>) -> bool {
+    let cfg = Cfg::build(ir);
+    let doms = Dominators::compute(ir, &cfg);
+    let liveness = Liveness::compute(ir, &cfg);
+    let first_free = ir
+        .iter()
+        .flat_map(|inst| inst.defs().into_iter().chain(inst.sources()))
+        .map(|slot| slot.as_usize())
+        .max()
+        .map_or(1, |max| max + 1);
+
+    for lp in find_loops(&cfg, &doms) {
+        let Some(preheader) = lp.preheader(&cfg) else {
+            continue;
+        };
+
+        let mut pinned = HashSet::new();
+        let hoists = loop {
+            let hoists = plan(
+                ir, &cfg, &doms, &liveness, &lp, preheader, &pinned, first_free,
+            );
+            let unused: Vec<usize> = hoists
+                .iter()
+                .filter(|h| is_cheap(&h.inst) && !feeds_other(h, &hoists))
+                .map(|h| h.index)
+                .collect();
+            if unused.is_empty() {
+                break hoists;
+            }
+            pinned.extend(unused);
+        };
+        if hoists.is_empty() {
+            continue;
+        }
+
+        apply(ir, pcs, &cfg, preheader, &hoists);
+        return true;
+    }
+    false
+}
+
+#[allow(clippy::too_many_arguments)]
+fn plan(
+    ir: &[IRInstruction],
+    cfg: &Cfg,
+    doms: &Dominators,
+    liveness: &Liveness,
+    lp: &Loop,
+    preheader: usize,
+    pinned: &HashSet<usize>,
+    first_free: usize,
+) -> Vec<Hoist> {
+    let indices: Vec<usize> = lp
+        .blocks
+        .iter()
+        .flat_map(|&b| cfg.blocks[b].start..cfg.blocks[b].end)
+        .collect();
+    let exits = lp.exits(cfg);
+    // the preheader's jump into the loop still reads its slots after the hoisted code
+    let last = &ir[cfg.blocks[preheader].end - 1];
+    let guarded = if last.is_terminator() {
+        last.sources()
+    } else {
+        Vec::new()
+    };
+
+    let mut hoists: Vec<Hoist> = Vec::new();
+    let mut next_free = first_free;
+    let is_hoisted = |hoists: &[Hoist], j: usize| hoists.iter().any(|h| h.index == j);
+    // instruction `j` with the renames planned so far applied
+    let current = |hoists: &[Hoist], j: usize| {
+        let mut inst = ir[j].clone();
+        for (from, to, readers) in hoists.iter().filter_map(|h| h.renamed.as_ref()) {
+            if readers.contains(&j) {
+                inst.rename_source(*from, *to);
+            }
+        }
+        inst
+    };
+
+    let mut progress = true;
+    while progress {
+        progress = false;
+        for &i in &indices {
+            if pinned.contains(&i) || is_hoisted(&hoists, i) || !is_pure(&ir[i]) {
+                continue;
+            }
+            let mut inst = current(&hoists, i);
+            let Some(dest) = inst.dest() else {
+                continue;
+            };
+
+            let remaining = || indices.iter().copied().filter(|&j| !is_hoisted(&hoists, j));
+            let invariant = inst
+                .sources()
+                .iter()
+                .all(|slot| remaining().all(|j| !writes(&ir[j], *slot)));
+            if !invariant {
+                continue;
+            }
+
+            let b = cfg.block_of(i).unwrap();
+            let only_def = remaining().all(|j| j == i || !writes(&ir[j], dest));
+            let uses_dominated = remaining().filter(|&j| j != i).all(|j| {
+                let c = cfg.block_of(j).unwrap();
+                !current(&hoists, j).sources().contains(&dest)
+                    || if c == b { j > i } else { doms.dominates(b, c) }
+            });
+            let dead_after_loop = exits.iter().all(|&e| !liveness.is_live_in(e, dest));
+
+            let in_place =
+                only_def && uses_dominated && dead_after_loop && !guarded.contains(&dest);
+
+            let renamed = if in_place {
+                None
+            } else {
+                // the value can only move to a fresh slot if it never leaves its block
+                let mut readers = Vec::new();
+                let mut killed = false;
+                let mut escapes = false;
+                for (k, next) in ir.iter().enumerate().take(cfg.blocks[b].end).skip(i + 1) {
+                    if is_hoisted(&hoists, k) {
+                        continue;
+                    }
+                    if current(&hoists, k).sources().contains(&dest) {
+                        // the callee reads its arguments from where they are
+                        escapes |= matches!(
+                            next,
+                            IRInstruction::Swap { .. } | IRInstruction::Call { .. }
+                        );
+                        readers.push(k);
+                    }
+                    if writes(next, dest) {
+                        killed = true;
+                        break;
+                    }
+                }
+                if escapes || (!killed && liveness.is_live_out(b, dest)) {
+                    continue;
+                }
+                let fresh = U256(U::from(next_free));
+                next_free += 1;
+                set_dest(&mut inst, fresh);
+                Some((dest, fresh, readers))
+            };
+
+            hoists.push(Hoist {
+                index: i,
+                inst,
+                renamed,
+            });
+            progress = true;
+        }
+    }
+    hoists
+}
+
+fn apply(
+    ir: &mut Vec<IRInstruction>,
+    pcs: &mut Vec<usize>,
+    cfg: &Cfg,
+    preheader: usize,
+    hoists: &[Hoist],
+) {
+    let block = &cfg.blocks[preheader];
+    let insert_at = if ir[block.end - 1].is_terminator() {
+        block.end - 1
+    } else {
+        block.end
+    };
+
+    let old_pcs = std::mem::take(pcs);
+    let mut moved = Vec::with_capacity(ir.len());
+    for (index, inst) in ir.iter().enumerate() {
+        if index == insert_at {
+            moved.extend(hoists.iter().map(|h| h.inst.clone()));
+            pcs.extend(hoists.iter().filter_map(|h| old_pcs.get(h.index)));
+        }
+        if hoists.iter().any(|h| h.index == index) {
+            continue;
+        }
+        pcs.extend(old_pcs.get(index));
+        let mut inst = inst.clone();
+        for (from, to, readers) in hoists.iter().filter_map(|h| h.renamed.as_ref()) {
+            if readers.contains(&index) {
+                inst.rename_source(*from, *to);
+            }
+        }
+        moved.push(inst);
+    }
+    *ir = moved;
+}
+
+// Check if `inst` may write `slot`, for a call anything its callee could
+fn writes(inst: &IRInstruction, slot: U256) -> bool {
+    match inst {
+        IRInstruction::Call { args, returns, .. } => {
+            // results come back from the frame up, arguments start right above it,
+            // and without either the frame could be anywhere
+            let frame = match (returns.first(), args.first()) {
+                (Some(&first), _) => first.0,
+                (None, Some(&arg)) => arg.0.saturating_sub(U::from(1)),
+                (None, None) => U::ZERO,
+            };
+            slot.0 >= frame
+        }
+        _ => inst.defs().contains(&slot),
+    }
+}
+
+fn is_pure(inst: &IRInstruction) -> bool {
+    match inst {
+        IRInstruction::UnaryOp { op, .. } => *op != "pop",
+        IRInstruction::BinaryOp { .. }
+        | IRInstruction::TernaryOp { .. }
+        | IRInstruction::LoadConst { .. }
+        | IRInstruction::Copy { .. } => true,
+        _ => false,
+    }
+}
+
+fn is_cheap(inst: &IRInstruction) -> bool {
+    matches!(
+        inst,
+        IRInstruction::LoadConst { .. } | IRInstruction::Copy { .. }
+    )
+}
+
+fn feeds_other(hoist: &Hoist, hoists: &[Hoist]) -> bool {
+    let Some(dest) = hoist.inst.dest() else {
+        return false;
+    };
+    hoists
+        .iter()
+        .any(|other| other.index != hoist.index && other.inst.sources().contains(&dest))
+}
+
+fn set_dest(inst: &mut IRInstruction, slot: U256) {
+    match inst {
+        IRInstruction::BinaryOp { dest, .. }
+        | IRInstruction::UnaryOp { dest, .. }
+        | IRInstruction::TernaryOp { dest, .. }
+        | IRInstruction::LoadConst { dest, .. }
+        | IRInstruction::Copy { dest, .. } => *dest = slot,
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::interpreter::host::MockHost;
+    use crate::ir::interpreter::{run_ir, Halt};
+    use crate::ir::test_util::{binary, load, s};
+
+    #[test]
+    fn test_hoist_invariant_product() {
+        let mut ir = vec![
+            load(2, 3),
+            load(3, 5),
+            IRInstruction::JumpDest { pc: 10 },
+            binary("mul", 4, 2, 3),
+            binary("add", 1, 4, 1),
+            load(5, 10),
+            IRInstruction::ConditionalJump {
+                condition: s(1),
+                target: s(5),
+            },
+            IRInstruction::Stop,
+        ];
+
+        assert!(hoist_invariants(&mut ir));
+        assert_eq!(ir[2], binary("mul", 4, 2, 3));
+        assert_eq!(ir[3], IRInstruction::JumpDest { pc: 10 });
+        assert_eq!(ir[4], binary("add", 1, 4, 1));
+        // the jump target constant stays in the loop
+        assert_eq!(ir[5], load(5, 10));
+        assert!(!hoist_invariants(&mut ir));
+    }
+
+    #[test]
+    fn test_hoist_into_fresh_slots() {
+        let mut ir = vec![
+            load(2, 3),
+            IRInstruction::JumpDest { pc: 10 },
+            load(3, 5),
+            binary("mul", 3, 3, 2),
+            binary("add", 1, 3, 1),
+            load(3, 10),
+            IRInstruction::ConditionalJump {
+                condition: s(1),
+                target: s(3),
+            },
+            IRInstruction::Stop,
+        ];
+
+        assert!(hoist_invariants(&mut ir));
+        assert_eq!(
+            ir,
+            vec![
+                load(2, 3),
+                load(4, 5),
+                binary("mul", 5, 4, 2),
+                IRInstruction::JumpDest { pc: 10 },
+                binary("add", 1, 5, 1),
+                load(3, 10),
+                IRInstruction::ConditionalJump {
+                    condition: s(1),
+                    target: s(3),
+                },
+                IRInstruction::Stop,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_value_read_after_loop_kept() {
+        let mut ir = vec![
+            load(2, 3),
+            IRInstruction::JumpDest { pc: 10 },
+            load(5, 20),
+            IRInstruction::ConditionalJump {
+                condition: s(1),
+                target: s(5),
+            },
+            binary("add", 4, 2, 2),
+            load(5, 10),
+            IRInstruction::Jump { target: s(5) },
+            IRInstruction::JumpDest { pc: 20 },
+            IRInstruction::Copy {
+                dest: s(1),
+                src: s(4),
+            },
+            IRInstruction::Stop,
+        ];
+        let original = ir.clone();
+
+        assert!(!hoist_invariants(&mut ir));
+        assert_eq!(ir, original);
+    }
+
+    #[test]
+    fn test_call_in_loop() {
+        // twice: sum += 3 * 3, then call a function that uses slot 4 as a temporary
+        let ir = vec![
+            load(1, 3),
+            load(8, 2),
+            IRInstruction::JumpDest { pc: 10 },
+            binary("mul", 4, 1, 1),
+            binary("add", 6, 4, 6),
+            IRInstruction::Call {
+                target: 30,
+                return_pc: 20,
+                args: vec![s(5)],
+                returns: Vec::new(),
+            },
+            IRInstruction::JumpDest { pc: 20 },
+            load(7, 1),
+            binary("sub", 8, 8, 7),
+            load(7, 10),
+            IRInstruction::ConditionalJump {
+                condition: s(8),
+                target: s(7),
+            },
+            load(7, 0),
+            IRInstruction::StorageStore {
+                key: s(7),
+                value: s(6),
+            },
+            IRInstruction::Stop,
+            IRInstruction::JumpDest { pc: 30 },
+            load(4, 0),
+            IRInstruction::Return { values: Vec::new() },
+        ];
+        assert!(writes(&ir[5], s(4)));
+        assert!(!writes(&ir[5], s(3)));
+
+        let mut hoisted = ir.clone();
+        assert!(hoist_invariants(&mut hoisted));
+        // the product moves out of slot 4, which the callee overwrites
+        assert_eq!(hoisted[2], binary("mul", 9, 1, 1));
+        assert_eq!(hoisted[4], binary("add", 6, 9, 6));
+
+        let expected = {
+            let mut host = MockHost::new();
+            assert_eq!(run_ir(&ir, &mut host), Halt::Stop);
+            host
+        };
+        let mut host = MockHost::new();
+        assert_eq!(run_ir(&hoisted, &mut host), Halt::Stop);
+        assert_eq!(host.storage[&s(0)], s(18));
+        assert_eq!(host, expected);
+    }
+
+    #[test]
+    fn test_loop_carried_value_kept() {
+        let mut ir = vec![
+            load(1, 0),
+            IRInstruction::JumpDest { pc: 10 },
+            load(2, 1),
+            binary("add", 1, 2, 1),
+            load(3, 10),
+            IRInstruction::ConditionalJump {
+                condition: s(1),
+                target: s(3),
+            },
+            IRInstruction::Stop,
+        ];
+
+        assert!(!hoist_invariants(&mut ir));
+    }
+}
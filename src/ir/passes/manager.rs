@@ -0,0 +1,312 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::inline::inline_functions_with_pcs;
+use super::licm::hoist_invariants_with_pcs;
+use super::memory_forwarding::forward_memory_with_pcs;
+use super::strength_reduction::reduce_strength_with_pcs;
+use super::verifier::{verify, VerifyError};
+use crate::ir::cfg::functions::recover_functions;
+use crate::ir::gas::parser::IRInstruction;
+
+// Optimisation pipeline
+//
+// Passes run once each, in order. Function recovery comes first since inlining
+// only sees `Call`s, and strength reduction runs again after inlining because
+// constant arguments only meet the arithmetic inside the body once it is copied
+// into the caller.
+//
+// Every pass keeps the EVM pc of each instruction, as `generate_ir_with_pcs`
+// returns them, in step with the IR it rewrites, so optimised IR can still be
+// selected with its pcs for the source map and block symbols.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pass {
+    RecoverFunctions,
+    Inline,
+    StrengthReduction,
+    MemoryForwarding,
+    Licm,
+}
+
+impl Pass {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pass::RecoverFunctions => "recover-functions",
+            Pass::Inline => "inline",
+            Pass::StrengthReduction => "strength-reduction",
+            Pass::MemoryForwarding => "memory-forwarding",
+            Pass::Licm => "licm",
+        }
+    }
+
+    /// Run the pass, returns true if it changed anything
+    ///
+    /// `pcs` is the EVM pc of every instruction, or empty if they aren't known.
+    pub fn run(&self, ir: &mut Vec<IRInstruction>, pcs: &mut Vec<usize>) -> bool {
+        match self {
+            // rewrites calls and returns in place
+            Pass::RecoverFunctions => !recover_functions(ir).is_empty(),
+            Pass::Inline => inline_functions_with_pcs(ir, pcs),
+            Pass::StrengthReduction => reduce_strength_with_pcs(ir, pcs),
+            Pass::MemoryForwarding => forward_memory_with_pcs(ir, pcs),
+            Pass::Licm => hoist_invariants_with_pcs(ir, pcs),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptLevel {
+    // no optimisation, IR as generated
+    O0,
+    // local rewrites only
+    O1,
+    // everything
+    O2,
+    // everything that doesn't grow the code
+    Os,
+}
+
+impl OptLevel {
+    pub fn pipeline(&self) -> Vec<Pass> {
+        match self {
+            OptLevel::O0 => vec![],
+            OptLevel::O1 => vec![Pass::StrengthReduction, Pass::MemoryForwarding],
+            OptLevel::O2 => vec![
+                Pass::RecoverFunctions,
+                Pass::Inline,
+                Pass::StrengthReduction,
+                Pass::MemoryForwarding,
+                Pass::Licm,
+                Pass::StrengthReduction,
+            ],
+            // inlining copies bodies and LICM renames into extra copies
+            OptLevel::Os => vec![
+                Pass::RecoverFunctions,
+                Pass::StrengthReduction,
+                Pass::MemoryForwarding,
+            ],
+        }
+    }
+}
+
+/// What a single pass did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassStats {
+    pub pass: Pass,
+    pub changed: bool,
+    pub duration: Duration,
+    // instruction counts before and after the pass
+    pub before: usize,
+    pub after: usize,
+}
+
+impl PassStats {
+    /// Change in instruction count, negative when the pass shrank the IR
+    pub fn delta(&self) -> isize {
+        self.after as isize - self.before as isize
+    }
+}
+
+/// A verifier failure, `pass` is None when the input was already broken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PassError {
+    pub pass: Option<Pass>,
+    pub error: VerifyError,
+}
+
+impl fmt::Display for PassError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pass {
+            Some(pass) => write!(f, "IR broken by {}: {}", pass.name(), self.error),
+            None => write!(f, "invalid input IR: {}", self.error),
+        }
+    }
+}
+
+impl std::error::Error for PassError {}
+
+#[derive(Debug, Clone)]
+pub struct PassManager {
+    pipeline: Vec<Pass>,
+    disabled: HashSet<Pass>,
+    verify: bool,
+}
+
+impl PassManager {
+    pub fn new(level: OptLevel) -> Self {
+        Self::with_pipeline(level.pipeline())
+    }
+
+    pub fn with_pipeline(pipeline: Vec<Pass>) -> Self {
+        PassManager {
+            pipeline,
+            disabled: HashSet::new(),
+            verify: true,
+        }
+    }
+
+    /// Skip every occurrence of `pass` in the pipeline
+    pub fn disable(&mut self, pass: Pass) -> &mut Self {
+        self.disabled.insert(pass);
+        self
+    }
+
+    /// Undo `disable`, or append `pass` if the pipeline doesn't have it
+    pub fn enable(&mut self, pass: Pass) -> &mut Self {
+        self.disabled.remove(&pass);
+        if !self.pipeline.contains(&pass) {
+            self.pipeline.push(pass);
+        }
+        self
+    }
+
+    /// Turn verification between passes on or off, it is on by default
+    pub fn set_verify(&mut self, verify: bool) -> &mut Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Passes that will run, in order
+    pub fn passes(&self) -> impl Iterator<Item = Pass> + '_ {
+        self.pipeline
+            .iter()
+            .copied()
+            .filter(|pass| !self.disabled.contains(pass))
+    }
+
+    /// Run the pipeline over `ir`, stopping at the first pass that leaves it invalid
+    pub fn run(&self, ir: &mut Vec<IRInstruction>) -> Result<Vec<PassStats>, PassError> {
+        self.run_with_pcs(ir, &mut Vec::new())
+    }
+
+    /// `run`, keeping the EVM pc of every instruction in `pcs` (if it isn't empty)
+    /// in step with the IR
+    pub fn run_with_pcs(
+        &self,
+        ir: &mut Vec<IRInstruction>,
+        pcs: &mut Vec<usize>,
+    ) -> Result<Vec<PassStats>, PassError> {
+        if self.verify {
+            verify(ir).map_err(|error| PassError { pass: None, error })?;
+        }
+
+        let mut stats = Vec::new();
+        for pass in self.passes() {
+            let before = ir.len();
+            let start = Instant::now();
+            let changed = pass.run(ir, pcs);
+            let duration = start.elapsed();
+
+            if self.verify && changed {
+                verify(ir).map_err(|error| PassError {
+                    pass: Some(pass),
+                    error,
+                })?;
+            }
+            stats.push(PassStats {
+                pass,
+                changed,
+                duration,
+                before,
+                after: ir.len(),
+            });
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::gas::parser::{generate_ir, generate_ir_with_pcs, parse_bytecode};
+    use crate::ir::memory::{memory::Memory, stack::Stack};
+
+    fn compile(bytecode: &[u8]) -> Vec<IRInstruction> {
+        let instructions = parse_bytecode(bytecode).unwrap();
+        generate_ir(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap()
+    }
+
+    // mstore(0x40, 0x80) mstore(0x40, 0xa0) mload(0x40) mul(_, 8) stop
+    const BYTECODE: [u8; 16] = [
+        0x60, 0x80, 0x60, 0x40, 0x52, 0x60, 0xa0, 0x60, 0x40, 0x52, 0x60, 0x40, 0x51, 0x60, 0x08,
+        0x02,
+    ];
+
+    #[test]
+    fn test_levels() {
+        let original = compile(&BYTECODE);
+
+        let mut ir = original.clone();
+        let stats = PassManager::new(OptLevel::O0).run(&mut ir).unwrap();
+        assert!(stats.is_empty());
+        assert_eq!(ir, original);
+
+        let mut ir = original.clone();
+        let stats = PassManager::new(OptLevel::O1).run(&mut ir).unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].pass, Pass::StrengthReduction);
+        assert!(stats.iter().all(|stat| stat.changed));
+        // the first store is overwritten before anything reads it
+        assert_eq!(stats[1].delta(), -1);
+        assert_eq!(stats[1].after, ir.len());
+        assert!(ir
+            .iter()
+            .all(|inst| !matches!(inst, IRInstruction::MemoryLoad { .. })));
+    }
+
+    #[test]
+    fn test_pcs_kept() {
+        let instructions = parse_bytecode(&BYTECODE).unwrap();
+        let (mut ir, mut pcs) =
+            generate_ir_with_pcs(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+        PassManager::new(OptLevel::O1)
+            .run_with_pcs(&mut ir, &mut pcs)
+            .unwrap();
+
+        // the store at pc 4 is gone, the load at pc 12 is a constant and the mul
+        // at pc 15 became a shift
+        assert_eq!(pcs.len(), ir.len());
+        assert!(!pcs.contains(&4));
+        let at = |pc: usize| pcs.iter().position(|&p| p == pc).unwrap();
+        assert!(matches!(ir[at(12)], IRInstruction::LoadConst { .. }));
+        assert!(matches!(
+            ir.last(),
+            Some(IRInstruction::BinaryOp { op: "shl", .. })
+        ));
+        assert_eq!(pcs.last(), Some(&15));
+        assert!(pcs.is_sorted());
+    }
+
+    #[test]
+    fn test_enable_disable() {
+        let mut manager = PassManager::new(OptLevel::O2);
+        manager.disable(Pass::StrengthReduction).disable(Pass::Licm);
+        assert_eq!(
+            manager.passes().collect::<Vec<_>>(),
+            vec![Pass::RecoverFunctions, Pass::Inline, Pass::MemoryForwarding]
+        );
+
+        let mut ir = compile(&BYTECODE);
+        let stats = manager.run(&mut ir).unwrap();
+        assert!(ir
+            .iter()
+            .any(|inst| matches!(inst, IRInstruction::BinaryOp { op: "mul", .. })));
+        assert!(!stats[0].changed);
+
+        let mut manager = PassManager::new(OptLevel::O0);
+        manager.enable(Pass::Licm);
+        assert_eq!(manager.passes().collect::<Vec<_>>(), vec![Pass::Licm]);
+    }
+
+    #[test]
+    fn test_invalid_input_rejected() {
+        let mut ir = vec![
+            IRInstruction::JumpDest { pc: 1 },
+            IRInstruction::JumpDest { pc: 1 },
+        ];
+        let err = PassManager::new(OptLevel::O1).run(&mut ir).unwrap_err();
+        assert_eq!(err.pass, None);
+    }
+}
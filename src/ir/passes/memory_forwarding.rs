@@ -0,0 +1,391 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ir::cfg::graph::Cfg;
+use crate::ir::gas::parser::IRInstruction;
+use crate::ir::memory::symbolic::SymbolicMemory;
+use crate::ir::passes::constants::ConstantSlots;
+use crate::MyU256 as U256;
+
+// Memory load/store forwarding
+//
+// Solidity keeps the free memory pointer at 0x40: every contract starts with
+// `mstore(0x40, 0x80)` and reads it back with `mload(0x40)` before each allocation.
+// Stores of constants to constant offsets are tracked in a `SymbolicMemory`, across
+// blocks too, so such loads turn into constants. Bumping the pointer is an `add` of
+// two constants, which is folded here to keep it known after an allocation.
+//
+// A store is only removed when a later store in the same block overwrites the same
+// word before anything could read it, so the size of memory never changes.
+
+/// Forward constant stores to loads and remove overwritten stores, returns true if anything changed
+pub fn forward_memory(ir: &mut Vec<IRInstruction>) -> bool {
+    forward_memory_with_pcs(ir, &mut Vec::new())
+}
+
+/// `forward_memory`, keeping the EVM pc of every instruction in `pcs` (if it
+/// isn't empty) in step
+pub fn forward_memory_with_pcs(ir: &mut Vec<IRInstruction>, pcs: &mut Vec<usize>) -> bool {
+    let cfg = Cfg::build(ir);
+    let entry_states = entry_states(ir, &cfg);
+
+    let mut changed = false;
+    let mut dead = HashSet::new();
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        let mut state = Tracker {
+            consts: ConstantSlots::new(),
+            memory: entry_states[b].clone(),
+        };
+        // constant-offset stores nothing has read yet
+        let mut pending: HashMap<usize, usize> = HashMap::new();
+
+        for (index, inst) in ir.iter_mut().enumerate().take(block.end).skip(block.start) {
+            if let Some(value) = state.known_load(inst) {
+                let dest = inst.dest().unwrap();
+                *inst = IRInstruction::LoadConst { dest, value };
+                changed = true;
+            }
+
+            let inst = &*inst;
+            match inst {
+                IRInstruction::MemoryStore { offset, .. } => {
+                    if let Some(offset) = state.offset(*offset) {
+                        if let Some(previous) = pending.insert(offset, index) {
+                            dead.insert(previous);
+                        }
+                    }
+                }
+                IRInstruction::MemoryLoad { offset, .. } => match state.offset(*offset) {
+                    Some(offset) => pending.retain(|&stored, _| {
+                        stored + 32 <= offset || stored >= offset.saturating_add(32)
+                    }),
+                    None => pending.clear(),
+                },
+                _ if reads_memory(inst) => pending.clear(),
+                _ => {}
+            }
+
+            state.step(inst);
+        }
+    }
+
+    if !dead.is_empty() {
+        remove(ir, &dead);
+        remove(pcs, &dead);
+        changed = true;
+    }
+    changed
+}
+
+// Drop the items at the indices in `dead`
+fn remove<T>(items: &mut Vec<T>, dead: &HashSet<usize>) {
+    let mut index = 0;
+    items.retain(|_| {
+        index += 1;
+        !dead.contains(&(index - 1))
+    });
+}
+
+// What is known about slots and memory while walking a block
+struct Tracker {
+    consts: ConstantSlots,
+    memory: SymbolicMemory,
+}
+
+impl Tracker {
+    fn offset(&self, slot: U256) -> Option<usize> {
+        self.consts
+            .get(slot)
+            .and_then(|offset| usize::try_from(offset.0).ok())
+    }
+
+    // value `inst` loads, if it is a load from a known word
+    fn known_load(&self, inst: &IRInstruction) -> Option<U256> {
+        match inst {
+            IRInstruction::MemoryLoad { offset, .. } => self.memory.load(self.offset(*offset)?),
+            _ => None,
+        }
+    }
+
+    fn step(&mut self, inst: &IRInstruction) {
+        match inst {
+            IRInstruction::MemoryStore { offset, value } => match self.offset(*offset) {
+                Some(offset) => self.memory.store(offset, self.consts.get(*value)),
+                None => self.memory.clobber_all(),
+            },
+            IRInstruction::MemoryStore8 { offset, .. } => match self.offset(*offset) {
+                Some(offset) => self.memory.clobber(offset, 1),
+                None => self.memory.clobber_all(),
+            },
+            _ if writes_memory(inst) => self.memory.clobber_all(),
+            _ => {}
+        }
+
+        let folded = match inst {
+            IRInstruction::BinaryOp {
+                op: "add",
+                dest,
+                src1,
+                src2,
+            } => match (self.consts.get(*src1), self.consts.get(*src2)) {
+                (Some(a), Some(b)) => Some((*dest, U256(a.0.wrapping_add(b.0)))),
+                _ => None,
+            },
+            _ => None,
+        };
+        let loaded = self
+            .known_load(inst)
+            .map(|value| (inst.dest().unwrap(), value));
+
+        self.consts.observe(inst);
+        if let Some((dest, value)) = folded.or(loaded) {
+            self.consts.set(dest, value);
+        }
+    }
+}
+
+// Memory known on entry to each block: the words every predecessor agrees on
+fn entry_states(ir: &[IRInstruction], cfg: &Cfg) -> Vec<SymbolicMemory> {
+    let n = cfg.len();
+    let mut exits: Vec<Option<SymbolicMemory>> = vec![None; n];
+    let mut entries = vec![SymbolicMemory::new(); n];
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (b, block) in cfg.blocks.iter().enumerate() {
+            // the program entry and function entries start from nothing
+            let entry = if b == 0 || block.predecessors.is_empty() {
+                SymbolicMemory::new()
+            } else {
+                let mut known = block.predecessors.iter().filter_map(|&p| exits[p].as_ref());
+                let Some(first) = known.next() else {
+                    continue;
+                };
+                known.fold(first.clone(), |acc, state| acc.meet(state))
+            };
+
+            let mut state = Tracker {
+                consts: ConstantSlots::new(),
+                memory: entry.clone(),
+            };
+            for inst in &ir[block.start..block.end] {
+                state.step(inst);
+            }
+
+            entries[b] = entry;
+            if exits[b].as_ref() != Some(&state.memory) {
+                exits[b] = Some(state.memory);
+                changed = true;
+            }
+        }
+    }
+    entries
+}
+
+// Instructions known not to touch memory, everything else is assumed to
+fn is_memory_free(inst: &IRInstruction) -> bool {
+    matches!(
+        inst,
+        IRInstruction::BinaryOp { .. }
+            | IRInstruction::UnaryOp { .. }
+            | IRInstruction::TernaryOp { .. }
+            | IRInstruction::LoadConst { .. }
+            | IRInstruction::Copy { .. }
+            | IRInstruction::Swap { .. }
+            | IRInstruction::StorageLoad { .. }
+            | IRInstruction::StorageStore { .. }
+            | IRInstruction::JumpDest { .. }
+            | IRInstruction::Jump { .. }
+            | IRInstruction::ConditionalJump { .. }
+            | IRInstruction::Stop
+            | IRInstruction::Return { .. }
+    )
+}
+
+fn reads_memory(inst: &IRInstruction) -> bool {
+    match inst {
+        IRInstruction::HostCall { kind, .. } => kind.reads_memory(),
+        IRInstruction::MemoryStore { .. } | IRInstruction::MemoryStore8 { .. } => false,
+        _ => !is_memory_free(inst),
+    }
+}
+
+fn writes_memory(inst: &IRInstruction) -> bool {
+    match inst {
+        IRInstruction::HostCall { kind, .. } => kind.writes_memory(),
+        IRInstruction::MemoryLoad { .. } | IRInstruction::Sha3 { .. } => false,
+        _ => !is_memory_free(inst),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::gas::parser::{generate_ir, parse_bytecode, HostOp};
+    use crate::ir::memory::{memory::Memory, stack::Stack};
+    use crate::ir::test_util::{load, mstore, s};
+
+    fn mload(slot: u64) -> IRInstruction {
+        IRInstruction::MemoryLoad {
+            offset: s(slot),
+            dest: s(slot),
+        }
+    }
+
+    #[test]
+    fn test_free_memory_pointer_from_bytecode() {
+        // mstore(0x40, 0x80) mload(0x40) stop
+        let bytecode = [0x60, 0x80, 0x60, 0x40, 0x52, 0x60, 0x40, 0x51, 0x00];
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        let mut ir = generate_ir(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+
+        assert!(forward_memory(&mut ir));
+        assert_eq!(ir[4], load(1, 0x80));
+    }
+
+    #[test]
+    fn test_pointer_bump_across_blocks() {
+        let mut ir = vec![
+            load(1, 0x80),
+            load(2, 0x40),
+            mstore(2, 1),
+            IRInstruction::JumpDest { pc: 10 },
+            // allocate 0x20 bytes
+            load(1, 0x40),
+            mload(1),
+            load(2, 0x20),
+            IRInstruction::BinaryOp {
+                op: "add",
+                dest: s(1),
+                src1: s(2),
+                src2: s(1),
+            },
+            load(2, 0x40),
+            mstore(2, 1),
+            IRInstruction::JumpDest { pc: 20 },
+            load(1, 0x40),
+            mload(1),
+            IRInstruction::Stop,
+        ];
+
+        assert!(forward_memory(&mut ir));
+        assert_eq!(ir[5], load(1, 0x80));
+        assert_eq!(ir[12], load(1, 0xa0));
+    }
+
+    #[test]
+    fn test_merge_keeps_only_agreeing_words() {
+        let mut ir = vec![
+            load(1, 0x80),
+            load(2, 0x40),
+            mstore(2, 1),
+            load(1, 1),
+            load(2, 20),
+            IRInstruction::ConditionalJump {
+                condition: s(1),
+                target: s(2),
+            },
+            load(1, 0xc0),
+            load(2, 0x40),
+            mstore(2, 1),
+            IRInstruction::JumpDest { pc: 20 },
+            load(1, 0x40),
+            mload(1),
+            IRInstruction::Stop,
+        ];
+        let original = ir.clone();
+
+        assert!(!forward_memory(&mut ir));
+        assert_eq!(ir, original);
+    }
+
+    #[test]
+    fn test_overwritten_store_removed() {
+        let mut ir = vec![
+            load(1, 1),
+            load(2, 0),
+            mstore(2, 1),
+            load(1, 2),
+            load(2, 0),
+            mstore(2, 1),
+            load(1, 0),
+            mload(1),
+            IRInstruction::Stop,
+        ];
+
+        assert!(forward_memory(&mut ir));
+        assert_eq!(ir.len(), 8);
+        assert!(matches!(ir[4], IRInstruction::MemoryStore { .. }));
+        assert_eq!(ir[6], load(1, 2));
+    }
+
+    #[test]
+    fn test_read_between_stores_keeps_both() {
+        let mut ir = vec![
+            load(1, 1),
+            load(2, 0x10),
+            mstore(2, 1),
+            // unaligned read overlapping the first store
+            load(1, 0x20),
+            mload(1),
+            load(1, 2),
+            load(2, 0x10),
+            mstore(2, 1),
+            IRInstruction::Stop,
+        ];
+
+        forward_memory(&mut ir);
+        assert_eq!(ir.len(), 9);
+        assert_eq!(
+            ir.iter()
+                .filter(|inst| matches!(inst, IRInstruction::MemoryStore { .. }))
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_unknown_store_clobbers() {
+        let mut ir = vec![
+            load(1, 0x80),
+            load(2, 0x40),
+            mstore(2, 1),
+            // offset comes from calldata or similar, unknown here
+            mstore(5, 6),
+            load(1, 0x40),
+            mload(1),
+            IRInstruction::Stop,
+        ];
+
+        assert!(!forward_memory(&mut ir));
+        assert_eq!(ir[5], mload(1));
+    }
+
+    #[test]
+    fn test_host_calls_only_clobber_what_they_write() {
+        let mut ir = vec![
+            load(1, 0x80),
+            load(2, 0x40),
+            mstore(2, 1),
+            IRInstruction::HostCall {
+                kind: HostOp::Caller,
+                dest: Some(s(1)),
+                args: vec![],
+            },
+            load(2, 0x40),
+            mload(2),
+            IRInstruction::HostCall {
+                kind: HostOp::ReturnDataCopy,
+                dest: None,
+                args: vec![s(3), s(2), s(1)],
+            },
+            load(1, 0x40),
+            mload(1),
+            IRInstruction::Stop,
+        ];
+
+        assert!(forward_memory(&mut ir));
+        assert_eq!(ir[5], load(2, 0x80));
+        assert_eq!(ir[8], mload(1));
+    }
+}
@@ -0,0 +1,9 @@
+pub mod bit_width;
+pub mod constants;
+pub mod inline;
+pub mod licm;
+pub mod manager;
+pub mod memory_forwarding;
+pub mod storage;
+pub mod strength_reduction;
+pub mod verifier;
@@ -0,0 +1,638 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::ir::cfg::graph::Cfg;
+use crate::ir::gas::parser::IRInstruction;
+use crate::ir::memory::symbolic::SymbolicMemory;
+use crate::MyU256 as U256;
+use alloy_primitives::U256 as U;
+
+// Storage slot analysis
+//
+// Solidity lays out state variables from slot 0, a mapping entry lives at
+// keccak(key . slot) and the elements of a dynamic array start at keccak(slot).
+// Slot and memory contents are tracked abstractly, across blocks, to recognise
+// those hashes when they reach SLOAD/SSTORE. Different shapes of hash are assumed
+// never to collide, which is what the Solidity layout itself relies on.
+
+/// Symbolic storage location
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SlotExpr {
+    Constant(U256),
+    // keccak(key . base), key is None when only known at runtime
+    Mapping {
+        base: Box<SlotExpr>,
+        key: Option<U256>,
+    },
+    // keccak(base) + index
+    Array {
+        base: Box<SlotExpr>,
+        index: Option<U256>,
+    },
+    // member of a struct stored at a hashed location
+    Offset {
+        base: Box<SlotExpr>,
+        offset: U256,
+    },
+    Unknown,
+}
+
+impl SlotExpr {
+    /// Check if two locations may be the same storage slot at runtime
+    pub fn may_alias(&self, other: &SlotExpr) -> bool {
+        let same_part = |a: &Option<U256>, b: &Option<U256>| match (a, b) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        match (self, other) {
+            (SlotExpr::Unknown, _) | (_, SlotExpr::Unknown) => true,
+            (SlotExpr::Constant(a), SlotExpr::Constant(b)) => a == b,
+            (
+                SlotExpr::Mapping { base, key },
+                SlotExpr::Mapping {
+                    base: other_base,
+                    key: other_key,
+                },
+            ) => same_part(key, other_key) && base.may_alias(other_base),
+            (
+                SlotExpr::Array { base, index },
+                SlotExpr::Array {
+                    base: other_base,
+                    index: other_index,
+                },
+            ) => same_part(index, other_index) && base.may_alias(other_base),
+            (
+                SlotExpr::Offset { base, offset },
+                SlotExpr::Offset {
+                    base: other_base,
+                    offset: other_offset,
+                },
+            ) => offset == other_offset && base.may_alias(other_base),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageAccess {
+    // index of the StorageLoad/StorageStore in the IR
+    pub index: usize,
+    pub kind: AccessKind,
+    pub slot: SlotExpr,
+}
+
+/// Storage touched by one function, not counting its callees
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageSummary {
+    // pc of the internal function entry, None for code reached from the contract entry
+    pub entry: Option<usize>,
+    pub reads: Vec<SlotExpr>,
+    pub writes: Vec<SlotExpr>,
+    // entries of the internal functions called
+    pub callees: Vec<usize>,
+}
+
+impl StorageSummary {
+    /// Check if running both functions in parallel could race on a slot
+    pub fn conflicts_with(&self, other: &StorageSummary) -> bool {
+        let any_alias =
+            |a: &[SlotExpr], b: &[SlotExpr]| a.iter().any(|x| b.iter().any(|y| x.may_alias(y)));
+        any_alias(&self.writes, &other.writes)
+            || any_alias(&self.writes, &other.reads)
+            || any_alias(&other.writes, &self.reads)
+    }
+}
+
+/// Classify the slot of every storage access in `ir`
+pub fn find_storage_accesses(ir: &[IRInstruction]) -> Vec<StorageAccess> {
+    let cfg = Cfg::build(ir);
+    let entry_states = entry_states(ir, &cfg);
+
+    let mut accesses = Vec::new();
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        let mut state = entry_states[b].clone();
+        for (index, inst) in ir.iter().enumerate().take(block.end).skip(block.start) {
+            let access = match inst {
+                IRInstruction::StorageLoad { key, .. } => Some((AccessKind::Read, key)),
+                IRInstruction::StorageStore { key, .. } => Some((AccessKind::Write, key)),
+                _ => None,
+            };
+            if let Some((kind, key)) = access {
+                accesses.push(StorageAccess {
+                    index,
+                    kind,
+                    slot: state.location(*key),
+                });
+            }
+            state.step(inst);
+        }
+    }
+    accesses
+}
+
+/// Storage accesses of the contract entry and of every internal function
+pub fn summarize_storage(ir: &[IRInstruction]) -> Vec<StorageSummary> {
+    let cfg = Cfg::build(ir);
+    let accesses = find_storage_accesses(ir);
+
+    let mut entries: Vec<Option<usize>> = vec![None];
+    for inst in ir {
+        if let IRInstruction::Call { target, .. } = inst {
+            if !entries.contains(&Some(*target)) {
+                entries.push(Some(*target));
+            }
+        }
+    }
+
+    entries
+        .into_iter()
+        .filter_map(|entry| {
+            let root = match entry {
+                Some(pc) => cfg.block_at_pc(pc)?,
+                None if cfg.is_empty() => return None,
+                None => 0,
+            };
+            // calls continue at their return address, so this steps over callees
+            let mut blocks = HashSet::from([root]);
+            let mut queue = VecDeque::from([root]);
+            while let Some(b) = queue.pop_front() {
+                for edge in &cfg.blocks[b].successors {
+                    if blocks.insert(edge.to) {
+                        queue.push_back(edge.to);
+                    }
+                }
+            }
+
+            let mut summary = StorageSummary {
+                entry,
+                reads: Vec::new(),
+                writes: Vec::new(),
+                callees: Vec::new(),
+            };
+            for access in &accesses {
+                if !blocks.contains(&cfg.block_of(access.index).unwrap()) {
+                    continue;
+                }
+                let list = match access.kind {
+                    AccessKind::Read => &mut summary.reads,
+                    AccessKind::Write => &mut summary.writes,
+                };
+                if !list.contains(&access.slot) {
+                    list.push(access.slot.clone());
+                }
+            }
+            for &b in &blocks {
+                let block = &cfg.blocks[b];
+                if let IRInstruction::Call { target, .. } = &ir[block.end - 1] {
+                    if !summary.callees.contains(target) {
+                        summary.callees.push(*target);
+                    }
+                }
+            }
+            summary.callees.sort_unstable();
+            Some(summary)
+        })
+        .collect()
+}
+
+// Abstract content of a slot or memory word
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Const(U256),
+    Location(SlotExpr),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct State {
+    slots: HashMap<U256, Value>,
+    memory: SymbolicMemory<Value>,
+}
+
+impl State {
+    fn constant(&self, slot: U256) -> Option<U256> {
+        match self.slots.get(&slot) {
+            Some(Value::Const(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn offset(&self, slot: U256) -> Option<usize> {
+        self.constant(slot)
+            .and_then(|offset| usize::try_from(offset.0).ok())
+    }
+
+    fn location(&self, slot: U256) -> SlotExpr {
+        match self.slots.get(&slot) {
+            Some(Value::Const(value)) => SlotExpr::Constant(*value),
+            Some(Value::Location(expr)) => expr.clone(),
+            None => SlotExpr::Unknown,
+        }
+    }
+
+    fn meet(&self, other: &State) -> State {
+        State {
+            slots: self
+                .slots
+                .iter()
+                .filter(|(slot, value)| other.slots.get(slot) == Some(value))
+                .map(|(slot, value)| (*slot, value.clone()))
+                .collect(),
+            memory: self.memory.meet(&other.memory),
+        }
+    }
+
+    fn step(&mut self, inst: &IRInstruction) {
+        let result = match inst {
+            IRInstruction::LoadConst { value, .. } => Some(Value::Const(*value)),
+            IRInstruction::Copy { src, .. } => self.slots.get(src).cloned(),
+            IRInstruction::Swap { a, b } => {
+                let value_a = self.slots.remove(a);
+                let value_b = self.slots.remove(b);
+                if let Some(value) = value_a {
+                    self.slots.insert(*b, value);
+                }
+                if let Some(value) = value_b {
+                    self.slots.insert(*a, value);
+                }
+                return;
+            }
+            IRInstruction::BinaryOp {
+                op: "add",
+                src1,
+                src2,
+                ..
+            } => add(self.slots.get(src1), self.slots.get(src2)),
+            IRInstruction::Sha3 { offset, size, .. } => self.hash(*offset, *size),
+            IRInstruction::MemoryStore { offset, value } => {
+                match self.offset(*offset) {
+                    Some(offset) => self.memory.store(offset, self.slots.get(value).cloned()),
+                    None => self.memory.clobber_all(),
+                }
+                None
+            }
+            IRInstruction::MemoryStore8 { offset, .. } => {
+                match self.offset(*offset) {
+                    Some(offset) => self.memory.clobber(offset, 1),
+                    None => self.memory.clobber_all(),
+                }
+                None
+            }
+            IRInstruction::BinaryOp { .. }
+            | IRInstruction::UnaryOp { .. }
+            | IRInstruction::TernaryOp { .. }
+            | IRInstruction::MemoryLoad { .. }
+            | IRInstruction::StorageLoad { .. }
+            | IRInstruction::StorageStore { .. }
+            | IRInstruction::JumpDest { .. }
+            | IRInstruction::Jump { .. }
+            | IRInstruction::ConditionalJump { .. }
+            | IRInstruction::Stop
+            | IRInstruction::Return { .. } => None,
+            IRInstruction::HostCall { kind, .. } => {
+                if kind.writes_memory() {
+                    self.memory.clobber_all();
+                }
+                None
+            }
+            // calls and anything else may write memory
+            _ => {
+                self.memory.clobber_all();
+                None
+            }
+        };
+
+        for slot in inst.defs() {
+            self.slots.remove(&slot);
+        }
+        if let (Some(dest), Some(value)) = (inst.dest(), result) {
+            self.slots.insert(dest, value);
+        }
+    }
+
+    // keccak(slot) starts an array, keccak(key . slot) is a mapping entry
+    fn hash(&self, offset: U256, size: U256) -> Option<Value> {
+        let offset = self.offset(offset)?;
+        let as_location = |value: Value| match value {
+            Value::Const(slot) => SlotExpr::Constant(slot),
+            Value::Location(expr) => expr,
+        };
+        let expr = match self.constant(size)? {
+            size if size.0 == U::from(32) => SlotExpr::Array {
+                base: Box::new(as_location(self.memory.load(offset)?)),
+                index: Some(U256::default()),
+            },
+            size if size.0 == U::from(64) => SlotExpr::Mapping {
+                base: Box::new(as_location(self.memory.load(offset + 32)?)),
+                key: match self.memory.load(offset) {
+                    Some(Value::Const(key)) => Some(key),
+                    _ => None,
+                },
+            },
+            _ => return None,
+        };
+        Some(Value::Location(expr))
+    }
+}
+
+fn add(a: Option<&Value>, b: Option<&Value>) -> Option<Value> {
+    let expr = match (a, b) {
+        (Some(Value::Const(a)), Some(Value::Const(b))) => {
+            return Some(Value::Const(U256(a.0.wrapping_add(b.0))))
+        }
+        (Some(Value::Location(expr)), other) | (other, Some(Value::Location(expr))) => {
+            let constant = match other {
+                Some(Value::Const(value)) => Some(*value),
+                _ => None,
+            };
+            match (expr, constant) {
+                (SlotExpr::Array { base, index }, constant) => SlotExpr::Array {
+                    base: base.clone(),
+                    index: index
+                        .zip(constant)
+                        .map(|(index, constant)| U256(index.0.wrapping_add(constant.0))),
+                },
+                (SlotExpr::Offset { base, offset }, Some(constant)) => SlotExpr::Offset {
+                    base: base.clone(),
+                    offset: U256(offset.0.wrapping_add(constant.0)),
+                },
+                (SlotExpr::Mapping { .. }, Some(constant)) => SlotExpr::Offset {
+                    base: Box::new(expr.clone()),
+                    offset: constant,
+                },
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    Some(Value::Location(expr))
+}
+
+// Slots and memory known on entry to each block, what all predecessors agree on
+fn entry_states(ir: &[IRInstruction], cfg: &Cfg) -> Vec<State> {
+    let n = cfg.len();
+    let mut exits: Vec<Option<State>> = vec![None; n];
+    let mut entries = vec![State::default(); n];
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (b, block) in cfg.blocks.iter().enumerate() {
+            let entry = if b == 0 || block.predecessors.is_empty() {
+                State::default()
+            } else {
+                let mut known = block.predecessors.iter().filter_map(|&p| exits[p].as_ref());
+                let Some(first) = known.next() else {
+                    continue;
+                };
+                known.fold(first.clone(), |acc, state| acc.meet(state))
+            };
+
+            let mut state = entry.clone();
+            for inst in &ir[block.start..block.end] {
+                state.step(inst);
+            }
+            // a callee may leave anything in memory and in its result slots
+            if let IRInstruction::Call { .. } = &ir[block.end - 1] {
+                state.memory.clobber_all();
+            }
+
+            entries[b] = entry;
+            if exits[b].as_ref() != Some(&state) {
+                exits[b] = Some(state);
+                changed = true;
+            }
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::test_util::{load, mstore, s};
+
+    fn sha3(size: u64, offset: u64) -> Vec<IRInstruction> {
+        vec![
+            load(1, size),
+            load(2, offset),
+            IRInstruction::Sha3 {
+                dest: s(1),
+                offset: s(2),
+                size: s(1),
+            },
+        ]
+    }
+
+    fn sload(slot: u64) -> IRInstruction {
+        IRInstruction::StorageLoad {
+            key: s(slot),
+            dest: s(slot),
+        }
+    }
+
+    fn constant(n: u64) -> SlotExpr {
+        SlotExpr::Constant(s(n))
+    }
+
+    fn mapping(base: SlotExpr, key: Option<u64>) -> SlotExpr {
+        SlotExpr::Mapping {
+            base: Box::new(base),
+            key: key.map(s),
+        }
+    }
+
+    // mstore(0, <slot 1>) mstore(0x20, base) keccak256(0, 0x40)
+    fn mapping_entry(base: u64) -> Vec<IRInstruction> {
+        let mut ir = vec![
+            load(2, 0),
+            mstore(2, 1),
+            load(1, base),
+            load(2, 0x20),
+            mstore(2, 1),
+        ];
+        ir.extend(sha3(0x40, 0));
+        ir
+    }
+
+    #[test]
+    fn test_constant_slot() {
+        let ir = vec![load(1, 3), sload(1), IRInstruction::Stop];
+        let accesses = find_storage_accesses(&ir);
+
+        assert_eq!(
+            accesses,
+            vec![StorageAccess {
+                index: 1,
+                kind: AccessKind::Read,
+                slot: constant(3),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_mapping_entries() {
+        // balances[msg.sender], then allowance[msg.sender][7]
+        let mut ir = mapping_entry(5);
+        ir.push(sload(1));
+        ir.extend(mapping_entry(6));
+        ir.extend([
+            load(2, 0x20),
+            mstore(2, 1),
+            load(1, 7),
+            load(2, 0),
+            mstore(2, 1),
+        ]);
+        ir.extend(sha3(0x40, 0));
+        ir.push(IRInstruction::StorageStore {
+            key: s(1),
+            value: s(3),
+        });
+
+        let accesses = find_storage_accesses(&ir);
+        assert_eq!(accesses.len(), 2);
+        assert_eq!(accesses[0].slot, mapping(constant(5), None));
+        assert_eq!(accesses[1].kind, AccessKind::Write);
+        assert_eq!(
+            accesses[1].slot,
+            mapping(mapping(constant(6), None), Some(7))
+        );
+    }
+
+    #[test]
+    fn test_array_element_and_struct_member() {
+        let mut ir = vec![load(1, 2), load(2, 0), mstore(2, 1)];
+        ir.extend(sha3(0x20, 0));
+        ir.extend([
+            load(2, 3),
+            IRInstruction::BinaryOp {
+                op: "add",
+                dest: s(1),
+                src1: s(2),
+                src2: s(1),
+            },
+            sload(1),
+            // the same array indexed by a runtime value
+            load(1, 2),
+            load(2, 0),
+            mstore(2, 1),
+        ]);
+        ir.extend(sha3(0x20, 0));
+        ir.extend([
+            IRInstruction::BinaryOp {
+                op: "add",
+                dest: s(1),
+                src1: s(9),
+                src2: s(1),
+            },
+            sload(1),
+        ]);
+        ir.extend(mapping_entry(4));
+        ir.extend([
+            load(2, 1),
+            IRInstruction::BinaryOp {
+                op: "add",
+                dest: s(1),
+                src1: s(2),
+                src2: s(1),
+            },
+            sload(1),
+        ]);
+
+        let slots: Vec<SlotExpr> = find_storage_accesses(&ir)
+            .into_iter()
+            .map(|access| access.slot)
+            .collect();
+        assert_eq!(
+            slots,
+            vec![
+                SlotExpr::Array {
+                    base: Box::new(constant(2)),
+                    index: Some(s(3)),
+                },
+                SlotExpr::Array {
+                    base: Box::new(constant(2)),
+                    index: None,
+                },
+                SlotExpr::Offset {
+                    base: Box::new(mapping(constant(4), None)),
+                    offset: s(1),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hash_input_across_blocks() {
+        let mut ir = vec![
+            load(2, 0),
+            mstore(2, 1),
+            load(1, 5),
+            load(2, 0x20),
+            mstore(2, 1),
+        ];
+        ir.push(IRInstruction::JumpDest { pc: 30 });
+        ir.extend(sha3(0x40, 0));
+        ir.push(sload(1));
+
+        let accesses = find_storage_accesses(&ir);
+        assert_eq!(accesses[0].slot, mapping(constant(5), None));
+    }
+
+    #[test]
+    fn test_aliasing() {
+        let a = mapping(constant(5), None);
+        let b = mapping(constant(6), None);
+        assert!(!a.may_alias(&b));
+        assert!(a.may_alias(&mapping(constant(5), Some(1))));
+        assert!(!mapping(constant(5), Some(1)).may_alias(&mapping(constant(5), Some(2))));
+        assert!(!a.may_alias(&constant(5)));
+        assert!(SlotExpr::Unknown.may_alias(&constant(0)));
+    }
+
+    #[test]
+    fn test_summary_per_function() {
+        let ir = vec![
+            load(1, 0),
+            sload(1),
+            IRInstruction::Call {
+                target: 10,
+                return_pc: 20,
+                args: vec![],
+                returns: vec![],
+            },
+            IRInstruction::JumpDest { pc: 10 },
+            load(1, 7),
+            load(2, 1),
+            IRInstruction::StorageStore {
+                key: s(2),
+                value: s(1),
+            },
+            IRInstruction::Return { values: vec![] },
+            IRInstruction::JumpDest { pc: 20 },
+            IRInstruction::Stop,
+        ];
+        let summaries = summarize_storage(&ir);
+
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].entry, None);
+        assert_eq!(summaries[0].reads, vec![constant(0)]);
+        assert!(summaries[0].writes.is_empty());
+        assert_eq!(summaries[0].callees, vec![10]);
+        assert_eq!(summaries[1].entry, Some(10));
+        assert_eq!(summaries[1].writes, vec![constant(1)]);
+        assert!(!summaries[0].conflicts_with(&summaries[1]));
+
+        let writer = StorageSummary {
+            entry: None,
+            reads: Vec::new(),
+            writes: vec![constant(0)],
+            callees: Vec::new(),
+        };
+        assert!(writer.conflicts_with(&summaries[0]));
+    }
+}
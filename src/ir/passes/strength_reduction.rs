@@ -0,0 +1,362 @@
+use super::constants::ConstantSlots;
+use crate::ir::gas::parser::IRInstruction;
+use crate::MyU256 as U256;
+use alloy_primitives::U256 as U;
+
+// Strength reduction for 256-bit arithmetic
+//
+// Generic `mul`, `div`, `mod`, `exp`, `addmod` and `mulmod` turn into long limb
+// sequences (or runtime calls) on RV64, so whenever one operand is a known power of
+// two the operation is rewritten into shifts, masks and plain adds. Shift amounts and
+// masks are materialised into the constant operand's slot, which the operation
+// consumes anyway.
+
+/// Rewrite expensive arithmetic with constant operands, returns true if anything changed
+pub fn reduce_strength(ir: &mut Vec<IRInstruction>) -> bool {
+    reduce_strength_with_pcs(ir, &mut Vec::new())
+}
+
+/// `reduce_strength`, keeping the EVM pc of every instruction in `pcs` (if it
+/// isn't empty) in step, a rewrite keeps the pc of what it replaced
+pub fn reduce_strength_with_pcs(ir: &mut Vec<IRInstruction>, pcs: &mut Vec<usize>) -> bool {
+    let mut constants = ConstantSlots::new();
+    let mut reduced = Vec::with_capacity(ir.len());
+    let old_pcs = std::mem::take(pcs);
+    let mut changed = false;
+
+    for (index, inst) in ir.drain(..).enumerate() {
+        match rewrite(&inst, &constants) {
+            Some(replacement) => {
+                for new_inst in &replacement {
+                    constants.observe(new_inst);
+                }
+                reduced.extend(replacement);
+                changed = true;
+            }
+            None => {
+                constants.observe(&inst);
+                reduced.push(inst);
+            }
+        }
+        if let Some(&pc) = old_pcs.get(index) {
+            pcs.resize(reduced.len(), pc);
+        }
+    }
+
+    *ir = reduced;
+    changed
+}
+
+fn rewrite(inst: &IRInstruction, constants: &ConstantSlots) -> Option<Vec<IRInstruction>> {
+    match *inst {
+        IRInstruction::BinaryOp {
+            op,
+            dest,
+            src1,
+            src2,
+        } if src1 != src2 => match op {
+            "mul" => {
+                // multiplication is commutative, the constant can sit in either slot
+                let (value, factor, factor_slot) = match constants.get(src2) {
+                    Some(factor) => (src1, factor, src2),
+                    None => (src2, constants.get(src1)?, src1),
+                };
+                if factor.0.is_zero() {
+                    return Some(vec![load(dest, U256::default())]);
+                }
+                let shift = log2_exact(factor)?;
+                Some(shift_by("shl", dest, factor_slot, value, shift))
+            }
+            "div" => {
+                let divisor = constants.get(src2)?;
+                if divisor.0.is_zero() {
+                    return Some(vec![load(dest, U256::default())]);
+                }
+                let shift = log2_exact(divisor)?;
+                Some(shift_by("shr", dest, src2, src1, shift))
+            }
+            "mod" => {
+                let modulus = constants.get(src2)?;
+                if modulus.0.is_zero() {
+                    return Some(vec![load(dest, U256::default())]);
+                }
+                let bits = log2_exact(modulus)?;
+                Some(vec![
+                    load(src2, low_mask(bits)),
+                    IRInstruction::BinaryOp {
+                        op: "and",
+                        dest,
+                        src1,
+                        src2,
+                    },
+                ])
+            }
+            "exp" => {
+                if constants.get(src1) == Some(constant(2)) {
+                    // 2 ** e == 1 << e, including the e >= 256 case where both are 0
+                    return Some(vec![
+                        load(src1, constant(1)),
+                        IRInstruction::BinaryOp {
+                            op: "shl",
+                            dest,
+                            src1: src2,
+                            src2: src1,
+                        },
+                    ]);
+                }
+                let exponent = constants.get(src2)?;
+                if exponent == constant(0) {
+                    Some(vec![load(dest, constant(1))])
+                } else if exponent == constant(1) {
+                    Some(shift_by("shl", dest, src2, src1, 0))
+                } else if exponent == constant(2) {
+                    Some(vec![IRInstruction::BinaryOp {
+                        op: "mul",
+                        dest,
+                        src1,
+                        src2: src1,
+                    }])
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        },
+        IRInstruction::TernaryOp {
+            op,
+            dest,
+            src1,
+            src2,
+            src3,
+        } if src1 != src2 && (op == "addmod" || op == "mulmod") => {
+            let modulus = constants.get(src3)?;
+            if modulus.0 <= U::from(1) {
+                return Some(vec![load(dest, U256::default())]);
+            }
+            // 2**256 is a multiple of any power of two modulus, so the wrapping
+            // add/mul followed by a mask gives the exact result
+            let bits = log2_exact(modulus)?;
+            let op = if op == "addmod" { "add" } else { "mul" };
+            Some(vec![
+                IRInstruction::BinaryOp {
+                    op,
+                    dest: src2,
+                    src1,
+                    src2,
+                },
+                load(src1, low_mask(bits)),
+                IRInstruction::BinaryOp {
+                    op: "and",
+                    dest,
+                    src1,
+                    src2,
+                },
+            ])
+        }
+        _ => None,
+    }
+}
+
+// Load the shift amount into `shift_slot`, then shift `value_slot` into `dest`
+fn shift_by(
+    op: &'static str,
+    dest: U256,
+    shift_slot: U256,
+    value_slot: U256,
+    shift: usize,
+) -> Vec<IRInstruction> {
+    vec![
+        load(shift_slot, constant(shift as u64)),
+        IRInstruction::BinaryOp {
+            op,
+            dest,
+            src1: shift_slot,
+            src2: value_slot,
+        },
+    ]
+}
+
+fn load(dest: U256, value: U256) -> IRInstruction {
+    IRInstruction::LoadConst { dest, value }
+}
+
+fn constant(value: u64) -> U256 {
+    U256(U::from(value))
+}
+
+fn log2_exact(value: U256) -> Option<usize> {
+    if value.0.is_power_of_two() {
+        Some(value.0.trailing_zeros())
+    } else {
+        None
+    }
+}
+
+// Mask with the low `bits` bits set
+fn low_mask(bits: usize) -> U256 {
+    U256((U::from(1) << bits) - U::from(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::test_util::{binary, s};
+
+    fn assert_binary(inst: &IRInstruction, expected_op: &str, dest: u64, src1: u64, src2: u64) {
+        match inst {
+            IRInstruction::BinaryOp {
+                op,
+                dest: d,
+                src1: a,
+                src2: b,
+            } => {
+                assert_eq!(*op, expected_op);
+                assert_eq!((*d, *a, *b), (s(dest), s(src1), s(src2)));
+            }
+            other => panic!("Expected BinaryOp, got {:?}", other),
+        }
+    }
+
+    fn assert_load(inst: &IRInstruction, dest: U256, expected: U256) {
+        match inst {
+            IRInstruction::LoadConst { dest: d, value } => {
+                assert_eq!(*d, dest);
+                assert_eq!(*value, expected);
+            }
+            other => panic!("Expected LoadConst, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_mul_by_power_of_two() {
+        // x * 8 with the constant on top of the stack
+        let mut ir = vec![load(s(2), s(8)), binary("mul", 1, 2, 1)];
+        assert!(reduce_strength(&mut ir));
+
+        assert_eq!(ir.len(), 3);
+        assert_load(&ir[1], s(2), s(3));
+        assert_binary(&ir[2], "shl", 1, 2, 1);
+    }
+
+    #[test]
+    fn test_mul_constant_below() {
+        let mut ir = vec![load(s(1), s(4)), binary("mul", 1, 2, 1)];
+        assert!(reduce_strength(&mut ir));
+
+        assert_load(&ir[1], s(1), s(2));
+        assert_binary(&ir[2], "shl", 1, 1, 2);
+    }
+
+    #[test]
+    fn test_mul_by_zero() {
+        let mut ir = vec![load(s(2), s(0)), binary("mul", 1, 2, 1)];
+        assert!(reduce_strength(&mut ir));
+        assert_load(&ir[1], s(1), s(0));
+    }
+
+    #[test]
+    fn test_div_by_power_of_two() {
+        let mut ir = vec![load(s(1), s(32)), binary("div", 1, 2, 1)];
+        assert!(reduce_strength(&mut ir));
+
+        assert_load(&ir[1], s(1), s(5));
+        assert_binary(&ir[2], "shr", 1, 1, 2);
+    }
+
+    #[test]
+    fn test_div_numerator_constant_untouched() {
+        // only the divisor matters
+        let mut ir = vec![load(s(2), s(32)), binary("div", 1, 2, 1)];
+        assert!(!reduce_strength(&mut ir));
+        assert_eq!(ir.len(), 2);
+    }
+
+    #[test]
+    fn test_mod_by_power_of_two() {
+        let mut ir = vec![load(s(1), s(256)), binary("mod", 1, 2, 1)];
+        assert!(reduce_strength(&mut ir));
+
+        assert_load(&ir[1], s(1), s(0xFF));
+        assert_binary(&ir[2], "and", 1, 2, 1);
+    }
+
+    #[test]
+    fn test_exp_base_two() {
+        let mut ir = vec![load(s(2), s(2)), binary("exp", 1, 2, 1)];
+        assert!(reduce_strength(&mut ir));
+
+        assert_load(&ir[1], s(2), s(1));
+        assert_binary(&ir[2], "shl", 1, 1, 2);
+    }
+
+    #[test]
+    fn test_exp_small_exponents() {
+        let mut ir = vec![load(s(1), s(0)), binary("exp", 1, 2, 1)];
+        assert!(reduce_strength(&mut ir));
+        assert_load(&ir[1], s(1), s(1));
+
+        let mut ir = vec![load(s(1), s(2)), binary("exp", 1, 2, 1)];
+        assert!(reduce_strength(&mut ir));
+        assert_binary(&ir[1], "mul", 1, 2, 2);
+    }
+
+    #[test]
+    fn test_addmod_power_of_two() {
+        let mut ir = vec![
+            load(s(1), s(1 << 16)),
+            IRInstruction::TernaryOp {
+                op: "addmod",
+                dest: s(1),
+                src1: s(3),
+                src2: s(2),
+                src3: s(1),
+            },
+        ];
+        assert!(reduce_strength(&mut ir));
+
+        assert_eq!(ir.len(), 4);
+        assert_binary(&ir[1], "add", 2, 3, 2);
+        assert_load(&ir[2], s(3), s(0xFFFF));
+        assert_binary(&ir[3], "and", 1, 3, 2);
+    }
+
+    #[test]
+    fn test_mulmod_trivial_modulus() {
+        let mut ir = vec![
+            load(s(1), s(1)),
+            IRInstruction::TernaryOp {
+                op: "mulmod",
+                dest: s(1),
+                src1: s(3),
+                src2: s(2),
+                src3: s(1),
+            },
+        ];
+        assert!(reduce_strength(&mut ir));
+        assert_load(&ir[1], s(1), s(0));
+    }
+
+    #[test]
+    fn test_non_power_of_two_untouched() {
+        let mut ir = vec![load(s(2), s(10)), binary("mul", 1, 2, 1)];
+        assert!(!reduce_strength(&mut ir));
+        assert_binary(&ir[1], "mul", 1, 2, 1);
+    }
+
+    #[test]
+    fn test_constants_forgotten_after_overwrite_and_jump() {
+        let mut ir = vec![
+            load(s(2), s(8)),
+            binary("add", 2, 3, 2),
+            binary("mul", 1, 2, 1),
+        ];
+        assert!(!reduce_strength(&mut ir));
+
+        let mut ir = vec![
+            load(s(2), s(8)),
+            IRInstruction::Jump { target: s(0) },
+            binary("mul", 1, 2, 1),
+        ];
+        assert!(!reduce_strength(&mut ir));
+    }
+}
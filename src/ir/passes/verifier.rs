@@ -0,0 +1,173 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::ir::cfg::graph::Cfg;
+use crate::ir::gas::parser::IRInstruction;
+use crate::MyU256 as U256;
+
+// IR verifier
+//
+// Structural checks every pass has to preserve. They are cheap enough to run
+// after each pass, which pins a broken rewrite on the pass that made it instead
+// of on whatever later trips over the result.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyErrorKind {
+    // two JUMPDESTs claim the same pc
+    DuplicateJumpDest { pc: usize },
+    // statically resolved jump to a pc without a JUMPDEST
+    InvalidJump { target: usize },
+    // call into or return to a pc without a JUMPDEST
+    InvalidCall { pc: usize },
+    // slots are numbered from 1, only a `pop` writes slot 0
+    ZeroSlot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyError {
+    // index of the offending instruction
+    pub index: usize,
+    pub reason: VerifyErrorKind,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "instruction {}: ", self.index)?;
+        match self.reason {
+            VerifyErrorKind::DuplicateJumpDest { pc } => {
+                write!(f, "duplicate JUMPDEST at pc {pc:#x}")
+            }
+            VerifyErrorKind::InvalidJump { target } => {
+                write!(f, "jump to {target:#x}, which is not a JUMPDEST")
+            }
+            VerifyErrorKind::InvalidCall { pc } => {
+                write!(f, "call references {pc:#x}, which is not a JUMPDEST")
+            }
+            VerifyErrorKind::ZeroSlot => write!(f, "slot 0 used"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Check the structural invariants of `ir`
+pub fn verify(ir: &[IRInstruction]) -> Result<(), VerifyError> {
+    let mut jumpdests = HashSet::new();
+    for (index, inst) in ir.iter().enumerate() {
+        if let IRInstruction::JumpDest { pc } = inst {
+            if !jumpdests.insert(*pc) {
+                return Err(VerifyError {
+                    index,
+                    reason: VerifyErrorKind::DuplicateJumpDest { pc: *pc },
+                });
+            }
+        }
+    }
+
+    let zero = U256::default();
+    for (index, inst) in ir.iter().enumerate() {
+        let fail = |reason| Err(VerifyError { index, reason });
+
+        let writes_zero = match inst {
+            IRInstruction::UnaryOp { op: "pop", .. } => false,
+            _ => inst.defs().contains(&zero),
+        };
+        if writes_zero || inst.sources().contains(&zero) {
+            return fail(VerifyErrorKind::ZeroSlot);
+        }
+
+        if let IRInstruction::Call {
+            target, return_pc, ..
+        } = inst
+        {
+            for pc in [*target, *return_pc] {
+                if !jumpdests.contains(&pc) {
+                    return fail(VerifyErrorKind::InvalidCall { pc });
+                }
+            }
+        }
+    }
+
+    let cfg = Cfg::build(ir);
+    for block in &cfg.blocks {
+        if let Some(target) = block.target {
+            if !jumpdests.contains(&target) {
+                return Err(VerifyError {
+                    index: block.end - 1,
+                    reason: VerifyErrorKind::InvalidJump { target },
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::gas::parser::{generate_ir, parse_bytecode};
+    use crate::ir::memory::{memory::Memory, stack::Stack};
+    use crate::ir::test_util::s;
+
+    #[test]
+    fn test_generated_ir_verifies() {
+        // push1 4 jump stop jumpdest pop(push1 1) stop
+        let bytecode = [0x60, 0x04, 0x56, 0x00, 0x5b, 0x60, 0x01, 0x50, 0x00];
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        let ir = generate_ir(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+
+        assert_eq!(verify(&ir), Ok(()));
+    }
+
+    #[test]
+    fn test_broken_ir() {
+        let jump_to = |target| {
+            vec![
+                IRInstruction::LoadConst {
+                    dest: s(1),
+                    value: s(target),
+                },
+                IRInstruction::Jump { target: s(1) },
+                IRInstruction::JumpDest { pc: 7 },
+                IRInstruction::Stop,
+            ]
+        };
+        assert_eq!(verify(&jump_to(7)), Ok(()));
+        assert_eq!(
+            verify(&jump_to(6)),
+            Err(VerifyError {
+                index: 1,
+                reason: VerifyErrorKind::InvalidJump { target: 6 },
+            })
+        );
+
+        let mut ir = jump_to(7);
+        ir.push(IRInstruction::JumpDest { pc: 7 });
+        assert_eq!(
+            verify(&ir).unwrap_err().reason,
+            VerifyErrorKind::DuplicateJumpDest { pc: 7 }
+        );
+
+        let mut ir = jump_to(7);
+        ir.insert(
+            3,
+            IRInstruction::Call {
+                target: 7,
+                return_pc: 9,
+                args: vec![],
+                returns: vec![],
+            },
+        );
+        assert_eq!(
+            verify(&ir).unwrap_err().reason,
+            VerifyErrorKind::InvalidCall { pc: 9 }
+        );
+
+        let ir = vec![IRInstruction::Copy {
+            dest: s(1),
+            src: s(0),
+        }];
+        assert_eq!(verify(&ir).unwrap_err().reason, VerifyErrorKind::ZeroSlot);
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use crate::ir::gas::parser::Instruction;
+
+// EVM pc <-> RISC-V offset mapping
+//
+// `generate_ir_with_pcs` tags every IR instruction with the pc of the EVM
+// instruction it came from, `PassManager::run_with_pcs` keeps the tags through
+// optimisation, and instruction selection hands that pc on to
+// every RISC-V instruction it emits for it. Consecutive RISC-V instructions
+// from the same pc are kept as one run of code.
+
+/// Size of an (uncompressed) RISC-V instruction in bytes
+pub const INSTRUCTION_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    // start pc of every EVM instruction -> end of its bytes (opcode and operand)
+    evm: BTreeMap<usize, usize>,
+    // byte ranges of RISC-V code, in order, with the pc they were generated from
+    runs: Vec<(Range<usize>, usize)>,
+}
+
+impl SourceMap {
+    /// Build the map for code where RISC-V instruction `i` came from `riscv_pcs[i]`
+    pub fn new(instructions: &[Instruction], riscv_pcs: &[usize]) -> Self {
+        let mut evm = BTreeMap::new();
+        let mut pc = 0;
+        for inst in instructions {
+            let end = pc + 1 + inst.operand.as_ref().map_or(0, |operand| operand.len());
+            evm.insert(pc, end);
+            pc = end;
+        }
+
+        let mut runs: Vec<(Range<usize>, usize)> = Vec::new();
+        for (i, &pc) in riscv_pcs.iter().enumerate() {
+            let offset = i * INSTRUCTION_SIZE;
+            match runs.last_mut() {
+                Some((code, last)) if *last == pc => code.end = offset + INSTRUCTION_SIZE,
+                _ => runs.push((offset..offset + INSTRUCTION_SIZE, pc)),
+            }
+        }
+
+        SourceMap { evm, runs }
+    }
+
+    /// Bytes of the EVM instruction that produced the RISC-V code at byte `offset`
+    pub fn evm_range(&self, offset: usize) -> Option<Range<usize>> {
+        let run = self.runs.partition_point(|(code, _)| code.end <= offset);
+        let (code, pc) = self.runs.get(run)?;
+        if !code.contains(&offset) {
+            return None;
+        }
+        let end = self.evm.get(pc).copied().unwrap_or(pc + 1);
+        Some(*pc..end)
+    }
+
+    /// RISC-V code generated for the EVM instruction covering `pc`, in order
+    ///
+    /// Optimisations can move parts of an instruction elsewhere (out of a loop,
+    /// into a caller), so there may be several ranges.
+    pub fn riscv_ranges(&self, pc: usize) -> Vec<Range<usize>> {
+        let start = match self.evm.range(..=pc).next_back() {
+            Some((&start, &end)) if pc < end => start,
+            _ => pc,
+        };
+        self.runs
+            .iter()
+            .filter(|(_, run_pc)| *run_pc == start)
+            .map(|(code, _)| code.clone())
+            .collect()
+    }
+
+    /// Runs of RISC-V code with the pc they came from, in code order
+    pub fn iter(&self) -> impl Iterator<Item = (Range<usize>, usize)> + '_ {
+        self.runs.iter().cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::gas::parser::{generate_ir_with_pcs, parse_bytecode};
+    use crate::ir::memory::{memory::Memory, stack::Stack};
+
+    #[test]
+    fn test_ir_pcs() {
+        // push1 1 dup1 push2 0x0100 mstore stop
+        let bytecode = [0x60, 0x01, 0x80, 0x61, 0x01, 0x00, 0x52, 0x00];
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        let (ir, pcs) =
+            generate_ir_with_pcs(&instructions, &mut Stack::new(), &mut Memory::new()).unwrap();
+
+        assert_eq!(ir.len(), pcs.len());
+        assert_eq!(pcs, vec![0, 2, 3, 6, 7]);
+    }
+
+    #[test]
+    fn test_lookup_both_ways() {
+        // push1 1 dup1 push2 0x0100 mstore stop
+        let bytecode = [0x60, 0x01, 0x80, 0x61, 0x01, 0x00, 0x52, 0x00];
+        let instructions = parse_bytecode(&bytecode).unwrap();
+        // the constant from pc 3 was hoisted in front of the rest
+        let riscv_pcs = [3, 3, 0, 2, 6, 6, 6, 3, 7];
+        let map = SourceMap::new(&instructions, &riscv_pcs);
+
+        assert_eq!(map.evm_range(0), Some(3..6));
+        assert_eq!(map.evm_range(7), Some(3..6));
+        assert_eq!(map.evm_range(8), Some(0..2));
+        assert_eq!(map.evm_range(16), Some(6..7));
+        assert_eq!(map.evm_range(24), Some(6..7));
+        assert_eq!(map.evm_range(32), Some(7..8));
+        assert_eq!(map.evm_range(36), None);
+
+        assert_eq!(map.riscv_ranges(3), vec![0..8, 28..32]);
+        // pcs inside a push operand belong to the push
+        assert_eq!(map.riscv_ranges(5), vec![0..8, 28..32]);
+        assert_eq!(map.riscv_ranges(6), vec![16..28]);
+        assert_eq!(map.riscv_ranges(9), Vec::<Range<usize>>::new());
+        assert_eq!(map.iter().count(), 6);
+    }
+}
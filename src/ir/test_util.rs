@@ -0,0 +1,34 @@
+use crate::ir::gas::parser::IRInstruction;
+use crate::MyU256 as U256;
+use alloy_primitives::U256 as U;
+
+// Shorthands for building IR in tests
+
+/// Slot or value `n`
+pub(crate) fn s(n: u64) -> U256 {
+    U256(U::from(n))
+}
+
+/// `value` loaded into slot `dest`
+pub(crate) fn load(dest: u64, value: u64) -> IRInstruction {
+    IRInstruction::LoadConst {
+        dest: s(dest),
+        value: s(value),
+    }
+}
+
+pub(crate) fn binary(op: &'static str, dest: u64, src1: u64, src2: u64) -> IRInstruction {
+    IRInstruction::BinaryOp {
+        op,
+        dest: s(dest),
+        src1: s(src1),
+        src2: s(src2),
+    }
+}
+
+pub(crate) fn mstore(offset: u64, value: u64) -> IRInstruction {
+    IRInstruction::MemoryStore {
+        offset: s(offset),
+        value: s(value),
+    }
+}
@@ -10,6 +10,11 @@ use std::ops::{Add, Div, Mul, Rem, Sub};
 use alloy_primitives::{ruint::ToUintError, U256};
 
 #[derive(Debug, PartialEq, Clone, Copy, Default, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
 pub struct MyU256(U256);
 
 impl TryFrom<[u8; 32]> for MyU256 {
@@ -84,7 +89,7 @@ impl MyU256 {
 
     fn u256_to_usize(bytes: [u8; 32]) -> usize {
         let mut result = 0usize;
-        for &byte in bytes.iter().rev().take(8) {
+        for &byte in bytes.iter().skip(24) {
             result = (result << 8) | (byte as usize);
         }
         result
@@ -129,21 +134,31 @@ impl I256 {
     }
 }
 
+impl Div for I256 {
+    type Output = Self;
+
+    fn div(self, rhs: Self) -> Self::Output {
+        // truncates towards zero, x / 0 is 0 like in the EVM
+        let q = self.abs().0.checked_div(rhs.abs().0).unwrap_or(U256::ZERO);
+        if self.is_negative() != rhs.is_negative() {
+            I256(q.wrapping_neg())
+        } else {
+            I256(q)
+        }
+    }
+}
+
 impl Rem for I256 {
     type Output = Self;
 
     fn rem(self, rhs: Self) -> Self::Output {
-        //TODO
-        let a = self.0;
-        let b = rhs.0;
-        let _r = a % b;
-        let _sign = if self.is_negative() ^ rhs.is_negative() {
-            -1
+        // the result takes the sign of the dividend, x % 0 is 0 like in the EVM
+        let r = self.abs().0.checked_rem(rhs.abs().0).unwrap_or(U256::ZERO);
+        if self.is_negative() {
+            I256(r.wrapping_neg())
         } else {
-            1
-        };
-        // I256(r * sign);
-        todo!()
+            I256(r)
+        }
     }
 }
 